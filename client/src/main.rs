@@ -1,3 +1,4 @@
+mod media_sync;
 mod server_handler;
 mod services;
 
@@ -11,10 +12,10 @@ use tracing_subscriber::{
 };
 
 use crate::{
-    server_handler::{Client, ServerHandlerError},
-    services::{midi::MidiService, playback::{PlaybackService, PlaybackServiceError}},
+    server_handler::{Client, ClientServices, ServerHandlerError},
+    services::{dmx::{DmxPatch, DmxService}, midi::MidiService, playback::{PlaybackService, PlaybackServiceError}},
 };
-use lamarrs_utils::{AudioFile, ColourRgb, MidiInstruction, Service as ServerService};
+use lamarrs_utils::{action_messages::{GroupName, MAX_GROUP_NAME_LENGTH}, audio::AudioControl, colour_effect::ColourEffect, subtitles::{LanguageTag, MAX_LANGUAGE_TAG_LENGTH}, AudioPlayback, ColourRgb, MidiInstruction, Position, Service as ServerService};
 use tokio::sync::mpsc::Sender;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -30,11 +31,14 @@ const VERSION: &str = env!("CARGO_PKG_VERSION");
 pub enum InternalEventMessageClient {
     ConnectedToServer(Sender<InternalEventMessageClient>),
     SubscribeToService(ServerService),
-    PlayAudio(AudioFile, Sender<InternalEventMessageClient>),
+    PlayAudio(Box<AudioPlayback>, Sender<InternalEventMessageClient>),
+    ControlAudio(AudioControl, Sender<InternalEventMessageClient>),
     ShowSubtitles(String, Sender<InternalEventMessageClient>),
-    NewMIDIMessage(MidiInstruction, Sender<InternalEventMessageClient>),
+    NewMIDIMessage(Box<MidiInstruction>, Sender<InternalEventMessageClient>),
     NewDmxColour(ColourRgb, Sender<InternalEventMessageClient>),
+    NewDmxColourEffect(ColourEffect, Sender<InternalEventMessageClient>),
     NewLedColour(ColourRgb, Sender<InternalEventMessageClient>),
+    ActionFailed(String), // An action requested by the Server could not be performed.
     Config(serde_json::Value), // Joker kind, must dissapear in the future.
 }
 
@@ -80,15 +84,25 @@ pub struct Args {
     /// Port number
     #[arg(short, long, default_value_t = 8080)]
     pub port: u16,
-    /// Relative position of the client. Unsupported by now.
-    //#[arg(long, default_value=None)]
-    //pub location: Option<RelativeLocation>,
+    /// Position of the client in the venue, as `x,y` normalised between 0 and 1.
+    #[arg(long)]
+    pub position: Option<Position>,
+    /// Group of clients this client belongs to, like `band monitors`. Can be repeated.
+    #[arg(long = "group")]
+    pub groups: Vec<String>,
+    /// Language the client prefers its subtitles in, as a BCP 47 tag like `es` or `pt-BR`.
+    #[arg(long)]
+    pub language: Option<String>,
     /// Relative Path to the executable where to look for the media to be used by
     /// the Client Services that executes files.
     #[arg(long)]
     pub media_path: PathBuf,
     #[arg(long)]
     pub output_midi_port_name: String,
+    /// Relative Path to the executable where to look for the YAML DMX patch. If given, the
+    /// colours are shown on the patched fixtures through Art-Net or sACN.
+    #[arg(long)]
+    pub dmx_patch_path: Option<PathBuf>,
 }
 
 #[tokio::main]
@@ -104,22 +118,57 @@ async fn main() -> Result<()> {
     // Order of creation is important, since the channels
     // pipelines to send and receive messages to each Actor.
     debug!("Creating Playback Service");
-    let mut playback_service = PlaybackService::new(args.media_path);
+    let mut playback_service = PlaybackService::new(args.media_path.clone());
     debug!("Creating MIDI Service");
     let mut midi_service = MidiService::try_new(args.output_midi_port_name, None).wrap_err("Failed to create Midi Service.")?;
 
+    let mut dmx_service = match &args.dmx_patch_path {
+        Some(dmx_patch_path) => {
+            debug!("Creating DMX Service");
+            let patch = DmxPatch::load(dmx_patch_path)
+                .wrap_err_with(|| format!("Failed to load DMX patch {}", dmx_patch_path.display()))?;
+            Some(DmxService::try_new(patch).await.wrap_err("Failed to create DMX Service.")?)
+        }
+        None => None,
+    };
+
     let server_address = Uri::builder()
         .scheme("ws")
         .authority(format!("{}:{}", args.server, args.port))
         .path_and_query("/")
         .build()?;
 
+    let groups = args
+        .groups
+        .iter()
+        .map(|group| {
+            GroupName::try_from(group.as_str()).map_err(|_| {
+                eyre!("Group name {group:?} is longer than {MAX_GROUP_NAME_LENGTH} characters.")
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let language = args
+        .language
+        .map(|language| {
+            LanguageTag::try_from(language.as_str()).map_err(|_| {
+                eyre!("Language {language:?} is longer than {MAX_LANGUAGE_TAG_LENGTH} characters.")
+            })
+        })
+        .transpose()?;
+
     debug!("Creating Client actor");
     let mut client_builder = Client::new(
-        None, // We are not supporting location for this iteration of the Client.
+        args.position,
+        groups,
+        language,
         server_address,
-        playback_service.sender.clone(),
-        midi_service.sender.clone(),
+        args.media_path,
+        ClientServices {
+            playback: playback_service.sender.clone(),
+            midi: midi_service.sender.clone(),
+            dmx: dmx_service.as_ref().map(|dmx_service| dmx_service.sender.clone()),
+        },
         // subtitle_service.sender.clone(),
         // colour_service.sender.clone(),
     );
@@ -130,6 +179,14 @@ async fn main() -> Result<()> {
         result = midi_service.run() => {
             Err(eyre!("Playback service crashed: {:?}", result))?
         }
+        result = async {
+            match dmx_service.as_mut() {
+                Some(dmx_service) => dmx_service.run().await,
+                None => std::future::pending().await,
+            }
+        } => {
+            Err(eyre!("DMX service crashed: {:?}", result))?
+        }
         result = client_builder.run() => {
             Err(eyre!("Server handler crashed: {:?}", result))?
         }
@@ -0,0 +1,248 @@
+//! Media synchronisation
+//!
+//! Keeps the media folder of the Client in line with the manifest published by the Server.
+//! Once all the pages of the manifest are received, the local files are checked against it,
+//! and the missing or outdated ones are fetched one at a time. Files are written next to their
+//! final path with a `.part` extension, and only moved into place once their size and hash match
+//! the manifest, so a playback never picks up a half downloaded file.
+
+use std::{
+    collections::VecDeque,
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use lamarrs_utils::{
+    action_messages::Event,
+    media::{
+        is_plain_file_name, MediaChunk, MediaEntry, MediaManifest, MediaStatus, Sha256Digest,
+    },
+};
+use sha2::{Digest, Sha256};
+use tokio::{fs::File, io::AsyncWriteExt, task::{self, JoinError}};
+use tracing::{info, warn};
+
+#[derive(Debug, thiserror::Error)]
+pub enum MediaSyncError {
+    #[error("Failed accessing the media folder")]
+    Io(#[from] io::Error),
+    #[error("The media folder check failed")]
+    Check(#[from] JoinError),
+    #[error("Received a chunk of {0} that was not expected")]
+    UnexpectedChunk(String),
+}
+
+/// A file being fetched from the Server.
+#[derive(Debug)]
+struct Download {
+    entry: MediaEntry,
+    file: File,
+    hasher: Sha256,
+    received: u64,
+}
+
+#[derive(Debug)]
+pub struct MediaSync {
+    media_path: PathBuf,
+    /// Pages of the manifest received so far.
+    manifest: Vec<MediaEntry>,
+    /// Files still to be fetched, after the one being downloaded.
+    pending: VecDeque<MediaEntry>,
+    download: Option<Download>,
+    missing: u16,
+}
+
+impl MediaSync {
+    pub fn new(media_path: PathBuf) -> Self {
+        Self {
+            media_path,
+            manifest: Vec::new(),
+            pending: VecDeque::new(),
+            download: None,
+            missing: 0,
+        }
+    }
+
+    /// Adds the page to the manifest. With the last one, the media folder is checked and the
+    /// Events to be sent to the Server are returned: the status of the folder and the first fetch.
+    pub async fn add_manifest_page(
+        &mut self,
+        manifest: MediaManifest,
+    ) -> Result<Vec<Event>, MediaSyncError> {
+        // The manifest is sent again on every registration, any previous sync is dropped.
+        if manifest.page == 0 {
+            self.manifest.clear();
+            self.pending.clear();
+            self.download = None;
+        }
+        // The files are written in the media folder under their name, which must not lead out of it.
+        for entry in manifest.entries.iter() {
+            if is_plain_file_name(&entry.name) {
+                self.manifest.push(entry.clone());
+            } else {
+                warn!("Ignoring media file {:?} of the manifest, its name is not a plain file name.", entry.name);
+            }
+        }
+        if !manifest.is_last_page() {
+            return Ok(Vec::new());
+        }
+
+        let media_path = self.media_path.clone();
+        let manifest = self.manifest.clone();
+        let pending = task::spawn_blocking(move || {
+            manifest
+                .into_iter()
+                .filter(|entry| !is_up_to_date(&media_path, entry))
+                .collect::<VecDeque<_>>()
+        })
+        .await?;
+        info!(
+            "{} of the {} media files of the show are missing or outdated.",
+            pending.len(),
+            self.manifest.len()
+        );
+        self.missing = pending.len() as u16;
+        self.pending = pending;
+        let mut events = vec![self.status()];
+        events.extend(self.fetch_next().await?);
+        Ok(events)
+    }
+
+    /// Writes the chunk to the file being downloaded. Once it is complete, the Events to be
+    /// sent to the Server are returned: the new status of the folder and the next fetch.
+    pub async fn add_chunk(&mut self, chunk: MediaChunk) -> Result<Vec<Event>, MediaSyncError> {
+        let Some(download) = self
+            .download
+            .as_mut()
+            .filter(|download| download.entry.name == chunk.name && download.received == chunk.offset)
+        else {
+            return Err(MediaSyncError::UnexpectedChunk(chunk.name.to_string()));
+        };
+        download.file.write_all(&chunk.data).await?;
+        download.hasher.update(&chunk.data);
+        download.received += chunk.data.len() as u64;
+        if download.received < download.entry.size {
+            return Ok(Vec::new());
+        }
+
+        let Some(mut download) = self.download.take() else {
+            return Ok(Vec::new());
+        };
+        download.file.flush().await?;
+        let part_path = part_path(&self.media_path, &download.entry);
+        let sha256: Sha256Digest = download.hasher.finalize().into();
+        if download.received == download.entry.size && sha256 == download.entry.sha256 {
+            tokio::fs::rename(&part_path, self.media_path.join(download.entry.name.as_str())).await?;
+            self.missing = self.missing.saturating_sub(1);
+            info!("Media file {} received, {} left.", download.entry.name, self.missing);
+        } else {
+            warn!("Media file {} doesn't match the manifest, discarding it.", download.entry.name);
+            tokio::fs::remove_file(&part_path).await?;
+        }
+        let mut events = vec![self.status()];
+        events.extend(self.fetch_next().await?);
+        Ok(events)
+    }
+
+    /// Gives up the file being downloaded, like when the Server refuses to send it,
+    /// returning the next fetch if any.
+    pub async fn skip_download(&mut self) -> Result<Vec<Event>, MediaSyncError> {
+        if let Some(download) = self.download.take() {
+            warn!("Media file {} could not be fetched, skipping it.", download.entry.name);
+            tokio::fs::remove_file(part_path(&self.media_path, &download.entry)).await?;
+        }
+        Ok(self.fetch_next().await?.into_iter().collect())
+    }
+
+    fn status(&self) -> Event {
+        Event::ReportMediaStatus(MediaStatus {
+            missing: self.missing,
+        })
+    }
+
+    /// Starts downloading the next pending file.
+    async fn fetch_next(&mut self) -> Result<Option<Event>, MediaSyncError> {
+        let Some(entry) = self.pending.pop_front() else {
+            return Ok(None);
+        };
+        info!("Fetching media file {} ({} bytes).", entry.name, entry.size);
+        let file = File::create(part_path(&self.media_path, &entry)).await?;
+        let name = entry.name.clone();
+        self.download = Some(Download {
+            entry,
+            file,
+            hasher: Sha256::new(),
+            received: 0,
+        });
+        Ok(Some(Event::FetchMedia(name)))
+    }
+}
+
+/// Where a file is written while it is being downloaded.
+fn part_path(media_path: &Path, entry: &MediaEntry) -> PathBuf {
+    media_path.join(format!("{}.part", entry.name))
+}
+
+/// Whether the local copy of the file matches the manifest.
+fn is_up_to_date(media_path: &Path, entry: &MediaEntry) -> bool {
+    let path = media_path.join(entry.name.as_str());
+    match fs::metadata(&path) {
+        Ok(metadata) if metadata.len() == entry.size => {
+            matches!(hash_file(&path), Ok(sha256) if sha256 == entry.sha256)
+        }
+        _ => false,
+    }
+}
+
+/// SHA-256 hash of a file.
+fn hash_file(path: &Path) -> Result<Sha256Digest, io::Error> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher.finalize().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use lamarrs_utils::media::MediaFileName;
+
+    use super::*;
+
+    fn entry(name: &str) -> MediaEntry {
+        MediaEntry {
+            name: MediaFileName::try_from(name).unwrap(),
+            size: 1,
+            sha256: [0; 32],
+        }
+    }
+
+    #[tokio::test]
+    async fn files_out_of_the_media_folder_are_never_fetched() {
+        let media_path = std::env::temp_dir().join(format!("lamarrs-media-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&media_path).unwrap();
+        let mut media_sync = MediaSync::new(media_path.clone());
+        let manifest = MediaManifest {
+            entries: [entry("../outside.mp3"), entry("/tmp/outside.mp3"), entry("inside.mp3")]
+                .into_iter()
+                .collect(),
+            page: 0,
+            pages: 1,
+        };
+
+        let events = media_sync.add_manifest_page(manifest).await.unwrap();
+
+        assert!(matches!(
+            events.as_slice(),
+            [Event::ReportMediaStatus(MediaStatus { missing: 1 }), Event::FetchMedia(name)] if name == "inside.mp3"
+        ));
+        fs::remove_dir_all(media_path).unwrap();
+    }
+}
@@ -1,16 +1,22 @@
-use std::time::Duration;
+use std::{
+    collections::{HashMap, VecDeque},
+    path::PathBuf,
+    time::Duration,
+};
 
 use async_time_mock_tokio::MockableClock;
 use futures_util::{stream::SplitSink, SinkExt, StreamExt};
 use http::Uri;
 use lamarrs_utils::{
-    action_messages::{Action, Event},
-    exchange_messages::{ExchangeMessage, NackResult},
-    ClientIdAndLocation, ErrorDescription, RelativeLocation, Service,
+    action_messages::{Action, ClientKind, Event, GroupName, Registration},
+    clock::{ClockEstimator, Schedule, TimeSyncResponse},
+    exchange_messages::{ExchangeMessage, NackResult, RequestId, PROTOCOL_VERSION},
+    subtitles::LanguageTag,
+    ClientIdAndLocation, ErrorDescription, Position, Service,
 };
 use tokio::{
     net::TcpStream,
-    sync::mpsc::{self, Receiver, Sender, channel}, time::sleep,
+    sync::mpsc::{self, Receiver, Sender, channel}, time::{interval, sleep, sleep_until, Instant},
 };
 use tokio_tungstenite::{
     connect_async, tungstenite::Message as TungsteniteMessage, MaybeTlsStream,
@@ -19,7 +25,23 @@ use tokio_tungstenite::{
 use tracing::{debug, error, info, instrument, warn};
 use uuid::Uuid;
 
-use crate::InternalEventMessageClient;
+use crate::{
+    media_sync::{MediaSync, MediaSyncError},
+    InternalEventMessageClient,
+};
+
+/// Biggest frame this Client accepts. It is the default limit of tungstenite.
+const MAX_FRAME_SIZE: u32 = 16 << 20;
+
+/// Time the Server has to answer a request before it is considered lost.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A request sent to the Server that is still waiting for its Ack or Nack.
+#[derive(Debug)]
+struct PendingRequest {
+    event: String,
+    sent_at: Instant,
+}
 
 #[derive(Debug, thiserror::Error)]
 pub enum ServerHandlerError {
@@ -39,44 +61,83 @@ pub enum ServerHandlerError {
     SendInternalMessage(#[from] mpsc::error::SendError<InternalEventMessageClient>),
     #[error("Fatar error deconding a binary ExchangeMessage received from Server: {0}")]
     FailureDecodingBinaryExchangeMessage(String),
+    #[error("Failed synchronising the media folder: {0}")]
+    MediaSync(#[from] MediaSyncError),
+}
+
+/// Senders of the services running in the Client, the actions are relayed to.
+#[derive(Debug)]
+pub struct ClientServices {
+    pub playback: Sender<InternalEventMessageClient>,
+    pub midi: Sender<InternalEventMessageClient>,
+    /// Only running if the Client has a DMX patch.
+    pub dmx: Option<Sender<InternalEventMessageClient>>,
+}
+
+impl ClientServices {
+    /// The Services of the Server the running ones perform.
+    fn remote_services(&self) -> impl Iterator<Item = Service> {
+        [Service::AudioPlayer, Service::Midi]
+            .into_iter()
+            .chain(self.dmx.as_ref().map(|_| Service::Colour))
+    }
+
+    fn senders(&self) -> impl Iterator<Item = &Sender<InternalEventMessageClient>> {
+        [&self.playback, &self.midi].into_iter().chain(self.dmx.as_ref())
+    }
 }
 
 pub struct Client {
     id: Uuid,
-    location: Option<RelativeLocation>,
+    location: Option<Position>,
+    groups: Vec<GroupName>,
+    language: Option<LanguageTag>,
     server_address: Uri,
     sender: Sender<InternalEventMessageClient>,
     inbox: Receiver<InternalEventMessageClient>,
-    playback_service: Sender<InternalEventMessageClient>,
-    midi_service: Sender<InternalEventMessageClient>,
-    //dmx: Sender<InternalEventMessageClient>,
+    services: ClientServices,
     //led: Sender<InternalEventMessageClient>,
+    next_request_id: RequestId,
+    pending_requests: HashMap<RequestId, PendingRequest>,
     clock: MockableClock,
+    /// Local clock used for the synchronisation with the Server one, counting from the Client start.
+    clock_origin: Instant,
+    clock_estimator: ClockEstimator,
+    /// Actions waiting for the time they were scheduled at, sorted by it.
+    scheduled_actions: VecDeque<(Instant, Action)>,
+    media_sync: MediaSync,
 }
 
 impl Client {
     /// Client Actor constructor.
     pub fn new(
-        location: Option<RelativeLocation>,
+        location: Option<Position>,
+        groups: Vec<GroupName>,
+        language: Option<LanguageTag>,
         server_address: Uri,
-        audio_player: Sender<InternalEventMessageClient>,
-        midi: Sender<InternalEventMessageClient>,
-        // dmx: Sender<InternalEventMessageClient>,
+        media_path: PathBuf,
+        services: ClientServices,
         // led: Sender<InternalEventMessageClient>,
     ) -> Self {
         let (sender, inbox) = channel(32);
         Self {
             id: Uuid::new_v4(),
             location,
+            groups,
+            language,
             server_address,
             sender,
             inbox,
-            playback_service: audio_player,
-            midi_service: midi,
+            services,
             //subtitle,
-            //dmx,
             //led,
+            next_request_id: 0,
+            pending_requests: HashMap::new(),
             clock: MockableClock::Real,
+            clock_origin: Instant::now(),
+            clock_estimator: ClockEstimator::new(),
+            scheduled_actions: VecDeque::new(),
+            media_sync: MediaSync::new(media_path),
         }
     }
 
@@ -95,29 +156,52 @@ impl Client {
                         self.server_address.to_string()
                     );
                     let (mut remote_sender, mut remote_inbox) = ws_stream.split();
+                    // Requests sent through a previous connection will never be answered.
+                    self.pending_requests.clear();
+                    let mut pending_requests_check = interval(Duration::from_secs(1));
 
                     // We add to the queue the request to Register to the Server.
-                    let register_message = ExchangeMessage::Request(Event::Register(ClientIdAndLocation {
-                        uuid: self.id,
-                        location: self.location.clone(),
+                    let register_message = self.new_request(Event::Register(Registration {
+                        client: ClientIdAndLocation {
+                            uuid: self.id,
+                            location: self.location,
+                        },
+                        protocol_version: PROTOCOL_VERSION,
+                        kind: ClientKind::LinuxClient,
+                        // These must match the services started by `main`.
+                        services: self.services.remote_services().collect(),
+                        max_frame_size: MAX_FRAME_SIZE,
+                        seat: None,
+                        language: self.language.clone(),
+                        section: None,
+                        group: None,
                     }));
                     self.send_message_to_lamarrs_server(&mut remote_sender, register_message).await;
+                    // Groups are joined again on every connection, in case the Server was restarted.
+                    for group in self.groups.clone() {
+                        let join_group_message = self.new_request(Event::JoinGroup(group.clone()));
+                        if let Err(error) = self.send_message_to_lamarrs_server(&mut remote_sender, join_group_message).await {
+                            error!(?error, "Failed to request joining group {}.", group);
+                        }
+                    }
                     // By now, we notify internal services that WS is go and all the services will answer
                     // with Subscribe requests. ALL of them.
                     // In th future we will be able to select the services to run on the client from a CLI.
                     // https://github.com/mgonzalezperna/lamarrs/issues/96
                     // We should also process the server ACKs.
-                    self.playback_service
-                        .send(InternalEventMessageClient::ConnectedToServer(
-                            self.sender.clone(),
-                        ))
-                        .await;
-                    self.midi_service
-                        .send(InternalEventMessageClient::ConnectedToServer(
-                            self.sender.clone(),
-                        ))
-                        .await;
+                    for service in self.services.senders() {
+                        if let Err(error) = service
+                            .send(InternalEventMessageClient::ConnectedToServer(self.sender.clone()))
+                            .await
+                        {
+                            error!(?error, "A service of the Client is not running, it won't subscribe to the Server.");
+                        }
+                    }
                     loop {
+                        let next_scheduled_action = self
+                            .scheduled_actions
+                            .front()
+                            .map_or_else(Instant::now, |(execute_at, _)| *execute_at);
                         tokio::select! {
                             // Receive messages from server via websocket connection
                             msg = remote_inbox.next() => {
@@ -127,7 +211,8 @@ impl Client {
                                         ServerHandlerError::UnrecognizableMessage(_) |
                                         ServerHandlerError::SendInternalMessage(_) |
                                         ServerHandlerError::InvalidExchangeMessage(_) |
-                                        ServerHandlerError::SerializationError(_) => (),
+                                        ServerHandlerError::SerializationError(_) |
+                                        ServerHandlerError::MediaSync(_) => (),
                                         ServerHandlerError::ParseError(_) |
                                         ServerHandlerError::Error(_) |
                                         ServerHandlerError::ServerConnectionLost |
@@ -144,13 +229,26 @@ impl Client {
                                 if let Some(message) = msg {
                                     match message {
                                         InternalEventMessageClient::SubscribeToService(service) => {
-                                            let subcription_message = ExchangeMessage::Request(Event::SuscribeToService(service, ClientIdAndLocation::new(self.id, self.location.clone())));
+                                            let subcription_message = self.new_request(Event::SuscribeToService(service, ClientIdAndLocation::new(self.id, self.location)));
                                             self.send_message_to_lamarrs_server(&mut remote_sender, subcription_message).await;
                                         }
+                                        InternalEventMessageClient::ActionFailed(description) => {
+                                            if let Err(error) = self.report_failed_action(&mut remote_sender, &description).await {
+                                                error!(?error, "Server can't be notified of the failed action.");
+                                            }
+                                        }
                                         _ => { error!("Invalid message type received from internal actor.") }
                                     }
                                 } 
                             }
+                            // Forget the requests the Server never answered.
+                            _ = pending_requests_check.tick() => {
+                                self.expire_pending_requests();
+                            }
+                            // Perform the actions whose time has come.
+                            _ = sleep_until(next_scheduled_action), if !self.scheduled_actions.is_empty() => {
+                                self.perform_scheduled_actions().await;
+                            }
                         }
                     }
                 }
@@ -162,6 +260,170 @@ impl Client {
         } 
     }
 
+    /// Wraps the Event in a new Request to the Server, keeping track of it until
+    /// the Server answers it.
+    fn new_request(&mut self, event: Event) -> ExchangeMessage {
+        let request_id = self.next_request_id;
+        self.next_request_id = self.next_request_id.wrapping_add(1);
+        self.pending_requests.insert(
+            request_id,
+            PendingRequest {
+                event: event.to_string(),
+                sent_at: Instant::now(),
+            },
+        );
+        ExchangeMessage::Request(request_id, event)
+    }
+
+    /// Stops tracking the request answered by the Server, returning it if it was still pending.
+    fn resolve_request(&mut self, request_id: Option<RequestId>) -> Option<PendingRequest> {
+        request_id.and_then(|request_id| self.pending_requests.remove(&request_id))
+    }
+
+    /// Drops the requests that were not answered by the Server in time.
+    fn expire_pending_requests(&mut self) {
+        self.pending_requests.retain(|request_id, pending_request| {
+            let is_pending = pending_request.sent_at.elapsed() < REQUEST_TIMEOUT;
+            if !is_pending {
+                warn!(
+                    request_id,
+                    "Request {} was not answered by the Server in {:?}.",
+                    pending_request.event,
+                    REQUEST_TIMEOUT
+                );
+            }
+            is_pending
+        });
+    }
+
+    /// Current time of the local clock used for the synchronisation, in microseconds.
+    fn local_time_us(&self) -> u64 {
+        self.clock_origin.elapsed().as_micros() as u64
+    }
+
+    /// Relays the action to the service able to perform it.
+    async fn perform_action(&mut self, action: Action) -> Result<(), ServerHandlerError> {
+        match action {
+            Action::ShowNewSubtitles(subtitles) => {
+                error!("NOT IMPLEMENTED!!!");
+                Ok(())
+            }
+            Action::ChangeColour(colour_rgb) => match &self.services.dmx {
+                Some(dmx_service) => Ok(dmx_service
+                    .send(InternalEventMessageClient::NewDmxColour(colour_rgb, self.sender.clone()))
+                    .await?),
+                None => {
+                    warn!("Colour received, but this Client has no DMX patch to show it.");
+                    Ok(())
+                }
+            },
+            Action::ColourEffect(colour_effect) => match &self.services.dmx {
+                Some(dmx_service) => Ok(dmx_service
+                    .send(InternalEventMessageClient::NewDmxColourEffect(colour_effect, self.sender.clone()))
+                    .await?),
+                None => {
+                    warn!("Colour effect received, but this Client has no DMX patch to show it.");
+                    Ok(())
+                }
+            },
+            Action::PlayAudio(audio_file) => Ok(self
+                .services
+                .playback
+                .send(InternalEventMessageClient::PlayAudio(
+                    Box::new(audio_file),
+                    self.sender.clone(),
+                ))
+                .await?),
+            Action::AudioControl(audio_control) => Ok(self
+                .services
+                .playback
+                .send(InternalEventMessageClient::ControlAudio(
+                    audio_control,
+                    self.sender.clone(),
+                ))
+                .await?),
+            Action::Midi(midi_instruction) => Ok(self.services.midi.send(InternalEventMessageClient::NewMIDIMessage(Box::new(midi_instruction), self.sender.clone())).await?),
+        }
+    }
+
+    /// Holds the action until the time it was scheduled at, translated to the local clock.
+    /// Actions received after their expiry are dropped and reported to the Server.
+    async fn schedule_action(
+        &mut self,
+        action: Action,
+        schedule: Schedule,
+        received_us: u64,
+        outgoing: &mut SplitSink<
+            TungsteniteWebSocketStream<MaybeTlsStream<TcpStream>>,
+            TungsteniteMessage,
+        >,
+    ) -> Result<(), ServerHandlerError> {
+        let (Some(server_received_us), Some(execute_at_us)) = (
+            self.clock_estimator.to_server_time(received_us),
+            self.clock_estimator.to_local_time(schedule.execute_at_us, received_us),
+        ) else {
+            warn!("Clock not synchronised with the Server yet, performing {} straight away.", action);
+            return self.perform_action(action).await;
+        };
+        if schedule.is_expired(server_received_us) {
+            let late_ms = server_received_us.saturating_sub(schedule.execute_at_us) / 1000;
+            warn!(?schedule, "Dropping {} as it arrived {} ms after its scheduled time.", action, late_ms);
+            return self
+                .report_failed_action(outgoing, &format!("Dropped expired {}, {} ms late.", action, late_ms))
+                .await;
+        }
+        let execute_at = self.clock_origin + Duration::from_micros(execute_at_us);
+        debug!(?schedule, "Scheduling {} in {:?}.", action, execute_at.saturating_duration_since(Instant::now()));
+        let index = self
+            .scheduled_actions
+            .partition_point(|(scheduled_at, _)| *scheduled_at <= execute_at);
+        self.scheduled_actions.insert(index, (execute_at, action));
+        Ok(())
+    }
+
+    /// Performs, in order, the scheduled actions whose time has come.
+    async fn perform_scheduled_actions(&mut self) {
+        let now = Instant::now();
+        while self.scheduled_actions.front().is_some_and(|(execute_at, _)| *execute_at <= now) {
+            if let Some((_, action)) = self.scheduled_actions.pop_front() {
+                if let Err(error) = self.perform_action(action).await {
+                    error!(?error, "Failed to perform a scheduled action.");
+                }
+            }
+        }
+    }
+
+    /// Sends each Event to the Server in a new Request.
+    async fn send_requests_to_lamarrs_server(
+        &mut self,
+        sender: &mut SplitSink<TungsteniteWebSocketStream<MaybeTlsStream<TcpStream>>, TungsteniteMessage>,
+        events: Vec<Event>,
+    ) -> Result<(), ServerHandlerError> {
+        for event in events {
+            let request = self.new_request(event);
+            self.send_message_to_lamarrs_server(sender, request).await?;
+        }
+        Ok(())
+    }
+
+    /// Tells the Server an action it sent was not performed. Actions are not requests, so they
+    /// can't be Nacked.
+    async fn report_failed_action(
+        &self,
+        outgoing: &mut SplitSink<TungsteniteWebSocketStream<MaybeTlsStream<TcpStream>>, TungsteniteMessage>,
+        description: &str,
+    ) -> Result<(), ServerHandlerError> {
+        match heapless::String::try_from(description) {
+            Ok(error_descr) => {
+                self.send_message_to_lamarrs_server(outgoing, ExchangeMessage::Error(ErrorDescription { error_descr })).await
+            }
+            Err(_) => {
+                error!("Server can't be notified of the failed action, there was an issue parsing the error message.");
+                Ok(())
+            }
+        }
+    }
+
     async fn send_message_to_lamarrs_server(
         &self,
         sender: &mut SplitSink<TungsteniteWebSocketStream<MaybeTlsStream<TcpStream>>, TungsteniteMessage>,
@@ -186,7 +448,7 @@ impl Client {
             // Process inbound messages
             Some(Ok(TungsteniteMessage::Text(string_payload))) => {
                 debug!(?string_payload, "Inbound String Payload");
-                self.process_message(string_payload.to_string()).await?
+                self.process_message(string_payload.to_string(), outgoing).await?
             }
             Some(Ok(TungsteniteMessage::Binary(bytes_payload))) => {
                 debug!(?bytes_payload, "Inbound Binary Payload");
@@ -194,7 +456,7 @@ impl Client {
                     postcard::from_bytes::<ExchangeMessage>(&bytes_payload).map_err(|e| {
                         ServerHandlerError::FailureDecodingBinaryExchangeMessage(e.to_string())
                     })?;
-                self.process_message(payload.to_string()).await?
+                self.process_message(payload.to_string(), outgoing).await?
             }
             // Handle ping responses
             Some(Ok(TungsteniteMessage::Ping(data))) => {
@@ -227,31 +489,86 @@ impl Client {
 
     /// This function process messages from trusted remote Clients that already have been
     /// cleared to be compatible, localizable, and that can be identified.
-    #[instrument(name = "Client::process_message", skip(self),fields(id=?self.id),  level = "INFO", ret, err)]
+    #[instrument(name = "Client::process_message", skip(self, outgoing),fields(id=?self.id),  level = "INFO", ret, err)]
     async fn process_message(
         &mut self,
         exchange_message: String,
+        outgoing: &mut SplitSink<
+            TungsteniteWebSocketStream<MaybeTlsStream<TcpStream>>,
+            TungsteniteMessage,
+        >,
     ) -> Result<(), ServerHandlerError> {
+        let received_us = self.local_time_us();
         match serde_json::from_str(&exchange_message) {
-            Ok(ExchangeMessage::Scene(Event::PerformAction(action))) => match action {
-                Action::ShowNewSubtitles(subtitles) => {
-                    error!("NOT IMPLEMENTED!!!");
-                    Ok(())
+            Ok(ExchangeMessage::Scene(Event::PerformAction(action), None)) => self.perform_action(action).await,
+            Ok(ExchangeMessage::Scene(Event::PerformAction(action), Some(schedule))) => {
+                self.schedule_action(action, schedule, received_us, outgoing).await
+            }
+            Ok(ExchangeMessage::ServerInfo(server_info)) => {
+                self.clock_estimator.add_server_time(received_us, server_info.server_time_us);
+                if server_info.is_compatible_with(PROTOCOL_VERSION) {
+                    info!(?server_info, "Registered in lamarrs server {}", server_info.server_version);
+                } else {
+                    error!(
+                        ?server_info,
+                        "Lamarrs server {} speaks protocol version {}, but this Client speaks {}. Update the Client or the Server.",
+                        server_info.server_version, server_info.protocol_version, PROTOCOL_VERSION
+                    );
                 }
-                Action::ChangeColour(colour_rgb) => {
-                    error!("NOT IMPLEMENTED!!!");
-                    Ok(())
+                Ok(())
+            }
+            Ok(ExchangeMessage::Ack(request_id, ack_result)) => {
+                match self.resolve_request(request_id) {
+                    Some(request) => info!(?request_id, ?ack_result, "Request {} accepted by the Server.", request.event),
+                    None => debug!(?request_id, ?ack_result, "Ack received for an unknown or already answered request."),
                 }
-                Action::PlayAudio(audio_file) => Ok(self
-                    .playback_service
-                    .send(InternalEventMessageClient::PlayAudio(
-                        audio_file,
-                        self.sender.clone(),
-                    ))
-                    .await?),
-                Action::Midi(midi_instruction) => Ok(self.midi_service.send(InternalEventMessageClient::NewMIDIMessage(midi_instruction, self.sender.clone())).await?),
-            },
-            Ok(ExchangeMessage::Request(_)) => {
+                Ok(())
+            }
+            Ok(ExchangeMessage::Nack(request_id, nack_result)) => {
+                let request = self.resolve_request(request_id);
+                let event = request.as_ref().map_or("Unknown", |request| request.event.as_str());
+                match nack_result {
+                    NackResult::IncompatibleProtocolVersion => error!(?request_id, "Request {} rejected by the Server: incompatible protocol version.", event),
+                    NackResult::UnknownMedia => {
+                        warn!(?request_id, "Request {} rejected by the Server: the file is not in its media library.", event);
+                        let events = self.media_sync.skip_download().await?;
+                        return self.send_requests_to_lamarrs_server(outgoing, events).await;
+                    }
+                    _ => warn!(?request_id, ?nack_result, "Request {} rejected by the Server.", event),
+                }
+                Ok(())
+            }
+            Ok(ExchangeMessage::TimeSyncRequest(time_sync_request)) => {
+                // Answered straight away, so the time spent by the Client doesn't spoil the round.
+                let time_sync_response = ExchangeMessage::TimeSyncResponse(TimeSyncResponse {
+                    server_sent_us: time_sync_request.server_sent_us,
+                    client_received_us: received_us,
+                    client_sent_us: self.local_time_us(),
+                });
+                self.send_message_to_lamarrs_server(outgoing, time_sync_response).await
+            }
+            Ok(ExchangeMessage::TimeSyncResult(time_sync_result)) => {
+                if self.clock_estimator.add_sample(received_us, &time_sync_result) {
+                    debug!(
+                        offset_us = time_sync_result.offset_us,
+                        round_trip_us = time_sync_result.round_trip_us,
+                        drift_ppb = self.clock_estimator.drift_ppb(),
+                        "Clock synchronised with the Server."
+                    );
+                } else {
+                    debug!(round_trip_us = time_sync_result.round_trip_us, "Clock synchronisation round discarded, the round trip was too slow.");
+                }
+                Ok(())
+            }
+            Ok(ExchangeMessage::MediaManifest(media_manifest)) => {
+                let events = self.media_sync.add_manifest_page(*media_manifest).await?;
+                self.send_requests_to_lamarrs_server(outgoing, events).await
+            }
+            Ok(ExchangeMessage::MediaChunk(media_chunk)) => {
+                let events = self.media_sync.add_chunk(*media_chunk).await?;
+                self.send_requests_to_lamarrs_server(outgoing, events).await
+            }
+            Ok(ExchangeMessage::Request(..)) => {
                 warn!(?exchange_message, "Requested Action by Server is not supported. Server may be sending Client Actions?");
                 // self.sender
                 //     .send(ExchangeMessage::Nack(NackResult::Failed))
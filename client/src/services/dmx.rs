@@ -0,0 +1,355 @@
+//! DMX output
+//!
+//! Shows the colours and colour effects sent by the Server on DMX fixtures, like house LED bars,
+//! sending the universe of the patch through Art-Net or sACN. The patch is a YAML file:
+//! ```yaml
+//! protocol: ArtNet
+//! universe: 0
+//! # Art-Net is broadcast, and sACN sent to the multicast group of the universe, if missing.
+//! destination: 192.168.1.50:6454
+//! fixtures:
+//!   - address: 1
+//!     channels: [Red, Green, Blue]
+//!   - address: 4
+//!     channels: [Dimmer, Red, Green, Blue, White]
+//! ```
+//! Dimmer channels are always at full, the colour carries the brightness. White channels show
+//! the white of the colour, if it has one.
+
+use std::{
+    fmt, fs, io,
+    net::{Ipv4Addr, SocketAddr},
+    path::Path,
+    time::Duration,
+};
+
+use lamarrs_utils::{
+    colour_effect::ColourEffect,
+    dmx::{
+        art_dmx_packet, sacn_data_packet, sacn_multicast_address, DmxChannel, DmxProtocol,
+        DMX_UNIVERSE_SIZE,
+    },
+    ColourRgb, Service,
+};
+use serde::Deserialize;
+use tokio::{
+    net::UdpSocket,
+    sync::mpsc::{self, channel, Receiver, Sender},
+    time::{interval, Instant, MissedTickBehavior},
+};
+use tracing::{debug, info};
+use uuid::Uuid;
+
+use crate::InternalEventMessageClient;
+
+/// How often the frames of the colour effects are sent.
+const FRAME_PERIOD: Duration = Duration::from_millis(25);
+
+/// How often the universe is sent again when it doesn't change, so the nodes don't time out.
+const KEEP_ALIVE_PERIOD: Duration = Duration::from_secs(1);
+
+/// Name this Client announces itself with in sACN.
+const SACN_SOURCE_NAME: &str = "lamarrs-client";
+
+#[derive(Debug, thiserror::Error)]
+pub enum DmxPatchError {
+    #[error("The DMX patch file could not be read")]
+    Io(#[from] io::Error),
+    #[error("Malformed YAML DMX patch: {0}")]
+    Yaml(#[from] serde_yml::Error),
+    #[error("Universe {universe} is not valid for {protocol}.")]
+    InvalidUniverse { protocol: DmxProtocol, universe: u16 },
+    #[error("Fixture at address {0} doesn't fit in the universe, addresses go from 1 to 512.")]
+    InvalidFixture(u16),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DmxServiceError {
+    #[error("There was a irrecoverable error with the service {}.", service)]
+    Service { service: String },
+    #[error("Error sending an InternalMessage to Server handler.")]
+    SendInternalMessage(#[from] mpsc::error::SendError<InternalEventMessageClient>),
+    #[error("Failed sending the DMX universe.")]
+    Network(#[from] io::Error),
+}
+
+/// A fixture of the patch, taking consecutive channels of the universe from its address.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Fixture {
+    /// From 1 to 512.
+    pub address: u16,
+    pub channels: Vec<DmxChannel>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct DmxPatch {
+    #[serde(default)]
+    pub protocol: DmxProtocol,
+    pub universe: u16,
+    pub destination: Option<SocketAddr>,
+    pub fixtures: Vec<Fixture>,
+}
+
+impl DmxPatch {
+    pub fn load(path: &Path) -> Result<Self, DmxPatchError> {
+        let patch: DmxPatch = serde_yml::from_str(&fs::read_to_string(path)?)?;
+        if !patch.protocol.is_valid_universe(patch.universe) {
+            return Err(DmxPatchError::InvalidUniverse {
+                protocol: patch.protocol,
+                universe: patch.universe,
+            });
+        }
+        for fixture in &patch.fixtures {
+            let last_channel = fixture.address as usize + fixture.channels.len().max(1) - 1;
+            if fixture.address == 0 || last_channel > DMX_UNIVERSE_SIZE {
+                return Err(DmxPatchError::InvalidFixture(fixture.address));
+            }
+        }
+        info!(
+            "Loaded DMX patch of {} fixtures in {} universe {}",
+            patch.fixtures.len(),
+            patch.protocol,
+            patch.universe
+        );
+        Ok(patch)
+    }
+
+    /// Where the universe is sent.
+    fn destination(&self) -> SocketAddr {
+        self.destination.unwrap_or_else(|| match self.protocol {
+            DmxProtocol::ArtNet => (Ipv4Addr::BROADCAST, self.protocol.port()).into(),
+            DmxProtocol::Sacn => {
+                (sacn_multicast_address(self.universe), self.protocol.port()).into()
+            }
+        })
+    }
+
+    /// Levels of the universe showing the colour in all the fixtures.
+    fn render(&self, colour: &ColourRgb) -> [u8; DMX_UNIVERSE_SIZE] {
+        let mut universe = [0; DMX_UNIVERSE_SIZE];
+        for fixture in &self.fixtures {
+            for (offset, channel) in fixture.channels.iter().enumerate() {
+                // Fixtures were checked to fit in the universe when the patch was loaded.
+                universe[fixture.address as usize - 1 + offset] = match channel {
+                    DmxChannel::Red => colour.r,
+                    DmxChannel::Green => colour.g,
+                    DmxChannel::Blue => colour.b,
+                    DmxChannel::White => colour.w.unwrap_or_default(),
+                    DmxChannel::Dimmer => u8::MAX,
+                };
+            }
+        }
+        universe
+    }
+}
+
+/// A colour effect being rendered.
+#[derive(Debug)]
+struct RunningEffect {
+    effect: ColourEffect,
+    started_at: Instant,
+    /// Colour shown when the effect started.
+    previous: ColourRgb,
+}
+
+#[derive(Debug)]
+pub struct DmxService {
+    pub sender: Sender<InternalEventMessageClient>,
+    receiver: Receiver<InternalEventMessageClient>,
+    patch: DmxPatch,
+    socket: UdpSocket,
+    /// Identifies this Client as sACN source.
+    cid: Uuid,
+    sequence: u8,
+    colour: ColourRgb,
+    effect: Option<RunningEffect>,
+    last_sent_at: Instant,
+}
+
+impl fmt::Display for DmxService {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DmxService")
+    }
+}
+
+impl DmxService {
+    pub async fn try_new(patch: DmxPatch) -> Result<Self, DmxServiceError> {
+        let (sender, receiver) = channel(32);
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        socket.set_broadcast(true)?;
+        Ok(Self {
+            sender,
+            receiver,
+            patch,
+            socket,
+            cid: Uuid::new_v4(),
+            sequence: 0,
+            colour: ColourRgb::new(0, 0, 0),
+            effect: None,
+            last_sent_at: Instant::now(),
+        })
+    }
+
+    /// Runs the Service.
+    pub async fn run(&mut self) -> Result<(), DmxServiceError> {
+        let mut frames = interval(FRAME_PERIOD);
+        frames.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        // Fixtures start dark, whatever they were showing before.
+        self.send_universe().await?;
+        loop {
+            tokio::select! {
+                message = self.receiver.recv() => match message {
+                    Some(InternalEventMessageClient::ConnectedToServer(sender)) => {
+                        self.subscribe_to_remote_service(sender).await?
+                    }
+                    Some(InternalEventMessageClient::NewDmxColour(colour, _)) => {
+                        info!("Showing colour {} on the DMX fixtures.", colour);
+                        self.effect = None;
+                        self.colour = colour;
+                        self.send_universe().await?
+                    }
+                    Some(InternalEventMessageClient::NewDmxColourEffect(effect, _)) => {
+                        info!("Showing {} effect on the DMX fixtures.", effect.kind);
+                        self.effect = Some(RunningEffect {
+                            effect,
+                            started_at: Instant::now(),
+                            previous: self.colour.clone(),
+                        });
+                    }
+                    _ => {
+                        return Err(DmxServiceError::Service {
+                            service: self.to_string(),
+                        })
+                    }
+                },
+                _ = frames.tick() => {
+                    if let Some(running_effect) = &self.effect {
+                        let elapsed_ms = running_effect.started_at.elapsed().as_millis() as u32;
+                        self.colour = running_effect.effect.evaluate(&running_effect.previous, elapsed_ms);
+                        if running_effect.effect.is_finished(elapsed_ms) {
+                            debug!("DMX colour effect finished.");
+                            self.effect = None;
+                        }
+                        self.send_universe().await?
+                    } else if self.last_sent_at.elapsed() >= KEEP_ALIVE_PERIOD {
+                        self.send_universe().await?
+                    }
+                }
+            }
+        }
+    }
+
+    /// Each service must know what remote services needs to consume. A Client Service could
+    /// several different messages if required.
+    async fn subscribe_to_remote_service(
+        &mut self,
+        server_sender: Sender<InternalEventMessageClient>,
+    ) -> Result<(), DmxServiceError> {
+        info!("{} subscribing to Server Colour", self.to_string());
+        Ok(server_sender
+            .send(InternalEventMessageClient::SubscribeToService(
+                Service::Colour,
+            ))
+            .await?)
+    }
+
+    /// Sends the universe showing the current colour.
+    async fn send_universe(&mut self) -> Result<(), DmxServiceError> {
+        let universe = self.patch.render(&self.colour);
+        // Art-Net reserves the sequence 0 for the sources that don't use them.
+        self.sequence = self.sequence.checked_add(1).unwrap_or(1);
+        let packet = match self.patch.protocol {
+            DmxProtocol::ArtNet => art_dmx_packet(self.patch.universe, self.sequence, &universe),
+            DmxProtocol::Sacn => sacn_data_packet(
+                self.cid.as_bytes(),
+                SACN_SOURCE_NAME,
+                self.patch.universe,
+                self.sequence,
+                &universe,
+            ),
+        };
+        self.socket.send_to(&packet, self.patch.destination()).await?;
+        self.last_sent_at = Instant::now();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lamarrs_utils::dmx::parse_dmx_packet;
+    use tokio::time::timeout;
+
+    use super::*;
+
+    /// Patch of a single RGB fixture at address 1, sent to a socket bound by the test.
+    async fn patch_sent_to_local_socket(protocol: DmxProtocol, universe: u16) -> (DmxPatch, UdpSocket) {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let patch = DmxPatch {
+            protocol,
+            universe,
+            destination: Some(socket.local_addr().unwrap()),
+            fixtures: vec![Fixture {
+                address: 1,
+                channels: vec![DmxChannel::Red, DmxChannel::Green, DmxChannel::Blue],
+            }],
+        };
+        (patch, socket)
+    }
+
+    async fn receive_packet(socket: &UdpSocket) -> Vec<u8> {
+        let mut buffer = [0; 1024];
+        let read = timeout(Duration::from_secs(1), socket.recv(&mut buffer))
+            .await
+            .expect("No DMX packet was sent")
+            .unwrap();
+        buffer[..read].to_vec()
+    }
+
+    /// Shows the colour on the patch, returning the packets sent: the dark universe the Service
+    /// starts with and the one with the colour.
+    async fn show_colour(protocol: DmxProtocol, universe: u16, colour: ColourRgb) -> (Vec<u8>, Vec<u8>) {
+        let (patch, socket) = patch_sent_to_local_socket(protocol, universe).await;
+        let mut dmx_service = DmxService::try_new(patch).await.unwrap();
+        let sender = dmx_service.sender.clone();
+        tokio::spawn(async move { dmx_service.run().await });
+        let dark = receive_packet(&socket).await;
+        let (server_handler, _server_handler_inbox) = channel(1);
+        sender
+            .send(InternalEventMessageClient::NewDmxColour(colour, server_handler))
+            .await
+            .unwrap();
+        (dark, receive_packet(&socket).await)
+    }
+
+    #[tokio::test]
+    async fn sends_the_colour_as_art_dmx() {
+        let (dark, packet) = show_colour(DmxProtocol::ArtNet, 3, ColourRgb::new(255, 128, 7)).await;
+
+        assert_eq!(&packet[..8], b"Art-Net\0");
+        // OpDmx, little endian, and protocol version 14.
+        assert_eq!(&packet[8..12], &[0x00, 0x50, 0, 14]);
+        // Each packet has its own sequence.
+        assert_eq!((dark[12], packet[12]), (1, 2));
+        // Universe, little endian, and length of a full universe, big endian.
+        assert_eq!(&packet[14..18], &[3, 0, 0x02, 0x00]);
+        assert_eq!(&packet[18..22], &[255, 128, 7, 0]);
+        assert_eq!(packet.len(), 18 + DMX_UNIVERSE_SIZE);
+        assert!(dark[18..].iter().all(|level| *level == 0));
+    }
+
+    #[tokio::test]
+    async fn sends_the_colour_as_sacn() {
+        let (_, packet) = show_colour(DmxProtocol::Sacn, 7, ColourRgb::new(10, 20, 30)).await;
+
+        assert_eq!(&packet[4..16], b"ASC-E1.17\0\0\0");
+        // Source name, priority and universe of the framing layer.
+        assert!(packet[44..].starts_with(SACN_SOURCE_NAME.as_bytes()));
+        assert_eq!(packet[108], 100);
+        assert_eq!(&packet[113..115], &[0, 7]);
+        // Start code followed by the slots.
+        assert_eq!(&packet[125..129], &[0, 10, 20, 30]);
+        let frame = parse_dmx_packet(&packet).unwrap();
+        assert_eq!((frame.protocol, frame.universe), (DmxProtocol::Sacn, 7));
+        assert_eq!(frame.data.len(), DMX_UNIVERSE_SIZE);
+    }
+}
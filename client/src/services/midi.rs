@@ -2,9 +2,9 @@ use std::{
     fmt::{self}, time::Duration,
 };
 
-use lamarrs_utils::{MidiInstruction, Service};
+use lamarrs_utils::{midi::MidiCommand, MidiInstruction, Service};
 use midir::{InitError, MidiOutput, MidiOutputConnection as OutputConnection, PortInfoError, SendError};
-use midly::{MidiMessage, num::u7};
+use midly::{MidiMessage, PitchBend, num::u7};
 use tokio::{sync::mpsc::{self, Receiver, Sender, channel}, time::sleep};
 use tracing::info;
 
@@ -106,8 +106,8 @@ impl MidiService {
                     InternalEventMessageClient::ConnectedToServer(sender) => {
                         self.subscribe_to_remote_service(sender).await?
                     }
-                    InternalEventMessageClient::NewMIDIMessage(midi_instruction, sender) => {
-                        self.preset_change(midi_instruction.new_preset.get()-1).await? // This solves the `offset-by-1` issues with the presets, as the list never start with 0 in the interfaces, but internally they do.
+                    InternalEventMessageClient::NewMIDIMessage(midi_instruction, _) => {
+                        self.perform_instruction(*midi_instruction).await?
                     }
                     InternalEventMessageClient::Config(_) => {
                         unimplemented!("This message is not yet functional.")
@@ -136,8 +136,45 @@ impl MidiService {
             .await?)
     }
 
+    /// Sends the instruction to the MIDI output, on its channel or the default one.
+    async fn perform_instruction(&mut self, midi_instruction: MidiInstruction) -> Result<(), MidiServiceError> {
+        let channel = midi_instruction.channel.unwrap_or(self.default_channel);
+        match midi_instruction.command {
+            MidiCommand::NoteOn { note, velocity } => {
+                let msg = MidiMessage::NoteOn {
+                    key: u7::from(note.min(127)),
+                    vel: u7::from(velocity.min(127)),
+                };
+                self.send_message(channel, msg).await
+            }
+            MidiCommand::NoteOff { note, velocity } => {
+                let msg = MidiMessage::NoteOff {
+                    key: u7::from(note.min(127)),
+                    vel: u7::from(velocity.min(127)),
+                };
+                self.send_message(channel, msg).await
+            }
+            MidiCommand::ControlChange { controller, value } => {
+                self.control_change(channel, controller, value).await
+            }
+            MidiCommand::ProgramChange { program, bank } => {
+                if let Some(bank) = bank {
+                    self.bank_select(channel, (bank >> 7) as u8, (bank & 0x7F) as u8).await?;
+                }
+                self.program_change(channel, program).await
+            }
+            MidiCommand::PitchBend { bend } => {
+                let msg = MidiMessage::PitchBend {
+                    bend: PitchBend::from_int(bend),
+                };
+                self.send_message(channel, msg).await
+            }
+            MidiCommand::SysEx { data } => self.sysex(&data).await,
+        }
+    }
+
     /// Convert a midly MidiMessage into bytes and send via midir
-    async fn send_message(&mut self, msg: MidiMessage) -> Result<(), MidiServiceError> {
+    async fn send_message(&mut self, channel: u8, msg: MidiMessage) -> Result<(), MidiServiceError> {
         let status = match msg {
             MidiMessage::NoteOff { .. } => 0x80,
             MidiMessage::NoteOn { .. } => 0x90,
@@ -146,52 +183,61 @@ impl MidiService {
             MidiMessage::ProgramChange { .. } => 0xC0,
             MidiMessage::ChannelAftertouch { .. } => 0xD0,
             MidiMessage::PitchBend { .. } => 0xE0,
-        } | ((self.default_channel - 1) & 0x0F);
+        } | ((channel.clamp(1, 16) - 1) & 0x0F);
 
         // Serialize message
         let mut bytes = vec![status];
         match msg {
+            MidiMessage::NoteOff { key, vel }
+            | MidiMessage::NoteOn { key, vel }
+            | MidiMessage::Aftertouch { key, vel } => {
+                bytes.push(key.as_int() & 0x7F);
+                bytes.push(vel.as_int() & 0x7F);
+            }
             MidiMessage::ProgramChange { program } => bytes.push(program.as_int() & 0x7F),
             MidiMessage::Controller { controller, value } => {
                 bytes.push(controller.as_int() & 0x7F);
                 bytes.push(value.as_int() & 0x7F);
             }
-            _ => unimplemented!("Only CC and PC are currently supported"),
+            MidiMessage::ChannelAftertouch { vel } => bytes.push(vel.as_int() & 0x7F),
+            MidiMessage::PitchBend { bend } => {
+                // 14 bits value, least significant 7 bits first.
+                let bend = bend.0.as_int();
+                bytes.push((bend & 0x7F) as u8);
+                bytes.push((bend >> 7) as u8 & 0x7F);
+            }
         }
 
         Ok(self.midi_port_output_connection.0.send(&bytes)?)
     }
 
-    async fn preset_change(&mut self, preset: u16) -> Result<(), MidiServiceError> {
-        let bank = (preset / 128) as u8;
-        let program = (preset % 128) as u8;
-        self.bank_select(0, bank).await?;
-        self.program_change(program).await?;
-        Ok(())
-    }
-
-    async fn program_change(&mut self, program: u8) -> Result<(), MidiServiceError> {
+    async fn program_change(&mut self, channel: u8, program: u8) -> Result<(), MidiServiceError> {
         let msg = MidiMessage::ProgramChange {
             program: u7::from(program.min(127)),
         };
-        self.send_message(msg).await
+        self.send_message(channel, msg).await
     }
 
-    async fn control_change(&mut self, cc: u8, value: u8) -> Result<(), MidiServiceError> {
+    async fn control_change(&mut self, channel: u8, cc: u8, value: u8) -> Result<(), MidiServiceError> {
         let msg = MidiMessage::Controller {
             controller: u7::from(cc.min(127)),
             value: u7::from(value.min(127)),
         };
-        self.send_message(msg).await
+        self.send_message(channel, msg).await
     }
 
-    async fn bank_select(&mut self, msb: u8, lsb: u8) -> Result<(), MidiServiceError> {
-        self.control_change(0, msb).await?;
+    async fn bank_select(&mut self, channel: u8, msb: u8, lsb: u8) -> Result<(), MidiServiceError> {
+        self.control_change(channel, 0, msb).await?;
         sleep(Duration::from_millis(10)).await;
-        self.control_change(32, lsb).await
+        self.control_change(channel, 32, lsb).await
     }
 
+    /// Sends the payload wrapped in the SysEx start and end bytes.
     async fn sysex(&mut self, data: &[u8]) -> Result<(), MidiServiceError> {
-        Ok(self.midi_port_output_connection.0.send(data)?)
+        let mut bytes = Vec::with_capacity(data.len() + 2);
+        bytes.push(0xF0);
+        bytes.extend_from_slice(data);
+        bytes.push(0xF7);
+        Ok(self.midi_port_output_connection.0.send(&bytes)?)
     }
 }
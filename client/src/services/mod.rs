@@ -1,2 +1,3 @@
 pub mod playback;
 pub mod midi;
+pub mod dmx;
@@ -2,15 +2,27 @@ use std::{
     fmt::{self},
     io,
     path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
 };
 
-use lamarrs_utils::{AudioFile, Service};
-use rodio::StreamError;
-use tokio::{sync::mpsc::{self, Receiver, Sender, channel}, task::{self, JoinError}};
-use tracing::info;
+use lamarrs_utils::{
+    audio::{AudioCommand, AudioControl, VoiceId, MAX_VOLUME},
+    AudioPlayback, Service,
+};
+use rodio::{OutputStream, Sink, Source, StreamError};
+use tokio::{
+    sync::mpsc::{self, Receiver, Sender, channel},
+    task::{self, JoinError, JoinHandle},
+    time::interval,
+};
+use tracing::{debug, error, info, warn};
 
 use crate::InternalEventMessageClient;
 
+/// How often the volume is updated while it ramps.
+const VOLUME_RAMP_STEP: Duration = Duration::from_millis(20);
+
 #[derive(Debug, thiserror::Error)]
 pub enum PlaybackServiceError {
     #[error("There was a irrecoverable error with the service {}.", service)]
@@ -27,11 +39,74 @@ pub enum PlaybackServiceError {
     ErrorDuringAudioReproduction(#[from] JoinError),
 }
 
+/// Wrapper for OutputStream to add Debug.
+pub struct AudioOutput(pub OutputStream);
+
+impl fmt::Debug for AudioOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<AudioOutput>")
+    }
+}
+
+/// A playback running in the Client, kept so later commands can control it.
+struct Voice {
+    id: Option<VoiceId>,
+    sink: Arc<Sink>,
+    /// Volume ramp in progress. Any later command on the voice cancels it.
+    ramp: Option<JoinHandle<()>>,
+}
+
+impl fmt::Debug for Voice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<Voice {:?}>", self.id)
+    }
+}
+
+impl Voice {
+    fn cancel_ramp(&mut self) {
+        if let Some(ramp) = self.ramp.take() {
+            ramp.abort();
+        }
+    }
+
+    /// Takes the volume to `volume`, in percent, linearly during `duration`.
+    /// If `stop` is set, the playback is stopped once it gets there.
+    fn ramp_volume(&mut self, volume: u8, duration: Duration, stop: bool) {
+        self.cancel_ramp();
+        let target = volume.min(MAX_VOLUME) as f32 / MAX_VOLUME as f32;
+        let sink = self.sink.clone();
+        if duration.is_zero() {
+            sink.set_volume(target);
+            if stop {
+                sink.stop();
+            }
+            return;
+        }
+        self.ramp = Some(task::spawn(async move {
+            let start = sink.volume();
+            let steps = (duration.as_millis() / VOLUME_RAMP_STEP.as_millis()).max(1) as u32;
+            let mut ticks = interval(duration / steps);
+            // The first tick completes immediately.
+            ticks.tick().await;
+            for step in 1..=steps {
+                ticks.tick().await;
+                sink.set_volume(start + (target - start) * step as f32 / steps as f32);
+            }
+            if stop {
+                sink.stop();
+            }
+        }));
+    }
+}
+
 #[derive(Debug)]
 pub struct PlaybackService {
     pub sender: Sender<InternalEventMessageClient>,
     receiver: Receiver<InternalEventMessageClient>,
     media_path: PathBuf,
+    /// Opened with the first playback, and kept open for all the voices to share it.
+    output: Option<AudioOutput>,
+    voices: Vec<Voice>,
 }
 
 impl PlaybackService {
@@ -41,6 +116,8 @@ impl PlaybackService {
             sender,
             receiver,
             media_path,
+            output: None,
+            voices: Vec::new(),
         }
     }
 }
@@ -61,12 +138,27 @@ impl PlaybackService {
     pub async fn run(&mut self) -> Result<(), PlaybackServiceError> {
         loop {
             while let Some(message) = self.receive_message().await {
+                // Voices that finished playing are forgotten.
+                self.voices.retain(|voice| !voice.sink.empty());
                 match message {
                     InternalEventMessageClient::ConnectedToServer(sender) => {
                         self.subscribe_to_remote_service(sender).await?
                     }
-                    InternalEventMessageClient::PlayAudio(audio_file_path, sender) => {
-                        self.play_audio(audio_file_path, sender).await?
+                    // A file that can't be played must not stop the ones still to come.
+                    InternalEventMessageClient::PlayAudio(audio_playback, sender) => {
+                        let file_name = audio_playback.file.file_name_with_extension();
+                        if let Err(error) = self.play_audio(*audio_playback).await {
+                            error!(%error, "Failed playing audio file {}.", file_name);
+                            sender
+                                .send(InternalEventMessageClient::ActionFailed(format!(
+                                    "Failed playing {}.",
+                                    file_name
+                                )))
+                                .await?
+                        }
+                    }
+                    InternalEventMessageClient::ControlAudio(audio_control, _) => {
+                        self.control_audio(audio_control)
                     }
                     InternalEventMessageClient::Config(_) => {
                         unimplemented!("This message is not yet functional.")
@@ -95,26 +187,79 @@ impl PlaybackService {
             .await?)
     }
 
-    async fn play_audio(
-        &mut self,
-        audio_file_data: AudioFile,
-        sender: Sender<InternalEventMessageClient>,
-    ) -> Result<(), PlaybackServiceError> {
-        info!("Playing audio file {:?}.", audio_file_data);
+    /// Starts playing the file in a new voice, and returns without waiting for it to end.
+    async fn play_audio(&mut self, audio_playback: AudioPlayback) -> Result<(), PlaybackServiceError> {
+        info!("Playing audio file {:?}.", audio_playback);
         let audio_file_path = Path::join(
             &self.media_path,
-            audio_file_data.file_name_with_extension().to_string(),
+            audio_playback.file.file_name_with_extension().to_string(),
         );
-        let stream_handle = rodio::OutputStreamBuilder::open_default_stream()?;
-        let sink = rodio::Sink::connect_new(stream_handle.mixer());
-        let playing_audio_task= task::spawn_blocking(move || -> Result<(), PlaybackServiceError> {
-            info!("Playing {:?}", audio_file_path);
+        let decoder = task::spawn_blocking(move || -> Result<_, PlaybackServiceError> {
+            debug!("Decoding {:?}", audio_file_path);
             let file = std::fs::File::open(audio_file_path).map_err( PlaybackServiceError::FailedOpeningTargetAudioFile)?;
-            /// Send here ACK to Client?
-            sink.append(rodio::Decoder::try_from(file).map_err(PlaybackServiceError::FailedPlayingTargetAudioFile)?);
-            sink.sleep_until_end();
-            Ok(())
+            // Send here ACK to Client?
+            rodio::Decoder::try_from(file).map_err(PlaybackServiceError::FailedPlayingTargetAudioFile)
+        })
+        .await??;
+
+        let output = match self.output.take() {
+            Some(output) => output,
+            None => AudioOutput(rodio::OutputStreamBuilder::open_default_stream()?),
+        };
+        let sink = Sink::connect_new(output.0.mixer());
+        self.output = Some(output);
+        sink.set_volume(audio_playback.volume.min(MAX_VOLUME) as f32 / MAX_VOLUME as f32);
+        let start_offset = Duration::from_millis(audio_playback.start_offset_ms as u64);
+        let fade_in = Duration::from_millis(audio_playback.fade_in_ms as u64);
+        if audio_playback.looped {
+            // Only the first time is played from the offset, the loops start from the beginning.
+            let source = decoder.buffered();
+            sink.append(source.clone().skip_duration(start_offset).fade_in(fade_in));
+            sink.append(source.repeat_infinite());
+        } else {
+            sink.append(decoder.skip_duration(start_offset).fade_in(fade_in));
+        }
+
+        // A new playback with the same voice replaces the previous one.
+        if let Some(voice_id) = &audio_playback.voice {
+            self.voices.retain_mut(|voice| {
+                let replaced = voice.id.as_ref() == Some(voice_id);
+                if replaced {
+                    voice.cancel_ramp();
+                    voice.sink.stop();
+                }
+                !replaced
+            });
+        }
+        self.voices.push(Voice {
+            id: audio_playback.voice,
+            sink: Arc::new(sink),
+            ramp: None,
         });
-        playing_audio_task.await?
+        Ok(())
+    }
+
+    /// Applies the command to the voice it refers to, or to all of them if none is given.
+    fn control_audio(&mut self, audio_control: AudioControl) {
+        let fade = Duration::from_millis(audio_control.fade_ms as u64);
+        let mut controlled_voices = self.voices.iter_mut().filter(|voice| {
+            audio_control.voice.is_none() || voice.id == audio_control.voice
+        }).peekable();
+        if controlled_voices.peek().is_none() {
+            warn!("No playback to {} for voice {:?}.", audio_control.command, audio_control.voice);
+            return;
+        }
+        for voice in controlled_voices {
+            info!("{} playback of voice {:?}.", audio_control.command, voice.id);
+            match audio_control.command {
+                AudioCommand::Stop => voice.ramp_volume(0, fade, true),
+                AudioCommand::Pause => {
+                    voice.cancel_ramp();
+                    voice.sink.pause()
+                }
+                AudioCommand::Resume => voice.sink.play(),
+                AudioCommand::SetVolume => voice.ramp_volume(audio_control.volume, fade, false),
+            }
+        }
     }
 }
@@ -1,52 +1,129 @@
 use core::fmt::Write;
 use serde::{Deserialize, Serialize};
 use strum::Display;
-use heapless::String;
+use heapless::{String, Vec};
 
-use crate::{AudioFile, ClientIdAndLocation, ColourRgb, MidiInstruction, Service, Subtitles};
+use crate::{audio::AudioControl, colour_effect::ColourEffect, media::{MediaFileName, MediaStatus}, subtitles::LanguageTag, target_selector::SectionName, AudioPlayback, ClientIdAndLocation, Boxed, ColourRgb, MidiInstruction, Service, Subtitles};
 
 /// These are the payloads the clients will be sending inside the Exchange Messages.
 /// In the future, they may be also the payloads between services. Some feature gating
 /// will be required for it.
 #[derive(Deserialize, Serialize, PartialEq, Debug, Display, Clone)]
+#[cfg_attr(not(feature = "alloc"), allow(clippy::large_enum_variant))]
 pub enum Event {
-    Register(ClientIdAndLocation),
+    Register(Registration),
     SuscribeToService(Service, ClientIdAndLocation),
     UnsubscribeFromService(Service, ClientIdAndLocation),
     UpdateLocation(ClientIdAndLocation),
     PerformAction(Action),
+    JoinGroup(GroupName),
+    LeaveGroup(GroupName),
+    /// Asks the Server to send a file of its media manifest.
+    FetchMedia(MediaFileName),
+    ReportMediaStatus(MediaStatus),
+}
+
+/// Maximum amount of Services a Client can declare as supported when registering.
+pub const MAX_DECLARED_SERVICES: usize = 8;
+
+/// Maximum length of a seat identifier, like `Row F, Seat 12`.
+pub const MAX_SEAT_ID_LENGTH: usize = 24;
+
+/// Identifier of a seat of the venue, as printed in the tickets.
+pub type SeatId = String<MAX_SEAT_ID_LENGTH>;
+
+/// Maximum length of the name of a group of Clients.
+pub const MAX_GROUP_NAME_LENGTH: usize = 24;
+
+/// Name of a group of Clients sharing a role in the show, like `choir phones` or `band monitors`.
+pub type GroupName = String<MAX_GROUP_NAME_LENGTH>;
+
+/// Maximum amount of groups that can be targeted by the same action.
+pub const MAX_TARGET_GROUPS: usize = 8;
+
+/// The different kinds of lamarrs Clients that can connect to the Server.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Display, Clone)]
+pub enum ClientKind {
+    LinuxClient,
+    RpClient,
+    Browser,
+}
+
+/// Payload of the registration request.
+/// Besides identifying the Client, it tells the Server which version of the protocol the Client
+/// speaks, what kind of device it is and what it is able to do, so the Server can reject it if
+/// both ends are not able to understand each other.
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
+pub struct Registration {
+    /// Kept as the first field, so it stays readable even if the rest of the layout changes.
+    pub protocol_version: u16,
+    pub client: ClientIdAndLocation,
+    pub kind: ClientKind,
+    pub services: Vec<Service, MAX_DECLARED_SERVICES>,
+    /// Biggest frame, in bytes, the Client is able to receive.
+    pub max_frame_size: u32,
+    /// Seat of the audience member holding the Client. When present, the Server resolves it
+    /// with its seat map, and the resulting location replaces the one declared by the Client.
+    pub seat: Option<SeatId>,
+    /// Language the Client prefers its subtitles in, as a BCP 47 tag like `es` or `pt-BR`.
+    pub language: Option<LanguageTag>,
+    /// Section of the venue preset for the Client, like the one of the join URL a browser was
+    /// opened with. Ignored when registering with a seat, as the seat map knows its section.
+    pub section: Option<SectionName>,
+    /// Group the Client joins straight away, like the one of the join URL a browser was opened with.
+    pub group: Option<GroupName>,
 }
 
 /// Internal message types to be transmited between actors inside Lamarrs.
 /// These are also the payloads the clients will be sending inside the Exchange Messages.
 #[derive(Deserialize, Serialize, PartialEq, Debug, Display, Clone)]
+#[cfg_attr(not(feature = "alloc"), allow(clippy::large_enum_variant))]
 pub enum Action {
-    ShowNewSubtitles(Subtitles),
+    ShowNewSubtitles(Boxed<Subtitles>),
     ChangeColour(ColourRgb),
-    PlayAudio(AudioFile),
+    PlayAudio(AudioPlayback),
+    AudioControl(AudioControl),
     Midi(MidiInstruction),
+    ColourEffect(ColourEffect),
 }
 
 impl Action {
+    /// Service performing the action, and so the one the Clients must be subscribed to.
+    pub fn service(&self) -> Service {
+        match self {
+            Action::ShowNewSubtitles(_) => Service::Subtitle,
+            Action::ChangeColour(_) | Action::ColourEffect(_) => Service::Colour,
+            Action::PlayAudio(_) | Action::AudioControl(_) => Service::AudioPlayer,
+            Action::Midi(_) => Service::Midi,
+        }
+    }
+
     pub fn as_str<'a>(&self, write_buffer: &'a mut String<128>) -> &'a str {
         write_buffer.clear();
         match self {
             Action::ShowNewSubtitles(subtitles) => {
-                let _ = write!(write_buffer, "Subs {:?}", subtitles.subtitles);
+                let text = subtitles.variants.first().map(|variant| variant.text.as_str());
+                let _ = write!(write_buffer, "Subs {:?}", text.unwrap_or_default());
             }
             Action::ChangeColour(colour_rgb) => {
-                let _ = write!(
-                    write_buffer,
-                    "Colour ({:?}/{:?}/{:?})",
-                    colour_rgb.r, colour_rgb.g, colour_rgb.b
-                );
+                let _ = write!(write_buffer, "Colour {}", colour_rgb);
             }
-            Action::PlayAudio(audio_file) => {
-                let _ = write!(write_buffer, "Audio {:?}", audio_file.file_name);
+            Action::PlayAudio(audio_playback) => {
+                let _ = write!(write_buffer, "Audio {:?}", audio_playback.file.file_name);
             },
+            Action::AudioControl(audio_control) => {
+                let _ = write!(write_buffer, "Audio {} {:?}", audio_control.command, audio_control.voice);
+            }
             Action::Midi(midi_instruction) => {
                 let _ = write!(write_buffer, "MIDI instruction {:?}", midi_instruction);
             }
+            Action::ColourEffect(colour_effect) => {
+                let _ = write!(
+                    write_buffer,
+                    "{} {} {}ms",
+                    colour_effect.kind, colour_effect.colour, colour_effect.duration_ms
+                );
+            }
         }
         write_buffer.as_str()
     }
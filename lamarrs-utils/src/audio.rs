@@ -0,0 +1,156 @@
+//! Audio
+//!
+//! An [`AudioPlayback`] starts playing a file on the Clients, and [`AudioControl`]s change the
+//! playbacks already running. Clients can play several files at the same time, so each playback
+//! can be given a [`VoiceId`] for the later commands to refer to it.
+//!
+//! Both are kept flat, so they can be written in the sequences as:
+//! ```yaml
+//! action: !PlayAudio
+//!   file_name: "Turbulent sea"
+//!   file_extension: "mp3"
+//!   voice: sea
+//!   volume: 80
+//!   looped: true
+//!   fade_in_ms: 2000
+//!   start_offset_ms: 15000
+//! ```
+//! ```yaml
+//! action: !AudioControl
+//!   command: Stop
+//!   voice: sea
+//!   fade_ms: 5000
+//! ```
+
+use heapless::String;
+use serde::{ser::SerializeStruct, Deserialize, Deserializer, Serialize, Serializer};
+use strum::Display;
+
+use crate::AudioFile;
+
+/// Maximum length of the identifier of a voice.
+pub const MAX_VOICE_ID_LENGTH: usize = 16;
+
+/// Volume at which files are played if not specified, in percent.
+pub const MAX_VOLUME: u8 = 100;
+
+/// Identifier given to a playback, like `sea` or `choir`, for later commands to control it.
+pub type VoiceId = String<MAX_VOICE_ID_LENGTH>;
+
+/// Payload for Audio Playback requests.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AudioPlayback {
+    pub file: AudioFile,
+    /// Replaces any playback with the same voice still running in the Client.
+    pub voice: Option<VoiceId>,
+    /// In percent.
+    pub volume: u8,
+    /// Plays the file again every time it ends, until it is stopped.
+    pub looped: bool,
+    pub fade_in_ms: u32,
+    /// Skips the beginning of the file. Loops start again from the beginning.
+    pub start_offset_ms: u32,
+}
+
+impl AudioPlayback {
+    /// Plays the whole file once, at full volume.
+    pub fn new(file: AudioFile) -> Self {
+        Self {
+            file,
+            voice: None,
+            volume: MAX_VOLUME,
+            looped: false,
+            fade_in_ms: 0,
+            start_offset_ms: 0,
+        }
+    }
+}
+
+// Manual Serialize / Deserialize AudioPlayback, to keep the file fields at the top level.
+impl Serialize for AudioPlayback {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("AudioPlayback", 7)?;
+        state.serialize_field("file_name", self.file.file_name.as_str())?;
+        state.serialize_field("file_extension", self.file.file_extension.as_str())?;
+        state.serialize_field("voice", &self.voice)?;
+        state.serialize_field("volume", &self.volume)?;
+        state.serialize_field("looped", &self.looped)?;
+        state.serialize_field("fade_in_ms", &self.fade_in_ms)?;
+        state.serialize_field("start_offset_ms", &self.start_offset_ms)?;
+        state.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for AudioPlayback {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        fn max_volume() -> u8 {
+            MAX_VOLUME
+        }
+
+        // Define a helper struct to receive the normal Strings from serde
+        #[derive(Deserialize)]
+        struct AudioPlaybackHelper<'a> {
+            file_name: &'a str,
+            file_extension: &'a str,
+            #[serde(default)]
+            voice: Option<VoiceId>,
+            #[serde(default = "max_volume")]
+            volume: u8,
+            #[serde(default)]
+            looped: bool,
+            #[serde(default)]
+            fade_in_ms: u32,
+            #[serde(default)]
+            start_offset_ms: u32,
+        }
+
+        let helper = AudioPlaybackHelper::deserialize(deserializer)?;
+        if helper.volume > MAX_VOLUME {
+            return Err(serde::de::Error::custom("volume must be at most 100"));
+        }
+        Ok(AudioPlayback {
+            file: AudioFile {
+                file_name: String::<50>::try_from(helper.file_name)
+                    .map_err(serde::de::Error::custom)?,
+                file_extension: String::<4>::try_from(helper.file_extension)
+                    .map_err(serde::de::Error::custom)?,
+            },
+            voice: helper.voice,
+            volume: helper.volume,
+            looped: helper.looped,
+            fade_in_ms: helper.fade_in_ms,
+            start_offset_ms: helper.start_offset_ms,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize, Display)]
+pub enum AudioCommand {
+    /// Stops the playback, fading it out during `fade_ms`.
+    Stop,
+    Pause,
+    Resume,
+    /// Ramps the volume to `volume` during `fade_ms`.
+    SetVolume,
+}
+
+/// Payload for the requests controlling the playbacks running in the Clients.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct AudioControl {
+    pub command: AudioCommand,
+    /// Voice the command applies to. All the playbacks running if missing.
+    #[serde(default)]
+    pub voice: Option<VoiceId>,
+    /// In percent. Only used by `SetVolume`.
+    #[serde(default)]
+    pub volume: u8,
+    /// Changes are immediate if 0.
+    #[serde(default)]
+    pub fade_ms: u32,
+}
@@ -0,0 +1,237 @@
+//! Clock synchronisation
+//!
+//! The Server periodically runs an NTP-style exchange with each Client:
+//!  1. The Server sends a [`TimeSyncRequest`] stamped with its clock, `t1`.
+//!  2. The Client stamps when it receives it, `t2`, and when it answers with a
+//!     [`TimeSyncResponse`], `t3`, using its own clock.
+//!  3. The Server stamps when it receives the answer, `t4`, estimates the offset between both
+//!     clocks and the round trip time, and shares them with the Client in a [`TimeSyncResult`].
+//!
+//! The Clients feed the results to a [`ClockEstimator`], which also tracks how fast their clock
+//! drifts from the Server one, so they can translate Server timestamps into their own clock.
+//! All the timestamps are microseconds. The Server uses the UNIX epoch, while the Clients can
+//! use any monotonic clock.
+//!
+//! Once synchronised, the Clients are able to perform the Scenes carrying a [`Schedule`] at the
+//! same instant, no matter when each of them received it.
+
+use heapless::Deque;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct TimeSyncRequest {
+    pub server_sent_us: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct TimeSyncResponse {
+    /// Echoed from the `TimeSyncRequest`.
+    pub server_sent_us: u64,
+    pub client_received_us: u64,
+    pub client_sent_us: u64,
+}
+
+/// Results of a synchronisation round.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct TimeSyncResult {
+    /// What has to be added to the Client clock to get the Server one.
+    pub offset_us: i64,
+    /// Time spent by the messages traveling, without the time the Client took to answer.
+    pub round_trip_us: u32,
+}
+
+impl TimeSyncResponse {
+    /// Estimates the results of the round, given when the Server received the response.
+    pub fn estimate(&self, server_received_us: u64) -> TimeSyncResult {
+        let t1 = self.server_sent_us as i64;
+        let t2 = self.client_received_us as i64;
+        let t3 = self.client_sent_us as i64;
+        let t4 = server_received_us as i64;
+        TimeSyncResult {
+            offset_us: ((t1 - t2) + (t4 - t3)) / 2,
+            round_trip_us: ((t4 - t1) - (t3 - t2)).clamp(0, u32::MAX as i64) as u32,
+        }
+    }
+}
+
+/// When a Scene must be performed, in Server time.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Schedule {
+    pub execute_at_us: u64,
+    /// Scenes received after this are dropped, as performing them late would be worse than not
+    /// performing them at all. If missing, the Scene is performed as soon as possible.
+    pub expires_at_us: Option<u64>,
+}
+
+impl Schedule {
+    pub fn is_expired(&self, server_now_us: u64) -> bool {
+        self.expires_at_us
+            .is_some_and(|expires_at_us| server_now_us > expires_at_us)
+    }
+}
+
+/// Rounds whose round trip is this many times slower than the fastest recent one are ignored,
+/// as the network delays were too asymmetric to trust them.
+const MAX_ROUND_TRIP_FACTOR: u32 = 4;
+
+/// Latest rounds the fastest round trip is looked for in, so the rounds are compared against
+/// the current state of the network rather than the best it ever was.
+const ROUND_TRIP_WINDOW: usize = 8;
+
+/// Minimum time between the samples used to estimate the drift, as shorter spans are dominated
+/// by the network jitter.
+const MIN_DRIFT_SPAN_US: u64 = 10_000_000;
+
+/// Keeps an estimation of the offset and drift of the local clock against the Server one.
+#[derive(Clone, Debug, Default)]
+pub struct ClockEstimator {
+    /// Latest accepted sample, as local time and offset.
+    last_sample: Option<(u64, i64)>,
+    /// Sample the drift is measured against.
+    drift_reference: Option<(u64, i64)>,
+    /// Parts per billion the offset changes per elapsed local time, once measured.
+    drift_ppb: Option<i64>,
+    /// Round trips of the latest rounds, whether they were discarded or not.
+    recent_round_trips_us: Deque<u32, ROUND_TRIP_WINDOW>,
+}
+
+impl ClockEstimator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the results of a synchronisation round, received at `local_us`.
+    /// Returns `false` if the round was discarded because of its round trip.
+    pub fn add_sample(&mut self, local_us: u64, result: &TimeSyncResult) -> bool {
+        if self.recent_round_trips_us.is_full() {
+            self.recent_round_trips_us.pop_front();
+        }
+        // There is always room, after dropping the oldest round.
+        let _ = self.recent_round_trips_us.push_back(result.round_trip_us);
+        let fastest_round_trip_us = self
+            .recent_round_trips_us
+            .iter()
+            .copied()
+            .min()
+            .unwrap_or(result.round_trip_us);
+        if result.round_trip_us > fastest_round_trip_us.max(1) * MAX_ROUND_TRIP_FACTOR {
+            return false;
+        }
+        match self.drift_reference {
+            Some((reference_us, reference_offset_us))
+                if local_us.saturating_sub(reference_us) >= MIN_DRIFT_SPAN_US =>
+            {
+                let span_us = (local_us - reference_us) as i128;
+                let drift_ppb =
+                    ((result.offset_us - reference_offset_us) as i128 * 1_000_000_000 / span_us)
+                        as i64;
+                // Smoothed, so a single bad sample doesn't spoil the estimation.
+                self.drift_ppb = Some(match self.drift_ppb {
+                    Some(previous_drift_ppb) => (previous_drift_ppb * 3 + drift_ppb) / 4,
+                    None => drift_ppb,
+                });
+                self.drift_reference = Some((local_us, result.offset_us));
+            }
+            Some(_) => (),
+            None => self.drift_reference = Some((local_us, result.offset_us)),
+        }
+        self.last_sample = Some((local_us, result.offset_us));
+        true
+    }
+
+    /// Adds a rough estimation of the offset, ignoring the network delay, from a Server timestamp
+    /// received at `local_us`. It is only used until the first synchronisation round ends.
+    pub fn add_server_time(&mut self, local_us: u64, server_us: u64) {
+        if !self.is_synchronised() {
+            self.last_sample = Some((local_us, server_us as i64 - local_us as i64));
+        }
+    }
+
+    /// Estimated offset at `local_us`, extrapolated from the latest sample with the drift.
+    pub fn offset_at(&self, local_us: u64) -> Option<i64> {
+        self.last_sample.map(|(sample_us, offset_us)| {
+            let elapsed_us = local_us as i128 - sample_us as i128;
+            offset_us + (elapsed_us * self.drift_ppb() as i128 / 1_000_000_000) as i64
+        })
+    }
+
+    /// Translates a local timestamp into the Server clock.
+    pub fn to_server_time(&self, local_us: u64) -> Option<u64> {
+        self.offset_at(local_us)
+            .map(|offset_us| (local_us as i64 + offset_us).max(0) as u64)
+    }
+
+    /// Translates a Server timestamp into the local clock. `local_now_us` is used to
+    /// estimate the offset, as it barely changes in the time between both.
+    pub fn to_local_time(&self, server_us: u64, local_now_us: u64) -> Option<u64> {
+        self.offset_at(local_now_us)
+            .map(|offset_us| (server_us as i64 - offset_us).max(0) as u64)
+    }
+
+    /// Parts per billion the local clock drifts from the Server one. 0 until it can be measured.
+    pub fn drift_ppb(&self) -> i64 {
+        self.drift_ppb.unwrap_or_default()
+    }
+
+    pub fn is_synchronised(&self) -> bool {
+        self.last_sample.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round(offset_us: i64, round_trip_us: u32) -> TimeSyncResult {
+        TimeSyncResult {
+            offset_us,
+            round_trip_us,
+        }
+    }
+
+    #[test]
+    fn estimates_the_round_from_the_four_timestamps() {
+        let response = TimeSyncResponse {
+            server_sent_us: 1_000,
+            client_received_us: 600,
+            client_sent_us: 700,
+        };
+
+        assert_eq!(response.estimate(1_300), round(500, 200));
+    }
+
+    #[test]
+    fn rounds_much_slower_than_the_recent_ones_are_discarded() {
+        let mut estimator = ClockEstimator::new();
+
+        assert!(estimator.add_sample(0, &round(1_000, 1_000)));
+        assert!(!estimator.add_sample(1, &round(9_000, 5_000)));
+        assert_eq!(estimator.offset_at(1), Some(1_000));
+    }
+
+    #[test]
+    fn follows_the_network_once_it_gets_slower() {
+        let mut estimator = ClockEstimator::new();
+        estimator.add_sample(0, &round(1_000, 1_000));
+
+        let accepted = (1..=ROUND_TRIP_WINDOW as u64)
+            .map(|local_us| estimator.add_sample(local_us, &round(2_000, 10_000)))
+            .collect::<std::vec::Vec<_>>();
+
+        // The fast round stops being the reference once it leaves the window.
+        assert!(accepted[..ROUND_TRIP_WINDOW - 1].iter().all(|accepted| !accepted));
+        assert!(accepted[ROUND_TRIP_WINDOW - 1]);
+        assert_eq!(estimator.offset_at(ROUND_TRIP_WINDOW as u64), Some(2_000));
+    }
+
+    #[test]
+    fn extrapolates_the_offset_with_the_drift() {
+        let mut estimator = ClockEstimator::new();
+        estimator.add_sample(0, &round(0, 100));
+        estimator.add_sample(MIN_DRIFT_SPAN_US, &round(1_000, 100));
+
+        assert_eq!(estimator.drift_ppb(), 100_000);
+        assert_eq!(estimator.offset_at(2 * MIN_DRIFT_SPAN_US), Some(2_000));
+        assert_eq!(estimator.to_server_time(2 * MIN_DRIFT_SPAN_US), Some(2 * MIN_DRIFT_SPAN_US + 2_000));
+    }
+}
@@ -0,0 +1,407 @@
+//! Colours
+//!
+//! [`ColourRgb`] is the normalised form every colour travels in between the Server and the
+//! Clients. Sequences and operators can write colours in friendlier forms, all of them
+//! converted into it when deserialised from a human readable format, like YAML or JSON:
+//!  * `"#ff8800"`, `"ff8800"` or `"#ff880040"` (RGBW) hex strings.
+//!  * Names from the palette, like `"amber"`.
+//!  * Maps with `r`, `g`, `b`, with `h`, `s`, `v` (hue in degrees, saturation and value in
+//!    percentage), with `hex` or with `name`. All of them accept an optional white channel `w`
+//!    and a `brightness` percentage that dims the whole colour.
+
+use core::fmt::{self, Write};
+
+use heapless::String;
+use serde::{
+    de::{self, MapAccess, Visitor},
+    ser::SerializeStruct,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+/// Named colours operators can use instead of their RGB values.
+pub const PALETTE: [(&str, ColourRgb); 16] = [
+    ("black", ColourRgb::new(0, 0, 0)),
+    ("white", ColourRgb::new(255, 255, 255)),
+    ("warm_white", ColourRgb::new(255, 214, 170)),
+    ("red", ColourRgb::new(255, 0, 0)),
+    ("green", ColourRgb::new(0, 255, 0)),
+    ("blue", ColourRgb::new(0, 0, 255)),
+    ("yellow", ColourRgb::new(255, 255, 0)),
+    ("amber", ColourRgb::new(255, 191, 0)),
+    ("orange", ColourRgb::new(255, 136, 0)),
+    ("cyan", ColourRgb::new(0, 255, 255)),
+    ("magenta", ColourRgb::new(255, 0, 255)),
+    ("purple", ColourRgb::new(128, 0, 255)),
+    ("pink", ColourRgb::new(255, 105, 180)),
+    ("lime", ColourRgb::new(128, 255, 0)),
+    ("teal", ColourRgb::new(0, 128, 128)),
+    ("uv", ColourRgb::new(75, 0, 130)),
+];
+
+/// Reasons a colour could not be parsed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ColourError {
+    /// Hex colours must have 6 (RGB) or 8 (RGBW) hex digits, optionally preceded by `#`.
+    InvalidHex,
+    UnknownName,
+    /// A map describing a colour must use only one of the RGB, HSV, hex or name forms.
+    AmbiguousColour,
+    MissingChannels,
+    OutOfRange,
+}
+
+impl fmt::Display for ColourError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ColourError::InvalidHex => write!(f, "hex colours must look like #rrggbb or #rrggbbww"),
+            ColourError::UnknownName => write!(f, "the colour name is not part of the palette"),
+            ColourError::AmbiguousColour => {
+                write!(f, "a colour must be given only as RGB, HSV, hex or name")
+            }
+            ColourError::MissingChannels => {
+                write!(f, "a colour needs all its r, g and b or h, s and v channels")
+            }
+            ColourError::OutOfRange => write!(
+                f,
+                "hue must be below 360, saturation, value and brightness at most 100"
+            ),
+        }
+    }
+}
+
+/// Payload for colour change requests, and normalised wire form of any colour.
+/// The white channel is only present for colours meant for RGBW fixtures.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ColourRgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub w: Option<u8>,
+}
+
+impl ColourRgb {
+    pub const fn new(red: u8, green: u8, blue: u8) -> Self {
+        Self {
+            r: red,
+            g: green,
+            b: blue,
+            w: None,
+        }
+    }
+
+    pub const fn with_white(mut self, white: u8) -> Self {
+        self.w = Some(white);
+        self
+    }
+
+    /// Parses `#rrggbb` or `#rrggbbww`, with or without the `#`.
+    pub fn from_hex(hex: &str) -> Result<Self, ColourError> {
+        let hex = hex.strip_prefix('#').unwrap_or(hex);
+        if !(hex.len() == 6 || hex.len() == 8) || !hex.is_ascii() {
+            return Err(ColourError::InvalidHex);
+        }
+        let channel = |index: usize| {
+            u8::from_str_radix(&hex[index..index + 2], 16).map_err(|_| ColourError::InvalidHex)
+        };
+        let colour = Self::new(channel(0)?, channel(2)?, channel(4)?);
+        match hex.len() {
+            8 => Ok(colour.with_white(channel(6)?)),
+            _ => Ok(colour),
+        }
+    }
+
+    /// Looks for the colour in the `PALETTE`, ignoring case.
+    pub fn from_name(name: &str) -> Result<Self, ColourError> {
+        PALETTE
+            .iter()
+            .find(|(palette_name, _)| palette_name.eq_ignore_ascii_case(name))
+            .map(|(_, colour)| colour.clone())
+            .ok_or(ColourError::UnknownName)
+    }
+
+    /// Converts a colour from HSV, with the hue in degrees and the saturation and value in percentage.
+    pub fn from_hsv(hue: u16, saturation: u8, value: u8) -> Result<Self, ColourError> {
+        if hue >= 360 || saturation > 100 || value > 100 {
+            return Err(ColourError::OutOfRange);
+        }
+        // Integer maths only, as most of the embedded Clients have no FPU.
+        let value = value as u32 * 255 / 100;
+        let saturation = saturation as u32;
+        let remainder = hue as u32 % 60;
+        let p = value * (100 - saturation) / 100;
+        let q = value * (6000 - saturation * remainder) / 6000;
+        let t = value * (6000 - saturation * (60 - remainder)) / 6000;
+        let (r, g, b) = match hue / 60 {
+            0 => (value, t, p),
+            1 => (q, value, p),
+            2 => (p, value, t),
+            3 => (p, q, value),
+            4 => (t, p, value),
+            _ => (value, p, q),
+        };
+        Ok(Self::new(r as u8, g as u8, b as u8))
+    }
+
+    /// Dims all the channels to `brightness` percent.
+    pub fn with_brightness(self, brightness: u8) -> Result<Self, ColourError> {
+        if brightness > 100 {
+            return Err(ColourError::OutOfRange);
+        }
+        let dim = |channel: u8| (channel as u16 * brightness as u16 / 100) as u8;
+        Ok(Self {
+            r: dim(self.r),
+            g: dim(self.g),
+            b: dim(self.b),
+            w: self.w.map(dim),
+        })
+    }
+
+    /// Channels for RGB devices. The white channel, if any, is mixed into the other three.
+    pub fn to_rgb(&self) -> [u8; 3] {
+        let white = self.w.unwrap_or_default();
+        [
+            self.r.saturating_add(white),
+            self.g.saturating_add(white),
+            self.b.saturating_add(white),
+        ]
+    }
+
+    /// Channels for RGBW fixtures. Without a white channel, the white common to the three
+    /// colours is moved to it.
+    pub fn to_rgbw(&self) -> [u8; 4] {
+        match self.w {
+            Some(white) => [self.r, self.g, self.b, white],
+            None => {
+                let white = self.r.min(self.g).min(self.b);
+                [self.r - white, self.g - white, self.b - white, white]
+            }
+        }
+    }
+
+    pub fn to_hex(&self) -> String<9> {
+        let mut hex = String::new();
+        // `#rrggbbww` always fits.
+        let _ = write!(hex, "#{:02x}{:02x}{:02x}", self.r, self.g, self.b);
+        if let Some(white) = self.w {
+            let _ = write!(hex, "{:02x}", white);
+        }
+        hex
+    }
+}
+
+impl fmt::Display for ColourRgb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_hex())
+    }
+}
+
+// Manual Serialize / Deserialize ColourRgb, to accept all the human readable forms.
+impl Serialize for ColourRgb {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("ColourRgb", 4)?;
+        state.serialize_field("r", &self.r)?;
+        state.serialize_field("g", &self.g)?;
+        state.serialize_field("b", &self.b)?;
+        state.serialize_field("w", &self.w)?;
+        state.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for ColourRgb {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(ColourVisitor)
+        } else {
+            // Binary formats always carry the normalised form.
+            #[derive(Deserialize)]
+            struct ColourRgbHelper {
+                r: u8,
+                g: u8,
+                b: u8,
+                w: Option<u8>,
+            }
+            let ColourRgbHelper { r, g, b, w } = ColourRgbHelper::deserialize(deserializer)?;
+            Ok(ColourRgb { r, g, b, w })
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(field_identifier, rename_all = "lowercase")]
+enum ColourField {
+    R,
+    G,
+    B,
+    W,
+    H,
+    S,
+    V,
+    Hex,
+    Name,
+    Brightness,
+}
+
+struct ColourVisitor;
+
+impl<'de> Visitor<'de> for ColourVisitor {
+    type Value = ColourRgb;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a hex string, a colour name or a map with RGB or HSV channels")
+    }
+
+    fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        let colour = if value.starts_with('#') {
+            ColourRgb::from_hex(value)
+        } else {
+            ColourRgb::from_name(value).or_else(|_| ColourRgb::from_hex(value))
+        };
+        colour.map_err(E::custom)
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let (mut r, mut g, mut b, mut w) = (None, None, None, None);
+        let (mut h, mut s, mut v) = (None, None, None);
+        let (mut hex, mut name, mut brightness) = (None, None, None);
+        while let Some(field) = map.next_key()? {
+            match field {
+                ColourField::R => r = Some(map.next_value::<u8>()?),
+                ColourField::G => g = Some(map.next_value::<u8>()?),
+                ColourField::B => b = Some(map.next_value::<u8>()?),
+                ColourField::W => w = map.next_value::<Option<u8>>()?,
+                ColourField::H => h = Some(map.next_value::<u16>()?),
+                ColourField::S => s = Some(map.next_value::<u8>()?),
+                ColourField::V => v = Some(map.next_value::<u8>()?),
+                ColourField::Hex => hex = Some(map.next_value::<String<9>>()?),
+                ColourField::Name => name = Some(map.next_value::<String<16>>()?),
+                ColourField::Brightness => brightness = Some(map.next_value::<u8>()?),
+            }
+        }
+        let is_rgb = r.is_some() || g.is_some() || b.is_some();
+        let is_hsv = h.is_some() || s.is_some() || v.is_some();
+        let colour = match (is_rgb, is_hsv, hex, name) {
+            (true, false, None, None) => match (r, g, b) {
+                (Some(r), Some(g), Some(b)) => Ok(ColourRgb::new(r, g, b)),
+                _ => Err(ColourError::MissingChannels),
+            },
+            (false, true, None, None) => match (h, s, v) {
+                (Some(h), Some(s), Some(v)) => ColourRgb::from_hsv(h, s, v),
+                _ => Err(ColourError::MissingChannels),
+            },
+            (false, false, Some(hex), None) => ColourRgb::from_hex(&hex),
+            (false, false, None, Some(name)) => ColourRgb::from_name(&name),
+            (false, false, None, None) => Err(ColourError::MissingChannels),
+            _ => Err(ColourError::AmbiguousColour),
+        };
+        let mut colour = colour.map_err(de::Error::custom)?;
+        if let Some(white) = w {
+            colour.w = Some(white);
+        }
+        match brightness {
+            Some(brightness) => colour.with_brightness(brightness).map_err(de::Error::custom),
+            None => Ok(colour),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_json(json: &str) -> Result<ColourRgb, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    #[test]
+    fn hex_colours_are_read_with_or_without_white() {
+        assert_eq!(ColourRgb::from_hex("#ff8800"), Ok(ColourRgb::new(255, 136, 0)));
+        assert_eq!(
+            ColourRgb::from_hex("FF880040"),
+            Ok(ColourRgb::new(255, 136, 0).with_white(64))
+        );
+        for invalid in ["#ff880", "#ff88000", "#ff88zz", "#ff88é"] {
+            assert_eq!(ColourRgb::from_hex(invalid), Err(ColourError::InvalidHex));
+        }
+        assert_eq!(
+            ColourRgb::new(255, 136, 0).with_white(64).to_hex().as_str(),
+            "#ff880040"
+        );
+    }
+
+    #[test]
+    fn hsv_colours_are_converted_without_floats() {
+        assert_eq!(ColourRgb::from_hsv(0, 100, 100), Ok(ColourRgb::new(255, 0, 0)));
+        assert_eq!(ColourRgb::from_hsv(120, 100, 100), Ok(ColourRgb::new(0, 255, 0)));
+        assert_eq!(ColourRgb::from_hsv(240, 100, 100), Ok(ColourRgb::new(0, 0, 255)));
+        assert_eq!(ColourRgb::from_hsv(30, 100, 100), Ok(ColourRgb::new(255, 127, 0)));
+        assert_eq!(ColourRgb::from_hsv(200, 0, 50), Ok(ColourRgb::new(127, 127, 127)));
+        assert_eq!(ColourRgb::from_hsv(360, 100, 100), Err(ColourError::OutOfRange));
+        assert_eq!(ColourRgb::from_hsv(0, 101, 100), Err(ColourError::OutOfRange));
+    }
+
+    #[test]
+    fn colours_are_read_in_every_human_readable_form() {
+        let orange = ColourRgb::new(255, 136, 0);
+        for json in [
+            r##""#ff8800""##,
+            r#""ff8800""#,
+            r#""Orange""#,
+            r#"{"r": 255, "g": 136, "b": 0}"#,
+            r##"{"hex": "#ff8800"}"##,
+            r#"{"name": "orange"}"#,
+        ] {
+            assert_eq!(from_json(json).unwrap(), orange, "{json}");
+        }
+        assert_eq!(
+            from_json(r#"{"name": "orange", "w": 10, "brightness": 50}"#).unwrap(),
+            ColourRgb::new(127, 68, 0).with_white(5)
+        );
+        assert_eq!(
+            from_json(r#"{"h": 120, "s": 100, "v": 100}"#).unwrap(),
+            ColourRgb::new(0, 255, 0)
+        );
+    }
+
+    #[test]
+    fn colours_that_cant_be_read_are_refused() {
+        for json in [
+            r#""chartreuse""#,
+            r#"{"r": 255, "g": 136}"#,
+            r#"{"r": 255, "g": 136, "b": 0, "name": "orange"}"#,
+            r#"{"h": 120, "s": 100, "v": 100, "hex": "00ff00"}"#,
+            r#"{"brightness": 50}"#,
+            r#"{"name": "orange", "brightness": 150}"#,
+            r#"{"r": 256, "g": 0, "b": 0}"#,
+        ] {
+            assert!(from_json(json).is_err(), "{json}");
+        }
+    }
+
+    #[test]
+    fn binary_formats_carry_the_normalised_colour() {
+        let colour = ColourRgb::new(1, 2, 3).with_white(4);
+
+        let bytes = postcard::to_stdvec(&colour).unwrap();
+
+        assert_eq!(bytes, [1, 2, 3, 1, 4]);
+        assert_eq!(postcard::from_bytes::<ColourRgb>(&bytes).unwrap(), colour);
+    }
+
+    #[test]
+    fn white_is_split_or_mixed_for_each_kind_of_device() {
+        assert_eq!(ColourRgb::new(200, 150, 100).to_rgbw(), [100, 50, 0, 100]);
+        assert_eq!(ColourRgb::new(200, 150, 100).with_white(80).to_rgb(), [255, 230, 180]);
+    }
+}
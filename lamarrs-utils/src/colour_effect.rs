@@ -0,0 +1,279 @@
+//! Colour effects
+//!
+//! Instead of streaming a colour per frame, the Server sends a single [`ColourEffect`] and each
+//! Client renders it locally, asking the evaluator for the colour to show at every frame.
+//! The evaluator uses integer maths only, so it runs on Clients without FPU.
+
+use serde::{Deserialize, Serialize};
+use strum::Display;
+
+use crate::ColourRgb;
+
+/// Progress of an effect is handled in permille, to avoid floats.
+const FULL: u32 = 1000;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize, Display)]
+pub enum EffectKind {
+    /// Transitions from `from` to `colour` in `duration_ms`.
+    Fade,
+    /// Breathes between `from` and `colour` every `period_ms`.
+    Pulse,
+    /// Flashes `colour` over `from` every `period_ms`.
+    Strobe,
+    /// Cycles through all the hues every `period_ms`.
+    Rainbow,
+    /// Shows `colour` for `duration_ms` and then reverts to `from`.
+    Hold,
+}
+
+/// Shape of the transitions of fades and pulses.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub enum Easing {
+    #[default]
+    Linear,
+    EaseIn,
+    EaseOut,
+    EaseInOut,
+}
+
+impl Easing {
+    /// Eases a progress given in permille.
+    fn apply(&self, progress: u32) -> u32 {
+        let progress = progress.min(FULL);
+        let remaining = FULL - progress;
+        match self {
+            Easing::Linear => progress,
+            Easing::EaseIn => progress * progress / FULL,
+            Easing::EaseOut => FULL - remaining * remaining / FULL,
+            Easing::EaseInOut if progress < FULL / 2 => 2 * progress * progress / FULL,
+            Easing::EaseInOut => FULL - 2 * remaining * remaining / FULL,
+        }
+    }
+}
+
+/// Payload for colour effect requests.
+/// It is kept flat, instead of one enum variant per effect, so it can be written in the sequences.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ColourEffect {
+    pub kind: EffectKind,
+    /// Colour the effect goes to, pulses, flashes or holds. Ignored by the rainbow.
+    pub colour: ColourRgb,
+    /// Colour the effect starts from and goes back to. If missing, the one the Client was
+    /// showing before the effect started.
+    pub from: Option<ColourRgb>,
+    /// How long the effect lasts. Pulses, strobes and rainbows last until the next colour
+    /// request if it is 0.
+    #[serde(default)]
+    pub duration_ms: u32,
+    /// Length of a cycle of the pulses, strobes and rainbows.
+    #[serde(default)]
+    pub period_ms: u32,
+    #[serde(default)]
+    pub easing: Easing,
+}
+
+impl ColourEffect {
+    /// Returns `true` if the effect is over `elapsed_ms` after it started. From then on,
+    /// the evaluator always returns the colour the effect ends with.
+    pub fn is_finished(&self, elapsed_ms: u32) -> bool {
+        match self.kind {
+            EffectKind::Fade | EffectKind::Hold => elapsed_ms >= self.duration_ms,
+            EffectKind::Pulse | EffectKind::Strobe | EffectKind::Rainbow => {
+                self.duration_ms != 0 && elapsed_ms >= self.duration_ms
+            }
+        }
+    }
+
+    /// Colour to show `elapsed_ms` after the effect started, with `previous` being the one the
+    /// Client was showing at that moment.
+    pub fn evaluate(&self, previous: &ColourRgb, elapsed_ms: u32) -> ColourRgb {
+        let from = self.from.as_ref().unwrap_or(previous);
+        if self.is_finished(elapsed_ms) {
+            return match self.kind {
+                EffectKind::Fade => self.colour.clone(),
+                _ => from.clone(),
+            };
+        }
+        match self.kind {
+            EffectKind::Fade => {
+                let progress = elapsed_ms as u64 * FULL as u64 / self.duration_ms.max(1) as u64;
+                mix(from, &self.colour, self.easing.apply(progress as u32))
+            }
+            EffectKind::Hold => self.colour.clone(),
+            _ if self.period_ms == 0 => self.colour.clone(),
+            EffectKind::Pulse => {
+                let phase = self.phase(elapsed_ms);
+                // Triangle wave, going up during the first half of the period and down after it.
+                let progress = if phase < FULL / 2 {
+                    phase * 2
+                } else {
+                    (FULL - phase) * 2
+                };
+                mix(from, &self.colour, self.easing.apply(progress))
+            }
+            EffectKind::Strobe if self.phase(elapsed_ms) < FULL / 2 => self.colour.clone(),
+            EffectKind::Strobe => from.clone(),
+            EffectKind::Rainbow => {
+                let hue = self.phase(elapsed_ms) * 360 / FULL;
+                // The hue is always below 360, so the conversion never fails.
+                ColourRgb::from_hsv(hue as u16, 100, 100).unwrap_or_else(|_| self.colour.clone())
+            }
+        }
+    }
+
+    /// Position inside the current cycle, in permille.
+    fn phase(&self, elapsed_ms: u32) -> u32 {
+        ((elapsed_ms % self.period_ms) as u64 * FULL as u64 / self.period_ms as u64) as u32
+    }
+}
+
+/// Mixes both colours, `progress` permille of the way from `from` to `to`.
+fn mix(from: &ColourRgb, to: &ColourRgb, progress: u32) -> ColourRgb {
+    let channel = |from: u8, to: u8| {
+        (from as i32 + (to as i32 - from as i32) * progress as i32 / FULL as i32) as u8
+    };
+    ColourRgb {
+        r: channel(from.r, to.r),
+        g: channel(from.g, to.g),
+        b: channel(from.b, to.b),
+        w: match (from.w, to.w) {
+            (None, None) => None,
+            (from_white, to_white) => Some(channel(
+                from_white.unwrap_or_default(),
+                to_white.unwrap_or_default(),
+            )),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLACK: ColourRgb = ColourRgb::new(0, 0, 0);
+    const ORANGE: ColourRgb = ColourRgb::new(200, 100, 0);
+
+    fn effect(kind: EffectKind, duration_ms: u32, period_ms: u32, easing: Easing) -> ColourEffect {
+        ColourEffect {
+            kind,
+            colour: ORANGE,
+            from: Some(BLACK),
+            duration_ms,
+            period_ms,
+            easing,
+        }
+    }
+
+    #[test]
+    fn fades_follow_their_easing() {
+        let fade = |easing| effect(EffectKind::Fade, 1000, 0, easing);
+        let linear = fade(Easing::Linear);
+        assert_eq!(linear.evaluate(&BLACK, 0), BLACK);
+        assert_eq!(linear.evaluate(&BLACK, 250), ColourRgb::new(50, 25, 0));
+        assert_eq!(linear.evaluate(&BLACK, 500), ColourRgb::new(100, 50, 0));
+        assert_eq!(fade(Easing::EaseIn).evaluate(&BLACK, 500), ColourRgb::new(50, 25, 0));
+        assert_eq!(fade(Easing::EaseOut).evaluate(&BLACK, 500), ColourRgb::new(150, 75, 0));
+        let ease_in_out = fade(Easing::EaseInOut);
+        assert_eq!(ease_in_out.evaluate(&BLACK, 250), ColourRgb::new(25, 12, 0));
+        assert_eq!(ease_in_out.evaluate(&BLACK, 500), ColourRgb::new(100, 50, 0));
+        assert_eq!(ease_in_out.evaluate(&BLACK, 750), ColourRgb::new(175, 87, 0));
+        for easing in [Easing::Linear, Easing::EaseIn, Easing::EaseOut, Easing::EaseInOut] {
+            let fade = fade(easing);
+            assert!(!fade.is_finished(999));
+            assert!(fade.is_finished(1000));
+            assert_eq!(fade.evaluate(&BLACK, 1000), ORANGE);
+            assert_eq!(fade.evaluate(&BLACK, u32::MAX), ORANGE);
+        }
+    }
+
+    #[test]
+    fn fades_start_from_the_previous_colour_and_mix_the_white_channel() {
+        let fade = ColourEffect {
+            colour: ORANGE.with_white(200),
+            from: None,
+            ..effect(EffectKind::Fade, 1000, 0, Easing::Linear)
+        };
+        let previous = ColourRgb::new(100, 200, 50);
+        assert_eq!(fade.evaluate(&previous, 0), previous.clone().with_white(0));
+        assert_eq!(
+            fade.evaluate(&previous, 500),
+            ColourRgb::new(150, 150, 25).with_white(100)
+        );
+    }
+
+    #[test]
+    fn pulses_follow_a_triangle_wave() {
+        let pulse = effect(EffectKind::Pulse, 0, 1000, Easing::Linear);
+        assert_eq!(pulse.evaluate(&BLACK, 0), BLACK);
+        assert_eq!(pulse.evaluate(&BLACK, 250), ColourRgb::new(100, 50, 0));
+        assert_eq!(pulse.evaluate(&BLACK, 500), ORANGE);
+        assert_eq!(pulse.evaluate(&BLACK, 750), ColourRgb::new(100, 50, 0));
+        assert_eq!(pulse.evaluate(&BLACK, 1000), BLACK);
+        assert_eq!(pulse.evaluate(&BLACK, 1250), ColourRgb::new(100, 50, 0));
+        let eased = effect(EffectKind::Pulse, 0, 1000, Easing::EaseIn);
+        assert_eq!(eased.evaluate(&BLACK, 250), ColourRgb::new(50, 25, 0));
+        assert_eq!(eased.evaluate(&BLACK, 750), ColourRgb::new(50, 25, 0));
+    }
+
+    #[test]
+    fn strobes_flash_during_the_first_half_of_each_period() {
+        let strobe = effect(EffectKind::Strobe, 0, 100, Easing::Linear);
+        assert_eq!(strobe.evaluate(&BLACK, 0), ORANGE);
+        assert_eq!(strobe.evaluate(&BLACK, 49), ORANGE);
+        assert_eq!(strobe.evaluate(&BLACK, 50), BLACK);
+        assert_eq!(strobe.evaluate(&BLACK, 99), BLACK);
+        assert_eq!(strobe.evaluate(&BLACK, 100), ORANGE);
+        assert_eq!(strobe.evaluate(&BLACK, 149), ORANGE);
+    }
+
+    #[test]
+    fn rainbows_cycle_through_the_hues() {
+        let rainbow = effect(EffectKind::Rainbow, 0, 1000, Easing::Linear);
+        assert_eq!(rainbow.evaluate(&BLACK, 0), ColourRgb::new(255, 0, 0));
+        assert_eq!(rainbow.evaluate(&BLACK, 250), ColourRgb::new(127, 255, 0));
+        assert_eq!(rainbow.evaluate(&BLACK, 500), ColourRgb::new(0, 255, 255));
+        assert_eq!(rainbow.evaluate(&BLACK, 1000), ColourRgb::new(255, 0, 0));
+    }
+
+    #[test]
+    fn holds_revert_to_the_colour_they_started_from() {
+        let hold = effect(EffectKind::Hold, 500, 0, Easing::Linear);
+        assert_eq!(hold.evaluate(&BLACK, 0), ORANGE);
+        assert_eq!(hold.evaluate(&BLACK, 499), ORANGE);
+        assert!(!hold.is_finished(499));
+        assert!(hold.is_finished(500));
+        assert_eq!(hold.evaluate(&BLACK, 500), BLACK);
+        let previous = ColourRgb::new(10, 20, 30);
+        let hold = ColourEffect { from: None, ..hold };
+        assert_eq!(hold.evaluate(&previous, 499), ORANGE);
+        assert_eq!(hold.evaluate(&previous, 500), previous);
+    }
+
+    #[test]
+    fn cycles_without_period_show_the_colour_instead_of_dividing_by_zero() {
+        for kind in [EffectKind::Pulse, EffectKind::Strobe, EffectKind::Rainbow] {
+            let effect = effect(kind, 0, 0, Easing::Linear);
+            assert_eq!(effect.evaluate(&BLACK, 0), ORANGE);
+            assert_eq!(effect.evaluate(&BLACK, 1234), ORANGE);
+        }
+    }
+
+    #[test]
+    fn effects_without_duration_end_at_once_or_never() {
+        let fade = effect(EffectKind::Fade, 0, 0, Easing::Linear);
+        assert!(fade.is_finished(0));
+        assert_eq!(fade.evaluate(&BLACK, 0), ORANGE);
+        let hold = effect(EffectKind::Hold, 0, 0, Easing::Linear);
+        assert!(hold.is_finished(0));
+        assert_eq!(hold.evaluate(&BLACK, 0), BLACK);
+        for kind in [EffectKind::Pulse, EffectKind::Strobe, EffectKind::Rainbow] {
+            let effect = effect(kind, 0, 100, Easing::Linear);
+            assert!(!effect.is_finished(0));
+            assert!(!effect.is_finished(u32::MAX));
+        }
+        let pulse = effect(EffectKind::Pulse, 300, 100, Easing::Linear);
+        assert!(!pulse.is_finished(299));
+        assert!(pulse.is_finished(300));
+        assert_eq!(pulse.evaluate(&ColourRgb::new(1, 2, 3), 300), BLACK);
+    }
+}
@@ -0,0 +1,314 @@
+//! DMX over the network
+//!
+//! Encoding and decoding of the DMX data packets of Art-Net (ArtDmx) and sACN (ANSI E1.31),
+//! the two protocols lighting consoles and nodes use to carry DMX universes over UDP.
+//! Only the data packets are handled, the discovery and synchronisation ones are ignored.
+
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+use strum::Display;
+
+/// Channels, or slots, of a DMX universe.
+pub const DMX_UNIVERSE_SIZE: usize = 512;
+
+pub const ART_NET_PORT: u16 = 6454;
+pub const SACN_PORT: u16 = 5568;
+
+/// Highest universe, or port address, of Art-Net. They start from 0.
+pub const MAX_ART_NET_UNIVERSE: u16 = 32767;
+/// Range of the universes of sACN.
+pub const MIN_SACN_UNIVERSE: u16 = 1;
+pub const MAX_SACN_UNIVERSE: u16 = 63999;
+
+const ART_NET_ID: &[u8; 8] = b"Art-Net\0";
+const ART_NET_OP_DMX: u16 = 0x5000;
+const ART_NET_PROTOCOL_VERSION: u16 = 14;
+const ART_NET_HEADER_SIZE: usize = 18;
+
+const SACN_ACN_ID: &[u8; 12] = b"ASC-E1.17\0\0\0";
+const SACN_ROOT_VECTOR_DATA: u32 = 0x0000_0004;
+const SACN_FRAMING_VECTOR_DATA: u32 = 0x0000_0002;
+const SACN_DMP_VECTOR_SET_PROPERTY: u8 = 0x02;
+const SACN_SOURCE_NAME_SIZE: usize = 64;
+const SACN_HEADER_SIZE: usize = 126;
+/// Priority of the data sent, the default of E1.31.
+const SACN_PRIORITY: u8 = 100;
+/// Start code of the DMX slots carrying dimmer levels.
+const DMX_NULL_START_CODE: u8 = 0x00;
+
+/// Biggest data packet of both protocols, the one of sACN with a full universe.
+pub const MAX_DMX_PACKET_SIZE: usize = SACN_HEADER_SIZE + DMX_UNIVERSE_SIZE;
+
+pub type DmxPacket = Vec<u8, MAX_DMX_PACKET_SIZE>;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize, Display)]
+pub enum DmxProtocol {
+    #[default]
+    ArtNet,
+    Sacn,
+}
+
+impl DmxProtocol {
+    pub fn port(&self) -> u16 {
+        match self {
+            DmxProtocol::ArtNet => ART_NET_PORT,
+            DmxProtocol::Sacn => SACN_PORT,
+        }
+    }
+
+    pub fn is_valid_universe(&self, universe: u16) -> bool {
+        match self {
+            DmxProtocol::ArtNet => universe <= MAX_ART_NET_UNIVERSE,
+            DmxProtocol::Sacn => (MIN_SACN_UNIVERSE..=MAX_SACN_UNIVERSE).contains(&universe),
+        }
+    }
+}
+
+/// What a channel of a fixture, or of a console, controls.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize, Display)]
+pub enum DmxChannel {
+    Red,
+    Green,
+    Blue,
+    White,
+    /// Intensity of the whole colour.
+    Dimmer,
+}
+
+/// Multicast group sACN sources send a universe to, and receivers listen to.
+pub fn sacn_multicast_address(universe: u16) -> [u8; 4] {
+    let [high, low] = universe.to_be_bytes();
+    [239, 255, high, low]
+}
+
+/// ArtDmx packet with the slots of the universe. Slots beyond the size of a universe are dropped,
+/// and an odd amount of them is padded, as Art-Net requires.
+pub fn art_dmx_packet(universe: u16, sequence: u8, data: &[u8]) -> DmxPacket {
+    let data = &data[..data.len().min(DMX_UNIVERSE_SIZE)];
+    let length = (data.len() + data.len() % 2).max(2);
+    let mut packet = DmxPacket::new();
+    // The packet is never bigger than its capacity, so the extensions never fail.
+    let _ = packet.extend_from_slice(ART_NET_ID);
+    let _ = packet.extend_from_slice(&ART_NET_OP_DMX.to_le_bytes());
+    let _ = packet.extend_from_slice(&ART_NET_PROTOCOL_VERSION.to_be_bytes());
+    let _ = packet.push(sequence);
+    // Physical port the data comes from, informative only.
+    let _ = packet.push(0);
+    let _ = packet.extend_from_slice(&(universe & MAX_ART_NET_UNIVERSE).to_le_bytes());
+    let _ = packet.extend_from_slice(&(length as u16).to_be_bytes());
+    let _ = packet.extend_from_slice(data);
+    let _ = packet.resize(ART_NET_HEADER_SIZE + length, 0);
+    packet
+}
+
+/// sACN data packet with the slots of the universe, sent by the source identified by `cid`.
+/// Slots beyond the size of a universe are dropped.
+pub fn sacn_data_packet(
+    cid: &[u8; 16],
+    source_name: &str,
+    universe: u16,
+    sequence: u8,
+    data: &[u8],
+) -> DmxPacket {
+    let data = &data[..data.len().min(DMX_UNIVERSE_SIZE)];
+    let packet_size = SACN_HEADER_SIZE + data.len();
+    // Flags of every PDU, followed by its length counted from itself.
+    let flags_and_length = |from: usize| (0x7000 | (packet_size - from) as u16).to_be_bytes();
+    let mut name = [0; SACN_SOURCE_NAME_SIZE];
+    let name_length = source_name.len().min(SACN_SOURCE_NAME_SIZE - 1);
+    name[..name_length].copy_from_slice(&source_name.as_bytes()[..name_length]);
+
+    let mut packet = DmxPacket::new();
+    // The packet is never bigger than its capacity, so the extensions never fail.
+    // Root layer.
+    let _ = packet.extend_from_slice(&0x0010u16.to_be_bytes());
+    let _ = packet.extend_from_slice(&0x0000u16.to_be_bytes());
+    let _ = packet.extend_from_slice(SACN_ACN_ID);
+    let _ = packet.extend_from_slice(&flags_and_length(16));
+    let _ = packet.extend_from_slice(&SACN_ROOT_VECTOR_DATA.to_be_bytes());
+    let _ = packet.extend_from_slice(cid);
+    // Framing layer.
+    let _ = packet.extend_from_slice(&flags_and_length(38));
+    let _ = packet.extend_from_slice(&SACN_FRAMING_VECTOR_DATA.to_be_bytes());
+    let _ = packet.extend_from_slice(&name);
+    let _ = packet.push(SACN_PRIORITY);
+    // Synchronisation address, none.
+    let _ = packet.extend_from_slice(&0u16.to_be_bytes());
+    let _ = packet.push(sequence);
+    // Options, none.
+    let _ = packet.push(0);
+    let _ = packet.extend_from_slice(&universe.to_be_bytes());
+    // DMP layer.
+    let _ = packet.extend_from_slice(&flags_and_length(115));
+    let _ = packet.push(SACN_DMP_VECTOR_SET_PROPERTY);
+    // Address and data type.
+    let _ = packet.push(0xA1);
+    // First property address and address increment.
+    let _ = packet.extend_from_slice(&0u16.to_be_bytes());
+    let _ = packet.extend_from_slice(&1u16.to_be_bytes());
+    // Property values, the start code and the slots.
+    let _ = packet.extend_from_slice(&(data.len() as u16 + 1).to_be_bytes());
+    let _ = packet.push(DMX_NULL_START_CODE);
+    let _ = packet.extend_from_slice(data);
+    packet
+}
+
+/// Slots of a universe carried by a DMX data packet.
+#[derive(Debug, PartialEq)]
+pub struct DmxFrame<'a> {
+    pub protocol: DmxProtocol,
+    pub universe: u16,
+    /// Slots of the universe, starting from the first one. Packets can carry less than 512.
+    pub data: &'a [u8],
+}
+
+/// Decodes an ArtDmx or sACN data packet. Any other packet, and sACN packets not carrying
+/// dimmer levels, are ignored.
+pub fn parse_dmx_packet(packet: &[u8]) -> Option<DmxFrame<'_>> {
+    if packet.starts_with(ART_NET_ID) {
+        parse_art_dmx_packet(packet)
+    } else if packet.get(4..16) == Some(SACN_ACN_ID.as_slice()) {
+        parse_sacn_data_packet(packet)
+    } else {
+        None
+    }
+}
+
+fn parse_art_dmx_packet(packet: &[u8]) -> Option<DmxFrame<'_>> {
+    let header = packet.get(..ART_NET_HEADER_SIZE)?;
+    if u16::from_le_bytes([header[8], header[9]]) != ART_NET_OP_DMX {
+        return None;
+    }
+    let universe = u16::from_le_bytes([header[14], header[15]]) & MAX_ART_NET_UNIVERSE;
+    let length = u16::from_be_bytes([header[16], header[17]]) as usize;
+    let data = packet.get(ART_NET_HEADER_SIZE..ART_NET_HEADER_SIZE + length)?;
+    Some(DmxFrame {
+        protocol: DmxProtocol::ArtNet,
+        universe,
+        data: &data[..data.len().min(DMX_UNIVERSE_SIZE)],
+    })
+}
+
+fn parse_sacn_data_packet(packet: &[u8]) -> Option<DmxFrame<'_>> {
+    let header = packet.get(..SACN_HEADER_SIZE)?;
+    let root_vector = u32::from_be_bytes([header[18], header[19], header[20], header[21]]);
+    let framing_vector = u32::from_be_bytes([header[40], header[41], header[42], header[43]]);
+    if root_vector != SACN_ROOT_VECTOR_DATA
+        || framing_vector != SACN_FRAMING_VECTOR_DATA
+        || header[117] != SACN_DMP_VECTOR_SET_PROPERTY
+        || header[125] != DMX_NULL_START_CODE
+    {
+        return None;
+    }
+    let universe = u16::from_be_bytes([header[113], header[114]]);
+    // The start code is counted as a property value.
+    let slots = (u16::from_be_bytes([header[123], header[124]]) as usize).checked_sub(1)?;
+    let data = packet.get(SACN_HEADER_SIZE..SACN_HEADER_SIZE + slots)?;
+    Some(DmxFrame {
+        protocol: DmxProtocol::Sacn,
+        universe,
+        data: &data[..data.len().min(DMX_UNIVERSE_SIZE)],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn art_dmx_packets_pad_odd_amounts_of_slots() {
+        let packet = art_dmx_packet(1, 9, &[1, 2, 3]);
+
+        assert_eq!(&packet[16..18], &[0, 4]);
+        assert_eq!(&packet[18..], &[1, 2, 3, 0]);
+    }
+
+    #[test]
+    fn art_dmx_packets_drop_the_slots_beyond_the_universe() {
+        let packet = art_dmx_packet(MAX_ART_NET_UNIVERSE, 1, &[7; DMX_UNIVERSE_SIZE + 10]);
+
+        assert_eq!(packet.len(), ART_NET_HEADER_SIZE + DMX_UNIVERSE_SIZE);
+        assert_eq!(&packet[14..16], &MAX_ART_NET_UNIVERSE.to_le_bytes());
+    }
+
+    #[test]
+    fn sacn_packets_carry_the_length_of_each_layer() {
+        let packet = sacn_data_packet(&[1; 16], "console", 1, 5, &[9; 4]);
+
+        assert_eq!(packet.len(), SACN_HEADER_SIZE + 4);
+        // Flags and length of the root, framing and DMP layers.
+        assert_eq!(&packet[16..18], &(0x7000u16 | 114).to_be_bytes());
+        assert_eq!(&packet[38..40], &(0x7000u16 | 92).to_be_bytes());
+        assert_eq!(&packet[115..117], &(0x7000u16 | 15).to_be_bytes());
+        assert_eq!(&packet[22..38], &[1; 16]);
+        assert_eq!(packet[111], 5);
+        // The start code is counted with the slots.
+        assert_eq!(&packet[123..125], &[0, 5]);
+    }
+
+    #[test]
+    fn sacn_source_names_are_cut_to_fit() {
+        let name = "n".repeat(100);
+
+        let packet = sacn_data_packet(&[0; 16], &name, 1, 1, &[]);
+
+        assert_eq!(&packet[44..44 + 63], &name.as_bytes()[..63]);
+        assert_eq!(packet[44 + 63], 0);
+    }
+
+    #[test]
+    fn packets_sent_are_parsed_back() {
+        let art_dmx = art_dmx_packet(300, 1, &[1, 2, 3]);
+        let sacn = sacn_data_packet(&[1; 16], "console", 7, 1, &[4, 5, 6]);
+
+        assert_eq!(
+            parse_dmx_packet(&art_dmx),
+            Some(DmxFrame {
+                protocol: DmxProtocol::ArtNet,
+                universe: 300,
+                // Art-Net carries an even amount of slots.
+                data: &[1, 2, 3, 0],
+            })
+        );
+        assert_eq!(
+            parse_dmx_packet(&sacn),
+            Some(DmxFrame {
+                protocol: DmxProtocol::Sacn,
+                universe: 7,
+                data: &[4, 5, 6],
+            })
+        );
+    }
+
+    #[test]
+    fn truncated_packets_are_ignored() {
+        let art_dmx = art_dmx_packet(1, 1, &[1; 8]);
+        let sacn = sacn_data_packet(&[1; 16], "console", 1, 1, &[1; 8]);
+
+        for length in [10, ART_NET_HEADER_SIZE - 1, art_dmx.len() - 1] {
+            assert_eq!(parse_dmx_packet(&art_dmx[..length]), None);
+        }
+        for length in [20, SACN_HEADER_SIZE - 1, sacn.len() - 1] {
+            assert_eq!(parse_dmx_packet(&sacn[..length]), None);
+        }
+    }
+
+    #[test]
+    fn packets_without_dmx_data_are_ignored() {
+        // ArtPoll, sent by the consoles looking for nodes.
+        let mut art_poll = art_dmx_packet(1, 1, &[1; 8]);
+        art_poll[8..10].copy_from_slice(&0x2000u16.to_le_bytes());
+        // sACN packet with an alternate start code, like the text packets.
+        let mut alternate_start_code = sacn_data_packet(&[1; 16], "console", 1, 1, &[1; 8]);
+        alternate_start_code[125] = 0x17;
+        // sACN universe discovery packet.
+        let mut discovery = sacn_data_packet(&[1; 16], "console", 1, 1, &[1; 8]);
+        discovery[18..22].copy_from_slice(&0x0000_0008u32.to_be_bytes());
+
+        assert_eq!(parse_dmx_packet(&art_poll), None);
+        assert_eq!(parse_dmx_packet(&alternate_start_code), None);
+        assert_eq!(parse_dmx_packet(&discovery), None);
+        assert_eq!(parse_dmx_packet(b"GET / HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(parse_dmx_packet(&[]), None);
+    }
+}
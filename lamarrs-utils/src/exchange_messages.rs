@@ -1,30 +1,77 @@
+use heapless::{String, Vec};
 use serde::{Deserialize, Serialize};
 use strum::Display;
 
-use crate::{action_messages::Event, ErrorDescription};
+use crate::{
+    action_messages::{Event, MAX_DECLARED_SERVICES},
+    clock::{Schedule, TimeSyncRequest, TimeSyncResponse, TimeSyncResult},
+    media::{MediaChunk, MediaManifest},
+    Boxed, ErrorDescription, Service,
+};
+
+/// Version of the protocol spoken between the lamarrs Clients and Server.
+/// It must be bumped every time the layout of `ExchangeMessage`, or of any payload it carries,
+/// changes, as postcard encoded messages can't be decoded by a peer using a different layout.
+pub const PROTOCOL_VERSION: u16 = 13;
+
+/// Identifier chosen by a Client for each of its requests. The Server echoes it in the
+/// `Ack` or `Nack` answering the request, so the Client can tell which request it answers.
+pub type RequestId = u16;
 
 /// Wrapper for the messages traveling between the Clients and the Server
 ///
 /// These are:
-///  * Ack: Server > Client. Confirmation of requested action and results.
-///  * Nack: Server > Client. Rejection of request and its reason.
-///  * Request: Client > Server. Request sent by the client to the server to perform an action.
-///  * NewScene: Server > Client. Update sent one of the service the client is subscribed to.
-///  * Error: Server > Client. Jocker type message for yet-unmapped error cases.
+///  * Ack: Server > Client. Confirmation of requested action and results. Carries the id of the request it answers, if any.
+///  * Nack: Server > Client. Rejection of request and its reason. Carries the id of the request it answers, if any.
+///  * Request: Client > Server. Request sent by the client to the server to perform an action, identified by the client.
+///  * Scene: Server > Client. Update sent one of the service the client is subscribed to. If it carries a `Schedule`,
+///    the client must perform it at the scheduled time, or drop it if it arrived after its expiry.
+///  * Error: Server <> Client. Jocker type message for yet-unmapped error cases.
 ///  * NextScene: Client > Server. Move to the next orchestrated scene. Sent by a client operated by a Scene commander -button, timer, etc.
 ///  * RetriggerScene: Client > Server. Same as NextScene but retriggers the same scene. Sent by a client operated by a Scene commander -button, timer, etc.
 ///  * Heartbeat & HeartbeatAck: Client > Server.
+///  * ServerInfo: Server > Client. Sent as answer to a registration request, describes the Server.
+///  * TimeSyncRequest: Server > Client. Starts a clock synchronisation round, see `clock`.
+///  * TimeSyncResponse: Client > Server. Answer to the TimeSyncRequest, stamped with the Client clock.
+///  * TimeSyncResult: Server > Client. Offset and round trip estimated by the Server for the round.
+///  * MediaManifest: Server > Client. A page of the list of media files the Client must have, see `media`.
+///  * MediaChunk: Server > Client. Part of a media file fetched by the Client.
 #[derive(Deserialize, Display, Serialize, PartialEq, Debug, Clone)]
+#[cfg_attr(not(feature = "alloc"), allow(clippy::large_enum_variant))]
 pub enum ExchangeMessage {
-    Ack(AckResult),
-    Nack(NackResult),
-    Request(Event),
-    Scene(Event),
+    Ack(Option<RequestId>, AckResult),
+    Nack(Option<RequestId>, NackResult),
+    Request(RequestId, Event),
+    Scene(Event, Option<Schedule>),
     Error(ErrorDescription),
     NextScene,
     RetriggerScene,
     Heartbeat,
     HeartbeatAck,
+    ServerInfo(ServerInfo),
+    TimeSyncRequest(TimeSyncRequest),
+    TimeSyncResponse(TimeSyncResponse),
+    TimeSyncResult(TimeSyncResult),
+    MediaManifest(Boxed<MediaManifest>),
+    MediaChunk(Boxed<MediaChunk>),
+}
+
+/// Description of the Server, sent to the Clients when they register.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ServerInfo {
+    pub protocol_version: u16,
+    pub server_version: String<16>,
+    pub services: Vec<Service, MAX_DECLARED_SERVICES>,
+    /// Server clock when it was sent, so the Client can estimate its offset until the first
+    /// clock synchronisation round ends.
+    pub server_time_us: u64,
+}
+
+impl ServerInfo {
+    /// Returns `true` if a Client speaking `protocol_version` can be handled by this Server.
+    pub fn is_compatible_with(&self, protocol_version: u16) -> bool {
+        self.protocol_version == protocol_version
+    }
 }
 
 /// Results on the latest request sent by client if succeeds.
@@ -41,4 +88,136 @@ pub enum NackResult {
     AlreadySubscribed,
     NotSubscribed,
     Failed,
+    IncompatibleProtocolVersion,
+    /// The seat the Client registered with is not in the seat map of the Server.
+    UnknownSeat,
+    /// The file fetched by the Client is not in the media manifest of the Server.
+    UnknownMedia,
+}
+
+/// Index of `ExchangeMessage::Request` in postcard encoded messages.
+const REQUEST_VARIANT: u32 = 2;
+
+/// Index of `Event::Register` in postcard encoded messages.
+const REGISTER_VARIANT: u32 = 0;
+
+/// Index of `ExchangeMessage::ServerInfo` in postcard encoded messages.
+const SERVER_INFO_VARIANT: u32 = 9;
+
+/// Leading fields of a postcard encoded registration request. Their layout is the same in every
+/// version of the protocol, so the Server can tell which version a Client speaks, and reject it,
+/// even when the rest of its registration can't be decoded.
+#[derive(Debug, Deserialize)]
+pub struct RegistrationHeader {
+    exchange_variant: u32,
+    pub request_id: RequestId,
+    event_variant: u32,
+    protocol_version: u16,
+}
+
+impl RegistrationHeader {
+    /// Protocol version of the Client, if the message is a registration request at all.
+    pub fn protocol_version(&self) -> Option<u16> {
+        (self.exchange_variant == REQUEST_VARIANT && self.event_variant == REGISTER_VARIANT)
+            .then_some(self.protocol_version)
+    }
+}
+
+/// Leading fields of a postcard encoded `ServerInfo`, readable by the Clients speaking any
+/// version of the protocol, like the `RegistrationHeader` is by the Server.
+#[derive(Debug, Deserialize)]
+pub struct ServerInfoHeader {
+    exchange_variant: u32,
+    protocol_version: u16,
+}
+
+impl ServerInfoHeader {
+    /// Protocol version of the Server, if the message is a `ServerInfo` at all.
+    pub fn protocol_version(&self) -> Option<u16> {
+        (self.exchange_variant == SERVER_INFO_VARIANT).then_some(self.protocol_version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        action_messages::{ClientKind, GroupName, Registration},
+        ClientIdAndLocation,
+    };
+    use uuid::Uuid;
+
+    fn registration(protocol_version: u16) -> ExchangeMessage {
+        ExchangeMessage::Request(
+            300,
+            Event::Register(Registration {
+                protocol_version,
+                client: ClientIdAndLocation {
+                    uuid: Uuid::from_u128(7),
+                    location: None,
+                },
+                kind: ClientKind::RpClient,
+                services: [Service::Colour].into_iter().collect(),
+                max_frame_size: 256,
+                seat: None,
+                language: None,
+                section: None,
+                group: None,
+            }),
+        )
+    }
+
+    #[test]
+    fn registration_header_reads_the_registration() {
+        let frame = postcard::to_allocvec(&registration(PROTOCOL_VERSION)).unwrap();
+        let (header, _) = postcard::take_from_bytes::<RegistrationHeader>(&frame).unwrap();
+        assert_eq!(header.request_id, 300);
+        assert_eq!(header.protocol_version(), Some(PROTOCOL_VERSION));
+    }
+
+    #[test]
+    fn registration_header_is_readable_from_other_layouts() {
+        // A registration of another version, whose fields after the header are different.
+        let mut frame = postcard::to_allocvec(&registration(PROTOCOL_VERSION + 1)).unwrap();
+        let header_len = 1 + 2 + 1 + 1;
+        frame.truncate(header_len);
+        frame.extend_from_slice(&[0xff, 0xff, 0xff]);
+        assert!(postcard::from_bytes::<ExchangeMessage>(&frame).is_err());
+
+        let (header, _) = postcard::take_from_bytes::<RegistrationHeader>(&frame).unwrap();
+        assert_eq!(header.request_id, 300);
+        assert_eq!(header.protocol_version(), Some(PROTOCOL_VERSION + 1));
+    }
+
+    #[test]
+    fn registration_header_ignores_other_requests() {
+        let frame = postcard::to_allocvec(&ExchangeMessage::Request(
+            1,
+            Event::JoinGroup(GroupName::try_from("choir").unwrap()),
+        ))
+        .unwrap();
+        let (header, _) = postcard::take_from_bytes::<RegistrationHeader>(&frame).unwrap();
+        assert_eq!(header.protocol_version(), None);
+
+        let frame = postcard::to_allocvec(&ExchangeMessage::Heartbeat).unwrap();
+        assert!(postcard::take_from_bytes::<RegistrationHeader>(&frame).is_err());
+    }
+
+    #[test]
+    fn server_info_header_reads_the_protocol_version() {
+        let frame = postcard::to_allocvec(&ExchangeMessage::ServerInfo(ServerInfo {
+            protocol_version: PROTOCOL_VERSION,
+            server_version: String::try_from("0.1.0").unwrap(),
+            services: Vec::new(),
+            server_time_us: 1_000_000,
+        }))
+        .unwrap();
+        let (header, _) = postcard::take_from_bytes::<ServerInfoHeader>(&frame).unwrap();
+        assert_eq!(header.protocol_version(), Some(PROTOCOL_VERSION));
+
+        let frame =
+            postcard::to_allocvec(&ExchangeMessage::Ack(Some(1), AckResult::Success)).unwrap();
+        let (header, _) = postcard::take_from_bytes::<ServerInfoHeader>(&frame).unwrap();
+        assert_eq!(header.protocol_version(), None);
+    }
 }
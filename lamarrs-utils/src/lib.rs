@@ -1,18 +1,42 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
 
 pub mod action_messages;
+pub mod audio;
+pub mod clock;
+pub mod colour;
+pub mod colour_effect;
+pub mod dmx;
 pub mod exchange_messages;
+pub mod media;
+pub mod midi;
 pub mod orchestration_messages;
+pub mod subtitles;
+pub mod target_selector;
 // pub mod midi_event;  I don´t know if this lib is no_std and I don´t need MIDI it right now.
 
-use core::{fmt, num::NonZeroU16};
+use core::{fmt, str::FromStr};
 use heapless::String;
 use serde::{ser::SerializeStruct, Deserialize, Deserializer, Serialize, Serializer};
 use strum::{Display, EnumIter};
 use uuid::Uuid;
 
+/// Payload much bigger than the rest of the message carrying it, boxed wherever there is a heap
+/// to box it in. Without the `alloc` feature, it is carried as is.
+#[cfg(feature = "alloc")]
+pub type Boxed<T> = alloc::boxed::Box<T>;
+#[cfg(not(feature = "alloc"))]
+pub type Boxed<T> = T;
+
+pub use audio::AudioPlayback;
+pub use colour::ColourRgb;
+pub use midi::MidiInstruction;
+pub use subtitles::Subtitles;
+
 /// Relative Location of the Client. Useful for certain special effects involving sound and colours.
+/// Each one of them is a third of the venue, split in vertical slices.
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize, EnumIter, Display)]
 pub enum RelativeLocation {
     Left,
@@ -20,15 +44,107 @@ pub enum RelativeLocation {
     Right,
 }
 
+impl RelativeLocation {
+    /// Position in the middle of the slice of the venue.
+    pub fn position(&self) -> Position {
+        match self {
+            RelativeLocation::Left => Position::new(1.0 / 6.0, 0.5),
+            RelativeLocation::Center => Position::new(0.5, 0.5),
+            RelativeLocation::Right => Position::new(5.0 / 6.0, 0.5),
+        }
+    }
+}
+
+/// Position of a Client in the venue, normalised between 0 and 1.
+/// `x` goes from the left (0) to the right (1) of the venue, as seen by the audience facing the stage,
+/// and `y` goes from the stage (0) to the back of the venue (1).
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Position {
+    pub x: f32,
+    pub y: f32,
+}
+
+impl Position {
+    pub fn new(x: f32, y: f32) -> Self {
+        Self { x, y }
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "({:.3}, {:.3})", self.x, self.y)
+    }
+}
+
+/// Parses a Position written as `x,y`.
+impl FromStr for Position {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (x, y) = s
+            .split_once(',')
+            .ok_or("A position must be written as `x,y`.")?;
+        let x = x.trim().parse().map_err(|_| "`x` must be a number.")?;
+        let y = y.trim().parse().map_err(|_| "`y` must be a number.")?;
+        Ok(Position { x, y })
+    }
+}
+
+/// Region of the venue targeted by an action, using the same normalised space as `Position`.
+/// `Left`, `Center` and `Right` are predefined regions matching the `RelativeLocation` slices.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Region {
+    Left,
+    Center,
+    Right,
+    /// Rectangle aligned with the venue, between its `min` and `max` corners.
+    Rectangle { min: Position, max: Position },
+    Circle { centre: Position, radius: f32 },
+    /// Half of the venue at one side of the line that crosses `point`. `normal` is perpendicular
+    /// to that line and points to the side contained by the region.
+    HalfPlane { point: Position, normal: Position },
+}
+
+impl Region {
+    /// Returns `true` if the position is inside the region. Borders are inside too.
+    pub fn contains(&self, position: &Position) -> bool {
+        match self {
+            Region::Left => position.x < 1.0 / 3.0,
+            Region::Center => (1.0 / 3.0..2.0 / 3.0).contains(&position.x),
+            Region::Right => position.x >= 2.0 / 3.0,
+            Region::Rectangle { min, max } => {
+                (min.x..=max.x).contains(&position.x) && (min.y..=max.y).contains(&position.y)
+            }
+            Region::Circle { centre, radius } => {
+                let (dx, dy) = (position.x - centre.x, position.y - centre.y);
+                dx * dx + dy * dy <= radius * radius
+            }
+            Region::HalfPlane { point, normal } => {
+                (position.x - point.x) * normal.x + (position.y - point.y) * normal.y >= 0.0
+            }
+        }
+    }
+}
+
+impl From<RelativeLocation> for Region {
+    fn from(relative_location: RelativeLocation) -> Self {
+        match relative_location {
+            RelativeLocation::Left => Region::Left,
+            RelativeLocation::Center => Region::Center,
+            RelativeLocation::Right => Region::Right,
+        }
+    }
+}
+
 /// Client data, relevant to identify itself and to update the location if needed.
 #[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
 pub struct ClientIdAndLocation {
     pub uuid: Uuid,
-    pub location: Option<RelativeLocation>,
+    pub location: Option<Position>,
 }
 
 impl ClientIdAndLocation {
-    pub fn new(uuid: Uuid, location: Option<RelativeLocation>) -> Self {
+    pub fn new(uuid: Uuid, location: Option<Position>) -> Self {
         Self { uuid, location }
     }
 }
@@ -51,62 +167,10 @@ pub enum Service {
     Midi,
 }
 
-/// Payload for colour change requests. TODO: allow other formats, or impl `to_hex()`, something like that.
-#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
-pub struct ColourRgb {
-    pub r: u8,
-    pub g: u8,
-    pub b: u8,
-}
-
-impl ColourRgb {
-    pub fn new(red: u8, green: u8, blue: u8) -> Self {
-        Self {
-            r: red,
-            g: green,
-            b: blue,
-        }
-    }
-}
-
 /* ################################################################################################*/
 
-/// Payload for subtitle change requests. The String can have up to 50 chars per line, following the
-/// avg of the current streaming services subtitle conventions.
-#[derive(Clone, Debug, PartialEq)]
-pub struct Subtitles {
-    pub subtitles: String<50>,
-}
-
-// Manual Serialize / Deserialize Subtitle, as Derive can´t do it.
-impl Serialize for Subtitles {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        serializer.serialize_str(self.subtitles.as_str())
-    }
-}
-
-impl<'de> Deserialize<'de> for Subtitles {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        // Deserialize into a regular String first
-        let subtitle = String::<50>::try_from(<&str>::deserialize(deserializer)?)
-            .map_err(serde::de::Error::custom)?;
-
-        Ok(Subtitles {
-            subtitles: subtitle,
-        })
-    }
-}
-
-/* ################################################################################################*/
-
-/// Payload for Audio Playback requests. The String can have up to 50 chars per line.
-/// More, better validations can come in the future, as format to play, or other params.
+/// Audio file to be played, as found in the media folder of the Clients. The name can have
+/// up to 50 chars.
 #[derive(Clone, Debug, PartialEq)]
 pub struct AudioFile {
     pub file_name: String<50>,
@@ -192,13 +256,3 @@ impl<'de> Deserialize<'de> for ErrorDescription {
         Ok(ErrorDescription { error_descr })
     }
 }
-
-/* ################################################################################################*/
-
-/// MidiInstructions supported for MIDI requests.
-/// WIP
-/// Also, serde_yml nor serde_yaml support nested enums, so don't make this an enum or add a custom Deserializer in the future.
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
-pub struct MidiInstruction {
-    pub new_preset: NonZeroU16
-}
\ No newline at end of file
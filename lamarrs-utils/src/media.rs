@@ -0,0 +1,132 @@
+//! Media distribution
+//!
+//! The Server publishes the audio files of the show as a [`MediaManifest`], with the size and
+//! SHA-256 hash of each of them. Clients compare it with their media folder, fetch the files
+//! they miss or have a different version of, and report how many are left with a [`MediaStatus`],
+//! so the Server knows which Clients are not ready for the show.
+//!
+//! Manifests are split in pages and files in chunks, so every message fits in a frame.
+
+use core::fmt;
+
+use heapless::{String, Vec};
+use serde::{Deserialize, Serialize};
+
+/// Files listed in each page of the manifest.
+pub const MEDIA_MANIFEST_PAGE_SIZE: usize = 8;
+
+/// Bytes of a file carried by each chunk.
+pub const MEDIA_CHUNK_SIZE: usize = 1024;
+
+/// Name of a media file, with its extension, as played by `AudioPlayback`.
+pub type MediaFileName = String<55>;
+
+pub type Sha256Digest = [u8; 32];
+
+/// Whether the name is a plain file name, that can only point to a file at the top level of
+/// the media folder. Names with separators, drive prefixes or standing for a folder, like `..`,
+/// are rejected whatever the platform of the Server or the Client.
+pub fn is_plain_file_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.contains(['/', '\\', ':', '\0'])
+}
+
+/// A file the Clients must have before the show starts.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct MediaEntry {
+    pub name: MediaFileName,
+    pub size: u64,
+    pub sha256: Sha256Digest,
+}
+
+/// One page of the manifest. The manifest is complete once the Client received all the pages.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct MediaManifest {
+    pub entries: Vec<MediaEntry, MEDIA_MANIFEST_PAGE_SIZE>,
+    /// Starting from 0.
+    pub page: u16,
+    pub pages: u16,
+}
+
+impl MediaManifest {
+    pub fn is_last_page(&self) -> bool {
+        self.page.saturating_add(1) >= self.pages
+    }
+}
+
+/// Part of a file, sent by the Server when a Client fetches it. The file is complete when
+/// the chunk reaches the size given by the manifest. Empty files are sent as a single empty chunk.
+#[derive(Clone, Deserialize, PartialEq, Serialize)]
+pub struct MediaChunk {
+    pub name: MediaFileName,
+    pub offset: u64,
+    pub data: Vec<u8, MEDIA_CHUNK_SIZE>,
+}
+
+// Manual Debug, as the data of the chunks would flood the logs.
+impl fmt::Debug for MediaChunk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MediaChunk")
+            .field("name", &self.name)
+            .field("offset", &self.offset)
+            .field("len", &self.data.len())
+            .finish()
+    }
+}
+
+/// How ready the media folder of a Client is for the show.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct MediaStatus {
+    /// Files of the manifest the Client doesn't have yet, or has a different version of.
+    pub missing: u16,
+}
+
+impl MediaStatus {
+    pub fn is_ready(&self) -> bool {
+        self.missing == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_file_names_are_accepted() {
+        assert!(is_plain_file_name("intro.mp3"));
+        assert!(is_plain_file_name("..intro.mp3"));
+        assert!(is_plain_file_name("intro..mp3"));
+    }
+
+    #[test]
+    fn names_leaving_the_media_folder_are_rejected() {
+        for name in [
+            "",
+            ".",
+            "..",
+            "../intro.mp3",
+            "/etc/passwd",
+            "songs/intro.mp3",
+            "..\\intro.mp3",
+            "C:intro.mp3",
+            "intro.mp3\0",
+        ] {
+            assert!(!is_plain_file_name(name), "{:?} was accepted", name);
+        }
+    }
+
+    #[test]
+    fn last_pages_are_found_without_overflowing() {
+        let page = |page, pages| MediaManifest {
+            entries: Vec::new(),
+            page,
+            pages,
+        };
+        assert!(!page(0, 2).is_last_page());
+        assert!(page(1, 2).is_last_page());
+        assert!(page(0, 0).is_last_page());
+        assert!(page(u16::MAX, u16::MAX).is_last_page());
+    }
+}
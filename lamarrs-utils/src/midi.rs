@@ -0,0 +1,490 @@
+//! MIDI
+//!
+//! A [`MidiInstruction`] is a channel message, or a SysEx one, to be sent by the Clients to
+//! their MIDI output. serde_yml can't express an enum nested in the `!Midi` action, so in the
+//! human readable formats the instruction is kept flat, with the kind of message given by
+//! `command`, and its fields next to it:
+//! ```yaml
+//! action: !Midi
+//!   command: NoteOn
+//!   channel: 10
+//!   note: 36
+//!   velocity: 110
+//! ```
+//! ```yaml
+//! action: !Midi
+//!   command: ProgramChange
+//!   program: 20
+//!   bank: 1
+//! ```
+//! The former `new_preset: 149` is still accepted, as a program change counting from 1 across banks.
+//! Binary formats carry the [`MidiCommand`] as it is.
+//!
+//! Standard MIDI Files are played by the Server, which sends their events as instructions to the
+//! Clients at the right time. A [`MidiFilePlayback`] can spread the arrangement across the venue,
+//! routing its tracks or channels to different Clients:
+//! ```yaml
+//! midi_file:
+//!   file: "intro.mid"
+//!   routes:
+//!     - track: 1
+//!       target:
+//!         groups: ["strings"]
+//!     - channel: 10
+//!       target:
+//!         sections: ["Stalls"]
+//! ```
+
+use core::num::NonZeroU16;
+
+use heapless::{String, Vec};
+use serde::{ser::SerializeStruct, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::target_selector::TargetSelector;
+
+/// Biggest SysEx payload carried by an instruction, without the `F0` and `F7` bytes around it.
+pub const MAX_SYSEX_LENGTH: usize = 128;
+
+/// Highest value of the 7 bits data bytes, like notes, velocities or programs.
+pub const MAX_DATA_VALUE: u8 = 127;
+
+/// Highest bank reachable with the bank select MSB and LSB.
+pub const MAX_BANK: u16 = 16383;
+
+/// Range of the pitch bend, centred on 0.
+pub const MIN_PITCH_BEND: i16 = -8192;
+pub const MAX_PITCH_BEND: i16 = 8191;
+
+/// Velocity of the notes when not specified.
+pub const DEFAULT_VELOCITY: u8 = 64;
+
+/// Maximum amount of routes a `MidiFilePlayback` can spread its events through.
+pub const MAX_MIDI_ROUTES: usize = 8;
+
+/// Maximum length of the path of a MIDI file, relative to the sequence file of the Server.
+pub const MAX_MIDI_FILE_NAME_LENGTH: usize = 64;
+
+pub type MidiFileName = String<MAX_MIDI_FILE_NAME_LENGTH>;
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum MidiCommand {
+    NoteOn { note: u8, velocity: u8 },
+    NoteOff { note: u8, velocity: u8 },
+    ControlChange { controller: u8, value: u8 },
+    /// Preceded by a bank select, if the bank is given.
+    ProgramChange { program: u8, bank: Option<u16> },
+    PitchBend { bend: i16 },
+    SysEx { data: Vec<u8, MAX_SYSEX_LENGTH> },
+}
+
+impl MidiCommand {
+    /// Program change from the preset numbers shown by most instruments, counting from 1
+    /// across the banks of 128 programs.
+    pub fn preset(preset: NonZeroU16) -> Self {
+        let preset = preset.get() - 1;
+        MidiCommand::ProgramChange {
+            program: (preset % 128) as u8,
+            bank: Some(preset / 128),
+        }
+    }
+}
+
+/// MidiInstructions supported for MIDI requests.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MidiInstruction {
+    pub command: MidiCommand,
+    /// From 1 to 16. The default channel of the Client if missing.
+    pub channel: Option<u8>,
+}
+
+impl MidiInstruction {
+    pub fn new(command: MidiCommand) -> Self {
+        Self {
+            command,
+            channel: None,
+        }
+    }
+}
+
+/// Kind of the [`MidiCommand`], as written in the `command` field of the flat representation.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+enum MidiCommandKind {
+    NoteOn,
+    NoteOff,
+    ControlChange,
+    ProgramChange,
+    PitchBend,
+    SysEx,
+}
+
+/// Flat representation of a [`MidiInstruction`], used by the human readable formats.
+#[derive(Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct FlatMidiInstruction {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    command: Option<MidiCommandKind>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    channel: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    note: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    velocity: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    controller: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    program: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bank: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bend: Option<i16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    data: Option<Vec<u8, MAX_SYSEX_LENGTH>>,
+    /// Former representation, only a program change.
+    #[serde(default, skip_serializing)]
+    new_preset: Option<NonZeroU16>,
+}
+
+impl From<&MidiInstruction> for FlatMidiInstruction {
+    fn from(midi_instruction: &MidiInstruction) -> Self {
+        let flat = FlatMidiInstruction {
+            channel: midi_instruction.channel,
+            ..Default::default()
+        };
+        match midi_instruction.command.clone() {
+            MidiCommand::NoteOn { note, velocity } => FlatMidiInstruction {
+                command: Some(MidiCommandKind::NoteOn),
+                note: Some(note),
+                velocity: Some(velocity),
+                ..flat
+            },
+            MidiCommand::NoteOff { note, velocity } => FlatMidiInstruction {
+                command: Some(MidiCommandKind::NoteOff),
+                note: Some(note),
+                velocity: Some(velocity),
+                ..flat
+            },
+            MidiCommand::ControlChange { controller, value } => FlatMidiInstruction {
+                command: Some(MidiCommandKind::ControlChange),
+                controller: Some(controller),
+                value: Some(value),
+                ..flat
+            },
+            MidiCommand::ProgramChange { program, bank } => FlatMidiInstruction {
+                command: Some(MidiCommandKind::ProgramChange),
+                program: Some(program),
+                bank,
+                ..flat
+            },
+            MidiCommand::PitchBend { bend } => FlatMidiInstruction {
+                command: Some(MidiCommandKind::PitchBend),
+                bend: Some(bend),
+                ..flat
+            },
+            MidiCommand::SysEx { data } => FlatMidiInstruction {
+                command: Some(MidiCommandKind::SysEx),
+                data: Some(data),
+                ..flat
+            },
+        }
+    }
+}
+
+impl TryFrom<FlatMidiInstruction> for MidiInstruction {
+    type Error = &'static str;
+
+    fn try_from(flat: FlatMidiInstruction) -> Result<Self, Self::Error> {
+        fn data_byte(value: u8) -> Result<u8, &'static str> {
+            if value <= MAX_DATA_VALUE {
+                Ok(value)
+            } else {
+                Err("MIDI notes, velocities, controllers, values and programs must be at most 127")
+            }
+        }
+
+        if flat.channel.is_some_and(|channel| !(1..=16).contains(&channel)) {
+            return Err("MIDI channel must be between 1 and 16");
+        }
+        let command = match (flat.command, flat.new_preset) {
+            (None, Some(new_preset)) => MidiCommand::preset(new_preset),
+            (None, None) => return Err("MIDI instruction needs a `command`"),
+            (Some(_), Some(_)) => return Err("`new_preset` can't be given together with a `command`"),
+            (Some(MidiCommandKind::NoteOn), None) => MidiCommand::NoteOn {
+                note: data_byte(flat.note.ok_or("NoteOn needs a `note`")?)?,
+                velocity: data_byte(flat.velocity.unwrap_or(DEFAULT_VELOCITY))?,
+            },
+            (Some(MidiCommandKind::NoteOff), None) => MidiCommand::NoteOff {
+                note: data_byte(flat.note.ok_or("NoteOff needs a `note`")?)?,
+                velocity: data_byte(flat.velocity.unwrap_or(DEFAULT_VELOCITY))?,
+            },
+            (Some(MidiCommandKind::ControlChange), None) => MidiCommand::ControlChange {
+                controller: data_byte(flat.controller.ok_or("ControlChange needs a `controller`")?)?,
+                value: data_byte(flat.value.ok_or("ControlChange needs a `value`")?)?,
+            },
+            (Some(MidiCommandKind::ProgramChange), None) => {
+                if flat.bank.is_some_and(|bank| bank > MAX_BANK) {
+                    return Err("MIDI bank must be at most 16383");
+                }
+                MidiCommand::ProgramChange {
+                    program: data_byte(flat.program.ok_or("ProgramChange needs a `program`")?)?,
+                    bank: flat.bank,
+                }
+            }
+            (Some(MidiCommandKind::PitchBend), None) => match flat.bend {
+                Some(bend) if (MIN_PITCH_BEND..=MAX_PITCH_BEND).contains(&bend) => {
+                    MidiCommand::PitchBend { bend }
+                }
+                Some(_) => return Err("MIDI pitch bend must be between -8192 and 8191"),
+                None => return Err("PitchBend needs a `bend`"),
+            },
+            (Some(MidiCommandKind::SysEx), None) => match flat.data {
+                Some(data) if data.iter().all(|byte| *byte <= MAX_DATA_VALUE) => {
+                    MidiCommand::SysEx { data }
+                }
+                Some(_) => return Err("SysEx `data` bytes must be at most 127, without the F0 and F7 around them"),
+                None => return Err("SysEx needs its `data`"),
+            },
+        };
+        Ok(MidiInstruction {
+            command,
+            channel: flat.channel,
+        })
+    }
+}
+
+// Manual Serialize / Deserialize MidiInstruction, to keep it flat in the human readable formats.
+impl Serialize for MidiInstruction {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        if serializer.is_human_readable() {
+            FlatMidiInstruction::from(self).serialize(serializer)
+        } else {
+            let mut state = serializer.serialize_struct("MidiInstruction", 2)?;
+            state.serialize_field("command", &self.command)?;
+            state.serialize_field("channel", &self.channel)?;
+            state.end()
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for MidiInstruction {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        if deserializer.is_human_readable() {
+            MidiInstruction::try_from(FlatMidiInstruction::deserialize(deserializer)?)
+                .map_err(serde::de::Error::custom)
+        } else {
+            #[derive(Deserialize)]
+            struct MidiInstructionHelper {
+                command: MidiCommand,
+                channel: Option<u8>,
+            }
+            let MidiInstructionHelper { command, channel } =
+                MidiInstructionHelper::deserialize(deserializer)?;
+            Ok(MidiInstruction { command, channel })
+        }
+    }
+}
+
+/// Sends the events of a track or a channel of a MIDI file to some of the Clients.
+/// Every given criteria narrows the events routed, so a route without them takes all the events.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct MidiRoute {
+    /// Index of the track in the file, starting from 0.
+    #[serde(default)]
+    pub track: Option<u16>,
+    /// From 1 to 16.
+    #[serde(default)]
+    pub channel: Option<u8>,
+    /// All the Clients if missing.
+    #[serde(default)]
+    pub target: TargetSelector,
+}
+
+impl MidiRoute {
+    /// Returns `true` if the event of the track, on the channel if it has one, must follow the route.
+    pub fn routes(&self, track: u16, channel: Option<u8>) -> bool {
+        self.track.is_none_or(|route_track| route_track == track)
+            && self
+                .channel
+                .is_none_or(|route_channel| channel == Some(route_channel))
+    }
+}
+
+/// Payload for the requests playing a Standard MIDI File from the Server.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct MidiFilePlayback {
+    pub file: MidiFileName,
+    /// Events followed by no route are not played. Without routes, all of them are sent to the
+    /// Clients targeted by the request.
+    #[serde(default)]
+    pub routes: Vec<MidiRoute, MAX_MIDI_ROUTES>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_json(json: &str) -> Result<MidiInstruction, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    fn sysex(data: &[u8]) -> MidiCommand {
+        MidiCommand::SysEx {
+            data: Vec::from_slice(data).unwrap(),
+        }
+    }
+
+    #[test]
+    fn human_readable_instructions_are_flat() {
+        let note_on = MidiInstruction {
+            command: MidiCommand::NoteOn {
+                note: 36,
+                velocity: 110,
+            },
+            channel: Some(10),
+        };
+        let json = r#"{"command":"NoteOn","channel":10,"note":36,"velocity":110}"#;
+        assert_eq!(serde_json::to_string(&note_on).unwrap(), json);
+        assert_eq!(from_json(json).unwrap(), note_on);
+
+        let program_change = MidiInstruction::new(MidiCommand::ProgramChange {
+            program: 20,
+            bank: None,
+        });
+        let json = r#"{"command":"ProgramChange","program":20}"#;
+        assert_eq!(serde_json::to_string(&program_change).unwrap(), json);
+        assert_eq!(from_json(json).unwrap(), program_change);
+    }
+
+    #[test]
+    fn missing_velocities_and_former_presets() {
+        assert_eq!(
+            from_json(r#"{"command":"NoteOff","note":60}"#).unwrap().command,
+            MidiCommand::NoteOff {
+                note: 60,
+                velocity: DEFAULT_VELOCITY
+            }
+        );
+        // Preset 149 is the 21st program of the second bank.
+        let preset = from_json(r#"{"new_preset":149}"#).unwrap();
+        assert_eq!(
+            preset.command,
+            MidiCommand::ProgramChange {
+                program: 20,
+                bank: Some(1)
+            }
+        );
+        assert_eq!(
+            MidiCommand::preset(NonZeroU16::MIN),
+            MidiCommand::ProgramChange {
+                program: 0,
+                bank: Some(0)
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_instructions_are_refused() {
+        for json in [
+            r#"{"command":"NoteOn","note":60,"channel":0}"#,
+            r#"{"command":"NoteOn","note":60,"channel":17}"#,
+            r#"{"command":"NoteOn","note":128}"#,
+            r#"{"command":"NoteOn"}"#,
+            r#"{"command":"ControlChange","controller":7}"#,
+            r#"{"command":"ProgramChange","program":1,"bank":16384}"#,
+            r#"{"command":"PitchBend","bend":8192}"#,
+            r#"{"command":"PitchBend","bend":-8193}"#,
+            r#"{"command":"SysEx","data":[126,127,247]}"#,
+            r#"{"command":"SysEx"}"#,
+            r#"{"command":"ProgramChange","program":1,"new_preset":2}"#,
+            r#"{"channel":1}"#,
+            r#"{"new_preset":0}"#,
+            r#"{"command":"NoteOn","note":60,"pitch":3}"#,
+        ] {
+            assert!(from_json(json).is_err(), "{json} was accepted");
+        }
+        assert!(from_json(r#"{"command":"PitchBend","bend":-8192}"#).is_ok());
+        assert!(from_json(r#"{"command":"ProgramChange","program":127,"bank":16383}"#).is_ok());
+    }
+
+    #[test]
+    fn sysex_data_is_limited() {
+        let longest = [0x7e; MAX_SYSEX_LENGTH];
+        let json = format!(r#"{{"command":"SysEx","data":{:?}}}"#, longest);
+        assert_eq!(from_json(&json).unwrap().command, sysex(&longest));
+        let too_long = [0x7e; MAX_SYSEX_LENGTH + 1];
+        let json = format!(r#"{{"command":"SysEx","data":{:?}}}"#, too_long);
+        assert!(from_json(&json).is_err());
+    }
+
+    #[test]
+    fn binary_instructions_keep_the_command() {
+        let note_on = MidiInstruction {
+            command: MidiCommand::NoteOn {
+                note: 36,
+                velocity: 110,
+            },
+            channel: Some(10),
+        };
+        let bytes = postcard::to_stdvec(&note_on).unwrap();
+        // Variant, note and velocity, then the channel.
+        assert_eq!(bytes, [0, 36, 110, 1, 10]);
+        assert_eq!(postcard::from_bytes::<MidiInstruction>(&bytes).unwrap(), note_on);
+
+        for command in [
+            MidiCommand::ControlChange {
+                controller: 7,
+                value: 100,
+            },
+            MidiCommand::ProgramChange {
+                program: 3,
+                bank: Some(MAX_BANK),
+            },
+            MidiCommand::PitchBend {
+                bend: MIN_PITCH_BEND,
+            },
+            sysex(&[0x7e, 0x7f, 0x09, 0x01]),
+        ] {
+            let instruction = MidiInstruction::new(command);
+            let bytes = postcard::to_stdvec(&instruction).unwrap();
+            assert_eq!(postcard::from_bytes::<MidiInstruction>(&bytes).unwrap(), instruction);
+        }
+    }
+
+    #[test]
+    fn routes_narrow_the_events() {
+        let route = |track, channel| MidiRoute {
+            track,
+            channel,
+            target: TargetSelector::all(),
+        };
+        assert!(route(None, None).routes(3, None));
+        assert!(route(Some(1), None).routes(1, Some(4)));
+        assert!(!route(Some(1), None).routes(2, Some(4)));
+        assert!(route(None, Some(10)).routes(2, Some(10)));
+        assert!(!route(None, Some(10)).routes(2, Some(9)));
+        // Meta and SysEx events have no channel.
+        assert!(!route(None, Some(10)).routes(2, None));
+        assert!(route(Some(0), Some(10)).routes(0, Some(10)));
+        assert!(!route(Some(0), Some(10)).routes(1, Some(10)));
+    }
+
+    #[test]
+    fn playbacks_route_everything_by_default() {
+        let playback: MidiFilePlayback = serde_json::from_str(
+            r#"{"file":"intro.mid","routes":[{"track":1,"target":{"groups":["strings"]}}]}"#,
+        )
+        .unwrap();
+        assert_eq!(playback.file, "intro.mid");
+        assert_eq!(playback.routes[0].channel, None);
+        assert_eq!(playback.routes[0].target.groups[0], "strings");
+        let playback: MidiFilePlayback = serde_json::from_str(r#"{"file":"intro.mid"}"#).unwrap();
+        assert!(playback.routes.is_empty());
+    }
+}
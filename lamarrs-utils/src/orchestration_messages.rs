@@ -1,10 +1,19 @@
 use serde::{Deserialize, Serialize};
 
-use crate::{action_messages::Event, RelativeLocation};
+use crate::{
+    action_messages::Event, midi::MidiFilePlayback, target_selector::TargetSelector, Boxed,
+};
 
 /// Wrapper for any message traveling between the Orchestrator and the Server
-///  * Request: Orchestrator > Server. Request sent by the Orchestrator to the server to perform an action.
+///  * Request: Orchestrator > Server. Request sent by the Orchestrator to the server to perform an action
+///    by the Clients chosen by the `TargetSelector`.
+///  * PlayMidiFile: Orchestrator > Server. Request to play a MIDI file from the Server, sending its
+///    events to the Clients chosen by the `TargetSelector`, or by the routes of the playback.
+///  * StopMidiFile: Orchestrator > Server. Request to stop the MIDI file being played.
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
+#[cfg_attr(not(feature = "alloc"), allow(clippy::large_enum_variant))]
 pub enum OrchestrationMessage {
-    Request(Event, Option<RelativeLocation>),
+    Request(Event, TargetSelector),
+    PlayMidiFile(Boxed<MidiFilePlayback>, TargetSelector),
+    StopMidiFile,
 }
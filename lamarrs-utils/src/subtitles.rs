@@ -0,0 +1,349 @@
+//! Subtitles
+//!
+//! A [`Subtitles`] action carries the same text in several languages, as [`SubtitleVariant`]s.
+//! The Server delivers to each Client only the variant matching the language it declared
+//! when registering, falling back to the first one, and splits texts too long to be shown at
+//! once into pages of up to [`MAX_SUBTITLE_LINES`] lines of [`MAX_SUBTITLE_LINE_CHARS`] characters.
+//! Only the pages are bounded: with the `alloc` feature, texts can be as long as needed.
+//!
+//! Sequences and operators can write subtitles, when deserialised from a human readable format,
+//! like YAML or JSON, as:
+//!  * A plain string, for a single variant without language.
+//!  * A map with `text` and optionally `language` and `speaker`, for a single variant.
+//!  * A map with a `variants` list, each of them with `text`, `language` and `speaker`.
+//!
+//! All of them accept an optional `page_duration_ms`.
+
+use core::{fmt, str::SplitWhitespace};
+
+use heapless::{String, Vec};
+use serde::{
+    de::{self, MapAccess, Visitor},
+    ser::SerializeStruct,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+/// Maximum amount of languages a `Subtitles` action can be written in.
+pub const MAX_SUBTITLE_LANGUAGES: usize = 4;
+
+/// Maximum length of a language tag, enough for BCP 47 tags like `es` or `pt-BR`.
+pub const MAX_LANGUAGE_TAG_LENGTH: usize = 8;
+
+/// Maximum length of the label telling who is speaking.
+pub const MAX_SPEAKER_LENGTH: usize = 24;
+
+/// Characters per line, following the avg of the current streaming services subtitle conventions.
+pub const MAX_SUBTITLE_LINE_CHARS: usize = 42;
+
+/// Lines shown at the same time.
+pub const MAX_SUBTITLE_LINES: usize = 2;
+
+/// Maximum length, in bytes, of a page: its lines, made of characters up to 4 bytes long,
+/// and the line breaks between them.
+pub const MAX_SUBTITLE_PAGE_LENGTH: usize = MAX_SUBTITLE_LINES * (MAX_SUBTITLE_LINE_CHARS * 4 + 1) - 1;
+
+/// How long each page is shown if it is not specified.
+pub const DEFAULT_PAGE_DURATION_MS: u32 = 3000;
+
+pub type LanguageTag = String<MAX_LANGUAGE_TAG_LENGTH>;
+pub type SpeakerLabel = String<MAX_SPEAKER_LENGTH>;
+
+/// Text of a variant. The Server splits it in pages before sending it to the Clients, so
+/// without a heap to hold longer texts, like in the rp-client, it is only as long as a page.
+#[cfg(feature = "alloc")]
+pub type SubtitleText = alloc::string::String;
+#[cfg(not(feature = "alloc"))]
+pub type SubtitleText = String<MAX_SUBTITLE_PAGE_LENGTH>;
+
+/// The text as a `SubtitleText`, if it fits in one.
+#[cfg(feature = "alloc")]
+pub fn subtitle_text(text: &str) -> Option<SubtitleText> {
+    Some(text.into())
+}
+#[cfg(not(feature = "alloc"))]
+pub fn subtitle_text(text: &str) -> Option<SubtitleText> {
+    SubtitleText::try_from(text).ok()
+}
+
+/// The subtitles in a single language.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SubtitleVariant {
+    /// Variants without language are shown to every Client that has no variant in its own.
+    #[serde(default)]
+    pub language: Option<LanguageTag>,
+    /// Who is speaking, for hard-of-hearing audiences.
+    #[serde(default)]
+    pub speaker: Option<SpeakerLabel>,
+    pub text: SubtitleText,
+}
+
+impl SubtitleVariant {
+    /// Returns `true` if the variant is written in `language`. Regional variants match their
+    /// language, so `es-AR` Clients get `es` subtitles and the other way around.
+    pub fn is_in(&self, language: &str) -> bool {
+        self.language.as_ref().is_some_and(|variant_language| {
+            variant_language.eq_ignore_ascii_case(language)
+                || primary_subtag(variant_language).eq_ignore_ascii_case(primary_subtag(language))
+        })
+    }
+
+    /// Splits the text in pages that fit the screen, wrapping the lines between words.
+    /// Empty texts have a single empty page, which clears the subtitles shown.
+    pub fn pages(&self) -> SubtitlePages<'_> {
+        SubtitlePages {
+            words: self.text.split_whitespace(),
+            carry: None,
+            started: false,
+        }
+    }
+}
+
+/// `es` for `es-AR`.
+fn primary_subtag(language: &str) -> &str {
+    language.split(['-', '_']).next().unwrap_or_default()
+}
+
+/// Iterator over the pages of a `SubtitleVariant` text, as returned by `SubtitleVariant::pages`.
+/// The lines of each page are separated by `\n`.
+pub struct SubtitlePages<'a> {
+    words: SplitWhitespace<'a>,
+    /// Word, or part of it, that didn't fit in the previous page.
+    carry: Option<&'a str>,
+    started: bool,
+}
+
+impl<'a> Iterator for SubtitlePages<'a> {
+    type Item = SubtitleText;
+
+    // Pushing to the page only returns a result when texts are bounded.
+    #[allow(clippy::let_unit_value)]
+    fn next(&mut self) -> Option<Self::Item> {
+        let first_page = !core::mem::replace(&mut self.started, true);
+        let mut page = SubtitleText::new();
+        let mut lines = 1;
+        let mut line_chars = 0;
+        while let Some(word) = self.carry.take().or_else(|| self.words.next()) {
+            // Words longer than a whole line are split, and the rest carried to the next line.
+            let (head, tail) = match word.char_indices().nth(MAX_SUBTITLE_LINE_CHARS) {
+                Some((split_at, _)) => (&word[..split_at], Some(&word[split_at..])),
+                None => (word, None),
+            };
+            let head_chars = head.chars().count();
+            if line_chars > 0 && line_chars + 1 + head_chars > MAX_SUBTITLE_LINE_CHARS {
+                if lines == MAX_SUBTITLE_LINES {
+                    self.carry = Some(word);
+                    break;
+                }
+                // Pages are never longer than `MAX_SUBTITLE_PAGE_LENGTH`, so they always fit.
+                let _ = page.push('\n');
+                lines += 1;
+                line_chars = 0;
+            } else if line_chars > 0 {
+                let _ = page.push(' ');
+                line_chars += 1;
+            }
+            let _ = page.push_str(head);
+            line_chars += head_chars;
+            self.carry = tail;
+        }
+        (first_page || !page.is_empty()).then_some(page)
+    }
+}
+
+/// Payload for subtitle change requests.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Subtitles {
+    /// The first one is the default, shown to the Clients whose language has no variant.
+    pub variants: Vec<SubtitleVariant, MAX_SUBTITLE_LANGUAGES>,
+    /// How long each page is shown when the text doesn't fit in a single one.
+    pub page_duration_ms: u32,
+}
+
+impl Subtitles {
+    /// Subtitles with a single variant without language nor speaker.
+    pub fn new(text: SubtitleText) -> Self {
+        let mut variants = Vec::new();
+        // A single variant always fits.
+        let _ = variants.push(SubtitleVariant {
+            language: None,
+            speaker: None,
+            text,
+        });
+        Self {
+            variants,
+            page_duration_ms: DEFAULT_PAGE_DURATION_MS,
+        }
+    }
+
+    /// Subtitles showing only a page of one of the variants.
+    pub fn page_of(&self, variant: &SubtitleVariant, page: SubtitleText) -> Self {
+        let mut variants = Vec::new();
+        // A single variant always fits.
+        let _ = variants.push(SubtitleVariant {
+            language: variant.language.clone(),
+            speaker: variant.speaker.clone(),
+            text: page,
+        });
+        Self {
+            variants,
+            page_duration_ms: self.page_duration_ms,
+        }
+    }
+
+    /// Picks the variant to show to a Client speaking `language`, or the default one.
+    pub fn variant_for(&self, language: Option<&str>) -> Option<&SubtitleVariant> {
+        language
+            .and_then(|language| self.variants.iter().find(|variant| variant.is_in(language)))
+            .or_else(|| self.variants.first())
+    }
+}
+
+// Manual Serialize / Deserialize Subtitles, to accept all the human readable forms.
+impl Serialize for Subtitles {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("Subtitles", 2)?;
+        state.serialize_field("variants", &self.variants)?;
+        state.serialize_field("page_duration_ms", &self.page_duration_ms)?;
+        state.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Subtitles {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(SubtitlesVisitor)
+        } else {
+            // Binary formats always carry the variants.
+            #[derive(Deserialize)]
+            struct SubtitlesHelper {
+                variants: Vec<SubtitleVariant, MAX_SUBTITLE_LANGUAGES>,
+                page_duration_ms: u32,
+            }
+            let SubtitlesHelper {
+                variants,
+                page_duration_ms,
+            } = SubtitlesHelper::deserialize(deserializer)?;
+            Ok(Subtitles {
+                variants,
+                page_duration_ms,
+            })
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(field_identifier, rename_all = "snake_case")]
+enum SubtitlesField {
+    Variants,
+    Text,
+    Language,
+    Speaker,
+    PageDurationMs,
+}
+
+struct SubtitlesVisitor;
+
+impl<'de> Visitor<'de> for SubtitlesVisitor {
+    type Value = Subtitles;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a string or a map with the text or the variants of the subtitles")
+    }
+
+    fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        let text = subtitle_text(value).ok_or_else(|| {
+            E::custom(format_args!(
+                "subtitles can't be longer than {} bytes",
+                MAX_SUBTITLE_PAGE_LENGTH
+            ))
+        })?;
+        Ok(Subtitles::new(text))
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let (mut variants, mut text, mut language, mut speaker) = (None, None, None, None);
+        let mut page_duration_ms = None;
+        while let Some(field) = map.next_key()? {
+            match field {
+                SubtitlesField::Variants => variants = Some(map.next_value()?),
+                SubtitlesField::Text => text = Some(map.next_value()?),
+                SubtitlesField::Language => language = map.next_value()?,
+                SubtitlesField::Speaker => speaker = map.next_value()?,
+                SubtitlesField::PageDurationMs => page_duration_ms = Some(map.next_value()?),
+            }
+        }
+        let variants: Vec<SubtitleVariant, MAX_SUBTITLE_LANGUAGES> = match (variants, text) {
+            (Some(variants), None) if language.is_none() && speaker.is_none() => variants,
+            (None, Some(text)) => {
+                let mut variants = Vec::new();
+                // A single variant always fits.
+                let _ = variants.push(SubtitleVariant {
+                    language,
+                    speaker,
+                    text,
+                });
+                variants
+            }
+            (None, None) => return Err(de::Error::missing_field("text")),
+            _ => {
+                return Err(de::Error::custom(
+                    "subtitles must be given either as a text or as a list of variants",
+                ))
+            }
+        };
+        if variants.is_empty() {
+            return Err(de::Error::invalid_length(0, &"at least one variant"));
+        }
+        Ok(Subtitles {
+            variants,
+            page_duration_ms: page_duration_ms.unwrap_or(DEFAULT_PAGE_DURATION_MS),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "alloc")]
+    fn long_texts_are_split_in_pages_that_fit() {
+        let text = "palabra ".repeat(200);
+        let subtitles = Subtitles::new(subtitle_text(&text).unwrap());
+        let pages = subtitles.variants[0].pages().collect::<std::vec::Vec<_>>();
+
+        assert!(pages.len() > 1);
+        for page in pages {
+            assert!(page.len() <= MAX_SUBTITLE_PAGE_LENGTH);
+            assert!(page.lines().count() <= MAX_SUBTITLE_LINES);
+            assert!(page.lines().all(|line| line.chars().count() <= MAX_SUBTITLE_LINE_CHARS));
+        }
+    }
+
+    #[test]
+    fn pages_of_4_bytes_characters_fit() {
+        let text = "🎵".repeat(MAX_SUBTITLE_LINES * MAX_SUBTITLE_LINE_CHARS);
+        let variant = SubtitleVariant {
+            language: None,
+            speaker: None,
+            text: subtitle_text(&text).unwrap(),
+        };
+
+        let pages = variant.pages().collect::<std::vec::Vec<_>>();
+
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[0].chars().filter(|char| *char == '🎵').count(), text.chars().count());
+    }
+}
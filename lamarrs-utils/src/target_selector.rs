@@ -0,0 +1,98 @@
+use heapless::{String, Vec};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    action_messages::{GroupName, MAX_TARGET_GROUPS},
+    Position, Region,
+};
+
+/// Maximum amount of Clients that can be listed in a `TargetSelector`.
+pub const MAX_TARGET_CLIENTS: usize = 16;
+
+/// Maximum amount of regions or sections a `TargetSelector` can combine.
+pub const MAX_TARGET_LOCATIONS: usize = 8;
+
+/// Maximum length of the name of a section of the venue, like `Stalls`.
+pub const MAX_SECTION_NAME_LENGTH: usize = 24;
+
+/// Name of a section of the venue, as defined in the seat map of the Server.
+pub type SectionName = String<MAX_SECTION_NAME_LENGTH>;
+
+/// Selects the Clients that perform an action.
+/// Every non empty criteria narrows the selection, so the default selector targets all the Clients:
+///  * clients: Only the listed Clients. Useful to test a single device.
+///  * regions and sections: Only the Clients located inside any of the regions, or seated in any
+///    of the sections. Clients with unknown location are never inside a region.
+///  * groups: Only the Clients belonging to any of the groups.
+///  * exclude: Clients that never perform the action.
+///  * percentage: Only a random subset of the selected Clients, for sparkle effects.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct TargetSelector {
+    pub clients: Vec<Uuid, MAX_TARGET_CLIENTS>,
+    pub regions: Vec<Region, MAX_TARGET_LOCATIONS>,
+    pub sections: Vec<SectionName, MAX_TARGET_LOCATIONS>,
+    pub groups: Vec<GroupName, MAX_TARGET_GROUPS>,
+    pub exclude: Vec<Uuid, MAX_TARGET_CLIENTS>,
+    /// Between 0 and 100. Values above 100 are handled as 100.
+    pub percentage: Option<u8>,
+}
+
+impl TargetSelector {
+    /// Targets all the Clients.
+    pub fn all() -> Self {
+        Self::default()
+    }
+
+    /// Targets the Clients inside the region.
+    pub fn region(region: Region) -> Self {
+        let mut regions = Vec::new();
+        // A single region always fits.
+        let _ = regions.push(region);
+        Self {
+            regions,
+            ..Self::default()
+        }
+    }
+
+    /// Returns `true` if the Client matches every criteria of the selector but the percentage,
+    /// which must be applied over the whole selection.
+    pub fn selects(
+        &self,
+        uuid: &Uuid,
+        location: Option<&Position>,
+        section: Option<&str>,
+        is_in_group: impl Fn(&str) -> bool,
+    ) -> bool {
+        if self.exclude.contains(uuid) {
+            return false;
+        }
+        if !self.clients.is_empty() && !self.clients.contains(uuid) {
+            return false;
+        }
+        if !(self.regions.is_empty() && self.sections.is_empty()) {
+            let in_region = location.is_some_and(|location| {
+                self.regions.iter().any(|region| region.contains(location))
+            });
+            let in_section = section.is_some_and(|section| {
+                self.sections
+                    .iter()
+                    .any(|target_section| target_section.as_str() == section)
+            });
+            if !(in_region || in_section) {
+                return false;
+            }
+        }
+        self.groups.is_empty() || self.groups.iter().any(|group| is_in_group(group))
+    }
+
+    /// How many Clients perform the action out of the `selected` ones.
+    pub fn amount_of_targets(&self, selected: usize) -> usize {
+        match self.percentage {
+            // Rounded up, so any percentage above 0 targets at least one Client.
+            Some(percentage) => (selected * percentage.min(100) as usize).div_ceil(100),
+            None => selected,
+        }
+    }
+}
@@ -4,7 +4,7 @@ use std::{
 
 use inquire::{CustomType, InquireError, Select};
 use lamarrs_utils::{
-    AudioFile, ColourRgb, MidiInstruction, RelativeLocation, Service, Subtitles, action_messages::{Action, Event}, orchestration_messages::OrchestrationMessage
+    AudioFile, AudioPlayback, ColourRgb, MidiInstruction, midi::{MidiCommand, MidiFileName, MidiFilePlayback}, RelativeLocation, Service, Subtitles, action_messages::{Action, Event, GroupName, MAX_TARGET_GROUPS}, orchestration_messages::OrchestrationMessage, target_selector::TargetSelector
 };
 use lipsum::lipsum_words_with_rng;
 use midir::{MidiOutput, MidiOutputConnection, os::unix::VirtualOutput};
@@ -59,21 +59,21 @@ fn main() {
         loop {
             let rnd_service = services.choose(&mut rand::rng()).unwrap();
             //let rnd_location = locations.choose(&mut rand::rng()).unwrap();
-            let rnd_location = None;
+            let target = TargetSelector::all();
             // I don't care if the subs are not random anymore. May fix it later, perhaps.
-            let subtitles = heapless::String::try_from(lipsum::lipsum(5).as_str()).unwrap();
+            let subtitles = Subtitles::new(lipsum::lipsum(5));
             let orchestrator_message: OrchestrationMessage = match rnd_service {
                 Service::Subtitle => OrchestrationMessage::Request(
-                    Event::PerformAction(Action::ShowNewSubtitles(Subtitles { subtitles })),
-                    rnd_location.to_owned(),
+                    Event::PerformAction(Action::ShowNewSubtitles(Box::new(subtitles))),
+                    target.to_owned(),
                 ),
                 Service::Colour => OrchestrationMessage::Request(
-                    Event::PerformAction(Action::ChangeColour(ColourRgb {
-                        r: rand::random_range(0..=255),
-                        g: rand::random_range(0..=255),
-                        b: rand::random_range(0..=255),
-                    })),
-                    rnd_location.to_owned(),
+                    Event::PerformAction(Action::ChangeColour(ColourRgb::new(
+                        rand::random_range(0..=255),
+                        rand::random_range(0..=255),
+                        rand::random_range(0..=255),
+                    ))),
+                    target.to_owned(),
                 ),
                 _ => break,
             };
@@ -93,13 +93,16 @@ fn main() {
     // if let Err(_) = target_location {
     //     panic!("There was an error processing the target location!");
     // }
-    let target_location = None;
+    let target = TargetSelector {
+        groups: on_target_groups(),
+        ..TargetSelector::all()
+    };
 
     let orchestrator_message: OrchestrationMessage = match selected_service {
-        Ok(Service::Subtitle) => on_subtitle(target_location),
-        Ok(Service::Colour) => on_color(target_location),
-        Ok(Service::AudioPlayer) => on_play_audio(target_location),
-        Ok(Service::Midi) => on_midi(target_location),
+        Ok(Service::Subtitle) => on_subtitle(target),
+        Ok(Service::Colour) => on_color(target),
+        Ok(Service::AudioPlayer) => on_play_audio(target),
+        Ok(Service::Midi) => on_midi(target),
         Err(_) => panic!("There was an error, please try again"),
     };
     send_to_mqtt(mqtt_sender, orchestrator_message);
@@ -108,21 +111,36 @@ fn main() {
     }
 }
 
+#[instrument(name = "Orchestrator::on_target_groups", level = "INFO", ret)]
+fn on_target_groups() -> heapless::Vec<GroupName, MAX_TARGET_GROUPS> {
+    let requested_groups = CustomType::<String>::new("Target groups, separated by commas:")
+    .with_default(String::new())
+    .with_help_message("Only the devices in any of these groups will be targetted. Leave it empty to target all of them.")
+    .prompt()
+    .unwrap();
+    requested_groups
+        .split(',')
+        .map(str::trim)
+        .filter(|group| !group.is_empty())
+        .map(|group| GroupName::try_from(group).expect("Group names can't be longer than 24 characters."))
+        .take(MAX_TARGET_GROUPS)
+        .collect()
+}
+
 #[instrument(name = "Orchestrator::on_subtitle", level = "INFO", ret)]
-fn on_subtitle(target_location: Option<RelativeLocation>) -> OrchestrationMessage {
+fn on_subtitle(target: TargetSelector) -> OrchestrationMessage {
     let requested_subtitles= CustomType::<String>::new("Subtitles to be sent:")
-    .with_error_message("Subtitles with more than 50 chars can't be sent.")
-    .with_help_message("A String of characters to be sent to the targetted devices. Must be shorter than 50 characters.")
+    .with_help_message("A String of characters to be sent to the targetted devices. Long texts are split in pages by the server.")
     .prompt();
-    let subtitles = heapless::String::try_from(requested_subtitles.unwrap().as_str()).unwrap();
+    let subtitles = Subtitles::new(requested_subtitles.unwrap());
     OrchestrationMessage::Request(
-        Event::PerformAction(Action::ShowNewSubtitles(Subtitles { subtitles })),
-        target_location,
+        Event::PerformAction(Action::ShowNewSubtitles(Box::new(subtitles))),
+        target,
     )
 }
 
 #[instrument(name = "Orchestrator::on_color", level = "INFO", ret)]
-fn on_color(target_location: Option<RelativeLocation>) -> OrchestrationMessage {
+fn on_color(target: TargetSelector) -> OrchestrationMessage {
     let red = CustomType::<u8>::new("Red:")
         .with_error_message("Red must have a value between 0 and 255.")
         .with_help_message("The value for Red in the RGB message to be sent.")
@@ -137,17 +155,17 @@ fn on_color(target_location: Option<RelativeLocation>) -> OrchestrationMessage {
         .prompt();
 
     OrchestrationMessage::Request(
-        Event::PerformAction(Action::ChangeColour(ColourRgb {
-            r: red.unwrap(),
-            g: green.unwrap(),
-            b: blue.unwrap(),
-        })),
-        target_location.to_owned(),
+        Event::PerformAction(Action::ChangeColour(ColourRgb::new(
+            red.unwrap(),
+            green.unwrap(),
+            blue.unwrap(),
+        ))),
+        target,
     )
 }
 
 #[instrument(name = "Orchestrator::on_subtitle", level = "INFO", ret)]
-fn on_play_audio(target_location: Option<RelativeLocation>) -> OrchestrationMessage {
+fn on_play_audio(target: TargetSelector) -> OrchestrationMessage {
     let requested_audio_file_with_extension= CustomType::<String>::new("Audio file to be played. INCLUDE EXTENSION:")
     .with_error_message("Audio file name with more than 55 chars can't be sent.")
     .with_help_message("A String of characters to be sent to the targetted devices. Must be shorter than 55 characters.")
@@ -158,16 +176,43 @@ fn on_play_audio(target_location: Option<RelativeLocation>) -> OrchestrationMess
     let file_extension = heapless::String::try_from(parsed_request[1]).unwrap();
 
     OrchestrationMessage::Request(
-        Event::PerformAction(Action::PlayAudio(AudioFile {
+        Event::PerformAction(Action::PlayAudio(AudioPlayback::new(AudioFile {
             file_name,
             file_extension,
-        })),
-        target_location,
+        }))),
+        target,
     )
 }
 
-#[instrument(name = "Orchestrator::on_subtitle", level = "INFO", ret)]
-fn on_midi(target_location: Option<RelativeLocation>) -> OrchestrationMessage {
+#[instrument(name = "Orchestrator::on_midi", level = "INFO", ret)]
+fn on_midi(target: TargetSelector) -> OrchestrationMessage {
+    let midi_requests = vec!["Preset change", "Play MIDI file", "Stop MIDI file"];
+    match Select::new("Select MIDI request", midi_requests).prompt().unwrap() {
+        "Play MIDI file" => on_play_midi_file(target),
+        "Stop MIDI file" => OrchestrationMessage::StopMidiFile,
+        _ => on_midi_preset(target),
+    }
+}
+
+#[instrument(name = "Orchestrator::on_play_midi_file", level = "INFO", ret)]
+fn on_play_midi_file(target: TargetSelector) -> OrchestrationMessage {
+    let file = CustomType::<MidiFileName>::new("MIDI file to be played, relative to the sequence file of the Server:")
+    .with_error_message("MIDI file path with more than 64 chars can't be sent.")
+    .with_help_message("All its tracks are played by the targetted devices.")
+    .prompt()
+    .unwrap();
+
+    OrchestrationMessage::PlayMidiFile(
+        Box::new(MidiFilePlayback {
+            file,
+            routes: heapless::Vec::new(),
+        }),
+        target,
+    )
+}
+
+#[instrument(name = "Orchestrator::on_midi_preset", level = "INFO", ret)]
+fn on_midi_preset(target: TargetSelector) -> OrchestrationMessage {
     // // Create a virtual MIDI output
     // let midi_out = MidiOutput::new("Pi MIDI Out").unwrap();
     // // If you want to open a hardware port:
@@ -198,8 +243,8 @@ fn on_midi(target_location: Option<RelativeLocation>) -> OrchestrationMessage {
     .unwrap();
 
     OrchestrationMessage::Request(
-        Event::PerformAction(Action::Midi(MidiInstruction { new_preset: midi_preset })),
-        target_location,
+        Event::PerformAction(Action::Midi(MidiInstruction::new(MidiCommand::preset(midi_preset)))),
+        target,
     )
 }
 
@@ -1,9 +1,10 @@
 use core::fmt::Write;
-use core::panic;
 
 use defmt::{debug, info};
+use embassy_futures::select::{select, Either};
 use embassy_rp::i2c::{Async, I2c};
 use embassy_rp::peripherals::I2C1;
+use embassy_time::{Duration, Instant, Timer};
 use embedded_graphics::primitives::{PrimitiveStyleBuilder, Rectangle};
 use embedded_graphics::{
     mono_font::{ascii::FONT_6X10, MonoTextStyleBuilder},
@@ -13,7 +14,9 @@ use embedded_graphics::{
 };
 use heapless::String;
 use lamarrs_utils::action_messages::{Action, Event as ActionEvent};
+use lamarrs_utils::colour_effect::ColourEffect;
 use lamarrs_utils::exchange_messages::{AckResult, ExchangeMessage, NackResult};
+use lamarrs_utils::ColourRgb;
 use ssd1306::mode::{BufferedGraphicsMode, DisplayConfig};
 use ssd1306::prelude::{DisplayRotation, I2CInterface};
 use ssd1306::size::DisplaySize128x64;
@@ -21,6 +24,9 @@ use ssd1306::{I2CDisplayInterface, Ssd1306};
 
 use crate::OLED_CHANNEL;
 
+/// Time between two frames of a running colour effect.
+const EFFECT_FRAME_PERIOD: Duration = Duration::from_millis(100);
+
 /// Events that worker tasks send to the OLED_CHANNEL.
 pub enum OledEvents {
     ConnectedToWifi(bool), // Connected stablished with router.
@@ -29,6 +35,13 @@ pub enum OledEvents {
     WsMessage(ExchangeMessage),    // New Message received from orchestrator.
 }
 
+/// Colour effect being shown, along with the colour the device had when it started.
+struct RunningEffect {
+    effect: ColourEffect,
+    previous: ColourRgb,
+    started_at: Instant,
+}
+
 // Function that updates the SSD1306 OLED display.
 fn update_line(
     display: &mut Ssd1306<
@@ -69,9 +82,34 @@ pub async fn oled_ssd1306_task(i2c1: I2c<'static, I2C1, Async>) {
 
     display.init().unwrap();
 
+    // The device has no light yet, so the colour it would show is written in the screen.
+    let mut colour = ColourRgb::new(0, 0, 0);
+    let mut running_effect: Option<RunningEffect> = None;
+
     loop {
-        // Do nothing until we receive any event
-        let event = inbound.receive().await;
+        // Do nothing until we receive any event, or the running colour effect needs a new frame.
+        let event = if running_effect.is_some() {
+            match select(inbound.receive(), Timer::after(EFFECT_FRAME_PERIOD)).await {
+                Either::First(event) => event,
+                Either::Second(()) => {
+                    if let Some(running) = &running_effect {
+                        let elapsed_ms =
+                            running.started_at.elapsed().as_millis().min(u32::MAX as u64) as u32;
+                        colour = running.effect.evaluate(&running.previous, elapsed_ms);
+                        let mut message: String<50> = String::new();
+                        let _ = write!(&mut message, "{} {}", running.effect.kind, colour);
+                        update_line(&mut display, 48, message.as_str(), 10);
+                        if running.effect.is_finished(elapsed_ms) {
+                            debug!("Colour effect finished");
+                            running_effect = None;
+                        }
+                    }
+                    continue;
+                }
+            }
+        } else {
+            inbound.receive().await
+        };
         match event {
             OledEvents::ConnectedToWifi(bool) => {
                 let status = match bool {
@@ -115,32 +153,56 @@ pub async fn oled_ssd1306_task(i2c1: I2c<'static, I2C1, Async>) {
                         ))
                         .await;
                 } else {
+                    // A new colour stops the running effect, a new effect starts from the current colour.
+                    if let ExchangeMessage::Scene(ActionEvent::PerformAction(action), _) = &exchange_message {
+                        match action {
+                            Action::ChangeColour(new_colour) => {
+                                colour = new_colour.clone();
+                                running_effect = None;
+                            }
+                            Action::ColourEffect(effect) => {
+                                running_effect = Some(RunningEffect {
+                                    effect: effect.clone(),
+                                    previous: colour.clone(),
+                                    started_at: Instant::now(),
+                                });
+                            }
+                            _ => {}
+                        }
+                    }
                     // This buffer will be used by certain structs to show themselves as &str.
                     // By now only Action implement the `as_str` function, but later we will
                     // implement them for all as a Trait.
                     let mut write_buffer = String::<128>::new();
                     let message_to_show = match exchange_message {
-                        ExchangeMessage::Ack(ack_result) => match ack_result {
+                        ExchangeMessage::Ack(_, ack_result) => match ack_result {
                             AckResult::Success => "Success!",
                             AckResult::UpdatedSubscription => "Updated subscription",
                             AckResult::UpdatedLocation => "Updated location",
                         },
-                        ExchangeMessage::Nack(nack_result) => match nack_result {
+                        ExchangeMessage::Nack(_, nack_result) => match nack_result {
                             NackResult::AlreadySubscribed => "Rejected: Already subscribed",
                             NackResult::NotSubscribed => "Rejected: Not subscribed",
                             NackResult::Failed => "Failed",
+                            NackResult::IncompatibleProtocolVersion => "Rejected: Incompatible",
+                            NackResult::UnknownSeat => "Rejected: Unknown seat",
+                            NackResult::UnknownMedia => "Rejected: Unknown media",
                         },
-                        ExchangeMessage::Scene(event) => {
-                            if let ActionEvent::PerformAction(action) = event {
-                                action.as_str(&mut write_buffer)
-                            } else {
-                                panic!("Invalid Scene event reported to the Oled screen.")
-                            }
+                        ExchangeMessage::ServerInfo(server_info) => {
+                            let _ = write!(write_buffer, "Server v{}", server_info.server_version);
+                            write_buffer.as_str()
+                        }
+                        ExchangeMessage::Scene(ActionEvent::PerformAction(action), _) => {
+                            action.as_str(&mut write_buffer)
                         }
                         ExchangeMessage::Error(error_description) => {
                             &error_description.error_descr.clone()
                         }
-                        _ => unreachable!("Oled received an unsuported message to show."),
+                        // Whatever the server sends, the screen keeps showing the last message it could show.
+                        other => {
+                            defmt::warn!("Oled can't show {} messages, ignoring it.", defmt::Display2Format(&other));
+                            continue;
+                        }
                     };
                     update_line(&mut display, 48, message_to_show, 10);
                     defmt::debug!("Updated Oled");
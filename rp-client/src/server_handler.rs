@@ -1,23 +1,97 @@
-use core::str::FromStr;
+use core::{fmt::Write, pin::pin, str::FromStr};
 
 use defmt::{error, info, warn};
-use embassy_futures::select::select;
+use embassy_futures::select::{select3, Either3};
 use embassy_net::IpEndpoint;
 use embassy_rp::clocks::RoscRng;
-use embassy_time::Duration;
-use heapless::String;
+use embassy_time::{Duration, Instant, Timer};
+use heapless::{String, Vec};
 use lamarrs_utils::{
-    action_messages::{Action, Event},
-    exchange_messages::ExchangeMessage,
-    ClientIdAndLocation, Service,
+    action_messages::{Action, ClientKind, Event, Registration},
+    clock::{ClockEstimator, TimeSyncResponse},
+    exchange_messages::{ExchangeMessage, RequestId, ServerInfoHeader, PROTOCOL_VERSION},
+    ClientIdAndLocation, ErrorDescription, Service,
 };
 use uuid::Builder;
 
 use crate::{
-    websocket_handler::{WebSocket, WsError},
+    websocket_handler::{WebSocket, WebSocketWriter, WsError, MAX_SENT_PAYLOAD_SIZE},
     OledEvents, ASYNC_GPIO_INPUT_CHANNEL, OLED_CHANNEL,
 };
 
+/// Size of the buffer where the frames sent by the server are read. Frames
+/// bigger than this can't be received.
+const WS_READING_BUFFER_SIZE: usize = 256;
+
+/// Size of the buffer where the messages sent to the server are serialised. The biggest one is
+/// a registration with all its optional fields, of about 140 bytes.
+const WS_SENDING_BUFFER_SIZE: usize = 256;
+const _: () = assert!(WS_SENDING_BUFFER_SIZE <= MAX_SENT_PAYLOAD_SIZE);
+
+/// Time the server has to answer a request before it is considered lost.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Maximum amount of requests waiting at the same time for an answer from the server.
+const MAX_PENDING_REQUESTS: usize = 8;
+
+/// Maximum amount of actions waiting for the time they were scheduled at.
+const MAX_SCHEDULED_ACTIONS: usize = 4;
+
+/// Requests sent to the server that are still waiting for their Ack or Nack.
+struct PendingRequests {
+    next_request_id: RequestId,
+    requests: Vec<(RequestId, Instant), MAX_PENDING_REQUESTS>,
+}
+
+impl PendingRequests {
+    fn new() -> Self {
+        Self {
+            next_request_id: 0,
+            requests: Vec::new(),
+        }
+    }
+
+    /// Wraps the Event in a new Request to the server, keeping track of it until
+    /// the server answers it. If too many requests are pending, the oldest one is forgotten.
+    fn request(&mut self, event: Event) -> ExchangeMessage {
+        let request_id = self.next_request_id;
+        self.next_request_id = self.next_request_id.wrapping_add(1);
+        if self.requests.is_full() {
+            let (forgotten_request_id, _) = self.requests.remove(0);
+            warn!("Too many pending requests, forgetting request {}", forgotten_request_id);
+        }
+        // There is always room, as the oldest request was removed if it was full.
+        let _ = self.requests.push((request_id, Instant::now()));
+        ExchangeMessage::Request(request_id, event)
+    }
+
+    /// Stops tracking the request answered by the server. Returns `false` if it wasn't pending.
+    fn resolve(&mut self, request_id: Option<RequestId>) -> bool {
+        match self
+            .requests
+            .iter()
+            .position(|(pending_request_id, _)| Some(*pending_request_id) == request_id)
+        {
+            Some(index) => {
+                self.requests.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drops the requests that were not answered by the server in time.
+    fn expire(&mut self) {
+        self.requests.retain(|(request_id, sent_at)| {
+            let is_pending = sent_at.elapsed() < REQUEST_TIMEOUT;
+            if !is_pending {
+                warn!("Request {} was not answered by the server in time", request_id);
+            }
+            is_pending
+        });
+    }
+}
+
 /// Lamarrs websocket handler.
 /// It connects to the target server, upgrades the connection to a Websocket,
 /// does the initial client base registration and subscribes to Color service.
@@ -40,6 +114,11 @@ pub async fn server_handler(stack: embassy_net::Stack<'static>, target: IpEndpoi
     let mut uuid_buffer = [0u8; 36];
     let uuid_str =
         String::from_str(client_id.uuid.hyphenated().encode_lower(&mut uuid_buffer)).unwrap();
+    let mut pending_requests = PendingRequests::new();
+    // Kept across connections, as neither the local clock nor the server one are reset by them.
+    let mut clock_estimator = ClockEstimator::new();
+    // Actions waiting for the time they were scheduled at, sorted by it.
+    let mut scheduled_actions: Vec<(Instant, Action), MAX_SCHEDULED_ACTIONS> = Vec::new();
     // Connects and upgrades to websocket.
     loop {
         // Inner block: borrow buffers here only.
@@ -52,6 +131,9 @@ pub async fn server_handler(stack: embassy_net::Stack<'static>, target: IpEndpoi
             { WebSocket::connect(stack, &mut rx_buffer, &mut tx_buffer, target).await };
         match websocket_handshake_result {
             Ok(mut websocket) => {
+                let (mut ws_reader, mut ws_writer) = websocket.split();
+                // Requests sent through a previous connection will never be answered.
+                pending_requests = PendingRequests::new();
                 // Notifies the screen that it is connected to lamarrs.
                 oled_sender
                     .send(OledEvents::ConnectedToLamarrs(true, None))
@@ -59,104 +141,227 @@ pub async fn server_handler(stack: embassy_net::Stack<'static>, target: IpEndpoi
 
                 // Sends initial basic registration message to lamarrs server.
                 defmt::info!("Sending Register request to lamarrs server.");
-                let lamarrs_message = ExchangeMessage::Request(Event::Register(client_id.clone()));
-                send_message_to_lamarrs_server(
-                    &mut websocket,
-                    &lamarrs_message,
-                )
-                .await;
+                let lamarrs_message = pending_requests.request(Event::Register(Registration {
+                    client: client_id.clone(),
+                    protocol_version: PROTOCOL_VERSION,
+                    kind: ClientKind::RpClient,
+                    services: [Service::Colour].into_iter().collect(),
+                    max_frame_size: WS_READING_BUFFER_SIZE as u32,
+                    seat: None,
+                    language: None,
+                    section: None,
+                    group: None,
+                }));
+                if let Err(error) = send_message_to_lamarrs_server(&mut ws_writer, &lamarrs_message).await {
+                    error!("Failed to send the registration to lamarrs server: {:?}", error);
+                }
                 oled_sender
                     .send(OledEvents::RegiteredWithUuid(uuid_str.clone()))
                     .await;
 
                 // Send subscription to color service.
                 defmt::info!("Subscribing to Colour service");
-                let lamarrs_message = ExchangeMessage::Request(Event::SuscribeToService(
+                let lamarrs_message = pending_requests.request(Event::SuscribeToService(
                     Service::Colour,
                     client_id.clone(),
                 ));
-                send_message_to_lamarrs_server(
-                    &mut websocket,
-                    &lamarrs_message,
-                )
-                .await;
+                if let Err(error) = send_message_to_lamarrs_server(&mut ws_writer, &lamarrs_message).await {
+                    error!("Failed to subscribe to the Colour service: {:?}", error);
+                }
 
+                let mut ws_reading_buffer = [0u8; WS_READING_BUFFER_SIZE];
                 loop {
-                    let mut ws_reading_buffer = [0u8; 256];
-                    let websocket_listener = websocket.recv_message(&mut ws_reading_buffer);
-                    let gpio_input_listener = async_gpio_receiver.receive();
-                    // Listener loop.
-                    match select(websocket_listener, gpio_input_listener).await {
-                        // Manage new message from the server.
-                        embassy_futures::select::Either::First(ws_message) => {
-                            match ws_message {
-                                Ok(_) => {
-                                    let message: ExchangeMessage =
-                                        postcard::from_bytes(&ws_reading_buffer).unwrap();
-                                    oled_sender
-                                        .send(OledEvents::WsMessage(message.clone()))
-                                        .await;
-
-                                    // This buffer will be used by certain structs to show themselves as &str.
-                                    // By now only Action implement the `as_str` function, but later we will
-                                    // implement them for all as a Trait.
-                                    let mut write_buffer = String::<128>::new();
-                                    match message {
-                                        ExchangeMessage::Ack(ack_result) => info!("Last request was successful!"),
-                                        ExchangeMessage::Nack(nack_result) => warn!("Last request was not accepted by the server"),
-                                        ExchangeMessage::Scene(event) => {
-                                            match event {
-                                                Event::PerformAction(action) => info!("New action requested: {:?}", action.as_str(&mut write_buffer)),
-                                                _ => unreachable!("The Event requested is not compatible with Scene messages: {:?}", event)
-                                            }
+                    // The frame being received is only awaited until it is complete, while the gpio input and the timers
+                    // are handled in between, as dropping it halfway would lose the rest of the frame.
+                    let ws_message = {
+                        let mut websocket_listener = pin!(ws_reader.recv_message(&mut ws_reading_buffer));
+                        loop {
+                            let gpio_input_listener = async_gpio_receiver.receive();
+                            // Wakes up every second to check the pending requests, or earlier if a scheduled action is due.
+                            let pending_requests_check = Instant::now() + Duration::from_secs(1);
+                            let next_wakeup = scheduled_actions
+                                .first()
+                                .map_or(pending_requests_check, |(execute_at, _)| {
+                                    (*execute_at).min(pending_requests_check)
+                                });
+                            let timer = Timer::at(next_wakeup);
+                            // Listener loop.
+                            match select3(websocket_listener.as_mut(), gpio_input_listener, timer).await {
+                                Either3::First(ws_message) => break ws_message,
+                                // Manage internal messages from gpio input triggers.
+                                Either3::Second(gpio_input_message) => {
+                                    defmt::info!("{}", gpio_input_message);
+                                    let lamarrs_message = match gpio_input_message {
+                                        crate::GpioInputEvents::NextTrigger => {
+                                            defmt::info!(
+                                                "Sending Request for NEXT scene to lamarrs server"
+                                            );
+                                            ExchangeMessage::NextScene
+                                        }
+                                        crate::GpioInputEvents::Retrigger => {
+                                            defmt::info!(
+                                                "Sending Request for RETRIGGER scene to lamarrs server"
+                                            );
+                                            ExchangeMessage::RetriggerScene
                                         }
-                                        ExchangeMessage::Error(error_description) => error!("An error was reported by the server: {:?}", error_description.error_descr),
-                                        ExchangeMessage::Heartbeat => {
-                                            info!("Watchdog send a heartbeat request");
-                                            let heartbeat_response = ExchangeMessage::HeartbeatAck;
-                                            send_message_to_lamarrs_server(&mut websocket, &heartbeat_response).await;
-                                        },
-                                        _ => error!("Received an invalid Exchange Message.")
+                                    };
+                                    if let Err(error) = send_message_to_lamarrs_server(&mut ws_writer, &lamarrs_message).await {
+                                        error!("Failed to send the scene request to lamarrs server: {:?}", error);
                                     }
-                                    info!("Received message from Server");
                                 }
-                                Err(e) => {
-                                    defmt::warn!(
-                                        "Error receiving frame from lamarrs server: {:?}",
-                                        e
-                                    );
-                                    // Fatal: break to reconnect or close clearly. This avoids getting trapped in an infinite logging loop.
-                                    if let WsError::InvalidResponse = e {
-                                        oled_sender
-                                            .send(OledEvents::ConnectedToLamarrs(false, None))
-                                            .await;
-                                        break;
+                                // Forget the requests the server never answered, and perform the scheduled actions whose time has come.
+                                Either3::Third(_) => {
+                                    pending_requests.expire();
+                                    while scheduled_actions
+                                        .first()
+                                        .is_some_and(|(execute_at, _)| *execute_at <= Instant::now())
+                                    {
+                                        let (_, action) = scheduled_actions.remove(0);
+                                        perform_action(action).await;
                                     }
                                 }
                             }
                         }
-                        // Manage internal messages from gpio input triggers.
-                        embassy_futures::select::Either::Second(gpio_input_message) => {
-                            defmt::info!("{}", gpio_input_message);
-                            let lamarrs_message = match gpio_input_message {
-                                crate::GpioInputEvents::NextTrigger => {
-                                    defmt::info!(
-                                        "Sending Request for NEXT scene to lamarrs server"
-                                    );
-                                    ExchangeMessage::NextScene
-                                }
-                                crate::GpioInputEvents::Retrigger => {
-                                    defmt::info!(
-                                        "Sending Request for RETRIGGER scene to lamarrs server"
-                                    );
-                                    ExchangeMessage::RetriggerScene
+                    };
+                    match ws_message {
+                        // Manage new message from the server.
+                        Ok(frame_len) => {
+                            let received_us = Instant::now().as_micros();
+                            let frame = &ws_reading_buffer[..frame_len];
+                            let message: ExchangeMessage = match postcard::from_bytes(frame) {
+                                Ok(message) => message,
+                                Err(_) => {
+                                    // A server speaking another version of the protocol still sends a readable header with its `ServerInfo`.
+                                    match postcard::take_from_bytes::<ServerInfoHeader>(frame)
+                                        .ok()
+                                        .and_then(|(header, _)| header.protocol_version())
+                                    {
+                                        Some(protocol_version) => error!("Lamarrs server speaks protocol version {}, but this device speaks {}", protocol_version, PROTOCOL_VERSION),
+                                        None => warn!("Ignoring a frame from the server that could not be decoded"),
+                                    }
+                                    continue;
                                 }
                             };
-                            send_message_to_lamarrs_server(
-                                &mut websocket,
-                                &lamarrs_message,
-                            )
-                            .await;
+                            // Clock synchronisation runs every few seconds, it would flood the screen.
+                            // Scenes are shown when they are performed.
+                            if !matches!(
+                                message,
+                                ExchangeMessage::TimeSyncRequest(_)
+                                    | ExchangeMessage::TimeSyncResult(_)
+                                    | ExchangeMessage::Scene(..)
+                            ) {
+                                oled_sender
+                                    .send(OledEvents::WsMessage(message.clone()))
+                                    .await;
+                            }
+
+                            // This buffer will be used by certain structs to show themselves as &str.
+                            // By now only Action implement the `as_str` function, but later we will
+                            // implement them for all as a Trait.
+                            let mut write_buffer = String::<128>::new();
+                            match message {
+                                ExchangeMessage::Ack(request_id, _) => {
+                                    if pending_requests.resolve(request_id) {
+                                        info!("Request {:?} was successful!", request_id)
+                                    } else {
+                                        warn!("Ack received for unknown request {:?}", request_id)
+                                    }
+                                },
+                                ExchangeMessage::Nack(request_id, _) => {
+                                    pending_requests.resolve(request_id);
+                                    warn!("Request {:?} was not accepted by the server", request_id)
+                                },
+                                ExchangeMessage::Scene(Event::PerformAction(action), None) => perform_action(action).await,
+                                ExchangeMessage::Scene(Event::PerformAction(action), Some(schedule)) => {
+                                    match (
+                                        clock_estimator.to_server_time(received_us),
+                                        clock_estimator.to_local_time(schedule.execute_at_us, received_us),
+                                    ) {
+                                        (Some(server_received_us), _) if schedule.is_expired(server_received_us) => {
+                                            let late_ms = server_received_us.saturating_sub(schedule.execute_at_us) / 1000;
+                                            warn!("Dropping {:?} as it arrived {} ms after its scheduled time", action.as_str(&mut write_buffer), late_ms);
+                                            let mut error_descr = String::new();
+                                            // The description is always short enough to fit.
+                                            let _ = write!(error_descr, "Dropped expired {}, {} ms late.", action, late_ms);
+                                            let report = ExchangeMessage::Error(ErrorDescription { error_descr });
+                                            if let Err(error) = send_message_to_lamarrs_server(&mut ws_writer, &report).await {
+                                                error!("Failed to report the expired action to lamarrs server: {:?}", error);
+                                            }
+                                        }
+                                        (Some(_), Some(execute_at_us)) if !scheduled_actions.is_full() => {
+                                            let execute_at = Instant::from_micros(execute_at_us);
+                                            let index = scheduled_actions
+                                                .iter()
+                                                .position(|(scheduled_at, _)| *scheduled_at > execute_at)
+                                                .unwrap_or(scheduled_actions.len());
+                                            // There is room, as it was checked before.
+                                            let _ = scheduled_actions.insert(index, (execute_at, action));
+                                        }
+                                        (Some(_), Some(_)) => {
+                                            warn!("Too many scheduled actions, performing the new one straight away");
+                                            perform_action(action).await
+                                        }
+                                        _ => {
+                                            warn!("Clock not synchronised with the server yet, performing the action straight away");
+                                            perform_action(action).await
+                                        }
+                                    }
+                                }
+                                ExchangeMessage::Scene(event, _) => warn!("Ignoring a Scene with a {} Event, which can't be performed", defmt::Display2Format(&event)),
+                                ExchangeMessage::Error(error_description) => error!("An error was reported by the server: {:?}", error_description.error_descr),
+                                ExchangeMessage::ServerInfo(server_info) => {
+                                    clock_estimator.add_server_time(received_us, server_info.server_time_us);
+                                    if server_info.is_compatible_with(PROTOCOL_VERSION) {
+                                        info!("Registered in lamarrs server {:?}", server_info.server_version.as_str())
+                                    } else {
+                                        error!("Lamarrs server speaks protocol version {}, but this device speaks {}", server_info.protocol_version, PROTOCOL_VERSION)
+                                    }
+                                },
+                                ExchangeMessage::Heartbeat => {
+                                    info!("Watchdog send a heartbeat request");
+                                    let heartbeat_response = ExchangeMessage::HeartbeatAck;
+                                    if let Err(error) = send_message_to_lamarrs_server(&mut ws_writer, &heartbeat_response).await {
+                                        error!("Failed to answer the heartbeat: {:?}", error);
+                                    }
+                                },
+                                ExchangeMessage::TimeSyncRequest(time_sync_request) => {
+                                    let time_sync_response = ExchangeMessage::TimeSyncResponse(TimeSyncResponse {
+                                        server_sent_us: time_sync_request.server_sent_us,
+                                        client_received_us: received_us,
+                                        client_sent_us: Instant::now().as_micros(),
+                                    });
+                                    if let Err(error) = send_message_to_lamarrs_server(&mut ws_writer, &time_sync_response).await {
+                                        error!("Failed to answer the time sync request: {:?}", error);
+                                    }
+                                },
+                                ExchangeMessage::TimeSyncResult(time_sync_result) => {
+                                    if clock_estimator.add_sample(received_us, &time_sync_result) {
+                                        info!(
+                                            "Clock synchronised: offset {} us, round trip {} us, drift {} ppb",
+                                            time_sync_result.offset_us,
+                                            time_sync_result.round_trip_us,
+                                            clock_estimator.drift_ppb()
+                                        )
+                                    } else {
+                                        warn!("Clock synchronisation discarded, round trip of {} us was too slow", time_sync_result.round_trip_us)
+                                    }
+                                },
+                                _ => error!("Received an invalid Exchange Message.")
+                            }
+                            info!("Received message from Server");
+                        }
+                        Err(e) => {
+                            defmt::warn!(
+                                "Error receiving frame from lamarrs server: {:?}",
+                                e
+                            );
+                            // Fatal: break to reconnect or close clearly. This avoids getting trapped in an infinite logging loop.
+                            if let WsError::InvalidResponse = e {
+                                oled_sender
+                                    .send(OledEvents::ConnectedToLamarrs(false, None))
+                                    .await;
+                                break;
+                            }
                         }
                     }
                 }
@@ -169,19 +374,47 @@ pub async fn server_handler(stack: embassy_net::Stack<'static>, target: IpEndpoi
     }
 }
 
+/// Performs the action. By now, the device only shows it in the screen, where colour effects
+/// are rendered frame by frame.
+async fn perform_action(action: Action) {
+    let mut write_buffer = String::<128>::new();
+    info!("New action requested: {:?}", action.as_str(&mut write_buffer));
+    OLED_CHANNEL
+        .send(OledEvents::WsMessage(ExchangeMessage::Scene(
+            Event::PerformAction(action),
+            None,
+        )))
+        .await;
+}
+
+/// Reasons a message could not be sent to the server.
+#[derive(Debug, defmt::Format)]
+pub enum SendError {
+    /// The message doesn't fit in the sending buffer.
+    Serialisation,
+    WebSocket(WsError),
+}
+
 pub async fn send_message_to_lamarrs_server<'a>(
-    websocket: &mut WebSocket<'a>,
+    websocket: &mut WebSocketWriter<'a>,
     lamarrs_message: &ExchangeMessage,
-) {
-    let mut ws_reading_buffer = [0u8; 256];
-
-    // Preallocate an array to not send a payload too big.
-    let mut buffer = [0u8; 125];
+) -> Result<(), SendError> {
+    let mut buffer = [0u8; WS_SENDING_BUFFER_SIZE];
 
     // Serialize into array
-    let payload_bytes = postcard::to_slice(&lamarrs_message, &mut buffer).unwrap();
+    let payload_bytes = postcard::to_slice(&lamarrs_message, &mut buffer).map_err(|error| {
+        error!(
+            "{} could not be serialised: {}",
+            defmt::Display2Format(lamarrs_message),
+            defmt::Debug2Format(&error)
+        );
+        SendError::Serialisation
+    })?;
 
     defmt::info!("Frame to be sent: {}", payload_bytes);
 
-    &websocket.send_bytes(payload_bytes).await;
+    websocket
+        .send_bytes(payload_bytes)
+        .await
+        .map_err(SendError::WebSocket)
 }
@@ -5,7 +5,7 @@ use defmt::debug;
 use defmt::info;
 use embassy_net::tcp::ConnectError;
 use embassy_net::tcp::Error as TcpError;
-use embassy_net::tcp::TcpSocket;
+use embassy_net::tcp::{TcpReader, TcpSocket, TcpWriter};
 use embassy_net::IpEndpoint;
 use embassy_rp::clocks::RoscRng;
 use heapless::{String, Vec};
@@ -14,6 +14,9 @@ use sha1::Digest;
 use sha1::Sha1;
 use {defmt_rtt as _, panic_probe as _};
 
+/// Biggest payload sent in a single frame.
+pub const MAX_SENT_PAYLOAD_SIZE: usize = 512;
+
 #[derive(Debug, defmt::Format)]
 pub enum WsError {
     Connect(ConnectError),
@@ -34,6 +37,16 @@ pub struct WebSocket<'a> {
     socket: TcpSocket<'a>,
 }
 
+/// Receiving half of the WebSocket, see [`WebSocket::split`].
+pub struct WebSocketReader<'a> {
+    socket: TcpReader<'a>,
+}
+
+/// Sending half of the WebSocket, see [`WebSocket::split`].
+pub struct WebSocketWriter<'a> {
+    socket: TcpWriter<'a>,
+}
+
 impl<'a> WebSocket<'a> {
     /// Connects to a targetted Websocket server.
     pub async fn connect(
@@ -110,14 +123,25 @@ impl<'a> WebSocket<'a> {
         Ok(WebSocket { socket })
     }
 
+    /// Splits the WebSocket, so a message can be sent while waiting for the next one to be
+    /// received, without dropping the one being received.
+    pub fn split(&mut self) -> (WebSocketReader<'_>, WebSocketWriter<'_>) {
+        let (reader, writer) = self.socket.split();
+        (
+            WebSocketReader { socket: reader },
+            WebSocketWriter { socket: writer },
+        )
+    }
+}
+
+impl WebSocketWriter<'_> {
     /// Send to the server a Binary message.
     pub async fn send_bytes(&mut self, payload: &[u8]) -> Result<(), WsError> {
         debug!("Payload to be send is: {}, len: {}", payload, payload.len());
         // According to a faily small research I've made,
         // embedded systems or no_std implementations often handle frames in chunks (e.g.<= 4096 bytes)
         // to avoid large buffers.
-        // The 125-byte limit only applies to the short (7-bit) payload length field.
-        if payload.len() > 125 {
+        if payload.len() > MAX_SENT_PAYLOAD_SIZE {
             return Err(WsError::FrameTooLarge);
         }
 
@@ -129,11 +153,20 @@ impl<'a> WebSocket<'a> {
         // Opcode = 0x2 > Binary
         // The OR operator combines the bits.
         let b0 = 0x80u8 | 0x02u8;
-        let mut header = [0u8; 6];
+        let mut header = [0u8; 8];
         header[0] = b0;
         // Similar to the previous entry, combines MASK + Payload length
         // Client > Server frames must always set MASK = 1
-        header[1] = 0x80u8 | (payload.len() as u8);
+        // The short (7-bit) payload length field only fits up to 125 bytes. Longer payloads set it
+        // to 126, and give their length in the next 2 bytes.
+        let mut header_len = 2;
+        if payload.len() <= 125 {
+            header[1] = 0x80u8 | (payload.len() as u8);
+        } else {
+            header[1] = 0x80u8 | 126;
+            header[2..4].copy_from_slice(&(payload.len() as u16).to_be_bytes());
+            header_len = 4;
+        }
 
         // Generates mask.
         // Random nonce. Present if the masked field is 1. The client generates a masking key for every masked frame.
@@ -142,33 +175,49 @@ impl<'a> WebSocket<'a> {
         let mut mask = [0u8; 4];
         rng.fill_bytes(&mut mask);
         defmt::debug!("The mask key is: {:?}", mask);
-        header[2..6].copy_from_slice(&mask);
+        header[header_len..header_len + 4].copy_from_slice(&mask);
+        header_len += 4;
 
         // Apply mask
         // https://en.wikipedia.org/wiki/WebSocket#Client-to-server_masking
-        let masked: Vec<u8, 512> = payload
+        let masked: Vec<u8, MAX_SENT_PAYLOAD_SIZE> = payload
             .iter()
             .enumerate()
             .map(|(i, &b)| b ^ mask[i % 4])
             .collect();
 
-        self.socket.write(&header).await.map_err(WsError::Tcp)?;
+        self.socket.write(&header[..header_len]).await.map_err(WsError::Tcp)?;
         self.socket.write(&masked).await.map_err(WsError::Tcp)?;
         self.socket.flush().await.map_err(WsError::Tcp)?;
         Ok(())
     }
+}
+
+impl WebSocketReader<'_> {
+    /// Reads until the buffer is full, as TCP may deliver a frame in several reads.
+    async fn read_exact(&mut self, buffer: &mut [u8]) -> Result<(), WsError> {
+        let mut read = 0;
+        while read < buffer.len() {
+            match self.socket.read(&mut buffer[read..]).await {
+                // The server closed the connection.
+                Ok(0) | Err(_) => return Err(WsError::InvalidResponse),
+                Ok(bytes) => read += bytes,
+            }
+        }
+        Ok(())
+    }
 
     /// Places the payload into the give reading_buffer, while it
     /// returns the usize for the slice of bytes to be read from the reading_buffer
     /// in order to get the text message received.
+    ///
+    /// A frame can't be resumed once its reading started, so the future must not be dropped
+    /// before it completes, or the next frames won't be readable.
     pub async fn recv_message(&mut self, reading_buffer: &mut [u8]) -> Result<usize, WsError> {
         // As defined by the spec, a single frame with short 7-bit payload
         // must have a header with only 2 bytes.
         let mut header = [0u8; 2];
-        self.socket
-            .read(&mut header)
-            .await
-            .map_err(|_| WsError::InvalidResponse)?;
+        self.read_exact(&mut header).await?;
 
         // If FIN is not 0, it means this is a multiframe payload. We don´t support those. Err mng missing here though...
         let fin = header[0] & 0x80 != 0;
@@ -186,10 +235,7 @@ impl<'a> WebSocket<'a> {
 
         if payload_len == 126 {
             let mut payload_data = [0u8; 2];
-            self.socket
-                .read(&mut payload_data)
-                .await
-                .map_err(|_| WsError::InvalidResponse)?;
+            self.read_exact(&mut payload_data).await?;
             payload_len = u16::from_be_bytes(payload_data) as usize;
         } else if payload_len == 127 {
             // ignore big frames for simplicity
@@ -199,10 +245,7 @@ impl<'a> WebSocket<'a> {
         // Very rough masking management.
         let mut mask = [0u8; 4];
         if masked {
-            self.socket
-                .read(&mut mask)
-                .await
-                .map_err(|_| WsError::InvalidResponse)?;
+            self.read_exact(&mut mask).await?;
         }
 
         // This validation is here in case I have inconsistencies between
@@ -213,18 +256,14 @@ impl<'a> WebSocket<'a> {
         }
 
         // Read exactly `payload_len` bytes of payload data into the start of the buffer.
-        let payload_bytes = self
-            .socket
-            .read(&mut reading_buffer[..payload_len])
-            .await
-            .map_err(|_| WsError::InvalidResponse)?;
+        self.read_exact(&mut reading_buffer[..payload_len]).await?;
 
         if masked {
-            for i in 0..payload_bytes {
+            for i in 0..payload_len {
                 reading_buffer[i] ^= mask[i % 4];
             }
         }
 
-        Ok(payload_bytes)
+        Ok(payload_len)
     }
 }
@@ -1,10 +1,20 @@
 use crate::client_handler::Client;
+use crate::events::EventPublisher;
+use crate::http::{is_websocket_upgrade, read_request, HttpResponse, REQUEST_TIMEOUT};
+use crate::join::JoinLink;
+use crate::media::MediaLibrary;
+use crate::seat_map::SeatMap;
 use crate::services::InternalEventMessageServer;
+use crate::static_files::StaticFiles;
+use crate::status::StatusEvent;
 use color_eyre::eyre::eyre;
+use http::StatusCode;
+use std::sync::Arc;
 use lamarrs_utils::exchange_messages::ExchangeMessage;
-use tokio::net::TcpListener;
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::mpsc::Sender;
-use tracing::info;
+use tracing::{debug, info, warn};
 
 /// The ClientBuilder holds copies of Senders to all the Actors in order to provide the different Clients
 /// with Senders before spinning them up.
@@ -14,16 +24,31 @@ pub struct ClientBuilder {
     playback: Sender<InternalEventMessageServer>,
     midi: Sender<InternalEventMessageServer>,
     sequencer: Sender<ExchangeMessage>,
+    status: Sender<StatusEvent>,
+    seat_map: Arc<SeatMap>,
+    media_library: Arc<MediaLibrary>,
+    /// Served to the plain HTTP requests, if any.
+    static_files: Option<Arc<StaticFiles>>,
+    /// QR codes of the join URL, served to the plain HTTP requests.
+    join_link: Arc<JoinLink>,
+    events: EventPublisher,
 }
 
 impl ClientBuilder {
     /// ClientBuilder Actor constructor.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         subtitle: Sender<InternalEventMessageServer>,
         color: Sender<InternalEventMessageServer>,
         playback: Sender<InternalEventMessageServer>,
         midi: Sender<InternalEventMessageServer>,
         sequencer: Sender<ExchangeMessage>,
+        status: Sender<StatusEvent>,
+        seat_map: Arc<SeatMap>,
+        media_library: Arc<MediaLibrary>,
+        static_files: Option<Arc<StaticFiles>>,
+        join_link: Arc<JoinLink>,
+        events: EventPublisher,
     ) -> Self {
         Self {
             subtitle,
@@ -31,11 +56,19 @@ impl ClientBuilder {
             playback,
             midi,
             sequencer,
+            status,
+            seat_map,
+            media_library,
+            static_files,
+            join_link,
+            events,
         }
     }
 
     /// Listen to incoming TCP connections.
     /// If the connection is successful, it creates a Client Actor and runs it as a new Async Task.
+    /// The connections not asking for a WebSocket upgrade are answered as plain HTTP ones,
+    /// with the QR codes of the join URL or the static files.
     ///
     /// Important!!
     /// The WebSocket is not being created here. That is being handled by the Client.
@@ -44,20 +77,9 @@ impl ClientBuilder {
             match listener.accept().await {
                 Ok((stream, socket_addr)) => {
                     // For each new TCP connection, we spawn a new task and return to listen for incoming connections.
-                    tokio::spawn({
-                        info!("Creating new Client: {}", socket_addr);
-                        let mut new_client = Client::new(
-                            self.subtitle.clone(),
-                            self.color.clone(),
-                            self.playback.clone(),
-                            self.midi.clone(),
-                            self.sequencer.clone(),
-                        );
-                        async move {
-                            info!("Starting new Client handler: {}", socket_addr);
-                            // Here is where the WS upgrade request will be handled.
-                            new_client.run(stream).await;
-                        }
+                    let client_builder = self.clone_senders();
+                    tokio::spawn(async move {
+                        client_builder.handle_connection(stream, socket_addr).await
                     });
                 }
                 Err(error) => {
@@ -67,4 +89,83 @@ impl ClientBuilder {
             }
         }
     }
+
+    /// Copy of the builder to move into the task of a connection.
+    fn clone_senders(&self) -> Self {
+        Self {
+            subtitle: self.subtitle.clone(),
+            color: self.color.clone(),
+            playback: self.playback.clone(),
+            midi: self.midi.clone(),
+            sequencer: self.sequencer.clone(),
+            status: self.status.clone(),
+            seat_map: self.seat_map.clone(),
+            media_library: self.media_library.clone(),
+            static_files: self.static_files.clone(),
+            join_link: self.join_link.clone(),
+            events: self.events.clone(),
+        }
+    }
+
+    async fn handle_connection(self, stream: TcpStream, socket_addr: SocketAddr) {
+        match tokio::time::timeout(REQUEST_TIMEOUT, is_websocket_upgrade(&stream)).await {
+            Ok(Ok(true)) => self.run_client(stream, socket_addr).await,
+            Ok(Ok(false)) => self.serve_http(stream, socket_addr).await,
+            Ok(Err(error)) => debug!(%socket_addr, "Dropping HTTP connection: {}", error),
+            Err(_) => debug!(%socket_addr, "HTTP request timed out."),
+        }
+    }
+
+    async fn run_client(self, stream: TcpStream, socket_addr: SocketAddr) {
+        info!("Creating new Client: {}", socket_addr);
+        let mut new_client = Client::new(
+            self.subtitle,
+            self.color,
+            self.playback,
+            self.midi,
+            self.sequencer,
+            self.status,
+            self.seat_map,
+            self.media_library,
+            self.events,
+        );
+        info!("Starting new Client handler: {}", socket_addr);
+        // Here is where the WS upgrade request will be handled.
+        match new_client.run(stream).await {
+            Ok(()) => info!("Client handler finished: {}", socket_addr),
+            Err(error) => warn!(%socket_addr, ?error, "Client handler failed."),
+        }
+    }
+
+    async fn serve_http(self, mut stream: TcpStream, socket_addr: SocketAddr) {
+        // The head was peeked within the timeout, but not the body.
+        let request = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+            Ok(Ok(request)) => request,
+            Ok(Err(error)) => {
+                debug!(%socket_addr, "Dropping HTTP request: {}", error);
+                if let Some(status) = error.status() {
+                    let _ = HttpResponse::text(status, &error.to_string())
+                        .write_to(&mut stream, true)
+                        .await;
+                }
+                return;
+            }
+            Err(_) => {
+                debug!(%socket_addr, "HTTP request timed out.");
+                return;
+            }
+        };
+        let response = match (self.join_link.serve(&request), &self.static_files) {
+            (Some(response), _) => response,
+            (None, Some(static_files)) => static_files.serve(&request).await,
+            (None, None) => HttpResponse::text(StatusCode::NOT_FOUND, "Not found."),
+        };
+        debug!(%socket_addr, path = request.path, status = %response.status, "HTTP request.");
+        if let Err(error) = response
+            .write_to(&mut stream, request.method != "HEAD")
+            .await
+        {
+            debug!(%socket_addr, "Failed to answer the HTTP request: {}", error);
+        }
+    }
 }
@@ -5,12 +5,19 @@
 use async_time_mock_tokio::MockableClock;
 use futures_util::stream::{SplitSink, SplitStream};
 use futures_util::{SinkExt, StreamExt};
-use lamarrs_utils::action_messages::Event;
-use lamarrs_utils::exchange_messages::{AckResult, ExchangeMessage, NackResult};
+use lamarrs_utils::action_messages::{Event, Registration};
+use lamarrs_utils::clock::TimeSyncRequest;
+use lamarrs_utils::media::{MediaChunk, MediaFileName, MEDIA_CHUNK_SIZE};
+use lamarrs_utils::exchange_messages::{
+    AckResult, ExchangeMessage, NackResult, RegistrationHeader, RequestId, ServerInfo,
+    PROTOCOL_VERSION,
+};
 use postcard::to_allocvec;
 use tokio::{
+    fs::File,
+    io::AsyncReadExt,
     net::TcpStream,
-    time::{timeout, Duration},
+    time::{interval, timeout, Duration, MissedTickBehavior},
 };
 use tokio_tungstenite::tungstenite::{self, Message as TungsteniteMessage};
 
@@ -18,10 +25,31 @@ use tokio_tungstenite::{accept_async, WebSocketStream as TungsteniteWebSocketStr
 use tracing::{debug, error, info, instrument, warn};
 
 use thiserror::Error;
-use tokio::sync::mpsc::{self, channel, Receiver, Sender};
+use tokio::sync::{
+    mpsc::{self, channel, Receiver, Sender},
+    oneshot,
+};
 
-use crate::services::{self, InternalEventMessageServer};
+use crate::clock::server_time_us;
+use crate::events::{EventPublisher, ServerEvent};
+use crate::media::MediaLibrary;
+use crate::metrics::METRICS;
+use crate::seat_map::{SeatLocation, SeatMap};
+use crate::services::{self, ClientProfile, InternalEventMessageServer};
+use crate::status::StatusEvent;
+use crate::VERSION;
 use lamarrs_utils::{ClientIdAndLocation, ErrorDescription, Service};
+use std::collections::HashSet;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Instant;
+use strum::IntoEnumIterator;
+
+/// How often the clock of the registered Clients is synchronised.
+const TIME_SYNC_PERIOD: Duration = Duration::from_secs(10);
+
+/// Media chunks read ahead of the ones sent to the Client.
+const MEDIA_CHUNK_QUEUE_SIZE: usize = 4;
 
 #[derive(Debug, Error)]
 pub enum ClientHandlerError {
@@ -47,6 +75,14 @@ pub enum ClientHandlerError {
     SendExchangeMessage(
         #[from] mpsc::error::SendError<lamarrs_utils::exchange_messages::ExchangeMessage>,
     ),
+    #[error("Error sending a StatusEvent")]
+    SendStatusEvent(#[from] mpsc::error::SendError<StatusEvent>),
+    #[error("Failed reading a media file to be sent: {0}")]
+    MediaTransfer(String),
+    #[error("Failed to read the address of the remote Client")]
+    PeerAddress(#[from] std::io::Error),
+    #[error("Client speaks protocol version {0}, incompatible with this Server")]
+    IncompatibleProtocolVersion(u16),
 }
 
 enum ClientWire {
@@ -56,39 +92,72 @@ enum ClientWire {
 
 pub struct Client {
     id: Option<ClientIdAndLocation>,
+    max_frame_size: Option<u32>,
+    /// Where the Client seat is, if it registered with one.
+    seat: Option<SeatLocation>,
+    /// Section the Client registered with, only used if it has no seat.
+    section: Option<String>,
+    /// Groups the Client joined through this connection.
+    groups: HashSet<String>,
+    /// Language the Client prefers its subtitles in.
+    language: Option<String>,
 
     subtitles_service: Sender<InternalEventMessageServer>,
     colour_service: Sender<InternalEventMessageServer>,
     playback_service: Sender<InternalEventMessageServer>,
     midi_service: Sender<InternalEventMessageServer>,
     sequencer: Sender<ExchangeMessage>,
+    status: Sender<StatusEvent>,
+    seat_map: Arc<SeatMap>,
+    media_library: Arc<MediaLibrary>,
+    events: EventPublisher,
 
     sender: Sender<ExchangeMessage>,
     inbox: Receiver<ExchangeMessage>,
+    /// Chunks of the media file being sent, kept apart so transfers never delay the show.
+    media_sender: Sender<MediaChunk>,
+    media_inbox: Receiver<MediaChunk>,
     clock: MockableClock,
     watchdog_sent: bool,
     wire: ClientWire,
 }
 
 impl Client {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         subtitles_service: Sender<InternalEventMessageServer>,
         colour_service: Sender<InternalEventMessageServer>,
         playback_service: Sender<InternalEventMessageServer>,
         midi_service: Sender<InternalEventMessageServer>,
         sequencer: Sender<ExchangeMessage>,
+        status: Sender<StatusEvent>,
+        seat_map: Arc<SeatMap>,
+        media_library: Arc<MediaLibrary>,
+        events: EventPublisher,
     ) -> Self {
         let (sender, inbox) = channel(32);
+        let (media_sender, media_inbox) = channel(MEDIA_CHUNK_QUEUE_SIZE);
         let subscriber_id = None;
         Self {
             id: subscriber_id,
+            max_frame_size: None,
+            seat: None,
+            section: None,
+            groups: HashSet::new(),
+            language: None,
             subtitles_service,
             colour_service,
             playback_service,
             midi_service,
             sequencer,
+            status,
+            seat_map,
+            media_library,
+            events,
             sender,
             inbox,
+            media_sender,
+            media_inbox,
             clock: MockableClock::Real,
             watchdog_sent: false,
             wire: ClientWire::Binary,
@@ -107,9 +176,15 @@ impl Client {
     /// other Actors.
     #[instrument(name = "Client::run", skip(self), fields(id=?self.id), level = "INFO", ret, err)]
     pub async fn run(&mut self, stream: TcpStream) -> Result<(), ClientHandlerError> {
+        let address = stream.peer_addr()?;
         // Creates the Sink and Stream.
         let (mut remote_sender, mut remote_inbox) = self.accept_and_connect(stream).await?;
+        self.events.publish(ServerEvent::ClientConnected { address });
         let connection_watchdog_timer = Duration::from_hours(5);
+        let mut time_sync_interval = interval(TIME_SYNC_PERIOD);
+        time_sync_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // Counts the connection while the Client is registered, whatever way `run` ends.
+        let mut _connected_client = None;
 
         loop {
             tokio::select! {
@@ -119,17 +194,24 @@ impl Client {
                         Ok(msg) => {
                             info!(?msg, "New message from remote Client via websocket");
                             // TODO: if 3 UnregisteredSubscriber messages are received, terminate the connection.
+                            let was_registered = self.id.is_some();
                             self.handle_ws_message(msg, &mut remote_sender).await?;
+                            if !was_registered && self.id.is_some() {
+                                // New Clients get their clock synchronised straight away.
+                                time_sync_interval.reset_immediately();
+                                _connected_client = Some(METRICS.client_connected(matches!(self.wire, ClientWire::Binary)));
+                            }
                         }
                         Err(_) => {
                             warn!("Watchdog state: {:?}", self.watchdog_sent);
                             if self.watchdog_sent {
                                 error!("{:?} is irresponsive, proceeding to close the connection", self.id);
+                                METRICS.heartbeat_timeouts.fetch_add(1, Ordering::Relaxed);
                                 if let Some(client_id) = &self.id {
-                                    self.subtitles_service.send(InternalEventMessageServer::RemoveTargetClient(client_id.clone())).await?;
-                                    self.colour_service.send(InternalEventMessageServer::RemoveTargetClient(client_id.clone())).await?;
-                                    self.playback_service.send(InternalEventMessageServer::RemoveTargetClient(client_id.clone())).await?;
-                                    self.midi_service.send(InternalEventMessageServer::RemoveTargetClient(client_id.clone())).await?;
+                                    self.subtitles_service.send(InternalEventMessageServer::RemoveTargetClient(client_id.clone(), self.sender.clone(), None)).await?;
+                                    self.colour_service.send(InternalEventMessageServer::RemoveTargetClient(client_id.clone(), self.sender.clone(), None)).await?;
+                                    self.playback_service.send(InternalEventMessageServer::RemoveTargetClient(client_id.clone(), self.sender.clone(), None)).await?;
+                                    self.midi_service.send(InternalEventMessageServer::RemoveTargetClient(client_id.clone(), self.sender.clone(), None)).await?;
                                 }
                                 break Err(ClientHandlerError::ConnectionLost { client_id: format!("{:?}", self.id) })
                             }
@@ -150,24 +232,35 @@ impl Client {
                     }
                 }
 
+                // Synchronise the clock of the remote Client, once it is registered.
+                _ = time_sync_interval.tick(), if self.id.is_some() => {
+                    self.sender.send(ExchangeMessage::TimeSyncRequest(TimeSyncRequest {
+                        server_sent_us: server_time_us(),
+                    })).await?;
+                }
+
                 // Receive messages from any other actors
                 msg = self.inbox.recv() => {
                     info!(?msg, "Sending message to remote Client via websocket");
                     if let Some(message) = msg {
-                        match self.wire{
-                            ClientWire::Binary => {
-                                match to_allocvec(&message) {
-                                    Ok(binary_message) =>  remote_sender.send(TungsteniteMessage::Binary(binary_message.into())).await?,
-                                    Err(_) => error!("Message {:?} to be relayed to Client could not be converted to Binary. Message was not sent.", message)
-                                }
-                            }
-                            ClientWire::Text => match serde_json::to_string(&message) {
-                                    Ok(string_message) => remote_sender.send(TungsteniteMessage::Text(string_message.into())).await?,
-                                    Err(_) => error!("Message {:?} to be relayed to Client could not be converted to String. Message was not sent.", message)
-                            }
+                        if let Some(frame) = self.frame_for(&message) {
+                            let sending_at = Instant::now();
+                            remote_sender.send(frame).await.inspect_err(|_| {
+                                METRICS.websocket_send_failures.fetch_add(1, Ordering::Relaxed);
+                            })?;
+                            METRICS.websocket_send_latency.observe(sending_at.elapsed());
                         }
                     }
                 }
+
+                // Relay the media chunks only while no other message is waiting.
+                Some(chunk) = self.media_inbox.recv(), if self.inbox.is_empty() => {
+                    if let Some(frame) = self.frame_for(&ExchangeMessage::MediaChunk(Box::new(chunk))) {
+                        remote_sender.send(frame).await.inspect_err(|_| {
+                            METRICS.websocket_send_failures.fetch_add(1, Ordering::Relaxed);
+                        })?;
+                    }
+                }
             }
         }
     }
@@ -211,6 +304,18 @@ impl Client {
                 let payload = match payload_as_str_result {
                     Ok(payload) => payload,
                     Err(error) => {
+                        if let Some((request_id, protocol_version)) = self
+                            .id
+                            .is_none()
+                            .then(|| text_registration_header(&string_payload))
+                            .flatten()
+                            .filter(|(_, protocol_version)| *protocol_version != PROTOCOL_VERSION)
+                        {
+                            self.wire = ClientWire::Text;
+                            return self
+                                .reject_incompatible_client(request_id, protocol_version, outgoing)
+                                .await;
+                        }
                         error!(
                             ?error,
                             "Malformed message received from unregistered device."
@@ -243,10 +348,26 @@ impl Client {
             // The embedded devices send the data encoded in Bytes using postcard.
             Some(Ok(TungsteniteMessage::Binary(bytes_payload))) => {
                 debug!(?bytes_payload, "Inbound Binary Payload");
-                let payload =
-                    postcard::from_bytes::<ExchangeMessage>(&bytes_payload).map_err(|e| {
-                        ClientHandlerError::FailureDecodingBinaryExchangeMessage(e.to_string())
-                    })?;
+                let payload = match postcard::from_bytes::<ExchangeMessage>(&bytes_payload) {
+                    Ok(payload) => payload,
+                    Err(error) => {
+                        if let Some((request_id, protocol_version)) = self
+                            .id
+                            .is_none()
+                            .then(|| binary_registration_header(&bytes_payload))
+                            .flatten()
+                            .filter(|(_, protocol_version)| *protocol_version != PROTOCOL_VERSION)
+                        {
+                            self.wire = ClientWire::Binary;
+                            return self
+                                .reject_incompatible_client(request_id, protocol_version, outgoing)
+                                .await;
+                        }
+                        return Err(ClientHandlerError::FailureDecodingBinaryExchangeMessage(
+                            error.to_string(),
+                        ));
+                    }
+                };
                 if self.id.is_none() {
                     // From now on, we are only going to talk Binary with this client.
                     self.wire = ClientWire::Binary;
@@ -282,49 +403,199 @@ impl Client {
         Ok(())
     }
 
+    /// Encodes the message in the format spoken by the remote Client. Messages that can't be
+    /// encoded, or that don't fit in the frames the Client can receive, are not sent.
+    fn frame_for(&self, message: &ExchangeMessage) -> Option<TungsteniteMessage> {
+        match self.wire {
+            ClientWire::Binary => match to_allocvec(message) {
+                Ok(binary_message) if self.fits_in_frame(binary_message.len()) => Some(TungsteniteMessage::Binary(binary_message.into())),
+                Ok(binary_message) => { error!("Message {:?} to be relayed to Client is {} bytes long, bigger than the Client max frame size {:?}. Message was not sent.", message, binary_message.len(), self.max_frame_size); None }
+                Err(_) => { error!("Message {:?} to be relayed to Client could not be converted to Binary. Message was not sent.", message); None }
+            },
+            ClientWire::Text => match serde_json::to_string(message) {
+                Ok(string_message) if self.fits_in_frame(string_message.len()) => Some(TungsteniteMessage::Text(string_message.into())),
+                Ok(string_message) => { error!("Message {:?} to be relayed to Client is {} bytes long, bigger than the Client max frame size {:?}. Message was not sent.", message, string_message.len(), self.max_frame_size); None }
+                Err(_) => { error!("Message {:?} to be relayed to Client could not be converted to String. Message was not sent.", message); None }
+            },
+        }
+    }
+
+    /// Answers the registration of a Client speaking another version of the protocol, which
+    /// couldn't be decoded past its `RegistrationHeader`, and closes the connection, as none of
+    /// the messages of the Client could be decoded either.
+    /// The answer is written straight away, as the connection is closed right after.
+    async fn reject_incompatible_client(
+        &mut self,
+        request_id: RequestId,
+        protocol_version: u16,
+        outgoing: &mut SplitSink<TungsteniteWebSocketStream<TcpStream>, TungsteniteMessage>,
+    ) -> Result<(), ClientHandlerError> {
+        warn!(
+            "Rejecting Client: it speaks protocol version {}, but this Server speaks {}.",
+            protocol_version, PROTOCOL_VERSION
+        );
+        let answers = [
+            ExchangeMessage::ServerInfo(Self::server_info()),
+            ExchangeMessage::Nack(Some(request_id), NackResult::IncompatibleProtocolVersion),
+        ];
+        for answer in answers {
+            if let Some(frame) = self.frame_for(&answer) {
+                outgoing.send(frame).await?;
+            }
+        }
+        outgoing.close().await?;
+        Err(ClientHandlerError::IncompatibleProtocolVersion(protocol_version))
+    }
+
+    /// Returns `true` if a frame of `frame_len` bytes can be received by the remote Client.
+    /// Clients that didn't register yet didn't declare any limit.
+    fn fits_in_frame(&self, frame_len: usize) -> bool {
+        self.max_frame_size
+            .is_none_or(|max_frame_size| frame_len <= max_frame_size as usize)
+    }
+
+    /// Describes this Server to the remote Clients.
+    fn server_info() -> ServerInfo {
+        let mut server_version = heapless::String::new();
+        // The version is only informative, so if it doesn't fit it is left empty.
+        if server_version.push_str(VERSION).is_err() {
+            warn!("Server version {} is too long to be reported to Clients.", VERSION);
+        }
+        ServerInfo {
+            protocol_version: PROTOCOL_VERSION,
+            server_version,
+            services: Service::iter().collect(),
+            server_time_us: server_time_us(),
+        }
+    }
+
+    /// Builds the profile the Services will know the Client by. If the Client registered with
+    /// a seat, the seat location and section prevail over the ones declared by the Client.
+    fn profile(&self, mut client_id_and_location: ClientIdAndLocation) -> ClientProfile {
+        match &self.seat {
+            Some(seat) => {
+                client_id_and_location.location = Some(seat.position);
+                ClientProfile {
+                    id: client_id_and_location,
+                    section: seat.section.clone(),
+                    groups: self.groups.clone(),
+                    language: self.language.clone(),
+                }
+            }
+            None => ClientProfile {
+                id: client_id_and_location,
+                section: self.section.clone(),
+                groups: self.groups.clone(),
+                language: self.language.clone(),
+            },
+        }
+    }
+
     /// If a remote Client didn't register itself, its messages will be processed by
-    /// this function. It currently expects the remote Client to present is UUID and
-    /// optionally, its location. In the future, the Client lamarrs version will also
-    /// be checked here to confirm API compatibility with the remote or reject the Client
-    /// if its not compatible or if other validations fail.
+    /// this function. It expects the remote Client to present its UUID, optionally its
+    /// location or seat, and the version of the protocol it speaks along with its capabilities.
+    /// The Server always answers with its own `ServerInfo`, and rejects the Client if
+    /// both protocol versions are not compatible or if its seat is unknown.
     #[instrument(name = "Client::on_unregistered_subscriber_message", skip(self), fields(id=?self.id), level = "INFO", ret, err)]
     async fn on_unregistered_subscriber_message(
         &mut self,
         exchange_message: ExchangeMessage,
     ) -> Result<(), ClientHandlerError> {
         match exchange_message {
-            ExchangeMessage::Request(Event::Register(client_id_and_location)) => {
-                info!("Registering new Client {client_id_and_location:?}");
-                self.id = Some(client_id_and_location.clone());
-                // Recreate sender in all services the if the client is reconnecting and was already subscribed.
-                self.subtitles_service
-                    .send(InternalEventMessageServer::UpdateClientData(
-                        client_id_and_location.clone(),
-                        self.sender.clone(),
-                    ))
-                    .await?;
-                self.colour_service
-                    .send(InternalEventMessageServer::UpdateClientData(
-                        client_id_and_location.clone(),
-                        self.sender.clone(),
-                    ))
-                    .await?;
-                self.playback_service
-                    .send(InternalEventMessageServer::UpdateClientData(
-                        client_id_and_location.clone(),
-                        self.sender.clone(),
-                    ))
+            ExchangeMessage::Request(
+                request_id,
+                Event::Register(Registration {
+                    client: client_id_and_location,
+                    protocol_version,
+                    kind,
+                    services,
+                    max_frame_size,
+                    seat,
+                    language,
+                    section,
+                    group,
+                }),
+            ) => {
+                let server_info = Self::server_info();
+                let is_compatible = server_info.is_compatible_with(protocol_version);
+                self.sender
+                    .send(ExchangeMessage::ServerInfo(server_info))
                     .await?;
-                self.midi_service
-                    .send(InternalEventMessageServer::UpdateClientData(
-                        client_id_and_location.clone(),
-                        self.sender.clone(),
-                    ))
+                if !is_compatible {
+                    // The Client is kept unregistered, so none of its messages will be processed.
+                    warn!(
+                        "Rejecting {} Client {client_id_and_location:?}: it speaks protocol version {}, but this Server speaks {}.",
+                        kind, protocol_version, PROTOCOL_VERSION
+                    );
+                    self.sender
+                        .send(ExchangeMessage::Nack(
+                            Some(request_id),
+                            NackResult::IncompatibleProtocolVersion,
+                        ))
+                        .await?;
+                    return Ok(());
+                }
+                if let Some(seat) = seat {
+                    match self.seat_map.resolve(&seat) {
+                        Some(seat_location) => self.seat = Some(seat_location.clone()),
+                        None => {
+                            // Kept unregistered, so the Client can try again with another seat.
+                            warn!(
+                                "Rejecting {} Client {client_id_and_location:?}: seat {} is not in the seat map.",
+                                kind, seat
+                            );
+                            self.sender
+                                .send(ExchangeMessage::Nack(
+                                    Some(request_id),
+                                    NackResult::UnknownSeat,
+                                ))
+                                .await?;
+                            return Ok(());
+                        }
+                    }
+                }
+                self.language = language.map(|language| language.to_string());
+                self.section = section.map(|section| section.to_string());
+                if let Some(group) = group {
+                    self.groups.insert(group.to_string());
+                }
+                let client_profile = self.profile(client_id_and_location);
+                info!(
+                    ?services,
+                    max_frame_size, "Registering new {} Client {client_profile:?}", kind
+                );
+                self.id = Some(client_profile.id.clone());
+                self.max_frame_size = Some(max_frame_size);
+                self.status
+                    .send(StatusEvent::Registered(client_profile.id.uuid, kind.clone()))
                     .await?;
+                self.events.publish(ServerEvent::ClientRegistered {
+                    uuid: client_profile.id.uuid,
+                    kind,
+                    section: client_profile.section.clone(),
+                    groups: client_profile.groups.iter().cloned().collect(),
+                });
+                // Recreate sender in all services the if the client is reconnecting and was already subscribed.
+                // The registration is answered once, whatever Services the Client was in.
+                self.update_client_data(&client_profile).await?;
                 // Confirm success to client
                 self.sender
-                    .send(ExchangeMessage::Ack(AckResult::Success))
+                    .send(ExchangeMessage::Ack(Some(request_id), AckResult::Success))
                     .await?;
+                // Clients able to play audio must have the media of the show.
+                if services.contains(&Service::AudioPlayer) && !self.media_library.is_empty() {
+                    for manifest_page in self.media_library.manifest_pages() {
+                        self.sender
+                            .send(ExchangeMessage::MediaManifest(Box::new(manifest_page)))
+                            .await?;
+                    }
+                    self.status
+                        .send(StatusEvent::MediaManifestSent(
+                            client_profile.id.uuid,
+                            self.media_library.len() as u16,
+                        ))
+                        .await?;
+                }
                 Ok(())
             }
             ExchangeMessage::HeartbeatAck => {
@@ -339,7 +610,10 @@ impl Client {
                     "Message received from unregistered device."
                 );
                 self.sender
-                    .send(ExchangeMessage::Nack(NackResult::NotSubscribed))
+                    .send(ExchangeMessage::Nack(
+                        request_id_of(&exchange_message),
+                        NackResult::NotSubscribed,
+                    ))
                     .await?;
                 Err(ClientHandlerError::UnregisteredSubscriber(
                     exchange_message.to_string(),
@@ -356,22 +630,36 @@ impl Client {
         exchange_message: ExchangeMessage,
     ) -> Result<(), ClientHandlerError> {
         match exchange_message {
-            ExchangeMessage::Request(action) => match action {
+            ExchangeMessage::Request(request_id, action) => match action {
                 Event::SuscribeToService(service, client_id_and_location) => {
-                    self.subscribe_to_service(service, client_id_and_location)
+                    self.subscribe_to_service(service, client_id_and_location, request_id)
                         .await
                 }
                 Event::UnsubscribeFromService(service, client_id_and_location) => {
-                    self.unsubscribe_from_service(service, client_id_and_location)
+                    self.unsubscribe_from_service(service, client_id_and_location, request_id)
                         .await
                 }
                 Event::UpdateLocation(client_id_and_location) => {
-                    self.update_location(client_id_and_location).await
+                    self.update_location(client_id_and_location, request_id).await
+                }
+                Event::JoinGroup(group) => self.join_group(group.to_string(), request_id).await,
+                Event::LeaveGroup(group) => self.leave_group(group.to_string(), request_id).await,
+                Event::FetchMedia(name) => self.send_media(name, request_id).await,
+                Event::ReportMediaStatus(media_status) => {
+                    if let Some(client_id) = &self.id {
+                        self.status
+                            .send(StatusEvent::MediaStatus(client_id.uuid, media_status))
+                            .await?;
+                    }
+                    Ok(self
+                        .sender
+                        .send(ExchangeMessage::Ack(Some(request_id), AckResult::Success))
+                        .await?)
                 }
                 _ => {
                     warn!(?action, "Requested Event by Client {:?} is not supported. Client may be sending Server Event?", self.id);
                     self.sender
-                        .send(ExchangeMessage::Nack(NackResult::Failed))
+                        .send(ExchangeMessage::Nack(Some(request_id), NackResult::Failed))
                         .await?;
                     Err(ClientHandlerError::InvalidExchangeMessage(
                         action.to_string(),
@@ -384,6 +672,31 @@ impl Client {
                 info!("Watchdog reset: {}", self.watchdog_sent);
                 Ok(())
             }
+            ExchangeMessage::TimeSyncResponse(time_sync_response) => {
+                let time_sync_result = time_sync_response.estimate(server_time_us());
+                info!(
+                    offset_us = time_sync_result.offset_us,
+                    round_trip_us = time_sync_result.round_trip_us,
+                    "Clock of {:?} synchronised.", self.id
+                );
+                if let Some(client_id) = &self.id {
+                    self.status
+                        .send(StatusEvent::ClockSynchronised(
+                            client_id.uuid,
+                            time_sync_result.clone(),
+                        ))
+                        .await?;
+                }
+                // The Client keeps its own estimation of the offset and drift with the results.
+                Ok(self
+                    .sender
+                    .send(ExchangeMessage::TimeSyncResult(time_sync_result))
+                    .await?)
+            }
+            ExchangeMessage::Error(error_description) => {
+                warn!("Error reported by {:?}: {}", self.id, error_description.error_descr);
+                Ok(())
+            }
             ExchangeMessage::NextScene => {
                 info!("Requesting moving to the next scene to the Orchestrator");
                 Ok(self.sequencer.send(exchange_message).await?)
@@ -398,7 +711,7 @@ impl Client {
                     "Invalid message received from device {:?}.", self.id
                 );
                 self.sender
-                    .send(ExchangeMessage::Nack(NackResult::Failed))
+                    .send(ExchangeMessage::Nack(None, NackResult::Failed))
                     .await?;
                 Err(ClientHandlerError::InvalidExchangeMessage(
                     exchange_message.to_string(),
@@ -412,10 +725,12 @@ impl Client {
         &mut self,
         service: Service,
         client_id_and_location: ClientIdAndLocation,
+        request_id: RequestId,
     ) -> Result<(), ClientHandlerError> {
         let message = InternalEventMessageServer::AddTargetClient(
-            client_id_and_location,
+            self.profile(client_id_and_location),
             self.sender.clone(),
+            request_id,
         );
         match service {
             Service::Subtitle => Ok(self.subtitles_service.send(message).await?),
@@ -430,8 +745,13 @@ impl Client {
         &mut self,
         service: Service,
         client_id_and_location: ClientIdAndLocation,
+        request_id: RequestId,
     ) -> Result<(), ClientHandlerError> {
-        let message = InternalEventMessageServer::RemoveTargetClient(client_id_and_location);
+        let message = InternalEventMessageServer::RemoveTargetClient(
+            client_id_and_location,
+            self.sender.clone(),
+            Some(request_id),
+        );
         match service {
             Service::Subtitle=>Ok(self.subtitles_service.send(message).await?),
             Service::Colour=>Ok(self.colour_service.send(message).await?),
@@ -444,19 +764,382 @@ impl Client {
     async fn update_location(
         &mut self,
         client_id_and_location: ClientIdAndLocation,
+        request_id: RequestId,
     ) -> Result<(), ClientHandlerError> {
-        self.subtitles_service
-            .send(InternalEventMessageServer::UpdateClientData(
-                client_id_and_location.clone(),
-                self.sender.clone(),
-            ))
-            .await?;
-        self.colour_service
-            .send(InternalEventMessageServer::UpdateClientData(
-                client_id_and_location,
-                self.sender.clone(),
-            ))
+        let client_profile = self.profile(client_id_and_location);
+        let subscriptions = self.update_client_data(&client_profile).await?;
+        let sender = self.sender.clone();
+        // Waits for the Services apart, as this handler relays their messages to the Client.
+        tokio::spawn(async move {
+            let mut subscribed = false;
+            for subscription in subscriptions {
+                // A Service that stopped doesn't have the Client anymore.
+                subscribed |= subscription.await.unwrap_or(false);
+            }
+            let answer = match subscribed {
+                true => ExchangeMessage::Ack(Some(request_id), AckResult::UpdatedLocation),
+                false => ExchangeMessage::Nack(Some(request_id), NackResult::NotSubscribed),
+            };
+            if sender.send(answer).await.is_err() {
+                warn!("The location update could not be answered, the Client is gone.");
+            }
+        });
+        Ok(())
+    }
+
+    /// Updates the data of the Client in all the Services, each telling through the returned
+    /// receivers whether the Client is subscribed to it.
+    async fn update_client_data(
+        &self,
+        client_profile: &ClientProfile,
+    ) -> Result<Vec<oneshot::Receiver<bool>>, ClientHandlerError> {
+        let mut subscriptions = Vec::new();
+        for service in [
+            &self.subtitles_service,
+            &self.colour_service,
+            &self.playback_service,
+            &self.midi_service,
+        ] {
+            let (reply, subscribed) = oneshot::channel();
+            service
+                .send(InternalEventMessageServer::UpdateClientData(
+                    client_profile.clone(),
+                    self.sender.clone(),
+                    reply,
+                ))
+                .await?;
+            subscriptions.push(subscribed);
+        }
+        Ok(subscriptions)
+    }
+
+    /// Adds the Client to the group in all the Services. Services where the Client is not
+    /// subscribed yet will learn about it when it subscribes.
+    #[instrument(name = "Client::join_group", skip(self), fields(id=?self.id), level = "INFO", ret, err)]
+    async fn join_group(
+        &mut self,
+        group: String,
+        request_id: RequestId,
+    ) -> Result<(), ClientHandlerError> {
+        // `self.id` is always set, as only registered Clients can join groups.
+        if let Some(client_id) = &self.id {
+            let uuid = client_id.uuid;
+            self.groups.insert(group.clone());
+            self.subtitles_service.send(InternalEventMessageServer::JoinGroup(uuid, group.clone())).await?;
+            self.colour_service.send(InternalEventMessageServer::JoinGroup(uuid, group.clone())).await?;
+            self.playback_service.send(InternalEventMessageServer::JoinGroup(uuid, group.clone())).await?;
+            self.midi_service.send(InternalEventMessageServer::JoinGroup(uuid, group)).await?;
+        }
+        Ok(self
+            .sender
+            .send(ExchangeMessage::Ack(Some(request_id), AckResult::Success))
+            .await?)
+    }
+
+    /// Removes the Client from the group in all the Services.
+    #[instrument(name = "Client::leave_group", skip(self), fields(id=?self.id), level = "INFO", ret, err)]
+    async fn leave_group(
+        &mut self,
+        group: String,
+        request_id: RequestId,
+    ) -> Result<(), ClientHandlerError> {
+        if let Some(client_id) = &self.id {
+            let uuid = client_id.uuid;
+            self.groups.remove(&group);
+            self.subtitles_service.send(InternalEventMessageServer::LeaveGroup(uuid, group.clone())).await?;
+            self.colour_service.send(InternalEventMessageServer::LeaveGroup(uuid, group.clone())).await?;
+            self.playback_service.send(InternalEventMessageServer::LeaveGroup(uuid, group.clone())).await?;
+            self.midi_service.send(InternalEventMessageServer::LeaveGroup(uuid, group)).await?;
+        }
+        Ok(self
+            .sender
+            .send(ExchangeMessage::Ack(Some(request_id), AckResult::Success))
+            .await?)
+    }
+}
+
+impl Client {
+    /// Sends a file of the media manifest to the remote Client, in chunks. The request is
+    /// acknowledged straight away and the chunks are sent from their own task, so the Client
+    /// handler keeps relaying the other messages while the file is being transferred.
+    #[instrument(name = "Client::send_media", skip(self), fields(id=?self.id), level = "INFO", ret, err)]
+    async fn send_media(
+        &mut self,
+        name: MediaFileName,
+        request_id: RequestId,
+    ) -> Result<(), ClientHandlerError> {
+        let Some((path, entry)) = self.media_library.resolve(&name) else {
+            warn!("{:?} fetched {}, which is not in the media manifest.", self.id, name);
+            return Ok(self
+                .sender
+                .send(ExchangeMessage::Nack(Some(request_id), NackResult::UnknownMedia))
+                .await?);
+        };
+        info!("Sending {} ({} bytes) to {:?}", name, entry.size, self.id);
+        self.sender
+            .send(ExchangeMessage::Ack(Some(request_id), AckResult::Success))
             .await?;
+        let media_sender = self.media_sender.clone();
+        tokio::spawn(async move {
+            if let Err(error) = send_media_chunks(&path, name.clone(), media_sender).await {
+                error!(?error, "Failed to send media file {}.", name);
+            }
+        });
         Ok(())
     }
 }
+
+/// Reads the file, sending its chunks to the Client handler for it to relay them. The media
+/// queue is short, so the file is only read as fast as the chunks are sent.
+async fn send_media_chunks(
+    path: &std::path::Path,
+    name: MediaFileName,
+    sender: Sender<MediaChunk>,
+) -> Result<(), ClientHandlerError> {
+    let mut file = File::open(path).await.map_err(|error| {
+        ClientHandlerError::MediaTransfer(error.to_string())
+    })?;
+    let mut buffer = [0; MEDIA_CHUNK_SIZE];
+    let mut offset = 0;
+    loop {
+        let read = file
+            .read(&mut buffer)
+            .await
+            .map_err(|error| ClientHandlerError::MediaTransfer(error.to_string()))?;
+        // Empty files are sent as a single empty chunk, so the Client knows they are complete.
+        if read == 0 && offset > 0 {
+            return Ok(());
+        }
+        sender
+            .send(MediaChunk {
+                name: name.clone(),
+                offset,
+                // The buffer is as big as the chunks.
+                data: heapless::Vec::from_slice(&buffer[..read]).unwrap_or_default(),
+            })
+            .await
+            .map_err(|_| ClientHandlerError::MediaTransfer("the connection with the Client was closed".to_string()))?;
+        if read == 0 {
+            return Ok(());
+        }
+        offset += read as u64;
+    }
+}
+
+impl Drop for Client {
+    /// Lets the status actor and the orchestrators know the Client is gone, however the
+    /// connection ended.
+    fn drop(&mut self) {
+        if let Some(client_id) = &self.id {
+            if let Err(error) = self
+                .status
+                .try_send(StatusEvent::Disconnected(client_id.uuid))
+            {
+                warn!(?error, "Status could not be notified of the Client disconnection.");
+            }
+            self.events.publish(ServerEvent::ClientDisconnected {
+                uuid: client_id.uuid,
+            });
+        }
+    }
+}
+
+/// Returns the id of the remote Client request, if the message is one.
+fn request_id_of(exchange_message: &ExchangeMessage) -> Option<RequestId> {
+    match exchange_message {
+        ExchangeMessage::Request(request_id, _) => Some(*request_id),
+        _ => None,
+    }
+}
+
+/// Request id and protocol version of a postcard encoded registration request, read from its
+/// `RegistrationHeader`, whatever the version of the protocol the Client speaks.
+fn binary_registration_header(payload: &[u8]) -> Option<(RequestId, u16)> {
+    let (header, _) = postcard::take_from_bytes::<RegistrationHeader>(payload).ok()?;
+    Some((header.request_id, header.protocol_version()?))
+}
+
+/// Same as `binary_registration_header`, for the JSON encoded registration requests.
+fn text_registration_header(payload: &str) -> Option<(RequestId, u16)> {
+    let payload: serde_json::Value = serde_json::from_str(payload).ok()?;
+    let request_id = payload.pointer("/Request/0")?.as_u64()?;
+    let protocol_version = payload
+        .pointer("/Request/1/Register/protocol_version")?
+        .as_u64()?;
+    Some((
+        RequestId::try_from(request_id).ok()?,
+        u16::try_from(protocol_version).ok()?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::EventPublisher;
+    use lamarrs_utils::action_messages::ClientKind;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::connect_async;
+    use uuid::Uuid;
+
+    /// Runs a Client handler for a single connection, returning the URL to connect to it.
+    async fn start_client_handler() -> String {
+        let (service, mut service_inbox) = channel(32);
+        // Kept alive while the Client handler runs, or sending to it would fail.
+        tokio::spawn(async move { while service_inbox.recv().await.is_some() {} });
+        start_client_handler_with(service).await
+    }
+
+    /// Runs a Client handler for a single connection, using the same Service for all of them.
+    async fn start_client_handler_with(service: Sender<InternalEventMessageServer>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            // Kept alive while the Client handler runs, or sending to them would fail.
+            let (sequencer, _sequencer_inbox) = channel(32);
+            let (status, _status_inbox) = channel(32);
+            let (events, _events_inbox) = EventPublisher::channel();
+            let mut client = Client::new(
+                service.clone(),
+                service.clone(),
+                service.clone(),
+                service,
+                sequencer,
+                status,
+                Arc::new(SeatMap::default()),
+                Arc::new(MediaLibrary::default()),
+                events,
+            );
+            let _ = client.run(stream).await;
+        });
+        url
+    }
+
+    #[test]
+    fn reads_the_header_of_text_registrations() {
+        let registration = r#"{"Request":[12,{"Register":{"protocol_version":2,"client":"old layout"}}]}"#;
+        assert_eq!(text_registration_header(registration), Some((12, 2)));
+        assert_eq!(text_registration_header(r#"{"Request":[12,{"JoinGroup":"choir"}]}"#), None);
+        assert_eq!(text_registration_header("not json"), None);
+    }
+
+    #[test]
+    fn reads_the_header_of_binary_registrations() {
+        // `Request(5, Register(..))` of protocol version 1, followed by fields of another layout.
+        assert_eq!(binary_registration_header(&[2, 5, 0, 1, 0xff, 0xff]), Some((5, 1)));
+        // `Request(5, JoinGroup(..))`.
+        assert_eq!(binary_registration_header(&[2, 5, 5, 1, b'a']), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_text_clients_of_other_protocol_versions_that_cant_be_decoded() {
+        let url = start_client_handler().await;
+        let (mut websocket, _) = connect_async(url).await.unwrap();
+        let old_registration = format!(
+            r#"{{"Request":[12,{{"Register":{{"protocol_version":{},"client":"old layout"}}}}]}}"#,
+            PROTOCOL_VERSION - 1
+        );
+        websocket
+            .send(TungsteniteMessage::Text(old_registration.into()))
+            .await
+            .unwrap();
+
+        let mut answers = Vec::new();
+        while let Some(Ok(TungsteniteMessage::Text(answer))) = websocket.next().await {
+            answers.push(serde_json::from_str::<ExchangeMessage>(&answer).unwrap());
+        }
+        assert!(matches!(
+            answers.as_slice(),
+            [
+                ExchangeMessage::ServerInfo(ServerInfo { protocol_version: PROTOCOL_VERSION, .. }),
+                ExchangeMessage::Nack(Some(12), NackResult::IncompatibleProtocolVersion),
+            ]
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_binary_clients_of_other_protocol_versions_that_cant_be_decoded() {
+        let url = start_client_handler().await;
+        let (mut websocket, _) = connect_async(url).await.unwrap();
+        let old_registration = vec![2, 12, 0, (PROTOCOL_VERSION - 1) as u8, 0xff, 0xff];
+        websocket
+            .send(TungsteniteMessage::Binary(old_registration.into()))
+            .await
+            .unwrap();
+
+        let mut answers = Vec::new();
+        while let Some(Ok(TungsteniteMessage::Binary(answer))) = websocket.next().await {
+            answers.push(postcard::from_bytes::<ExchangeMessage>(&answer).unwrap());
+        }
+        assert!(matches!(
+            answers.as_slice(),
+            [
+                ExchangeMessage::ServerInfo(ServerInfo { protocol_version: PROTOCOL_VERSION, .. }),
+                ExchangeMessage::Nack(Some(12), NackResult::IncompatibleProtocolVersion),
+            ]
+        ));
+    }
+
+    /// Registers a Client and updates its location, with Services that answer whether they have
+    /// the Client, returning the Acks and Nacks received in the meantime.
+    async fn register_and_update_location(subscribed: bool) -> Vec<ExchangeMessage> {
+        let (service, mut service_inbox) = channel(32);
+        tokio::spawn(async move {
+            while let Some(message) = service_inbox.recv().await {
+                if let InternalEventMessageServer::UpdateClientData(_, _, reply) = message {
+                    let _ = reply.send(subscribed);
+                }
+            }
+        });
+        let url = start_client_handler_with(service).await;
+        let (mut websocket, _) = connect_async(url).await.unwrap();
+        let client = ClientIdAndLocation::new(Uuid::from_u128(7), None);
+        let registration = Registration {
+            protocol_version: PROTOCOL_VERSION,
+            client: client.clone(),
+            kind: ClientKind::Browser,
+            services: [Service::Colour].into_iter().collect(),
+            max_frame_size: 1024,
+            seat: None,
+            language: None,
+            section: None,
+            group: None,
+        };
+        for request in [
+            ExchangeMessage::Request(1, Event::Register(registration)),
+            ExchangeMessage::Request(2, Event::UpdateLocation(client)),
+        ] {
+            let request = serde_json::to_string(&request).unwrap();
+            websocket.send(TungsteniteMessage::Text(request.into())).await.unwrap();
+        }
+
+        let mut answers = Vec::new();
+        while let Ok(Some(Ok(TungsteniteMessage::Text(answer)))) =
+            timeout(Duration::from_millis(300), websocket.next()).await
+        {
+            let answer = serde_json::from_str::<ExchangeMessage>(&answer).unwrap();
+            if matches!(answer, ExchangeMessage::Ack(..) | ExchangeMessage::Nack(..)) {
+                answers.push(answer);
+            }
+        }
+        answers
+    }
+
+    #[tokio::test]
+    async fn answers_each_request_once_whatever_the_services_the_client_is_in() {
+        assert_eq!(
+            register_and_update_location(true).await,
+            [
+                ExchangeMessage::Ack(Some(1), AckResult::Success),
+                ExchangeMessage::Ack(Some(2), AckResult::UpdatedLocation),
+            ]
+        );
+        assert_eq!(
+            register_and_update_location(false).await,
+            [
+                ExchangeMessage::Ack(Some(1), AckResult::Success),
+                ExchangeMessage::Nack(Some(2), NackResult::NotSubscribed),
+            ]
+        );
+    }
+}
@@ -0,0 +1,31 @@
+//! Server clock
+//!
+//! The Server clock is the reference all the Clients synchronise with, and the one the
+//! [`Schedule`]s of the Scenes are written in.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use lamarrs_utils::clock::Schedule;
+
+/// Current time of the Server clock, in microseconds since the UNIX epoch.
+pub fn server_time_us() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_micros() as u64)
+        .unwrap_or_default()
+}
+
+/// Schedules a Scene `lead_time` from now, so all the Clients receive it before it must be
+/// performed. Clients receiving it more than `lead_time` late drop it.
+/// A zero `lead_time` means the Scenes are performed as soon as they are received.
+pub fn schedule_in(lead_time: Duration) -> Option<Schedule> {
+    if lead_time.is_zero() {
+        return None;
+    }
+    let lead_time_us = lead_time.as_micros() as u64;
+    let execute_at_us = server_time_us() + lead_time_us;
+    Some(Schedule {
+        execute_at_us,
+        expires_at_us: Some(execute_at_us + lead_time_us),
+    })
+}
@@ -0,0 +1,330 @@
+//! DMX input
+//!
+//! Lets a lighting console drive the colours of the Clients, listening for the Art-Net or sACN
+//! universe it sends. Ranges of channels of the universe are mapped to the Clients of a target,
+//! so the console patches each of them as a fixture. The mapping is a YAML file:
+//! ```yaml
+//! protocol: Sacn
+//! universe: 1
+//! # Colours sent per second to each target at most, from 1 to 1000, 20 if missing.
+//! max_rate: 20
+//! mappings:
+//!   - address: 1
+//!     channels: [Red, Green, Blue]
+//!     target:
+//!       regions: [Left]
+//!   - address: 4
+//!     channels: [Dimmer, Red, Green, Blue, White]
+//!     target:
+//!       groups: [band]
+//! ```
+//! Dimmer channels scale the colour of their mapping. Colours are only sent when they change,
+//! and never faster than `max_rate`, as consoles send the universe continuously.
+
+use std::{
+    fs, io,
+    net::{Ipv4Addr, SocketAddr},
+    path::Path,
+    time::Duration,
+};
+
+use lamarrs_utils::{
+    action_messages::Action,
+    dmx::{parse_dmx_packet, sacn_multicast_address, DmxChannel, DmxProtocol, DMX_UNIVERSE_SIZE},
+    target_selector::TargetSelector,
+    ColourRgb,
+};
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::{
+    net::UdpSocket,
+    sync::mpsc::Sender,
+    time::{interval, MissedTickBehavior},
+};
+use tracing::{debug, info, instrument, warn};
+
+use crate::{clock::schedule_in, services::InternalEventMessageServer};
+
+const DEFAULT_MAX_RATE: u32 = 20;
+
+/// Highest `max_rate`, far above the refresh rate of DMX, so the ticks stay at least 1 ms apart.
+const HIGHEST_MAX_RATE: u32 = 1000;
+
+#[derive(Debug, Error)]
+pub enum DmxInputConfigError {
+    #[error("The DMX input file could not be read")]
+    Io(#[from] io::Error),
+    #[error("Malformed YAML DMX input: {0}")]
+    Yaml(#[from] serde_yml::Error),
+    #[error("Universe {universe} is not valid for {protocol}.")]
+    InvalidUniverse { protocol: DmxProtocol, universe: u16 },
+    #[error("Mapping at address {0} doesn't fit in the universe, addresses go from 1 to 512.")]
+    InvalidMapping(u16),
+    #[error("The maximum rate must be from 1 to {HIGHEST_MAX_RATE} colours per second.")]
+    InvalidMaxRate,
+}
+
+#[derive(Debug, Error)]
+pub enum DmxInputError {
+    /// Only returned before listening starts, as the universe can't be received at all.
+    #[error("Failed listening for the DMX universe.")]
+    Network(#[from] io::Error),
+    #[error("The Colour service is gone.")]
+    ColourServiceGone,
+}
+
+/// Consecutive channels of the universe, from its address, controlling the colour of a target.
+#[derive(Clone, Debug, Deserialize)]
+pub struct DmxMapping {
+    /// From 1 to 512.
+    pub address: u16,
+    pub channels: Vec<DmxChannel>,
+    #[serde(default)]
+    pub target: TargetSelector,
+}
+
+impl DmxMapping {
+    /// Colour the channels of the mapping set in the universe. Channels the packet doesn't carry
+    /// are at 0, as in the consoles sending short universes.
+    fn colour(&self, universe: &[u8]) -> ColourRgb {
+        let mut colour = ColourRgb::new(0, 0, 0);
+        let mut dimmer = None;
+        for (offset, channel) in self.channels.iter().enumerate() {
+            let level = universe
+                .get(self.address as usize - 1 + offset)
+                .copied()
+                .unwrap_or_default();
+            match channel {
+                DmxChannel::Red => colour.r = level,
+                DmxChannel::Green => colour.g = level,
+                DmxChannel::Blue => colour.b = level,
+                DmxChannel::White => colour.w = Some(level),
+                DmxChannel::Dimmer => dimmer = Some(level),
+            }
+        }
+        if let Some(dimmer) = dimmer {
+            let dim = |level: u8| (level as u16 * dimmer as u16 / u8::MAX as u16) as u8;
+            colour.r = dim(colour.r);
+            colour.g = dim(colour.g);
+            colour.b = dim(colour.b);
+            colour.w = colour.w.map(dim);
+        }
+        colour
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct DmxInputConfig {
+    #[serde(default)]
+    pub protocol: DmxProtocol,
+    pub universe: u16,
+    /// Colours sent per second to each target at most.
+    #[serde(default = "default_max_rate")]
+    pub max_rate: u32,
+    pub mappings: Vec<DmxMapping>,
+}
+
+fn default_max_rate() -> u32 {
+    DEFAULT_MAX_RATE
+}
+
+impl DmxInputConfig {
+    pub fn load(path: &Path) -> Result<Self, DmxInputConfigError> {
+        let config: DmxInputConfig = serde_yml::from_str(&fs::read_to_string(path)?)?;
+        if !config.protocol.is_valid_universe(config.universe) {
+            return Err(DmxInputConfigError::InvalidUniverse {
+                protocol: config.protocol,
+                universe: config.universe,
+            });
+        }
+        if !(1..=HIGHEST_MAX_RATE).contains(&config.max_rate) {
+            return Err(DmxInputConfigError::InvalidMaxRate);
+        }
+        for mapping in &config.mappings {
+            let last_channel = mapping.address as usize + mapping.channels.len().max(1) - 1;
+            if mapping.address == 0 || last_channel > DMX_UNIVERSE_SIZE {
+                return Err(DmxInputConfigError::InvalidMapping(mapping.address));
+            }
+        }
+        info!(
+            "Loaded DMX input of {} mappings from {} universe {}",
+            config.mappings.len(),
+            config.protocol,
+            config.universe
+        );
+        Ok(config)
+    }
+}
+
+/// Colours of a mapping, the one the Clients were last sent and the one waiting to be sent.
+#[derive(Debug, Default)]
+struct MappingState {
+    sent: Option<ColourRgb>,
+    pending: Option<ColourRgb>,
+}
+
+pub struct DmxInput {
+    colour: Sender<InternalEventMessageServer>,
+    config: DmxInputConfig,
+    /// How long before the Clients must perform the actions they are sent.
+    scene_lead_time: Duration,
+    states: Vec<MappingState>,
+}
+
+impl DmxInput {
+    pub fn new(
+        colour: Sender<InternalEventMessageServer>,
+        config: DmxInputConfig,
+        scene_lead_time: Duration,
+    ) -> Self {
+        let states = config
+            .mappings
+            .iter()
+            .map(|_| MappingState::default())
+            .collect();
+        Self {
+            colour,
+            config,
+            scene_lead_time,
+            states,
+        }
+    }
+
+    #[instrument(name = "DmxInput::run", skip(self), level = "INFO")]
+    pub async fn run(&mut self) -> Result<(), DmxInputError> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, self.config.protocol.port())).await?;
+        if self.config.protocol == DmxProtocol::Sacn {
+            socket.join_multicast_v4(
+                sacn_multicast_address(self.config.universe).into(),
+                Ipv4Addr::UNSPECIFIED,
+            )?;
+        }
+        info!(
+            "Listening for {} universe {} on {}",
+            self.config.protocol,
+            self.config.universe,
+            socket.local_addr()?
+        );
+
+        // Changes are collected between ticks and sent at once, so each target gets at most one
+        // colour per tick.
+        let mut ticks = interval(Duration::from_secs(1) / self.config.max_rate);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        let mut packet = [0; 1500];
+        loop {
+            tokio::select! {
+                received = socket.recv_from(&mut packet) => match received {
+                    Ok((length, source)) => self.on_packet(&packet[..length], source),
+                    // Like the ICMP errors of unreachable sources, reported on some systems.
+                    Err(error) => warn!(?error, "Failed to receive a DMX packet."),
+                },
+                _ = ticks.tick() => self.send_pending().await?,
+            }
+        }
+    }
+
+    fn on_packet(&mut self, packet: &[u8], source: SocketAddr) {
+        let Some(frame) = parse_dmx_packet(packet) else {
+            debug!("Ignoring a packet from {} that is not DMX data.", source);
+            return;
+        };
+        if frame.protocol != self.config.protocol || frame.universe != self.config.universe {
+            return;
+        }
+        for (mapping, state) in self.config.mappings.iter().zip(self.states.iter_mut()) {
+            let colour = mapping.colour(frame.data);
+            state.pending = (state.sent.as_ref() != Some(&colour)).then_some(colour);
+        }
+    }
+
+    async fn send_pending(&mut self) -> Result<(), DmxInputError> {
+        for (mapping, state) in self.config.mappings.iter().zip(self.states.iter_mut()) {
+            let Some(colour) = state.pending.take() else {
+                continue;
+            };
+            debug!("DMX address {} changed to colour {}.", mapping.address, colour);
+            self.colour
+                .send(InternalEventMessageServer::PerformAction(
+                    Action::ChangeColour(colour.clone()),
+                    Box::new(mapping.target.clone()),
+                    schedule_in(self.scene_lead_time),
+                ))
+                .await
+                .map_err(|_| DmxInputError::ColourServiceGone)?;
+            state.sent = Some(colour);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Loads the configuration from a file of its own, so the tests can run in parallel.
+    fn load(name: &str, yaml: &str) -> Result<DmxInputConfig, DmxInputConfigError> {
+        let path = std::env::temp_dir().join(format!(
+            "lamarrs-dmx-input-{}-{}.yaml",
+            name,
+            std::process::id()
+        ));
+        fs::write(&path, yaml).unwrap();
+        let config = DmxInputConfig::load(&path);
+        fs::remove_file(path).unwrap();
+        config
+    }
+
+    #[test]
+    fn max_rates_out_of_range_are_refused() {
+        let config = |max_rate: u32| {
+            format!("universe: 1\nmax_rate: {max_rate}\nmappings:\n  - address: 1\n    channels: [Red]\n")
+        };
+
+        assert_eq!(load("default-rate", "universe: 1\nmappings: []\n").unwrap().max_rate, 20);
+        assert!(load("highest-rate", &config(HIGHEST_MAX_RATE)).is_ok());
+        for max_rate in [0, HIGHEST_MAX_RATE + 1, u32::MAX] {
+            assert!(matches!(
+                load("invalid-rate", &config(max_rate)),
+                Err(DmxInputConfigError::InvalidMaxRate)
+            ));
+        }
+    }
+
+    #[test]
+    fn mappings_out_of_the_universe_are_refused() {
+        for address in [0, 511] {
+            let yaml = format!(
+                "universe: 1\nmappings:\n  - address: {address}\n    channels: [Red, Green, Blue]\n"
+            );
+            assert!(matches!(
+                load("invalid-mapping", &yaml),
+                Err(DmxInputConfigError::InvalidMapping(invalid)) if invalid == address
+            ));
+        }
+    }
+
+    #[test]
+    fn dimmers_scale_the_colour_of_their_mapping() {
+        let mapping = DmxMapping {
+            address: 2,
+            channels: vec![
+                DmxChannel::Dimmer,
+                DmxChannel::Red,
+                DmxChannel::Green,
+                DmxChannel::Blue,
+                DmxChannel::White,
+            ],
+            target: TargetSelector::default(),
+        };
+
+        let colour = |r, g, b, w| {
+            let mut colour = ColourRgb::new(r, g, b);
+            colour.w = Some(w);
+            colour
+        };
+        assert_eq!(mapping.colour(&[9, 255, 100, 50, 254, 2]), colour(100, 50, 254, 2));
+        assert_eq!(mapping.colour(&[9, 51, 100, 50, 255, 5]), colour(20, 10, 51, 1));
+        // Short universes leave the missing channels at 0.
+        assert_eq!(mapping.colour(&[9, 255, 255]), colour(255, 0, 0, 0));
+    }
+}
@@ -0,0 +1,198 @@
+//! Server events
+//!
+//! What happens in the Server that the orchestrators may want to follow: Clients coming and
+//! going, the results of their requests, the sequence advancing and the Services failing.
+//! The actors publish them through an [`EventPublisher`], and the MQTT interface relays them
+//! to the broker.
+
+use std::net::SocketAddr;
+
+use lamarrs_utils::{action_messages::ClientKind, Service};
+use serde::Serialize;
+use tokio::sync::mpsc::{channel, error::TrySendError, Receiver, Sender};
+use tracing::warn;
+use uuid::Uuid;
+
+/// Events waiting to be relayed. Once full, new events are dropped.
+const EVENTS_QUEUE_SIZE: usize = 256;
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event")]
+pub enum ServerEvent {
+    /// The WebSocket handshake with a Client succeeded.
+    ClientConnected { address: SocketAddr },
+    ClientRegistered {
+        uuid: Uuid,
+        kind: ClientKind,
+        section: Option<String>,
+        groups: Vec<String>,
+    },
+    /// Only for the Clients that registered.
+    ClientDisconnected { uuid: Uuid },
+    ClientSubscribed { uuid: Uuid, service: Service },
+    /// Result of an orchestration message, along with the message, as received.
+    RequestResult {
+        request: serde_json::Value,
+        result: RequestResult,
+    },
+    StepStarted { index: usize, name: String },
+    ServiceError { service: String, error: String },
+}
+
+impl ServerEvent {
+    /// Topic the event is published at, under the events one.
+    pub fn topic(&self) -> &'static str {
+        match self {
+            ServerEvent::ClientConnected { .. } => "clients/connected",
+            ServerEvent::ClientRegistered { .. } => "clients/registered",
+            ServerEvent::ClientDisconnected { .. } => "clients/disconnected",
+            ServerEvent::ClientSubscribed { .. } => "clients/subscribed",
+            ServerEvent::RequestResult { .. } => "requests",
+            ServerEvent::StepStarted { .. } => "sequencer/steps",
+            ServerEvent::ServiceError { .. } => "errors",
+        }
+    }
+}
+
+/// Retained at the status topic, so the orchestrators know whether the Server is running, even
+/// when they connect after it. The broker publishes the offline one when the Server dies.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "status")]
+pub enum ServerStatus {
+    Online { version: &'static str },
+    Offline,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub enum RequestResult {
+    Accepted,
+    Rejected { reason: String },
+}
+
+/// Publishes events without ever waiting, so the actors reporting them are never slowed down.
+#[derive(Clone, Debug)]
+pub struct EventPublisher {
+    sender: Sender<ServerEvent>,
+}
+
+impl EventPublisher {
+    pub fn channel() -> (Self, Receiver<ServerEvent>) {
+        let (sender, inbox) = channel(EVENTS_QUEUE_SIZE);
+        (Self { sender }, inbox)
+    }
+
+    pub fn publish(&self, event: ServerEvent) {
+        match self.sender.try_send(event) {
+            Ok(()) => {}
+            Err(TrySendError::Full(event)) => warn!(?event, "Too many events, dropping it."),
+            // Nobody relays the events anymore, the Server is shutting down.
+            Err(TrySendError::Closed(_)) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn events_are_published_at_the_topic_of_their_kind() {
+        let uuid = Uuid::from_u128(1);
+        let events = [
+            (
+                ServerEvent::ClientConnected {
+                    address: ([127, 0, 0, 1], 8080).into(),
+                },
+                "clients/connected",
+            ),
+            (
+                ServerEvent::ClientRegistered {
+                    uuid,
+                    kind: ClientKind::RpClient,
+                    section: None,
+                    groups: Vec::new(),
+                },
+                "clients/registered",
+            ),
+            (
+                ServerEvent::ClientDisconnected { uuid },
+                "clients/disconnected",
+            ),
+            (
+                ServerEvent::ClientSubscribed {
+                    uuid,
+                    service: Service::Colour,
+                },
+                "clients/subscribed",
+            ),
+            (
+                ServerEvent::RequestResult {
+                    request: json!(null),
+                    result: RequestResult::Accepted,
+                },
+                "requests",
+            ),
+            (
+                ServerEvent::StepStarted {
+                    index: 0,
+                    name: "intro".to_string(),
+                },
+                "sequencer/steps",
+            ),
+            (
+                ServerEvent::ServiceError {
+                    service: "Colour".to_string(),
+                    error: "lost".to_string(),
+                },
+                "errors",
+            ),
+        ];
+        for (event, topic) in events {
+            assert_eq!(event.topic(), topic, "{:?}", event);
+        }
+    }
+
+    #[test]
+    fn events_and_statuses_are_tagged_with_their_kind() {
+        let registered = ServerEvent::ClientRegistered {
+            uuid: Uuid::from_u128(1),
+            kind: ClientKind::Browser,
+            section: Some("stalls".to_string()),
+            groups: vec!["choir".to_string()],
+        };
+        assert_eq!(
+            serde_json::to_value(registered).unwrap(),
+            json!({
+                "event": "ClientRegistered",
+                "uuid": "00000000-0000-0000-0000-000000000001",
+                "kind": "Browser",
+                "section": "stalls",
+                "groups": ["choir"],
+            })
+        );
+        let rejected = ServerEvent::RequestResult {
+            request: json!({"StopMidiFile": null}),
+            result: RequestResult::Rejected {
+                reason: "busy".to_string(),
+            },
+        };
+        assert_eq!(
+            serde_json::to_value(rejected).unwrap(),
+            json!({
+                "event": "RequestResult",
+                "request": {"StopMidiFile": null},
+                "result": {"Rejected": {"reason": "busy"}},
+            })
+        );
+        assert_eq!(
+            serde_json::to_value(ServerStatus::Online { version: "1.2.3" }).unwrap(),
+            json!({"status": "Online", "version": "1.2.3"})
+        );
+        assert_eq!(
+            serde_json::to_value(ServerStatus::Offline).unwrap(),
+            json!({"status": "Offline"})
+        );
+    }
+}
@@ -0,0 +1,385 @@
+//! HTTP/1.1 plumbing
+//!
+//! Just enough HTTP for the endpoints of the Server: one request per connection, with its body
+//! sized by `Content-Length`, answered with a response that closes the connection.
+
+use std::{io, time::Duration};
+
+use http::StatusCode;
+use serde::Serialize;
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+
+/// Biggest request line and headers read.
+pub const MAX_HEAD_SIZE: usize = 8 * 1024;
+
+/// Biggest body read.
+pub const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// Time a connection has to send its request.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+const MAX_HEADERS: usize = 32;
+
+/// How often the head of a request is peeked again while it arrives.
+const PEEK_PERIOD: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Error)]
+pub enum HttpError {
+    #[error("The connection failed")]
+    Io(#[from] io::Error),
+    #[error("Malformed HTTP request: {0}")]
+    Malformed(#[from] httparse::Error),
+    #[error("The connection was closed before the end of the request.")]
+    Incomplete,
+    #[error("The request is too large.")]
+    TooLarge,
+    #[error("Only requests with a Content-Length are supported.")]
+    UnsupportedBody,
+}
+
+impl HttpError {
+    /// Status the Server answers the request with, if it can still be answered.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            HttpError::Io(_) | HttpError::Incomplete => None,
+            HttpError::Malformed(_) => Some(StatusCode::BAD_REQUEST),
+            HttpError::TooLarge => Some(StatusCode::PAYLOAD_TOO_LARGE),
+            HttpError::UnsupportedBody => Some(StatusCode::LENGTH_REQUIRED),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    /// Path of the target, without its query.
+    pub path: String,
+    pub query: Option<String>,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    /// Value of the header, whose name is compared ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Non empty segments of the path.
+    pub fn path_segments(&self) -> Vec<&str> {
+        self.path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect()
+    }
+}
+
+/// Length of the request head, if `bytes` holds all of it.
+fn head_length(bytes: &[u8]) -> Option<usize> {
+    bytes
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|position| position + 4)
+}
+
+/// Whether the request waiting in the stream asks for a WebSocket upgrade. Its head is only
+/// peeked, so the request can still be read by the WebSocket handshake or by `read_request`.
+pub async fn is_websocket_upgrade(stream: &TcpStream) -> Result<bool, HttpError> {
+    let mut bytes = vec![0; MAX_HEAD_SIZE];
+    let mut peeked = 0;
+    let head_length = loop {
+        let length = stream.peek(&mut bytes).await?;
+        if length == 0 {
+            return Err(HttpError::Incomplete);
+        }
+        if let Some(head_length) = head_length(&bytes[..length]) {
+            break head_length;
+        }
+        if length == MAX_HEAD_SIZE {
+            return Err(HttpError::TooLarge);
+        }
+        // Peeking returns straight away while the data is not read, so the rest of the head
+        // is waited for.
+        if length == peeked {
+            tokio::time::sleep(PEEK_PERIOD).await;
+        }
+        peeked = length;
+    };
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut parsed = httparse::Request::new(&mut headers);
+    parsed.parse(&bytes[..head_length])?;
+    Ok(parsed.headers.iter().any(|header| {
+        header.name.eq_ignore_ascii_case("Upgrade")
+            && String::from_utf8_lossy(header.value)
+                .to_ascii_lowercase()
+                .contains("websocket")
+    }))
+}
+
+/// Reads a request from the stream.
+pub async fn read_request<S: AsyncRead + Unpin>(stream: &mut S) -> Result<HttpRequest, HttpError> {
+    let mut bytes = Vec::with_capacity(1024);
+    let mut chunk = [0; 1024];
+    let head_length = loop {
+        if let Some(head_length) = head_length(&bytes) {
+            break head_length;
+        }
+        if bytes.len() > MAX_HEAD_SIZE {
+            return Err(HttpError::TooLarge);
+        }
+        let length = stream.read(&mut chunk).await?;
+        if length == 0 {
+            return Err(HttpError::Incomplete);
+        }
+        bytes.extend_from_slice(&chunk[..length]);
+    };
+    // The end of the head may come in the chunk going past the limit.
+    if head_length > MAX_HEAD_SIZE {
+        return Err(HttpError::TooLarge);
+    }
+
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut parsed = httparse::Request::new(&mut headers);
+    parsed.parse(&bytes[..head_length])?;
+    let target = parsed.path.unwrap_or("/");
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path.to_string(), Some(query.to_string())),
+        None => (target.to_string(), None),
+    };
+    let mut request = HttpRequest {
+        method: parsed.method.unwrap_or_default().to_string(),
+        path,
+        query,
+        headers: parsed
+            .headers
+            .iter()
+            .map(|header| {
+                (
+                    header.name.to_string(),
+                    String::from_utf8_lossy(header.value).into_owned(),
+                )
+            })
+            .collect(),
+        body: Vec::new(),
+    };
+
+    if request.header("Transfer-Encoding").is_some() {
+        return Err(HttpError::UnsupportedBody);
+    }
+    let content_length = match request.header("Content-Length") {
+        Some(content_length) => content_length
+            .trim()
+            .parse::<usize>()
+            .map_err(|_| HttpError::UnsupportedBody)?,
+        None => 0,
+    };
+    if content_length > MAX_BODY_SIZE {
+        return Err(HttpError::TooLarge);
+    }
+    let mut body = bytes.split_off(head_length);
+    body.truncate(content_length);
+    while body.len() < content_length {
+        let length = stream.read(&mut chunk).await?;
+        if length == 0 {
+            return Err(HttpError::Incomplete);
+        }
+        let missing = content_length - body.len();
+        body.extend_from_slice(&chunk[..length.min(missing)]);
+    }
+    request.body = body;
+    Ok(request)
+}
+
+#[derive(Debug)]
+pub struct HttpResponse {
+    pub status: StatusCode,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn new(status: StatusCode, content_type: &str, body: Vec<u8>) -> Self {
+        Self {
+            status,
+            headers: vec![("Content-Type".to_string(), content_type.to_string())],
+            body,
+        }
+    }
+
+    pub fn json<T: Serialize>(status: StatusCode, value: &T) -> Self {
+        match serde_json::to_vec(value) {
+            Ok(body) => Self::new(status, "application/json", body),
+            Err(_) => Self::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "text/plain; charset=utf-8",
+                b"Failed to serialise the response.".to_vec(),
+            ),
+        }
+    }
+
+    pub fn text(status: StatusCode, text: &str) -> Self {
+        Self::new(status, "text/plain; charset=utf-8", text.as_bytes().to_vec())
+    }
+
+    pub fn no_content() -> Self {
+        Self {
+            status: StatusCode::NO_CONTENT,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    /// Writes the response, leaving the body out if it answers a `HEAD` request. Responses that
+    /// can't have a body, like `204 No Content`, are written without the headers describing it.
+    pub async fn write_to<S: AsyncWrite + Unpin>(
+        &self,
+        stream: &mut S,
+        with_body: bool,
+    ) -> Result<(), io::Error> {
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\n",
+            self.status.as_u16(),
+            self.status.canonical_reason().unwrap_or_default()
+        );
+        let has_body =
+            !(self.status.is_informational() || self.status == StatusCode::NO_CONTENT);
+        for (name, value) in &self.headers {
+            if has_body || !name.eq_ignore_ascii_case("Content-Type") {
+                head.push_str(&format!("{}: {}\r\n", name, value));
+            }
+        }
+        if has_body {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        head.push_str("Connection: close\r\n\r\n");
+        stream.write_all(head.as_bytes()).await?;
+        if with_body && has_body {
+            stream.write_all(&self.body).await?;
+        }
+        stream.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(mut bytes: &[u8]) -> Result<HttpRequest, HttpError> {
+        read_request(&mut bytes).await
+    }
+
+    async fn written(response: HttpResponse, with_body: bool) -> String {
+        let mut bytes = Vec::new();
+        response.write_to(&mut bytes, with_body).await.unwrap();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn requests_are_read_up_to_their_content_length() {
+        let request = read(
+            b"POST /api/sequencer/go?now=1 HTTP/1.1\r\ncontent-length: 4\r\n\r\nstepMORE",
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/api/sequencer/go");
+        assert_eq!(request.query.as_deref(), Some("now=1"));
+        assert_eq!(request.header("Content-Length"), Some("4"));
+        assert_eq!(request.path_segments(), ["api", "sequencer", "go"]);
+        assert_eq!(request.body, b"step");
+    }
+
+    #[tokio::test]
+    async fn requests_that_cant_be_read_are_refused() {
+        let oversized_head = format!("GET / HTTP/1.1\r\nX: {}\r\n\r\n", "a".repeat(MAX_HEAD_SIZE));
+        let oversized_body = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY_SIZE + 1
+        );
+
+        assert!(matches!(
+            read(oversized_head.as_bytes()).await,
+            Err(HttpError::TooLarge)
+        ));
+        assert!(matches!(
+            read(oversized_body.as_bytes()).await,
+            Err(HttpError::TooLarge)
+        ));
+        assert!(matches!(
+            read(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n").await,
+            Err(HttpError::UnsupportedBody)
+        ));
+        assert!(matches!(
+            read(b"POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n").await,
+            Err(HttpError::UnsupportedBody)
+        ));
+        assert!(matches!(
+            read(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort").await,
+            Err(HttpError::Incomplete)
+        ));
+        assert!(matches!(
+            read(b"GET / HTTP/1.1\r\n").await,
+            Err(HttpError::Incomplete)
+        ));
+        assert!(matches!(
+            read(b"GET /\x01 HTTP/1.1\r\n\r\n").await,
+            Err(HttpError::Malformed(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn responses_without_content_have_no_body_headers() {
+        let response = written(HttpResponse::no_content(), true).await;
+        let mut with_content_type = HttpResponse::no_content();
+        with_content_type
+            .headers
+            .push(("Content-Type".to_string(), "application/json".to_string()));
+
+        assert_eq!(response, "HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n");
+        assert_eq!(written(with_content_type, true).await, response);
+    }
+
+    #[tokio::test]
+    async fn head_responses_keep_the_length_of_their_body() {
+        let response = HttpResponse::text(StatusCode::OK, "lamarrs");
+
+        assert_eq!(
+            written(response, false).await,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\n\
+             Content-Length: 7\r\nConnection: close\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn websocket_upgrades_are_told_apart_without_reading_the_request() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        for (request, is_upgrade) in [
+            ("GET / HTTP/1.1\r\nUpgrade: WebSocket\r\n\r\n", true),
+            ("GET /index.html HTTP/1.1\r\nHost: lamarrs\r\n\r\n", false),
+        ] {
+            let mut client = TcpStream::connect(address).await.unwrap();
+            let (mut stream, _) = listener.accept().await.unwrap();
+            // The head arrives in two parts.
+            client.write_all(&request.as_bytes()[..10]).await.unwrap();
+            let peeked = tokio::spawn(async move {
+                let is_upgrade = is_websocket_upgrade(&stream).await.unwrap();
+                (is_upgrade, read_request(&mut stream).await.unwrap().path)
+            });
+            tokio::time::sleep(PEEK_PERIOD * 2).await;
+            client.write_all(&request.as_bytes()[10..]).await.unwrap();
+
+            let (peeked_upgrade, path) = peeked.await.unwrap();
+            assert_eq!(peeked_upgrade, is_upgrade);
+            assert!(request.contains(&path));
+        }
+    }
+}
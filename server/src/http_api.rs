@@ -0,0 +1,714 @@
+//! HTTP API
+//!
+//! Lets operators and show control tools inspect and drive the Server with JSON over HTTP:
+//!  * `GET /api/server`: version of the Server and of the protocol, and its uptime.
+//!  * `GET /api/clients`: registered Clients, with their clock, media and Service subscriptions.
+//!  * `POST /api/services/{Service}/actions`: sends `{"action": ..., "target": ...}` to the
+//!    Clients of the Service, as the sequence does. The target is optional and defaults to all
+//!    of them.
+//!  * `GET /api/sequencer`: steps of the sequence and which one plays next.
+//!  * `POST /api/sequencer/next` and `POST /api/sequencer/retrigger`: as the Clients requesting
+//!    them.
+//!  * `POST /api/sequencer/go`: plays the step named as in `{"step": ...}`.
+//!  * `POST /api/sequencer/reload`: reads the sequence file again and starts it over.
+//!  * `GET /metrics`: load of the Server, in the Prometheus text format. Only served here, not
+//!    on the WebSocket port the audience reaches.
+//!
+//! Errors are answered as `{"error": {"kind": ..., "message": ...}}`, with the status matching
+//! them.
+//!
+//! The `GET` requests, and their `HEAD` ones, are answered to anyone reaching the API. The `POST`
+//! ones control the show, so they need the token of the API, given with `--api-token`, as
+//! `Authorization: Bearer <token>`. Without a token they are only accepted from the host of the
+//! Server.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    io,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use http::StatusCode;
+use lamarrs_utils::{
+    action_messages::{Action, ClientKind},
+    exchange_messages::{ExchangeMessage, PROTOCOL_VERSION},
+    target_selector::TargetSelector,
+    Position, Service,
+};
+use serde::{Deserialize, Serialize};
+use strum::IntoEnumIterator;
+use thiserror::Error;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{mpsc::Sender, oneshot},
+};
+use tracing::{debug, error, info, instrument, warn};
+use uuid::Uuid;
+
+use crate::{
+    clock::schedule_in,
+    metrics::METRICS,
+    http::{read_request, HttpRequest, HttpResponse, REQUEST_TIMEOUT},
+    sequencer::SequencerCommand,
+    services::{InternalEventMessageServer, LamarrsServiceError},
+    status::StatusEvent,
+    VERSION,
+};
+
+#[derive(Debug, Error)]
+pub enum HttpApiError {
+    #[error("Failed listening for HTTP API requests.")]
+    Network(#[from] io::Error),
+}
+
+/// Errors answered to the API requests.
+#[derive(Debug, Error)]
+pub enum ApiError {
+    /// Boxed, as it is much bigger than the rest of the errors.
+    #[error(transparent)]
+    Service(Box<LamarrsServiceError>),
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("No resource at {0}.")]
+    NotFound(String),
+    #[error("Method {0} is not allowed for this resource.")]
+    MethodNotAllowed(String),
+    #[error("The {0} did not answer.")]
+    Unavailable(&'static str),
+    #[error("Control requests need the token of the API, as `Authorization: Bearer <token>`.")]
+    Unauthorized,
+    #[error("Control requests are only accepted from the host of the Server without an API token.")]
+    Forbidden,
+}
+
+impl From<LamarrsServiceError> for ApiError {
+    fn from(error: LamarrsServiceError) -> Self {
+        ApiError::Service(Box::new(error))
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: ErrorDetail,
+}
+
+#[derive(Serialize)]
+struct ErrorDetail {
+    kind: &'static str,
+    message: String,
+}
+
+impl ApiError {
+    fn status(&self) -> StatusCode {
+        match self {
+            ApiError::Service(service_error) => match service_error.as_ref() {
+                LamarrsServiceError::ClientNotFound { .. } | LamarrsServiceError::StepNotFound(_) => {
+                    StatusCode::NOT_FOUND
+                }
+                LamarrsServiceError::ClientAlreadySubscribed { .. } => StatusCode::CONFLICT,
+                LamarrsServiceError::NotAllowedMessageType { .. } => {
+                    StatusCode::UNPROCESSABLE_ENTITY
+                }
+                LamarrsServiceError::SendExchangeMessage(_)
+                | LamarrsServiceError::SendInternalEventMessage(_)
+                | LamarrsServiceError::SendMidiFilePlayerMessage(_) => {
+                    StatusCode::SERVICE_UNAVAILABLE
+                }
+                LamarrsServiceError::Service { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::MethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+            ApiError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden => StatusCode::FORBIDDEN,
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            ApiError::Service(service_error) => match service_error.as_ref() {
+                LamarrsServiceError::Service { .. } => "Service",
+                LamarrsServiceError::SendExchangeMessage(_)
+                | LamarrsServiceError::SendInternalEventMessage(_)
+                | LamarrsServiceError::SendMidiFilePlayerMessage(_) => "ServiceUnavailable",
+                LamarrsServiceError::NotAllowedMessageType { .. } => "NotAllowedMessageType",
+                LamarrsServiceError::ClientNotFound { .. } => "ClientNotFound",
+                LamarrsServiceError::ClientAlreadySubscribed { .. } => "ClientAlreadySubscribed",
+                LamarrsServiceError::StepNotFound(_) => "StepNotFound",
+            },
+            ApiError::BadRequest(_) => "BadRequest",
+            ApiError::NotFound(_) => "NotFound",
+            ApiError::MethodNotAllowed(_) => "MethodNotAllowed",
+            ApiError::Unavailable(_) => "ServiceUnavailable",
+            ApiError::Unauthorized => "Unauthorized",
+            ApiError::Forbidden => "Forbidden",
+        }
+    }
+
+    fn to_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::json(
+            self.status(),
+            &ErrorBody {
+                error: ErrorDetail {
+                    kind: self.kind(),
+                    message: self.to_string(),
+                },
+            },
+        );
+        if let ApiError::Unauthorized = self {
+            response
+                .headers
+                .push(("WWW-Authenticate".to_string(), "Bearer".to_string()));
+        }
+        response
+    }
+}
+
+#[derive(Serialize)]
+struct ServerInfoResponse {
+    version: &'static str,
+    protocol_version: u16,
+    uptime_secs: u64,
+}
+
+#[derive(Serialize)]
+struct ClockResponse {
+    offset_us: i64,
+    round_trip_us: u32,
+    /// Seconds since the latest synchronisation round.
+    age_secs: u64,
+}
+
+#[derive(Serialize)]
+struct SubscriptionResponse {
+    service: Service,
+    location: Option<Position>,
+    section: Option<String>,
+    groups: HashSet<String>,
+    language: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ClientResponse {
+    uuid: Uuid,
+    /// Unknown for the Clients subscribed to a Service that are not registered anymore.
+    kind: Option<ClientKind>,
+    clock: Option<ClockResponse>,
+    /// Amount of media files the Client misses, if the media manifest was sent to it.
+    missing_media: Option<u16>,
+    subscriptions: Vec<SubscriptionResponse>,
+}
+
+#[derive(Deserialize)]
+struct ActionRequest {
+    action: Action,
+    #[serde(default)]
+    target: TargetSelector,
+}
+
+#[derive(Serialize)]
+struct ActionResponse {
+    service: Service,
+    /// Server time, in microseconds, the Clients perform the action at.
+    execute_at_us: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct GoToStepRequest {
+    step: String,
+}
+
+#[derive(Serialize)]
+struct ReloadResponse {
+    steps: usize,
+}
+
+/// Channels to the actors the API talks to, shared by every connection.
+#[derive(Clone)]
+struct ApiState {
+    subtitles: Sender<InternalEventMessageServer>,
+    colour: Sender<InternalEventMessageServer>,
+    playback_audio: Sender<InternalEventMessageServer>,
+    midi: Sender<InternalEventMessageServer>,
+    sequencer: Sender<ExchangeMessage>,
+    sequencer_commands: Sender<SequencerCommand>,
+    status: Sender<StatusEvent>,
+    started_at: Instant,
+    scene_lead_time: Duration,
+    /// Needed by the control requests, which are only accepted from the host of the Server
+    /// without it.
+    token: Option<String>,
+}
+
+pub struct HttpApi {
+    address: SocketAddr,
+    state: ApiState,
+}
+
+impl HttpApi {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        subtitles: Sender<InternalEventMessageServer>,
+        colour: Sender<InternalEventMessageServer>,
+        playback_audio: Sender<InternalEventMessageServer>,
+        midi: Sender<InternalEventMessageServer>,
+        sequencer: Sender<ExchangeMessage>,
+        sequencer_commands: Sender<SequencerCommand>,
+        status: Sender<StatusEvent>,
+        address: SocketAddr,
+        token: Option<String>,
+        scene_lead_time: Duration,
+    ) -> Self {
+        Self {
+            address,
+            state: ApiState {
+                subtitles,
+                colour,
+                playback_audio,
+                midi,
+                sequencer,
+                sequencer_commands,
+                status,
+                started_at: Instant::now(),
+                scene_lead_time,
+                token,
+            },
+        }
+    }
+
+    #[instrument(name = "HttpApi::run", skip(self), fields(address = %self.address), err)]
+    pub async fn run(&mut self) -> Result<(), HttpApiError> {
+        let listener = TcpListener::bind(self.address).await?;
+        info!("HTTP API listening on: {}", self.address);
+        if self.state.token.is_none() && !self.address.ip().is_loopback() {
+            warn!("The HTTP API has no token, only the host of the Server can control the show.");
+        }
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    let state = self.state.clone();
+                    tokio::spawn(async move { handle_connection(stream, peer, state).await });
+                }
+                // Like running out of file descriptors, the connections already open go on.
+                Err(error) => error!(?error, "Failed to accept an HTTP API connection."),
+            }
+        }
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream, peer: SocketAddr, state: ApiState) {
+    let request = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(Ok(request)) => request,
+        Ok(Err(error)) => {
+            debug!(%peer, "Dropping HTTP request: {}", error);
+            if let Some(status) = error.status() {
+                let _ = HttpResponse::text(status, &error.to_string())
+                    .write_to(&mut stream, true)
+                    .await;
+            }
+            return;
+        }
+        Err(_) => {
+            debug!(%peer, "HTTP request timed out.");
+            return;
+        }
+    };
+    debug!(%peer, method = request.method, path = request.path, "HTTP API request.");
+    let response = match state.route(&request, peer).await {
+        Ok(response) => response,
+        Err(error) => {
+            warn!(%peer, method = request.method, path = request.path, "{}", error);
+            error.to_response()
+        }
+    };
+    if let Err(error) = response.write_to(&mut stream, request.method != "HEAD").await {
+        debug!(%peer, "Failed to answer the HTTP request: {}", error);
+    }
+}
+
+/// Parses the JSON body of the request.
+fn json_body<'a, T: Deserialize<'a>>(request: &'a HttpRequest) -> Result<T, ApiError> {
+    serde_json::from_slice(&request.body)
+        .map_err(|error| ApiError::BadRequest(format!("Malformed JSON body: {}", error)))
+}
+
+/// Compares every byte of the tokens, so the time taken doesn't tell how much of it was right.
+fn is_same_token(given: &str, token: &str) -> bool {
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |difference, (given, token)| difference | (given ^ token))
+            == 0
+}
+
+impl ApiState {
+    async fn route(
+        &self,
+        request: &HttpRequest,
+        peer: SocketAddr,
+    ) -> Result<HttpResponse, ApiError> {
+        let method = request.method.as_str();
+        let allow = |allowed: &str| {
+            // `HEAD` requests are answered as the `GET` ones, leaving the body out.
+            if method == allowed || (allowed == "GET" && method == "HEAD") {
+                Ok(())
+            } else {
+                Err(ApiError::MethodNotAllowed(method.to_string()))
+            }
+        };
+        let control = || {
+            allow("POST")?;
+            self.authorize(request, peer)
+        };
+        match request.path_segments().as_slice() {
+            ["api", "server"] => {
+                allow("GET")?;
+                Ok(HttpResponse::json(
+                    StatusCode::OK,
+                    &ServerInfoResponse {
+                        version: VERSION,
+                        protocol_version: PROTOCOL_VERSION,
+                        uptime_secs: self.started_at.elapsed().as_secs(),
+                    },
+                ))
+            }
+            ["api", "clients"] => {
+                allow("GET")?;
+                Ok(HttpResponse::json(StatusCode::OK, &self.clients().await?))
+            }
+            ["api", "services", service, "actions"] => {
+                control()?;
+                let service = Service::iter()
+                    .find(|known_service| known_service.to_string() == *service)
+                    .ok_or_else(|| ApiError::NotFound(request.path.clone()))?;
+                let action_request = json_body::<ActionRequest>(request)?;
+                Ok(HttpResponse::json(
+                    StatusCode::ACCEPTED,
+                    &self.perform_action(service, action_request).await?,
+                ))
+            }
+            ["api", "sequencer"] => {
+                allow("GET")?;
+                let (reply, state) = oneshot::channel();
+                self.send_command(SequencerCommand::Describe(reply)).await?;
+                let state = state.await.map_err(|_| ApiError::Unavailable("Sequencer"))?;
+                Ok(HttpResponse::json(StatusCode::OK, &state))
+            }
+            ["api", "sequencer", scene @ ("next" | "retrigger")] => {
+                control()?;
+                let message = match *scene {
+                    "next" => ExchangeMessage::NextScene,
+                    _ => ExchangeMessage::RetriggerScene,
+                };
+                self.sequencer
+                    .send(message)
+                    .await
+                    .map_err(LamarrsServiceError::from)?;
+                Ok(HttpResponse::no_content())
+            }
+            ["api", "sequencer", "go"] => {
+                control()?;
+                let go_to_step = json_body::<GoToStepRequest>(request)?;
+                let (reply, result) = oneshot::channel();
+                self.send_command(SequencerCommand::GoToStep(go_to_step.step, reply))
+                    .await?;
+                result.await.map_err(|_| ApiError::Unavailable("Sequencer"))??;
+                Ok(HttpResponse::no_content())
+            }
+            ["api", "sequencer", "reload"] => {
+                control()?;
+                let (reply, result) = oneshot::channel();
+                self.send_command(SequencerCommand::Reload(reply)).await?;
+                let steps = result.await.map_err(|_| ApiError::Unavailable("Sequencer"))??;
+                Ok(HttpResponse::json(StatusCode::OK, &ReloadResponse { steps }))
+            }
+            ["metrics"] => {
+                allow("GET")?;
+                Ok(HttpResponse::new(
+                    StatusCode::OK,
+                    "text/plain; version=0.0.4; charset=utf-8",
+                    METRICS.render().into_bytes(),
+                ))
+            }
+            _ => Err(ApiError::NotFound(request.path.clone())),
+        }
+    }
+
+    /// Control requests need the token of the API if it has one, or to come from the host of
+    /// the Server.
+    fn authorize(&self, request: &HttpRequest, peer: SocketAddr) -> Result<(), ApiError> {
+        match &self.token {
+            Some(token) => {
+                let bearer = request
+                    .header("Authorization")
+                    .and_then(|authorization| authorization.trim().strip_prefix("Bearer "));
+                if bearer.is_some_and(|bearer| is_same_token(bearer.trim(), token)) {
+                    Ok(())
+                } else {
+                    Err(ApiError::Unauthorized)
+                }
+            }
+            // IPv4 peers of IPv6 listeners are mapped to IPv6.
+            None if peer.ip().to_canonical().is_loopback() => Ok(()),
+            None => Err(ApiError::Forbidden),
+        }
+    }
+
+    fn service_sender(&self, service: &Service) -> &Sender<InternalEventMessageServer> {
+        match service {
+            Service::Subtitle => &self.subtitles,
+            Service::Colour => &self.colour,
+            Service::AudioPlayer => &self.playback_audio,
+            Service::Midi => &self.midi,
+        }
+    }
+
+    async fn send_command(&self, command: SequencerCommand) -> Result<(), ApiError> {
+        self.sequencer_commands
+            .send(command)
+            .await
+            .map_err(|_| ApiError::Unavailable("Sequencer"))
+    }
+
+    async fn perform_action(
+        &self,
+        service: Service,
+        action_request: ActionRequest,
+    ) -> Result<ActionResponse, ApiError> {
+        if action_request.action.service() != service {
+            return Err(LamarrsServiceError::NotAllowedMessageType {
+                action_message: action_request.action.to_string(),
+                service: service.to_string(),
+            }
+            .into());
+        }
+        let schedule = schedule_in(self.scene_lead_time);
+        let execute_at_us = schedule.as_ref().map(|schedule| schedule.execute_at_us);
+        self.service_sender(&service)
+            .send(InternalEventMessageServer::PerformAction(
+                action_request.action,
+                Box::new(action_request.target),
+                schedule,
+            ))
+            .await
+            .map_err(LamarrsServiceError::from)?;
+        Ok(ActionResponse {
+            service,
+            execute_at_us,
+        })
+    }
+
+    /// Registered Clients, merged with the subscribers of every Service.
+    async fn clients(&self) -> Result<Vec<ClientResponse>, ApiError> {
+        let (reply, statuses) = oneshot::channel();
+        self.status
+            .send(StatusEvent::ListClients(reply))
+            .await
+            .map_err(|_| ApiError::Unavailable("Status service"))?;
+        let statuses = statuses
+            .await
+            .map_err(|_| ApiError::Unavailable("Status service"))?;
+
+        // Sorted, so the Clients are always listed in the same order.
+        let mut clients: BTreeMap<Uuid, ClientResponse> = statuses
+            .into_iter()
+            .map(|(uuid, client_status)| {
+                let response = ClientResponse {
+                    uuid,
+                    kind: Some(client_status.kind),
+                    clock: client_status
+                        .clock
+                        .map(|(time_sync_result, synchronised_at)| ClockResponse {
+                            offset_us: time_sync_result.offset_us,
+                            round_trip_us: time_sync_result.round_trip_us,
+                            age_secs: synchronised_at.elapsed().as_secs(),
+                        }),
+                    missing_media: client_status.media.map(|media_status| media_status.missing),
+                    subscriptions: Vec::new(),
+                };
+                (uuid, response)
+            })
+            .collect();
+
+        for service in Service::iter() {
+            let (reply, target_clients) = oneshot::channel();
+            self.service_sender(&service)
+                .send(InternalEventMessageServer::ListTargetClients(reply))
+                .await
+                .map_err(LamarrsServiceError::from)?;
+            let target_clients = target_clients
+                .await
+                .map_err(|_| ApiError::Unavailable("Service"))?;
+            for target_client in target_clients {
+                clients
+                    .entry(target_client.uuid)
+                    .or_insert_with(|| ClientResponse {
+                        uuid: target_client.uuid,
+                        kind: None,
+                        clock: None,
+                        missing_media: None,
+                        subscriptions: Vec::new(),
+                    })
+                    .subscriptions
+                    .push(SubscriptionResponse {
+                        service: service.clone(),
+                        location: target_client.location,
+                        section: target_client.section,
+                        groups: target_client.groups,
+                        language: target_client.language,
+                    });
+            }
+        }
+        Ok(clients.into_values().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::{channel, Receiver};
+
+    fn state(token: Option<&str>) -> (ApiState, Receiver<ExchangeMessage>) {
+        let (sequencer, sequencer_inbox) = channel(4);
+        let state = ApiState {
+            subtitles: channel(1).0,
+            colour: channel(1).0,
+            playback_audio: channel(1).0,
+            midi: channel(1).0,
+            sequencer,
+            sequencer_commands: channel(1).0,
+            status: channel(1).0,
+            started_at: Instant::now(),
+            scene_lead_time: Duration::ZERO,
+            token: token.map(str::to_string),
+        };
+        (state, sequencer_inbox)
+    }
+
+    fn request(method: &str, path: &str, authorization: Option<&str>) -> HttpRequest {
+        HttpRequest {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: None,
+            headers: authorization
+                .map(|authorization| ("authorization".to_string(), authorization.to_string()))
+                .into_iter()
+                .collect(),
+            body: Vec::new(),
+        }
+    }
+
+    const LOCAL_PEER: ([u8; 4], u16) = ([127, 0, 0, 1], 50000);
+    const REMOTE_PEER: ([u8; 4], u16) = ([192, 168, 1, 20], 50000);
+
+    #[tokio::test]
+    async fn control_requests_need_the_token() {
+        let (state, mut sequencer_inbox) = state(Some("s3cr3t"));
+        let next = |authorization| request("POST", "/api/sequencer/next", authorization);
+
+        for authorization in [None, Some("Bearer wrong!"), Some("s3cr3t"), Some("Basic s3cr3t")] {
+            let error = state
+                .route(&next(authorization), REMOTE_PEER.into())
+                .await
+                .unwrap_err();
+            assert!(matches!(error, ApiError::Unauthorized));
+            assert!(error
+                .to_response()
+                .headers
+                .contains(&("WWW-Authenticate".to_string(), "Bearer".to_string())));
+        }
+        let response = state
+            .route(&next(Some("Bearer s3cr3t")), REMOTE_PEER.into())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status, StatusCode::NO_CONTENT);
+        assert!(response.headers.is_empty());
+        assert!(matches!(
+            sequencer_inbox.try_recv(),
+            Ok(ExchangeMessage::NextScene)
+        ));
+    }
+
+    #[tokio::test]
+    async fn control_requests_without_token_only_come_from_the_host() {
+        let (state, mut sequencer_inbox) = state(None);
+        let retrigger = request("POST", "/api/sequencer/retrigger", None);
+        let mapped_local_peer: SocketAddr = "[::ffff:127.0.0.1]:50000".parse().unwrap();
+
+        assert!(matches!(
+            state.route(&retrigger, REMOTE_PEER.into()).await,
+            Err(ApiError::Forbidden)
+        ));
+        assert!(state.route(&retrigger, LOCAL_PEER.into()).await.is_ok());
+        assert!(state.route(&retrigger, mapped_local_peer).await.is_ok());
+        assert!(matches!(
+            sequencer_inbox.try_recv(),
+            Ok(ExchangeMessage::RetriggerScene)
+        ));
+    }
+
+    #[tokio::test]
+    async fn reading_requests_need_no_token() {
+        let (state, _sequencer_inbox) = state(Some("s3cr3t"));
+
+        for method in ["GET", "HEAD"] {
+            let response = state
+                .route(&request(method, "/api/server", None), REMOTE_PEER.into())
+                .await
+                .unwrap();
+
+            assert_eq!(response.status, StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn only_control_routes_need_the_token() {
+        let (state, _sequencer_inbox) = state(Some("s3cr3t"));
+
+        for (method, path) in [("POST", "/api/unknown"), ("HEAD", "/api/unknown")] {
+            let result = state.route(&request(method, path, None), REMOTE_PEER.into()).await;
+            assert!(matches!(result, Err(ApiError::NotFound(_))), "{method} {path}");
+        }
+        for (method, path) in [("POST", "/api/server"), ("GET", "/api/sequencer/next")] {
+            let result = state.route(&request(method, path, None), REMOTE_PEER.into()).await;
+            assert!(matches!(result, Err(ApiError::MethodNotAllowed(_))), "{method} {path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn actions_are_only_sent_to_the_service_performing_them() {
+        let (colour, mut colour_inbox) = channel(1);
+        let state = ApiState {
+            colour,
+            ..state(None).0
+        };
+        let actions = |service: &str| HttpRequest {
+            body: br##"{"action": {"ChangeColour": "#ff0000"}}"##.to_vec(),
+            ..request("POST", &format!("/api/services/{service}/actions"), None)
+        };
+
+        let Err(ApiError::Service(error)) = state.route(&actions("Subtitle"), LOCAL_PEER.into()).await
+        else {
+            panic!("A colour was sent to the subtitles Service");
+        };
+        assert!(matches!(*error, LamarrsServiceError::NotAllowedMessageType { .. }));
+        let response = state.route(&actions("Colour"), LOCAL_PEER.into()).await.unwrap();
+        assert_eq!(response.status, StatusCode::ACCEPTED);
+        assert!(matches!(
+            colour_inbox.try_recv(),
+            Ok(InternalEventMessageServer::PerformAction(Action::ChangeColour(_), _, None))
+        ));
+    }
+
+    #[test]
+    fn tokens_are_compared_whole() {
+        assert!(is_same_token("s3cr3t", "s3cr3t"));
+        assert!(!is_same_token("s3cr3", "s3cr3t"));
+        assert!(!is_same_token("s3cr3t!", "s3cr3t"));
+        assert!(!is_same_token("S3cr3t", "s3cr3t"));
+    }
+}
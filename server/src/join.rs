@@ -0,0 +1,206 @@
+//! Join link
+//!
+//! Audience members join the show opening the URL of the Server on their phones, usually by
+//! scanning a QR code shown at the doors. The URL can preset the section, group or seat the
+//! browser Client registers with, like `http://lamarrs.local:8080/?section=Stalls`.
+//!
+//! The QR code of the plain URL is printed in the terminal at startup, and the Server serves
+//! the one of any URL at `/join.svg` and `/join.png`, taking the presets in their query:
+//! `/join.svg?group=choir` encodes `http://lamarrs.local:8080/?group=choir`. Other query
+//! parameters are left out of the URL.
+
+use std::net::IpAddr;
+
+use http::StatusCode;
+use qrcode::{
+    render::{svg, unicode::Dense1x2},
+    types::QrError,
+    Color, QrCode,
+};
+use thiserror::Error;
+
+use crate::http::{HttpRequest, HttpResponse};
+
+/// Query parameters of the join URL the browser Client passes on when registering.
+pub const PRESET_PARAMETERS: [&str; 3] = ["section", "group", "seat"];
+
+/// Pixels per module of the PNG QR codes.
+const PNG_MODULE_SIZE: usize = 8;
+
+/// Modules of white border around the PNG QR codes, as the standard asks for.
+const PNG_QUIET_ZONE: usize = 4;
+
+#[derive(Debug, Error)]
+pub enum JoinError {
+    #[error("The join URL does not fit in a QR code")]
+    QrCode(#[from] QrError),
+    #[error("Failed to encode the QR code as PNG")]
+    Png(#[from] png::EncodingError),
+}
+
+#[derive(Debug)]
+pub struct JoinLink {
+    /// URL without presets, always ending with `/`.
+    base_url: String,
+}
+
+impl JoinLink {
+    /// The URL points to the public hostname if given, or to the address the Server is bound
+    /// to otherwise, with the port of the Server in both cases.
+    pub fn new(bind_ip: &str, port: u16, public_hostname: Option<&str>) -> Self {
+        let host = match public_hostname {
+            Some(public_hostname) => public_hostname.to_string(),
+            None => match bind_ip.parse::<IpAddr>() {
+                Ok(IpAddr::V6(ip)) => format!("[{}]", ip),
+                _ => bind_ip.to_string(),
+            },
+        };
+        Self {
+            base_url: format!("http://{}:{}/", host, port),
+        }
+    }
+
+    /// Join URL with the presets found in the query, if any.
+    pub fn url(&self, query: Option<&str>) -> String {
+        let mut presets = form_urlencoded::Serializer::new(String::new());
+        let mut has_presets = false;
+        for (name, value) in form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+            if PRESET_PARAMETERS.contains(&name.as_ref()) && !value.is_empty() {
+                presets.append_pair(&name, &value);
+                has_presets = true;
+            }
+        }
+        if has_presets {
+            format!("{}?{}", self.base_url, presets.finish())
+        } else {
+            self.base_url.clone()
+        }
+    }
+
+    /// QR code drawn with half blocks, light on dark so it scans from dark terminals too.
+    pub fn terminal_qr_code(url: &str) -> Result<String, JoinError> {
+        Ok(QrCode::new(url)?
+            .render::<Dense1x2>()
+            .dark_color(Dense1x2::Light)
+            .light_color(Dense1x2::Dark)
+            .build())
+    }
+
+    pub fn svg_qr_code(url: &str) -> Result<String, JoinError> {
+        Ok(QrCode::new(url)?
+            .render::<svg::Color>()
+            .min_dimensions(256, 256)
+            .build())
+    }
+
+    /// Greyscale PNG, with `PNG_MODULE_SIZE` pixels per module.
+    pub fn png_qr_code(url: &str) -> Result<Vec<u8>, JoinError> {
+        let qr_code = QrCode::new(url)?;
+        let modules = qr_code.width();
+        let size = (modules + 2 * PNG_QUIET_ZONE) * PNG_MODULE_SIZE;
+        let colours = qr_code.to_colors();
+        let mut pixels = vec![u8::MAX; size * size];
+        for (index, colour) in colours.iter().enumerate() {
+            if *colour == Color::Light {
+                continue;
+            }
+            let x = (index % modules + PNG_QUIET_ZONE) * PNG_MODULE_SIZE;
+            let y = (index / modules + PNG_QUIET_ZONE) * PNG_MODULE_SIZE;
+            for row in y..y + PNG_MODULE_SIZE {
+                pixels[row * size + x..row * size + x + PNG_MODULE_SIZE].fill(0);
+            }
+        }
+
+        let mut png = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png, size as u32, size as u32);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&pixels)?;
+        writer.finish()?;
+        Ok(png)
+    }
+
+    /// Answers the requests for the QR codes, leaving the rest unanswered.
+    pub fn serve(&self, request: &HttpRequest) -> Option<HttpResponse> {
+        if request.path != "/join.svg" && request.path != "/join.png" {
+            return None;
+        }
+        if request.method != "GET" && request.method != "HEAD" {
+            return Some(HttpResponse::text(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed."));
+        }
+        let url = self.url(request.query.as_deref());
+        let response = match request.path.as_str() {
+            "/join.svg" => Self::svg_qr_code(&url)
+                .map(|svg| HttpResponse::new(StatusCode::OK, "image/svg+xml", svg.into_bytes())),
+            _ => Self::png_qr_code(&url)
+                .map(|png| HttpResponse::new(StatusCode::OK, "image/png", png)),
+        };
+        Some(response.unwrap_or_else(|error| {
+            HttpResponse::text(StatusCode::BAD_REQUEST, &error.to_string())
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, path: &str, query: Option<&str>) -> HttpRequest {
+        HttpRequest {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: query.map(str::to_string),
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn urls_keep_only_the_presets_given() {
+        let join_link = JoinLink::new("0.0.0.0", 8080, Some("lamarrs.local"));
+        assert_eq!(join_link.url(None), "http://lamarrs.local:8080/");
+        assert_eq!(join_link.url(Some("utm_source=poster&group=")), "http://lamarrs.local:8080/");
+        assert_eq!(
+            join_link.url(Some("section=Stalls&utm_source=poster&group=&seat=Row%20F%2C%2012")),
+            "http://lamarrs.local:8080/?section=Stalls&seat=Row+F%2C+12"
+        );
+    }
+
+    #[test]
+    fn urls_point_to_the_bound_address_without_public_hostname() {
+        let url = |bind_ip, port| JoinLink::new(bind_ip, port, None).url(None);
+        assert_eq!(url("192.168.1.20", 8080), "http://192.168.1.20:8080/");
+        assert_eq!(url("fe80::1", 80), "http://[fe80::1]:80/");
+        assert_eq!(url("::", 8080), "http://[::]:8080/");
+        assert_eq!(url("localhost", 8080), "http://localhost:8080/");
+    }
+
+    #[test]
+    fn serves_the_qr_codes_of_the_url_with_presets() {
+        let join_link = JoinLink::new("0.0.0.0", 8080, Some("lamarrs.local"));
+
+        let svg = join_link.serve(&request("GET", "/join.svg", Some("group=choir"))).unwrap();
+        assert_eq!(svg.status, StatusCode::OK);
+        assert_eq!(svg.headers, [("Content-Type".to_string(), "image/svg+xml".to_string())]);
+        assert!(String::from_utf8(svg.body).unwrap().contains("<svg"));
+
+        let png = join_link.serve(&request("HEAD", "/join.png", Some("group=choir"))).unwrap();
+        assert_eq!(png.status, StatusCode::OK);
+        assert_eq!(png.headers, [("Content-Type".to_string(), "image/png".to_string())]);
+        let reader = png::Decoder::new(png.body.as_slice()).read_info().unwrap();
+        let modules = QrCode::new("http://lamarrs.local:8080/?group=choir").unwrap().width();
+        let size = (modules + 2 * PNG_QUIET_ZONE) * PNG_MODULE_SIZE;
+        assert_eq!(reader.info().width as usize, size);
+        assert_eq!(reader.info().height as usize, size);
+    }
+
+    #[test]
+    fn only_answers_gets_and_heads_of_the_qr_codes() {
+        let join_link = JoinLink::new("0.0.0.0", 8080, None);
+        let post = join_link.serve(&request("POST", "/join.svg", None)).unwrap();
+        assert_eq!(post.status, StatusCode::METHOD_NOT_ALLOWED);
+        assert!(join_link.serve(&request("GET", "/index.html", None)).is_none());
+        assert!(join_link.serve(&request("GET", "/join.jpg", None)).is_none());
+    }
+}
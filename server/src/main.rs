@@ -1,27 +1,55 @@
 use std::env;
 use std::io;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 mod client_factory;
+mod clock;
 mod client_handler;
+mod dmx_input;
+mod events;
+mod http;
+mod http_api;
+mod join;
+mod media;
+mod metrics;
+mod midi_file;
 mod mqtt;
+mod osc;
+mod seat_map;
 mod sequencer;
 mod services;
+mod show_control;
+mod static_files;
+mod status;
 //mod test; Tests are all broken, will fix them as soon as possible.
 
 use crate::client_factory::ClientBuilder;
+use crate::dmx_input::{DmxInput, DmxInputConfig, DmxInputError};
+use crate::events::EventPublisher;
+use crate::http_api::HttpApi;
+use crate::join::JoinLink;
+use crate::media::MediaLibrary;
+use crate::midi_file::MidiFilePlayer;
+use crate::osc::{OscConfig, OscInterface};
+use crate::seat_map::SeatMap;
 use crate::sequencer::Sequencer;
 use crate::services::service::ColourService;
 use crate::services::service::MidiService;
 use crate::services::service::PlaybackService;
 use crate::services::service::SubtitleService;
 use crate::services::LamarrsService;
+use crate::show_control::{ShowControl, ShowControlSource, MSC_ALL_CALL};
+use crate::static_files::StaticFiles;
+use crate::status::StatusService;
 use clap::Parser;
-use color_eyre::eyre::eyre;
+use color_eyre::eyre::{eyre, WrapErr};
 use color_eyre::Result;
 use mqtt::MqttInterface;
 use tokio::net::TcpListener;
 use tracing::instrument;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 use tracing_subscriber::filter::{EnvFilter, ParseError};
 use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt};
 
@@ -57,10 +85,67 @@ pub struct Args {
     /// Port number
     #[arg(short, long, default_value_t = 8080)]
     pub port: u16,
+    /// Hostname the audience phones reach the Server at, like `lamarrs.local`, used in the join
+    /// URL and its QR code instead of the Server IP.
+    #[arg(long)]
+    pub public_hostname: Option<String>,
     /// Relative Path to the executable where to look for the sequencer yaml
     /// file with the show list of instructions.
     #[arg(long)]
     pub sequence_path: PathBuf,
+    /// Relative Path to the executable where to look for the CSV or YAML seat map,
+    /// used to locate the Clients registering with a seat.
+    #[arg(long)]
+    pub seat_map_path: Option<PathBuf>,
+    /// Relative Path to the executable where to look for the media files of the show,
+    /// published to the Clients so they can fetch the ones they miss.
+    #[arg(long)]
+    pub media_path: Option<PathBuf>,
+    /// Relative Path to the executable of a folder served over plain HTTP on the WebSocket port,
+    /// like the `dist/` one of the web UI, so the audience phones only need the Server URL.
+    #[arg(long)]
+    pub static_path: Option<PathBuf>,
+    /// Relative Path to the executable where to look for the YAML DMX input mapping, used to
+    /// control the colours of the Clients from a lighting console through Art-Net or sACN.
+    #[arg(long)]
+    pub dmx_input_path: Option<PathBuf>,
+    /// Relative Path to the executable where to look for the YAML OSC configuration, mapping the
+    /// OSC addresses show control software sends to the commands of the Server.
+    #[arg(long)]
+    pub osc_config_path: Option<PathBuf>,
+    /// Name, or part of it, of the MIDI input port to read MIDI Show Control and MIDI Time Code
+    /// from, to drive the sequence from a console or a playback rig.
+    #[arg(long, conflicts_with = "show_control_stream_path")]
+    pub show_control_midi_port: Option<String>,
+    /// Relative Path to the executable of a file or named pipe with raw MIDI bytes, read as the
+    /// show control MIDI input port. Useful to try a show without the rig.
+    #[arg(long)]
+    pub show_control_stream_path: Option<PathBuf>,
+    /// Device ID this Server answers MIDI Show Control messages for. 127 answers all of them.
+    #[arg(long, default_value_t = MSC_ALL_CALL)]
+    pub msc_device_id: u8,
+    /// Address to serve the HTTP API on, to inspect the Clients and control the services and
+    /// the sequence, and the Prometheus metrics at `/metrics`. Disabled if not given.
+    #[arg(long)]
+    pub api_address: Option<SocketAddr>,
+    /// Token the HTTP API requests controlling the show must carry, as
+    /// `Authorization: Bearer <token>`. Without it, they are only accepted from this host.
+    #[arg(long, requires = "api_address")]
+    pub api_token: Option<String>,
+    /// Milliseconds the Scenes are sent in advance, so all the Clients perform them at the same
+    /// time. Clients receiving them later than that drop them. 0 disables the scheduling.
+    #[arg(long, default_value_t = 250)]
+    pub scene_lead_time_ms: u64,
+    /// Prefix of the MQTT topics, where the orchestrators send their messages to and follow the
+    /// status and the events of the Server.
+    #[arg(long, default_value = "lamarrs")]
+    pub mqtt_topic_prefix: String,
+    /// Hostname or IP of the MQTT broker the orchestrators are connected to.
+    #[arg(long, default_value = "localhost")]
+    pub mqtt_host: String,
+    /// Port of the MQTT broker.
+    #[arg(long, default_value_t = 1883)]
+    pub mqtt_port: u16,
 }
 
 #[tokio::main]
@@ -81,33 +166,147 @@ async fn main() -> Result<()> {
     let listener = try_socket?;
     info!("Listening on: {}", server_ip_addr);
 
+    let join_link = JoinLink::new(&args.server_ip, args.port, args.public_hostname.as_deref());
+    let join_url = join_link.url(None);
+    if args.public_hostname.is_none()
+        && args
+            .server_ip
+            .parse::<IpAddr>()
+            .is_ok_and(|server_ip| server_ip.is_unspecified())
+    {
+        warn!("The join URL {} can't be reached by the audience phones, set --public-hostname.", join_url);
+    }
+    match JoinLink::terminal_qr_code(&join_url) {
+        Ok(qr_code) => info!("Join the show at {}\n{}", join_url, qr_code),
+        Err(error) => warn!("Join the show at {}, {}.", join_url, error),
+    }
+
     // Service creation.
     // Order of creation is important, since the channels
     // pipelines to send and receive messages to each Actor.
+    // Events of the Server, relayed to the orchestrators by the MQTT interface.
+    let (events, events_inbox) = EventPublisher::channel();
     debug!("Creating SubtitleService");
-    let mut subtitle_service = SubtitleService::new();
+    let mut subtitle_service = SubtitleService::new(events.clone());
     debug!("Creating ColourService");
-    let mut colour_service = ColourService::new();
+    let mut colour_service = ColourService::new(events.clone());
     debug!("Creating PlaybackService");
-    let mut playback_service = PlaybackService::new();
+    let mut playback_service = PlaybackService::new(events.clone());
     debug!("Creating MidiService");
-    let mut midi_service= MidiService::new();
+    let mut midi_service= MidiService::new(events.clone());
+    debug!("Creating MidiFilePlayer");
+    // MIDI files are looked for next to the sequence file, as the files it references.
+    let mut midi_file_player = MidiFilePlayer::new(
+        midi_service.sender.clone(),
+        args.sequence_path
+            .parent()
+            .map(|sequence_directory| sequence_directory.to_path_buf())
+            .unwrap_or_default(),
+        Duration::from_millis(args.scene_lead_time_ms),
+    );
+    debug!("Creating StatusService");
+    let mut status_service = StatusService::new();
     debug!("Creating MQTT Interface");
     let mut mqtt_interface = MqttInterface::new(
         subtitle_service.sender.clone(),
         colour_service.sender.clone(),
         playback_service.sender.clone(),
         midi_service.sender.clone(),
+        midi_file_player.sender.clone(),
+        Duration::from_millis(args.scene_lead_time_ms),
+        events_inbox,
+        &args.mqtt_host,
+        args.mqtt_port,
+        &args.mqtt_topic_prefix,
     );
+    let mut dmx_input = match args.dmx_input_path {
+        Some(dmx_input_path) => {
+            debug!("Creating DMX input");
+            let config = DmxInputConfig::load(&dmx_input_path).wrap_err_with(|| {
+                format!("Failed to load DMX input {}", dmx_input_path.display())
+            })?;
+            Some(DmxInput::new(
+                colour_service.sender.clone(),
+                config,
+                Duration::from_millis(args.scene_lead_time_ms),
+            ))
+        }
+        None => None,
+    };
     debug!("Creating Sequencer Service");
     let mut sequencer = Sequencer::new(
         subtitle_service.sender.clone(),
         colour_service.sender.clone(),
         playback_service.sender.clone(),
         midi_service.sender.clone(),
+        midi_file_player.sender.clone(),
         args.sequence_path,
+        Duration::from_millis(args.scene_lead_time_ms),
+        events.clone(),
     );
 
+    let mut osc_interface = match args.osc_config_path {
+        Some(osc_config_path) => {
+            debug!("Creating OSC Interface");
+            let config = OscConfig::load(&osc_config_path).wrap_err_with(|| {
+                format!("Failed to load OSC configuration {}", osc_config_path.display())
+            })?;
+            Some(OscInterface::new(
+                subtitle_service.sender.clone(),
+                colour_service.sender.clone(),
+                sequencer.sender.clone(),
+                config,
+                Duration::from_millis(args.scene_lead_time_ms),
+            ))
+        }
+        None => None,
+    };
+
+    let show_control_source = match (args.show_control_midi_port, args.show_control_stream_path) {
+        (Some(port_name), _) => Some(ShowControlSource::MidiPort(port_name)),
+        (None, Some(stream_path)) => Some(ShowControlSource::Stream(stream_path)),
+        (None, None) => None,
+    };
+    let mut show_control = show_control_source.map(|source| {
+        debug!("Creating Show control input");
+        ShowControl::new(sequencer.command_sender.clone(), source, args.msc_device_id)
+    });
+
+    let mut http_api = args.api_address.map(|api_address| {
+        debug!("Creating HTTP API");
+        HttpApi::new(
+            subtitle_service.sender.clone(),
+            colour_service.sender.clone(),
+            playback_service.sender.clone(),
+            midi_service.sender.clone(),
+            sequencer.sender.clone(),
+            sequencer.command_sender.clone(),
+            status_service.sender.clone(),
+            api_address,
+            args.api_token.clone(),
+            Duration::from_millis(args.scene_lead_time_ms),
+        )
+    });
+
+    let seat_map = match args.seat_map_path {
+        Some(seat_map_path) => SeatMap::load(&seat_map_path)
+            .wrap_err_with(|| format!("Failed to load seat map {}", seat_map_path.display()))?,
+        None => SeatMap::default(),
+    };
+
+    let media_library = match args.media_path {
+        Some(media_path) => MediaLibrary::load(&media_path)
+            .wrap_err_with(|| format!("Failed to load media folder {}", media_path.display()))?,
+        None => MediaLibrary::default(),
+    };
+
+    let static_files = match args.static_path {
+        Some(static_path) => Some(Arc::new(StaticFiles::load(&static_path).wrap_err_with(
+            || format!("Failed to load static files folder {}", static_path.display()),
+        )?)),
+        None => None,
+    };
+
     debug!("Creating Client builder");
     let client_builder = ClientBuilder::new(
         subtitle_service.sender.clone(),
@@ -115,6 +314,12 @@ async fn main() -> Result<()> {
         playback_service.sender.clone(),
         midi_service.sender.clone(),
         sequencer.sender.clone(),
+        status_service.sender.clone(),
+        Arc::new(seat_map),
+        Arc::new(media_library),
+        static_files,
+        Arc::new(join_link),
+        events,
     );
 
     tokio::select! {
@@ -130,9 +335,54 @@ async fn main() -> Result<()> {
         result = midi_service.run() => {
             Err(eyre!("MQTT service crashed: {:?}", result))?
         }
+        result = midi_file_player.run() => {
+            Err(eyre!("MIDI file player crashed: {:?}", result))?
+        }
         result = mqtt_interface.run() => {
             Err(eyre!("MQTT service crashed: {:?}", result))?
         }
+        result = async {
+            match dmx_input.as_mut() {
+                // The rest of the show goes on without the console.
+                Some(dmx_input) => match dmx_input.run().await {
+                    Err(DmxInputError::Network(error)) => {
+                        error!(?error, "DMX input disabled, the universe can't be received.");
+                        std::future::pending().await
+                    }
+                    result => result,
+                },
+                None => std::future::pending().await,
+            }
+        } => {
+            Err(eyre!("DMX input crashed: {:?}", result))?
+        }
+        result = async {
+            match osc_interface.as_mut() {
+                Some(osc_interface) => osc_interface.run().await,
+                None => std::future::pending().await,
+            }
+        } => {
+            Err(eyre!("OSC interface crashed: {:?}", result))?
+        }
+        result = async {
+            match show_control.as_mut() {
+                Some(show_control) => show_control.run().await,
+                None => std::future::pending().await,
+            }
+        } => {
+            Err(eyre!("Show control input crashed: {:?}", result))?
+        }
+        result = async {
+            match http_api.as_mut() {
+                Some(http_api) => http_api.run().await,
+                None => std::future::pending().await,
+            }
+        } => {
+            Err(eyre!("HTTP API crashed: {:?}", result))?
+        }
+        result = status_service.run() => {
+            Err(eyre!("Status service crashed: {:?}", result))?
+        }
         result = sequencer.run() => {
             Err(eyre!("Sequencer crashed: {:?}", result))?
         }
@@ -0,0 +1,189 @@
+//! Media library
+//!
+//! The [`MediaLibrary`] holds the audio files of the show, read from the media folder of the
+//! Server at startup. Its manifest is sent to the Clients able to play audio when they register,
+//! and they fetch from it the files they miss, so nobody has to copy them by hand before the show.
+//! Only the files at the top level of the folder are published.
+
+use std::{
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use lamarrs_utils::media::{
+    is_plain_file_name, MediaEntry, MediaFileName, MediaManifest, Sha256Digest,
+    MEDIA_MANIFEST_PAGE_SIZE,
+};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tracing::{info, warn};
+
+#[derive(Debug, Error)]
+pub enum MediaLibraryError {
+    #[error("The media folder could not be read")]
+    Io(#[from] io::Error),
+}
+
+#[derive(Debug, Default)]
+pub struct MediaLibrary {
+    directory: PathBuf,
+    entries: Vec<MediaEntry>,
+}
+
+impl MediaLibrary {
+    /// Reads all the files of the folder, hashing them.
+    pub fn load(directory: &Path) -> Result<Self, MediaLibraryError> {
+        let mut entries = Vec::new();
+        for dir_entry in fs::read_dir(directory)? {
+            let path = dir_entry?.path();
+            if !path.is_file() {
+                continue;
+            }
+            let Some(name) = path
+                .file_name()
+                .and_then(|file_name| file_name.to_str())
+                .filter(|file_name| is_plain_file_name(file_name))
+                .and_then(|file_name| MediaFileName::try_from(file_name).ok())
+            else {
+                warn!("Skipping media file {}: its name must be UTF-8 and up to 55 bytes long.", path.display());
+                continue;
+            };
+            let (size, sha256) = hash_file(&path)?;
+            entries.push(MediaEntry { name, size, sha256 });
+        }
+        entries.sort_by(|entry, other| entry.name.cmp(&other.name));
+        info!("Loaded {} media files from {}", entries.len(), directory.display());
+        Ok(Self {
+            directory: directory.to_path_buf(),
+            entries,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// The manifest, split in the pages sent to the Clients.
+    pub fn manifest_pages(&self) -> Vec<MediaManifest> {
+        let pages = self.entries.chunks(MEDIA_MANIFEST_PAGE_SIZE);
+        let page_count = pages.len() as u16;
+        pages
+            .enumerate()
+            .map(|(page, entries)| MediaManifest {
+                // Chunks are never bigger than a page.
+                entries: entries.iter().cloned().collect(),
+                page: page as u16,
+                pages: page_count,
+            })
+            .collect()
+    }
+
+    /// Path and manifest entry of a published file. Files out of the manifest, or out of the
+    /// media folder, are never resolved, so Clients can't fetch anything else from the Server disk.
+    pub fn resolve(&self, name: &str) -> Option<(PathBuf, &MediaEntry)> {
+        if !is_plain_file_name(name) {
+            return None;
+        }
+        self.entries
+            .iter()
+            .find(|entry| entry.name == name)
+            .map(|entry| (self.directory.join(entry.name.as_str()), entry))
+    }
+}
+
+/// Size and SHA-256 hash of a file.
+pub fn hash_file(path: &Path) -> Result<(u64, Sha256Digest), io::Error> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0; 64 * 1024];
+    let mut size = 0;
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        size += read as u64;
+    }
+    Ok((size, hasher.finalize().into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty media folder, in a folder of its own so the tests can run in parallel.
+    /// Returns the media folder.
+    fn media_folder(name: &str) -> PathBuf {
+        let base =
+            std::env::temp_dir().join(format!("lamarrs-media-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&base);
+        let directory = base.join("media");
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    #[test]
+    fn empty_folders_publish_no_pages() {
+        let library = MediaLibrary::load(&media_folder("empty")).unwrap();
+        assert!(library.is_empty());
+        assert!(library.manifest_pages().is_empty());
+    }
+
+    #[test]
+    fn manifests_are_split_in_pages_sorted_by_name() {
+        let directory = media_folder("pages");
+        for index in (0..9).rev() {
+            fs::write(directory.join(format!("track-{index}.wav")), vec![index; index as usize])
+                .unwrap();
+        }
+        let library = MediaLibrary::load(&directory).unwrap();
+        assert_eq!(library.len(), 9);
+        let pages = library.manifest_pages();
+        assert_eq!(pages.len(), 2);
+        assert_eq!((pages[0].page, pages[0].pages, pages[0].entries.len()), (0, 2, 8));
+        assert_eq!((pages[1].page, pages[1].pages, pages[1].entries.len()), (1, 2, 1));
+        assert!(!pages[0].is_last_page());
+        assert!(pages[1].is_last_page());
+        assert_eq!(pages[0].entries[0].name, "track-0.wav");
+        let last = &pages[1].entries[0];
+        assert_eq!(last.name, "track-8.wav");
+        assert_eq!(last.size, 8);
+        assert_eq!(last.sha256, <[u8; 32]>::from(Sha256::digest([8; 8])));
+    }
+
+    #[test]
+    fn only_files_at_the_top_level_with_short_names_are_published() {
+        let directory = media_folder("skipped");
+        fs::write(directory.join("intro.wav"), "intro").unwrap();
+        fs::create_dir(directory.join("extras")).unwrap();
+        fs::write(directory.join("extras").join("bonus.wav"), "bonus").unwrap();
+        let long_name = format!("{}.wav", "a".repeat(52));
+        assert!(long_name.len() > 55);
+        fs::write(directory.join(long_name), "long").unwrap();
+        let library = MediaLibrary::load(&directory).unwrap();
+        let pages = library.manifest_pages();
+        assert_eq!(pages.len(), 1);
+        let names = pages[0].entries.iter().map(|entry| entry.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["intro.wav"]);
+    }
+
+    #[test]
+    fn only_files_in_the_manifest_are_resolved() {
+        let directory = media_folder("resolve");
+        fs::write(directory.join("intro.wav"), "intro").unwrap();
+        fs::write(directory.parent().unwrap().join("x"), "secret").unwrap();
+        let library = MediaLibrary::load(&directory).unwrap();
+        let (path, entry) = library.resolve("intro.wav").unwrap();
+        assert_eq!(path, directory.join("intro.wav"));
+        assert_eq!(entry.size, 5);
+        for name in ["../x", "..", "", "outro.wav", "/intro.wav", "media/intro.wav"] {
+            assert!(library.resolve(name).is_none(), "{name} was resolved");
+        }
+    }
+}
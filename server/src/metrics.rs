@@ -0,0 +1,295 @@
+//! Metrics
+//!
+//! Counters and gauges of the load of the Server, rendered in the Prometheus text format by the
+//! `/metrics` endpoint of the HTTP API, so they are only served when the Server is started with
+//! `--api-address`. They are atomics in the static [`METRICS`], so the actors update them without
+//! locking or messaging anyone.
+//!
+//! The handoff latency is measured by the Services, from receiving an action until the handlers
+//! of all the targeted Clients accepted it, as a slow Client keeps the Service waiting once its
+//! queue is full. It doesn't include writing the action in the WebSockets, which the handlers
+//! measure apart, nor the subtitle pages sent after the first one.
+
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicI64, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use lamarrs_utils::Service;
+use strum::IntoEnumIterator;
+
+pub static METRICS: Metrics = Metrics::new();
+
+/// Upper bounds, in seconds, of the buckets of the latency histograms.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0,
+];
+
+pub struct Histogram {
+    /// Observations of each bucket alone, cumulated when rendered.
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    sum_us: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; LATENCY_BUCKETS.len()],
+            sum_us: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        // Observations above the last bound are only in `+Inf`, which is the count.
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum_us
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, output: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                output,
+                "{name}_bucket{{{labels}{separator}le=\"{bound}\"}} {cumulative}"
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(output, "{name}_bucket{{{labels}{separator}le=\"+Inf\"}} {count}");
+        let sum = self.sum_us.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{labels}}}")
+        };
+        let _ = writeln!(output, "{name}_sum{labels} {sum}");
+        let _ = writeln!(output, "{name}_count{labels} {count}");
+    }
+}
+
+/// Reads one of the counters of a Service.
+type ServiceCounter = fn(&ServiceMetrics) -> &AtomicU64;
+
+pub struct ServiceMetrics {
+    pub subscribers: AtomicU64,
+    pub actions_dispatched: AtomicU64,
+    /// Actions that could not be handed to the handler of a targeted Client.
+    pub send_failures: AtomicU64,
+    pub handoff_latency: Histogram,
+}
+
+impl ServiceMetrics {
+    const fn new() -> Self {
+        Self {
+            subscribers: AtomicU64::new(0),
+            actions_dispatched: AtomicU64::new(0),
+            send_failures: AtomicU64::new(0),
+            handoff_latency: Histogram::new(),
+        }
+    }
+}
+
+pub struct Metrics {
+    binary_clients: AtomicI64,
+    text_clients: AtomicI64,
+    subtitle: ServiceMetrics,
+    colour: ServiceMetrics,
+    audio_player: ServiceMetrics,
+    midi: ServiceMetrics,
+    pub websocket_send_failures: AtomicU64,
+    pub websocket_send_latency: Histogram,
+    pub heartbeat_timeouts: AtomicU64,
+    /// Index of the latest step played, -1 before the first one.
+    pub sequencer_step: AtomicI64,
+    pub mqtt_messages_received: AtomicU64,
+}
+
+/// Connection of a registered Client, counted until dropped.
+pub struct ConnectedClient {
+    gauge: &'static AtomicI64,
+}
+
+impl Drop for ConnectedClient {
+    fn drop(&mut self) {
+        self.gauge.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            binary_clients: AtomicI64::new(0),
+            text_clients: AtomicI64::new(0),
+            subtitle: ServiceMetrics::new(),
+            colour: ServiceMetrics::new(),
+            audio_player: ServiceMetrics::new(),
+            midi: ServiceMetrics::new(),
+            websocket_send_failures: AtomicU64::new(0),
+            websocket_send_latency: Histogram::new(),
+            heartbeat_timeouts: AtomicU64::new(0),
+            sequencer_step: AtomicI64::new(-1),
+            mqtt_messages_received: AtomicU64::new(0),
+        }
+    }
+
+    pub fn service(&self, service: &Service) -> &ServiceMetrics {
+        match service {
+            Service::Subtitle => &self.subtitle,
+            Service::Colour => &self.colour,
+            Service::AudioPlayer => &self.audio_player,
+            Service::Midi => &self.midi,
+        }
+    }
+
+    /// Counts a Client talking binary postcard messages, or JSON text ones otherwise.
+    pub fn client_connected(&'static self, is_binary: bool) -> ConnectedClient {
+        let gauge = if is_binary {
+            &self.binary_clients
+        } else {
+            &self.text_clients
+        };
+        gauge.fetch_add(1, Ordering::Relaxed);
+        ConnectedClient { gauge }
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+
+        let _ = writeln!(output, "# HELP lamarrs_connected_clients Registered Clients connected, by wire format.");
+        let _ = writeln!(output, "# TYPE lamarrs_connected_clients gauge");
+        for (wire, gauge) in [("binary", &self.binary_clients), ("text", &self.text_clients)] {
+            let _ = writeln!(
+                output,
+                "lamarrs_connected_clients{{wire=\"{wire}\"}} {}",
+                gauge.load(Ordering::Relaxed)
+            );
+        }
+
+        let service_counters: [(&str, &str, &str, ServiceCounter); 3] = [
+            (
+                "lamarrs_service_subscribers",
+                "gauge",
+                "Clients subscribed to each Service.",
+                |metrics| &metrics.subscribers,
+            ),
+            (
+                "lamarrs_actions_dispatched_total",
+                "counter",
+                "Actions each Service sent to its Clients.",
+                |metrics| &metrics.actions_dispatched,
+            ),
+            (
+                "lamarrs_send_failures_total",
+                "counter",
+                "Actions each Service could not hand to the handler of a Client.",
+                |metrics| &metrics.send_failures,
+            ),
+        ];
+        for (name, kind, help, counter) in service_counters {
+            let _ = writeln!(output, "# HELP {name} {help}");
+            let _ = writeln!(output, "# TYPE {name} {kind}");
+            for service in Service::iter() {
+                let _ = writeln!(
+                    output,
+                    "{name}{{service=\"{service}\"}} {}",
+                    counter(self.service(&service)).load(Ordering::Relaxed)
+                );
+            }
+        }
+
+        let _ = writeln!(output, "# HELP lamarrs_service_handoff_latency_seconds Time from a Service receiving an action until the handlers of all its targets accepted it, before they write it in the WebSocket.");
+        let _ = writeln!(output, "# TYPE lamarrs_service_handoff_latency_seconds histogram");
+        for service in Service::iter() {
+            self.service(&service).handoff_latency.render(
+                &mut output,
+                "lamarrs_service_handoff_latency_seconds",
+                &format!("service=\"{service}\""),
+            );
+        }
+
+        let _ = writeln!(output, "# HELP lamarrs_websocket_send_latency_seconds Time the Client handlers take to write a message in the WebSocket.");
+        let _ = writeln!(output, "# TYPE lamarrs_websocket_send_latency_seconds histogram");
+        self.websocket_send_latency
+            .render(&mut output, "lamarrs_websocket_send_latency_seconds", "");
+
+        let counters = [
+            (
+                "lamarrs_websocket_send_failures_total",
+                "counter",
+                "Messages the Client handlers could not write in the WebSocket.",
+                self.websocket_send_failures.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "lamarrs_heartbeat_timeouts_total",
+                "counter",
+                "Connections closed as the Client did not answer the heartbeat.",
+                self.heartbeat_timeouts.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "lamarrs_sequencer_step",
+                "gauge",
+                "Index of the latest step of the sequence played, -1 before the first one.",
+                self.sequencer_step.load(Ordering::Relaxed),
+            ),
+            (
+                "lamarrs_mqtt_messages_received_total",
+                "counter",
+                "Messages received from the MQTT broker.",
+                self.mqtt_messages_received.load(Ordering::Relaxed) as i64,
+            ),
+        ];
+        for (name, kind, help, value) in counters {
+            let _ = writeln!(output, "# HELP {name} {help}");
+            let _ = writeln!(output, "# TYPE {name} {kind}");
+            let _ = writeln!(output, "{name} {value}");
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let histogram = Histogram::new();
+        histogram.observe(Duration::from_micros(50));
+        histogram.observe(Duration::from_micros(100));
+        histogram.observe(Duration::from_millis(3));
+        histogram.observe(Duration::from_secs(2));
+
+        let mut output = String::new();
+        histogram.render(&mut output, "latency", "service=\"Colour\"");
+
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[0], "latency_bucket{service=\"Colour\",le=\"0.0001\"} 2");
+        assert_eq!(lines[5], "latency_bucket{service=\"Colour\",le=\"0.005\"} 3");
+        assert_eq!(lines[11], "latency_bucket{service=\"Colour\",le=\"1\"} 3");
+        assert_eq!(lines[12], "latency_bucket{service=\"Colour\",le=\"+Inf\"} 4");
+        assert_eq!(lines[13], "latency_sum{service=\"Colour\"} 2.00315");
+        assert_eq!(lines[14], "latency_count{service=\"Colour\"} 4");
+    }
+
+    #[test]
+    fn histograms_without_labels_only_label_the_buckets() {
+        let histogram = Histogram::new();
+        histogram.observe(Duration::from_millis(1));
+
+        let mut output = String::new();
+        histogram.render(&mut output, "latency", "");
+
+        assert!(output.starts_with("latency_bucket{le=\"0.0001\"} 0\n"));
+        assert!(output.ends_with("latency_sum 0.001\nlatency_count 1\n"));
+    }
+}
@@ -0,0 +1,460 @@
+//! MIDI file player
+//!
+//! Plays Standard MIDI Files from the Server. The events of all the tracks are merged in a single
+//! timeline, following the tempo changes of the file, and sent as MIDI instructions to the Clients
+//! through the MIDI service. Each event is sent `scene_lead_time` before it must be played and
+//! scheduled at its time in the Server clock, so the Clients play the arrangement together no
+//! matter how far from the Server they are. Only one file is played at a time.
+
+use std::{
+    collections::BTreeSet,
+    fs, io,
+    path::{Component, Path, PathBuf},
+    time::Duration,
+};
+
+use lamarrs_utils::{
+    action_messages::Action,
+    clock::Schedule,
+    midi::{MidiCommand, MidiFilePlayback, MAX_SYSEX_LENGTH},
+    target_selector::TargetSelector,
+    MidiInstruction,
+};
+use midly::{MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+use thiserror::Error;
+use tokio::{
+    sync::mpsc::{channel, Receiver, Sender},
+    task::JoinHandle,
+    time::{sleep_until, Instant},
+};
+use tracing::{debug, error, info, instrument, warn};
+
+use crate::{
+    clock::{schedule_in, server_time_us},
+    services::InternalEventMessageServer,
+};
+
+/// Tempo of the files until they set one, in microseconds per quarter note.
+const DEFAULT_TEMPO_US: u64 = 500_000;
+
+/// Controller silencing the notes still sounding in a channel.
+const ALL_NOTES_OFF: u8 = 123;
+
+#[derive(Debug, Error)]
+pub enum MidiFileError {
+    #[error("The MIDI file could not be read")]
+    Io(#[from] io::Error),
+    #[error("The MIDI file is malformed: {0}")]
+    Parse(#[from] midly::Error),
+    #[error("The MIDI file {0} is not in the MIDI files folder")]
+    OutOfFolder(String),
+}
+
+/// Path of a MIDI file in the folder of the MIDI files. Names leaving the folder, like absolute
+/// ones, ones with `..` or links to files out of it, are refused, as they come from the network.
+pub fn resolve(directory: &Path, file: &str) -> Result<PathBuf, MidiFileError> {
+    let out_of_folder = || MidiFileError::OutOfFolder(file.to_string());
+    let relative = Path::new(file);
+    if file.is_empty()
+        || !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(out_of_folder());
+    }
+    // The Server may be started from the folder of the sequence.
+    let directory = if directory.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        directory
+    };
+    let directory = directory.canonicalize()?;
+    let path = directory.join(relative).canonicalize()?;
+    if !path.starts_with(&directory) {
+        return Err(out_of_folder());
+    }
+    Ok(path)
+}
+
+/// An event of the file, with the time it must be played at since the start of the playback.
+#[derive(Clone, Debug)]
+pub struct MidiFileEvent {
+    pub at: Duration,
+    pub track: u16,
+    pub instruction: MidiInstruction,
+}
+
+/// Reads the file into the timeline of the instructions it plays. Events with no equivalent
+/// instruction, like the aftertouch, are left out.
+pub fn load(path: &Path) -> Result<Vec<MidiFileEvent>, MidiFileError> {
+    let bytes = fs::read(path)?;
+    let smf = Smf::parse(&bytes)?;
+
+    // Events of all the tracks in ticks since the start, with the index of the track they are in.
+    let mut events = Vec::new();
+    for (track, track_events) in smf.tracks.iter().enumerate() {
+        let mut ticks = 0u64;
+        for track_event in track_events {
+            ticks += track_event.delta.as_int() as u64;
+            events.push((ticks, track as u16, track_event.kind));
+        }
+    }
+    // Stable, so the events at the same tick keep the order of the file.
+    events.sort_by_key(|(ticks, track, _)| (*ticks, *track));
+
+    let mut timeline = Vec::new();
+    let mut tempo_us = DEFAULT_TEMPO_US;
+    let (mut last_ticks, mut last_us) = (0u64, 0u64);
+    for (ticks, track, kind) in events {
+        // Times are accumulated at every event, as the tempo can change in between.
+        last_us += match smf.header.timing {
+            Timing::Metrical(ticks_per_beat) => {
+                (ticks - last_ticks) * tempo_us / ticks_per_beat.as_int().max(1) as u64
+            }
+            Timing::Timecode(fps, ticks_per_frame) => {
+                ((ticks - last_ticks) as f64 * 1_000_000.0
+                    / (fps.as_f32() as f64 * ticks_per_frame.max(1) as f64)) as u64
+            }
+        };
+        last_ticks = ticks;
+        let (command, channel) = match kind {
+            TrackEventKind::Meta(MetaMessage::Tempo(tempo)) => {
+                tempo_us = tempo.as_int() as u64;
+                continue;
+            }
+            TrackEventKind::Midi { channel, message } => {
+                let command = match message {
+                    MidiMessage::NoteOn { key, vel } => MidiCommand::NoteOn {
+                        note: key.as_int(),
+                        velocity: vel.as_int(),
+                    },
+                    MidiMessage::NoteOff { key, vel } => MidiCommand::NoteOff {
+                        note: key.as_int(),
+                        velocity: vel.as_int(),
+                    },
+                    MidiMessage::Controller { controller, value } => MidiCommand::ControlChange {
+                        controller: controller.as_int(),
+                        value: value.as_int(),
+                    },
+                    MidiMessage::ProgramChange { program } => MidiCommand::ProgramChange {
+                        program: program.as_int(),
+                        bank: None,
+                    },
+                    MidiMessage::PitchBend { bend } => MidiCommand::PitchBend {
+                        bend: bend.as_int(),
+                    },
+                    MidiMessage::Aftertouch { .. } | MidiMessage::ChannelAftertouch { .. } => {
+                        continue
+                    }
+                };
+                (command, Some(channel.as_int() + 1))
+            }
+            TrackEventKind::SysEx(data) => {
+                // The Clients add the end byte themselves.
+                let data = data.strip_suffix(&[0xF7]).unwrap_or(data);
+                match heapless::Vec::from_slice(data) {
+                    Ok(data) => (MidiCommand::SysEx { data }, None),
+                    Err(_) => {
+                        warn!(
+                            "Skipping a SysEx of {} bytes in {}, the maximum is {}.",
+                            data.len(),
+                            path.display(),
+                            MAX_SYSEX_LENGTH
+                        );
+                        continue;
+                    }
+                }
+            }
+            TrackEventKind::Escape(_) | TrackEventKind::Meta(_) => continue,
+        };
+        timeline.push(MidiFileEvent {
+            at: Duration::from_micros(last_us),
+            track,
+            instruction: MidiInstruction { command, channel },
+        });
+    }
+    Ok(timeline)
+}
+
+/// Messages to control the [`MidiFilePlayer`].
+#[derive(Debug)]
+pub enum MidiFilePlayerMessage {
+    /// Replaces the file being played, if any. The target is used when the playback has no routes.
+    Play(Box<MidiFilePlayback>, Box<TargetSelector>),
+    Stop,
+}
+
+/// A file being played, with what is needed to silence it if it is stopped.
+#[derive(Debug)]
+struct Playing {
+    file: String,
+    task: JoinHandle<()>,
+    targets: Vec<TargetSelector>,
+    channels: BTreeSet<u8>,
+}
+
+pub struct MidiFilePlayer {
+    pub sender: Sender<MidiFilePlayerMessage>,
+    inbox: Receiver<MidiFilePlayerMessage>,
+    midi_service: Sender<InternalEventMessageServer>,
+    /// Folder the paths of the files are relative to.
+    directory: PathBuf,
+    /// How long before the Clients must play the events they are sent.
+    scene_lead_time: Duration,
+    playing: Option<Playing>,
+}
+
+impl MidiFilePlayer {
+    pub fn new(
+        midi_service: Sender<InternalEventMessageServer>,
+        directory: PathBuf,
+        scene_lead_time: Duration,
+    ) -> Self {
+        let (sender, inbox) = channel(32);
+        Self {
+            sender,
+            inbox,
+            midi_service,
+            directory,
+            scene_lead_time,
+            playing: None,
+        }
+    }
+
+    #[instrument(name = "MidiFilePlayer::run", skip(self), level = "INFO")]
+    pub async fn run(&mut self) {
+        while let Some(message) = self.inbox.recv().await {
+            match message {
+                MidiFilePlayerMessage::Play(midi_file_playback, target) => {
+                    self.stop().await;
+                    self.play(*midi_file_playback, *target).await;
+                }
+                MidiFilePlayerMessage::Stop => self.stop().await,
+            }
+        }
+    }
+
+    async fn play(&mut self, midi_file_playback: MidiFilePlayback, target: TargetSelector) {
+        let timeline = match tokio::task::spawn_blocking({
+            let directory = self.directory.clone();
+            let file = midi_file_playback.file.clone();
+            move || load(&resolve(&directory, &file)?)
+        })
+        .await
+        {
+            Ok(Ok(timeline)) => timeline,
+            result => {
+                error!(?result, "Failed to load MIDI file {}.", midi_file_playback.file);
+                return;
+            }
+        };
+
+        // Each event is sent to the targets of all the routes it follows.
+        let routed_timeline: Vec<(Duration, MidiInstruction, Vec<usize>)> =
+            if midi_file_playback.routes.is_empty() {
+                timeline
+                    .into_iter()
+                    .map(|event| (event.at, event.instruction, vec![0]))
+                    .collect()
+            } else {
+                timeline
+                    .into_iter()
+                    .filter_map(|event| {
+                        let routes: Vec<usize> = midi_file_playback
+                            .routes
+                            .iter()
+                            .enumerate()
+                            .filter(|(_, route)| {
+                                route.routes(event.track, event.instruction.channel)
+                            })
+                            .map(|(index, _)| index)
+                            .collect();
+                        (!routes.is_empty()).then_some((event.at, event.instruction, routes))
+                    })
+                    .collect()
+            };
+        let targets: Vec<TargetSelector> = if midi_file_playback.routes.is_empty() {
+            vec![target]
+        } else {
+            midi_file_playback
+                .routes
+                .iter()
+                .map(|route| route.target.clone())
+                .collect()
+        };
+        let channels = routed_timeline
+            .iter()
+            .filter_map(|(_, instruction, _)| instruction.channel)
+            .collect();
+        info!(
+            "Playing MIDI file {}, {} events.",
+            midi_file_playback.file,
+            routed_timeline.len()
+        );
+
+        let midi_service = self.midi_service.clone();
+        let lead_time = self.scene_lead_time;
+        let task_targets = targets.clone();
+        let task = tokio::spawn(async move {
+            // The first events are sent straight away, to be played `lead_time` later.
+            let start = Instant::now();
+            let start_us = server_time_us() + lead_time.as_micros() as u64;
+            for (at, instruction, routes) in routed_timeline {
+                sleep_until(start + at).await;
+                let schedule = (!lead_time.is_zero()).then(|| {
+                    let execute_at_us = start_us + at.as_micros() as u64;
+                    Schedule {
+                        execute_at_us,
+                        expires_at_us: Some(execute_at_us + lead_time.as_micros() as u64),
+                    }
+                });
+                for route in routes {
+                    let message = InternalEventMessageServer::PerformAction(
+                        Action::Midi(instruction.clone()),
+                        Box::new(task_targets[route].clone()),
+                        schedule.clone(),
+                    );
+                    if midi_service.send(message).await.is_err() {
+                        error!("MIDI service is gone, stopping the MIDI file.");
+                        return;
+                    }
+                }
+            }
+            debug!("MIDI file finished.");
+        });
+        self.playing = Some(Playing {
+            file: midi_file_playback.file.to_string(),
+            task,
+            targets,
+            channels,
+        });
+    }
+
+    /// Stops the file being played, silencing the notes it left sounding once the Clients played
+    /// the events they were already sent.
+    async fn stop(&mut self) {
+        let Some(playing) = self.playing.take() else {
+            return;
+        };
+        if playing.task.is_finished() {
+            return;
+        }
+        playing.task.abort();
+        info!("Stopping MIDI file {}.", playing.file);
+        for target in playing.targets {
+            for channel in &playing.channels {
+                let all_notes_off = MidiInstruction {
+                    command: MidiCommand::ControlChange {
+                        controller: ALL_NOTES_OFF,
+                        value: 0,
+                    },
+                    channel: Some(*channel),
+                };
+                if let Err(error) = self
+                    .midi_service
+                    .send(InternalEventMessageServer::PerformAction(
+                        Action::Midi(all_notes_off),
+                        Box::new(target.clone()),
+                        schedule_in(self.scene_lead_time),
+                    ))
+                    .await
+                {
+                    error!(?error, "Failed to silence the MIDI file {}.", playing.file);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Format 0 file at 96 ticks per quarter note and a tempo of 1 s per quarter note, playing
+    /// the middle C for one quarter note.
+    const MIDDLE_C: &[u8] = &[
+        b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 0, 0, 1, 0, 96, //
+        b'M', b'T', b'r', b'k', 0, 0, 0, 19, //
+        0x00, 0xFF, 0x51, 0x03, 0x0F, 0x42, 0x40, //
+        0x00, 0x90, 60, 64, //
+        0x60, 0x80, 60, 0, //
+        0x00, 0xFF, 0x2F, 0x00,
+    ];
+
+    /// Empty folder of its own for each test, so they can run in parallel.
+    fn folder(name: &str) -> PathBuf {
+        let folder = std::env::temp_dir().join(format!(
+            "lamarrs-midi-file-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(folder.join("songs")).unwrap();
+        folder
+    }
+
+    #[test]
+    fn files_are_played_following_the_tempo() {
+        let folder = folder("tempo");
+        fs::write(folder.join("songs/middle_c.mid"), MIDDLE_C).unwrap();
+
+        let timeline = load(&resolve(&folder, "songs/middle_c.mid").unwrap()).unwrap();
+
+        assert_eq!(timeline.len(), 2);
+        assert_eq!(timeline[0].at, Duration::ZERO);
+        assert_eq!(
+            timeline[0].instruction.command,
+            MidiCommand::NoteOn {
+                note: 60,
+                velocity: 64
+            }
+        );
+        assert_eq!(timeline[1].at, Duration::from_secs(1));
+        assert_eq!(
+            timeline[1].instruction.command,
+            MidiCommand::NoteOff {
+                note: 60,
+                velocity: 0
+            }
+        );
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn malformed_files_are_refused() {
+        let folder = folder("malformed");
+        fs::write(folder.join("broken.mid"), b"RIFF, not a MIDI file").unwrap();
+
+        assert!(matches!(
+            load(&resolve(&folder, "broken.mid").unwrap()),
+            Err(MidiFileError::Parse(_))
+        ));
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn files_out_of_the_folder_are_refused() {
+        let folder = folder("out-of-folder");
+        fs::write(folder.join("songs/middle_c.mid"), MIDDLE_C).unwrap();
+        let songs = folder.join("songs");
+        let outside = folder.join("outside.mid");
+        fs::write(&outside, MIDDLE_C).unwrap();
+
+        assert!(resolve(&songs, "./middle_c.mid").is_ok());
+        for file in ["", "../outside.mid", outside.to_str().unwrap()] {
+            assert!(
+                matches!(resolve(&songs, file), Err(MidiFileError::OutOfFolder(_))),
+                "{file} was not refused"
+            );
+        }
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&outside, songs.join("link.mid")).unwrap();
+            assert!(matches!(
+                resolve(&songs, "link.mid"),
+                Err(MidiFileError::OutOfFolder(_))
+            ));
+        }
+        fs::remove_dir_all(folder).unwrap();
+    }
+}
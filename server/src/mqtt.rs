@@ -1,126 +1,351 @@
+//! MQTT interface
+//!
+//! Orchestrators publish `OrchestrationMessage`s to `{prefix}/orchestrator`, and follow the Server
+//! through the topics under the same prefix:
+//!
+//! - `{prefix}/server/status`: retained `Online` status, replaced by the broker with the
+//!   `Offline` last will if the Server dies.
+//! - `{prefix}/events/...`: the [`ServerEvent`]s, like `{prefix}/events/clients/registered` or
+//!   `{prefix}/events/requests`, with the result of each orchestration message received.
+
+use lamarrs_utils::action_messages::Event as ActionEvent;
 use lamarrs_utils::orchestration_messages::OrchestrationMessage;
-use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, Publish, QoS};
+use lamarrs_utils::Service;
+use rumqttc::{AsyncClient, Event, EventLoop, LastWill, MqttOptions, Packet, Publish, QoS};
+use serde::Serialize;
+use std::sync::atomic::Ordering;
 use std::time::Duration;
-use tokio::sync::mpsc::Sender;
-use tracing::{debug, error, instrument};
+use tokio::sync::mpsc::{unbounded_channel, Receiver, Sender, UnboundedSender};
+use tracing::{debug, error, info, instrument, warn};
 
+use crate::clock::schedule_in;
+use crate::events::{RequestResult, ServerEvent, ServerStatus};
+use crate::metrics::METRICS;
+use crate::midi_file::MidiFilePlayerMessage;
 use crate::services::InternalEventMessageServer;
 
+/// Wait before polling the broker again after a connection error.
+const RECONNECTION_DELAY: Duration = Duration::from_secs(1);
+
+/// Messages waiting to be sent to the broker before publishing more waits for room.
+const MQTT_QUEUE_SIZE: usize = 256;
+
 pub struct MqttInterface {
     subtitles: Sender<InternalEventMessageServer>,
     colour: Sender<InternalEventMessageServer>,
     playback_audio: Sender<InternalEventMessageServer>,
     midi: Sender<InternalEventMessageServer>,
+    midi_file_player: Sender<MidiFilePlayerMessage>,
+    /// How long before the Clients must perform the actions they are sent.
+    scene_lead_time: Duration,
+    /// Events of the Server, relayed to the broker.
+    events_inbox: Receiver<ServerEvent>,
+    /// Prefix of all the topics, without trailing `/`.
+    topic_prefix: String,
 
     mqtt_sender: AsyncClient,
-    mqtt_receiver: EventLoop,
+    /// Polled from its own task once the interface runs, so publishing never waits for it.
+    mqtt_receiver: Option<EventLoop>,
 }
 
 impl MqttInterface {
-    #[instrument(name = "MqttInterface::new", level = "INFO")]
+    #[allow(clippy::too_many_arguments)]
+    #[instrument(name = "MqttInterface::new", skip(events_inbox), level = "INFO")]
     pub fn new(
         subtitles: Sender<InternalEventMessageServer>,
         colour: Sender<InternalEventMessageServer>,
         playback_audio: Sender<InternalEventMessageServer>,
         midi: Sender<InternalEventMessageServer>,
+        midi_file_player: Sender<MidiFilePlayerMessage>,
+        scene_lead_time: Duration,
+        events_inbox: Receiver<ServerEvent>,
+        broker_host: &str,
+        broker_port: u16,
+        topic_prefix: &str,
     ) -> Self {
-        let host = "192.168.178.70";
-        let port: u16 = 1883;
+        let topic_prefix = topic_prefix.trim_end_matches('/').to_string();
 
-        let mut mqttoptions = MqttOptions::new("lamarrs-server", host, port);
+        let mut mqttoptions = MqttOptions::new("lamarrs-server", broker_host, broker_port);
         mqttoptions.set_keep_alive(Duration::from_secs(5));
-        let (mqtt_sender, mqtt_receiver) = AsyncClient::new(mqttoptions, 10);
+        mqttoptions.set_last_will(LastWill::new(
+            format!("{}/server/status", topic_prefix),
+            to_payload(&ServerStatus::Offline),
+            QoS::AtLeastOnce,
+            true,
+        ));
+        let (mqtt_sender, mqtt_receiver) = AsyncClient::new(mqttoptions, MQTT_QUEUE_SIZE);
 
         Self {
             subtitles,
             colour,
             playback_audio,
             midi,
+            midi_file_player,
+            scene_lead_time,
+            events_inbox,
+            topic_prefix,
             mqtt_sender,
-            mqtt_receiver,
+            mqtt_receiver: Some(mqtt_receiver),
+        }
+    }
+
+    /// Queues a message for the broker, waiting for room in the queue if it is full, like
+    /// while the broker is unreachable.
+    #[instrument(name = "MqttInterface::publish", skip(self, payload), level = "DEBUG")]
+    async fn publish(&self, topic: String, retain: bool, payload: Vec<u8>) {
+        if let Err(error) = self
+            .mqtt_sender
+            .publish(topic, QoS::AtLeastOnce, retain, payload)
+            .await
+        {
+            warn!(?error, "Failed to publish to the MQTT broker.");
         }
     }
 
-    #[instrument(name = "MqttInterface::send", skip(self), level = "INFO")]
-    pub async fn send(&mut self, message: String) {
-        // Basic implementation, in the future must have a good API to report Subscribers info, errors, etc.
-        self.mqtt_sender
-            .publish("lamarrs/orchestrator", QoS::AtLeastOnce, false, message)
+    async fn publish_event(&self, event: &ServerEvent) {
+        self.publish(
+            format!("{}/events/{}", self.topic_prefix, event.topic()),
+            false,
+            to_payload(event),
+        )
+        .await;
+    }
+
+    /// Subscribes again and renews the status on every connection, as the broker forgets
+    /// both when the Server reconnects with a clean session.
+    async fn on_connected(&self) {
+        info!("Connected to the MQTT broker.");
+        if let Err(error) = self
+            .mqtt_sender
+            .subscribe(format!("{}/orchestrator", self.topic_prefix), QoS::AtMostOnce)
             .await
-            .unwrap();
+        {
+            error!(?error, "Failed to subscribe to the orchestrator topic.");
+        }
+        self.publish(
+            format!("{}/server/status", self.topic_prefix),
+            true,
+            to_payload(&ServerStatus::Online {
+                version: crate::VERSION,
+            }),
+        )
+        .await;
     }
 
     #[instrument(name = "MqttInterface::run", skip(self), level = "INFO")]
     pub async fn run(&mut self) -> () {
-        self.mqtt_sender
-            .subscribe("lamarrs/orchestrator", QoS::AtMostOnce)
-            .await
-            .unwrap();
+        let (packets, mut broker_inbox) = unbounded_channel();
+        if let Some(mqtt_receiver) = self.mqtt_receiver.take() {
+            tokio::spawn(poll_broker(mqtt_receiver, packets));
+        }
         loop {
-            while let Ok(message) = self.mqtt_receiver.poll().await {
-                match message {
-                    Event::Incoming(Packet::Publish(packet)) => {
-                        self.on_mqtt_published(packet).await;
-                    }
-                    _ => {} //error!("Unsupported message type received"),
-                }
+            tokio::select! {
+                Some(packet) = broker_inbox.recv() => match packet {
+                    Packet::ConnAck(_) => self.on_connected().await,
+                    Packet::Publish(packet) => self.on_mqtt_published(packet).await,
+                    _ => {}
+                },
+                Some(event) = self.events_inbox.recv() => self.publish_event(&event).await,
+                // Neither the broker nor the actors have anything else to say, the Server is
+                // shutting down.
+                else => break,
             }
         }
     }
 
-    #[instrument(name = "MqttInterface::run", skip(self), level = "INFO")]
+    /// Performs an orchestration message, reporting whether it was accepted along with the
+    /// message as received.
+    #[instrument(name = "MqttInterface::on_mqtt_published", skip(self), level = "INFO")]
     pub async fn on_mqtt_published(&mut self, packet: Publish) {
+        METRICS.mqtt_messages_received.fetch_add(1, Ordering::Relaxed);
         debug!(?packet.payload, "Payload:");
-        match serde_json::from_slice(&packet.payload) {
-            Ok::<OrchestrationMessage, serde_json::Error>(message) => match message {
-                OrchestrationMessage::Request(action_message, relative_location) => {
-                    match action_message {
-                        lamarrs_utils::action_messages::Event::PerformAction(service_action) => {
-                            match &service_action {
-                                lamarrs_utils::action_messages::Action::ShowNewSubtitles(_) => {
-                                    self.subtitles
-                                        .send(InternalEventMessageServer::PerformAction(
-                                            service_action,
-                                            relative_location,
-                                        ))
-                                        .await;
-                                }
-                                lamarrs_utils::action_messages::Action::ChangeColour(_) => {
-                                    self.colour
-                                        .send(InternalEventMessageServer::PerformAction(
-                                            service_action,
-                                            relative_location,
-                                        ))
-                                        .await;
-                                }
-                                lamarrs_utils::action_messages::Action::PlayAudio(_) => {
-                                    self.playback_audio
-                                        .send(InternalEventMessageServer::PerformAction(
-                                            service_action,
-                                            relative_location,
-                                        ))
-                                        .await;
-                                }
-                                lamarrs_utils::action_messages::Action::Midi(_) => {
-                                    self.midi
-                                        .send(InternalEventMessageServer::PerformAction(
-                                            service_action,
-                                            relative_location,
-                                        ))
-                                        .await;
-                                }
-                                _ => {
-                                    error!("Unsuported Action message request: {}", service_action)
-                                }
-                            }
-                        }
-                        _ => error!("Action Message not supported."),
-                    }
+        let result = match self.perform_request(&packet.payload).await {
+            Ok(()) => RequestResult::Accepted,
+            Err(reason) => {
+                error!(reason, "Orchestration message rejected.");
+                RequestResult::Rejected { reason }
+            }
+        };
+        let request = serde_json::from_slice(&packet.payload).unwrap_or_else(|_| {
+            serde_json::Value::String(String::from_utf8_lossy(&packet.payload).into_owned())
+        });
+        self.publish_event(&ServerEvent::RequestResult { request, result })
+            .await;
+    }
+
+    async fn perform_request(&self, payload: &[u8]) -> Result<(), String> {
+        let message: OrchestrationMessage = serde_json::from_slice(payload)
+            .map_err(|error| format!("Malformed orchestration message: {}", error))?;
+        match message {
+            OrchestrationMessage::Request(action_message, target_selector) => {
+                let ActionEvent::PerformAction(service_action) = action_message else {
+                    return Err("Action Message not supported.".to_string());
+                };
+                let service = match service_action.service() {
+                    Service::Subtitle => &self.subtitles,
+                    Service::Colour => &self.colour,
+                    Service::AudioPlayer => &self.playback_audio,
+                    Service::Midi => &self.midi,
+                };
+                service
+                    .send(InternalEventMessageServer::PerformAction(
+                        service_action,
+                        Box::new(target_selector),
+                        schedule_in(self.scene_lead_time),
+                    ))
+                    .await
+                    .map_err(|_| "The Service of the Action is not running.".to_string())
+            }
+            OrchestrationMessage::PlayMidiFile(midi_file_playback, target_selector) => self
+                .midi_file_player
+                .send(MidiFilePlayerMessage::Play(
+                    midi_file_playback,
+                    Box::new(target_selector),
+                ))
+                .await
+                .map_err(|_| "The MIDI file player is not running.".to_string()),
+            OrchestrationMessage::StopMidiFile => self
+                .midi_file_player
+                .send(MidiFilePlayerMessage::Stop)
+                .await
+                .map_err(|_| "The MIDI file player is not running.".to_string()),
+        }
+    }
+}
+
+/// Polls the broker, relaying the packets received to the interface, and retrying the
+/// connection for as long as the interface runs. The queue is unbounded, so the polling never
+/// waits for the interface while the interface waits for the polling to make room to publish.
+async fn poll_broker(mut mqtt_receiver: EventLoop, packets: UnboundedSender<Packet>) {
+    loop {
+        match mqtt_receiver.poll().await {
+            Ok(Event::Incoming(packet @ (Packet::ConnAck(_) | Packet::Publish(_)))) => {
+                if packets.send(packet).is_err() {
+                    return;
                 }
-            },
-            Err(msg) => {
-                error!(?msg, "An error happened!")
             }
+            Ok(_) => {}
+            Err(error) => {
+                warn!(?error, "MQTT broker connection failed, retrying.");
+                tokio::time::sleep(RECONNECTION_DELAY).await;
+            }
+        }
+    }
+}
+
+/// Every payload published is JSON, like the orchestration messages.
+fn to_payload(message: &impl Serialize) -> Vec<u8> {
+    serde_json::to_vec(message).expect("Server events always serialize to JSON.")
+}
+
+#[cfg(test)]
+mod tests {
+    use lamarrs_utils::{action_messages::Action, target_selector::TargetSelector, ColourRgb};
+    use tokio::sync::mpsc::channel;
+
+    use super::*;
+
+    /// Inboxes of the Services and of the MIDI file player, as the interface sends to them.
+    struct Inboxes {
+        subtitles: Receiver<InternalEventMessageServer>,
+        colour: Receiver<InternalEventMessageServer>,
+        playback_audio: Receiver<InternalEventMessageServer>,
+        midi: Receiver<InternalEventMessageServer>,
+        midi_file_player: Receiver<MidiFilePlayerMessage>,
+    }
+
+    /// An interface that never connects to the broker, as nobody polls it.
+    fn interface() -> (MqttInterface, Inboxes) {
+        let (subtitles, subtitles_inbox) = channel(1);
+        let (colour, colour_inbox) = channel(1);
+        let (playback_audio, playback_audio_inbox) = channel(1);
+        let (midi, midi_inbox) = channel(1);
+        let (midi_file_player, midi_file_player_inbox) = channel(1);
+        let (_, events_inbox) = channel(1);
+        let interface = MqttInterface::new(
+            subtitles,
+            colour,
+            playback_audio,
+            midi,
+            midi_file_player,
+            Duration::ZERO,
+            events_inbox,
+            "localhost",
+            1883,
+            "lamarrs/",
+        );
+        let inboxes = Inboxes {
+            subtitles: subtitles_inbox,
+            colour: colour_inbox,
+            playback_audio: playback_audio_inbox,
+            midi: midi_inbox,
+            midi_file_player: midi_file_player_inbox,
+        };
+        (interface, inboxes)
+    }
+
+    fn payload(message: &OrchestrationMessage) -> Vec<u8> {
+        serde_json::to_vec(message).unwrap()
+    }
+
+    #[tokio::test]
+    async fn actions_are_sent_to_their_service() {
+        let (interface, mut inboxes) = interface();
+        let change_colour = Action::ChangeColour(ColourRgb::new(255, 0, 0));
+        let request = OrchestrationMessage::Request(
+            ActionEvent::PerformAction(change_colour.clone()),
+            TargetSelector::all(),
+        );
+        assert_eq!(interface.perform_request(&payload(&request)).await, Ok(()));
+        let Ok(InternalEventMessageServer::PerformAction(action, target_selector, schedule)) =
+            inboxes.colour.try_recv()
+        else {
+            panic!("The colour Service received nothing");
         };
+        assert_eq!(action, change_colour);
+        assert_eq!(*target_selector, TargetSelector::all());
+        assert!(schedule.is_none());
+        for inbox in [&mut inboxes.subtitles, &mut inboxes.playback_audio, &mut inboxes.midi] {
+            assert!(inbox.try_recv().is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn midi_files_are_sent_to_the_midi_file_player() {
+        let (interface, mut inboxes) = interface();
+        let stop = payload(&OrchestrationMessage::StopMidiFile);
+        assert_eq!(interface.perform_request(&stop).await, Ok(()));
+        assert!(matches!(
+            inboxes.midi_file_player.try_recv(),
+            Ok(MidiFilePlayerMessage::Stop)
+        ));
+    }
+
+    #[tokio::test]
+    async fn requests_that_cant_be_performed_are_rejected() {
+        let (interface, inboxes) = interface();
+        let reason = interface.perform_request(b"{\"Request\": 1}").await.unwrap_err();
+        assert!(reason.starts_with("Malformed orchestration message"), "{reason}");
+        let join_group = OrchestrationMessage::Request(
+            ActionEvent::JoinGroup("choir".try_into().unwrap()),
+            TargetSelector::all(),
+        );
+        assert_eq!(
+            interface.perform_request(&payload(&join_group)).await,
+            Err("Action Message not supported.".to_string())
+        );
+        drop(inboxes);
+        let change_colour = OrchestrationMessage::Request(
+            ActionEvent::PerformAction(Action::ChangeColour(ColourRgb::new(0, 0, 255))),
+            TargetSelector::all(),
+        );
+        assert_eq!(
+            interface.perform_request(&payload(&change_colour)).await,
+            Err("The Service of the Action is not running.".to_string())
+        );
+        assert_eq!(
+            interface.perform_request(&payload(&OrchestrationMessage::StopMidiFile)).await,
+            Err("The MIDI file player is not running.".to_string())
+        );
     }
 }
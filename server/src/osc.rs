@@ -0,0 +1,578 @@
+//! OSC interface
+//!
+//! Lets show control software, like QLab or Ableton, drive the show sending OSC messages over UDP.
+//! Each OSC address is mapped to a command, in a YAML file:
+//! ```yaml
+//! port: 9000
+//! mappings:
+//!   - address: /lamarrs/colour
+//!     command: Colour
+//!   - address: /lamarrs/colour/left
+//!     command: Colour
+//!     target:
+//!       regions: [Left]
+//!   - address: /lamarrs/subtitle
+//!     command: Subtitle
+//!   - address: /lamarrs/cue/next
+//!     command: NextScene
+//!   - address: /lamarrs/cue/retrigger
+//!     command: RetriggerScene
+//! ```
+//! Both keys are optional: the listener defaults to port 9000, and to the mappings above without
+//! the one of the left region. The arguments each command takes are:
+//!  * `Colour`: a colour name or hexadecimal string, or 3 or 4 numbers for the red, green, blue
+//!    and white channels. Integers go from 0 to 255 and floats from 0.0 to 1.0, as faders send.
+//!  * `Subtitle`: the text to show.
+//!  * `NextScene` and `RetriggerScene`: none. A first argument of 0, sent by buttons when they
+//!    are released, is ignored.
+//!
+//! Addresses are matched exactly, and bundles are performed as soon as they are received,
+//! whatever their time tag. Malformed packets and messages are logged and dropped.
+
+use std::{fs, io, net::SocketAddr, path::Path, time::Duration};
+
+use lamarrs_utils::{
+    action_messages::Action, exchange_messages::ExchangeMessage, subtitles::Subtitles,
+    target_selector::TargetSelector, ColourRgb,
+};
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::{net::UdpSocket, sync::mpsc::Sender};
+use tracing::{debug, error, info, instrument, warn};
+
+use crate::{clock::schedule_in, services::InternalEventMessageServer};
+
+const DEFAULT_OSC_PORT: u16 = 9000;
+
+/// Biggest UDP datagram.
+const MAX_PACKET_SIZE: usize = 65_507;
+
+const BUNDLE_TAG: &[u8] = b"#bundle\0";
+
+#[derive(Debug, Error)]
+pub enum OscConfigError {
+    #[error("The OSC configuration file could not be read")]
+    Io(#[from] io::Error),
+    #[error("Malformed YAML OSC configuration: {0}")]
+    Yaml(#[from] serde_yml::Error),
+    #[error("OSC address {0:?} must start with '/'.")]
+    InvalidAddress(String),
+}
+
+#[derive(Debug, Error)]
+pub enum OscInterfaceError {
+    #[error("Failed listening for OSC messages.")]
+    Network(#[from] io::Error),
+}
+
+/// Reasons an OSC packet or message is dropped.
+#[derive(Debug, Error, PartialEq)]
+pub enum OscError {
+    #[error("the packet ends before its content")]
+    Truncated,
+    #[error("strings must be UTF-8 and end with a null byte")]
+    InvalidString,
+    #[error("messages must start with an address, and bundles with #bundle")]
+    InvalidPacket,
+    #[error("the type tags must start with ','")]
+    InvalidTypeTags,
+    #[error("arguments of type '{0}' are not supported")]
+    UnsupportedType(char),
+    #[error("the arguments of the message don't fit its command")]
+    InvalidArguments,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum OscArgument {
+    Int(i32),
+    Float(f32),
+    String(String),
+    Blob(Vec<u8>),
+    Long(i64),
+    Double(f64),
+    True,
+    False,
+    Nil,
+    Impulse,
+}
+
+impl OscArgument {
+    fn as_f64(&self) -> Option<f64> {
+        match self {
+            OscArgument::Int(value) => Some(*value as f64),
+            OscArgument::Float(value) => Some(*value as f64),
+            OscArgument::Long(value) => Some(*value as f64),
+            OscArgument::Double(value) => Some(*value),
+            OscArgument::True => Some(1.0),
+            OscArgument::False => Some(0.0),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OscMessage {
+    pub address: String,
+    pub arguments: Vec<OscArgument>,
+}
+
+/// Reads the elements of OSC packets, which are aligned to 4 bytes.
+struct OscReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> OscReader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], OscError> {
+        if length > self.bytes.len() {
+            return Err(OscError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(length);
+        // The padding of the last element may be missing in sloppy senders.
+        self.bytes = rest.get(length.next_multiple_of(4) - length..).unwrap_or_default();
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], OscError> {
+        // `take` returns exactly N bytes.
+        Ok(self.take(N)?.try_into().unwrap_or([0; N]))
+    }
+
+    fn string(&mut self) -> Result<String, OscError> {
+        let length = self
+            .bytes
+            .iter()
+            .position(|byte| *byte == 0)
+            .ok_or(OscError::InvalidString)?;
+        let bytes = self.take(length + 1)?;
+        String::from_utf8(bytes[..length].to_vec()).map_err(|_| OscError::InvalidString)
+    }
+
+    fn argument(&mut self, type_tag: char) -> Result<OscArgument, OscError> {
+        Ok(match type_tag {
+            'i' => OscArgument::Int(i32::from_be_bytes(self.array()?)),
+            'f' => OscArgument::Float(f32::from_be_bytes(self.array()?)),
+            's' | 'S' => OscArgument::String(self.string()?),
+            'b' => {
+                let length = u32::from_be_bytes(self.array()?) as usize;
+                OscArgument::Blob(self.take(length)?.to_vec())
+            }
+            'h' => OscArgument::Long(i64::from_be_bytes(self.array()?)),
+            'd' => OscArgument::Double(f64::from_be_bytes(self.array()?)),
+            'T' => OscArgument::True,
+            'F' => OscArgument::False,
+            'N' => OscArgument::Nil,
+            'I' => OscArgument::Impulse,
+            unsupported => return Err(OscError::UnsupportedType(unsupported)),
+        })
+    }
+}
+
+/// Decodes an OSC packet into its messages, flattening its bundles.
+pub fn parse_osc_packet(packet: &[u8]) -> Result<Vec<OscMessage>, OscError> {
+    let mut messages = Vec::new();
+    parse_osc_element(packet, &mut messages)?;
+    Ok(messages)
+}
+
+fn parse_osc_element(element: &[u8], messages: &mut Vec<OscMessage>) -> Result<(), OscError> {
+    let mut reader = OscReader { bytes: element };
+    if element.starts_with(BUNDLE_TAG) {
+        // The tag and the time tag.
+        reader.take(BUNDLE_TAG.len() + 8)?;
+        while !reader.bytes.is_empty() {
+            let length = u32::from_be_bytes(reader.array()?) as usize;
+            parse_osc_element(reader.take(length)?, messages)?;
+        }
+        return Ok(());
+    }
+    if !element.starts_with(b"/") {
+        return Err(OscError::InvalidPacket);
+    }
+    let address = reader.string()?;
+    // Type tags are optional in the oldest implementations, meaning no arguments.
+    let type_tags = if reader.bytes.is_empty() {
+        String::from(",")
+    } else {
+        reader.string()?
+    };
+    let type_tags = type_tags
+        .strip_prefix(',')
+        .ok_or(OscError::InvalidTypeTags)?;
+    let arguments = type_tags
+        .chars()
+        .map(|type_tag| reader.argument(type_tag))
+        .collect::<Result<_, _>>()?;
+    messages.push(OscMessage { address, arguments });
+    Ok(())
+}
+
+/// What the Server does when it receives a message in an address.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum OscCommand {
+    Colour,
+    Subtitle,
+    NextScene,
+    RetriggerScene,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct OscMapping {
+    pub address: String,
+    pub command: OscCommand,
+    /// Clients performing the `Colour` and `Subtitle` commands.
+    #[serde(default)]
+    pub target: TargetSelector,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct OscConfig {
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default = "default_mappings")]
+    pub mappings: Vec<OscMapping>,
+}
+
+fn default_port() -> u16 {
+    DEFAULT_OSC_PORT
+}
+
+fn default_mappings() -> Vec<OscMapping> {
+    [
+        ("/lamarrs/colour", OscCommand::Colour),
+        ("/lamarrs/subtitle", OscCommand::Subtitle),
+        ("/lamarrs/cue/next", OscCommand::NextScene),
+        ("/lamarrs/cue/retrigger", OscCommand::RetriggerScene),
+    ]
+    .into_iter()
+    .map(|(address, command)| OscMapping {
+        address: address.to_string(),
+        command,
+        target: TargetSelector::all(),
+    })
+    .collect()
+}
+
+impl Default for OscConfig {
+    fn default() -> Self {
+        Self {
+            port: default_port(),
+            mappings: default_mappings(),
+        }
+    }
+}
+
+impl OscConfig {
+    pub fn load(path: &Path) -> Result<Self, OscConfigError> {
+        let config: OscConfig = serde_yml::from_str(&fs::read_to_string(path)?)?;
+        if let Some(mapping) = config
+            .mappings
+            .iter()
+            .find(|mapping| !mapping.address.starts_with('/'))
+        {
+            return Err(OscConfigError::InvalidAddress(mapping.address.clone()));
+        }
+        info!("Loaded {} OSC mappings", config.mappings.len());
+        Ok(config)
+    }
+}
+
+/// Colour written as a name, a hexadecimal string, or 3 or 4 levels.
+fn colour_from_arguments(arguments: &[OscArgument]) -> Result<ColourRgb, OscError> {
+    if let [OscArgument::String(colour)] = arguments {
+        return ColourRgb::from_hex(colour)
+            .or_else(|_| ColourRgb::from_name(colour))
+            .map_err(|_| OscError::InvalidArguments);
+    }
+    let level = |argument: &OscArgument| {
+        let level = match argument {
+            OscArgument::Float(_) | OscArgument::Double(_) => argument.as_f64()? * 255.0,
+            _ => argument.as_f64()?,
+        };
+        Some(level.round().clamp(0.0, 255.0) as u8)
+    };
+    let levels = arguments
+        .iter()
+        .map(level)
+        .collect::<Option<Vec<u8>>>()
+        .ok_or(OscError::InvalidArguments)?;
+    match levels[..] {
+        [r, g, b] => Ok(ColourRgb::new(r, g, b)),
+        [r, g, b, w] => Ok(ColourRgb::new(r, g, b).with_white(w)),
+        _ => Err(OscError::InvalidArguments),
+    }
+}
+
+fn subtitles_from_arguments(arguments: &[OscArgument]) -> Result<Subtitles, OscError> {
+    match arguments {
+        [OscArgument::String(text)] => Ok(Subtitles::new(text.as_str().into())),
+        _ => Err(OscError::InvalidArguments),
+    }
+}
+
+pub struct OscInterface {
+    subtitles: Sender<InternalEventMessageServer>,
+    colour: Sender<InternalEventMessageServer>,
+    sequencer: Sender<ExchangeMessage>,
+    config: OscConfig,
+    /// How long before the Clients must perform the actions they are sent.
+    scene_lead_time: Duration,
+}
+
+impl OscInterface {
+    pub fn new(
+        subtitles: Sender<InternalEventMessageServer>,
+        colour: Sender<InternalEventMessageServer>,
+        sequencer: Sender<ExchangeMessage>,
+        config: OscConfig,
+        scene_lead_time: Duration,
+    ) -> Self {
+        Self {
+            subtitles,
+            colour,
+            sequencer,
+            config,
+            scene_lead_time,
+        }
+    }
+
+    #[instrument(name = "OscInterface::run", skip(self), level = "INFO")]
+    pub async fn run(&mut self) -> Result<(), OscInterfaceError> {
+        let socket = UdpSocket::bind(("0.0.0.0", self.config.port)).await?;
+        info!("Listening for OSC messages on {}", socket.local_addr()?);
+        let mut packet = vec![0; MAX_PACKET_SIZE];
+        loop {
+            let (length, source) = match socket.recv_from(&mut packet).await {
+                Ok(received) => received,
+                // Like the ICMP errors of unreachable sources, reported on some systems.
+                Err(error) => {
+                    warn!(?error, "Failed to receive an OSC packet.");
+                    continue;
+                }
+            };
+            match parse_osc_packet(&packet[..length]) {
+                Ok(messages) => {
+                    for message in messages {
+                        self.on_message(message, source).await;
+                    }
+                }
+                Err(error) => warn!("Dropping malformed OSC packet from {}: {}.", source, error),
+            }
+        }
+    }
+
+    async fn on_message(&self, message: OscMessage, source: SocketAddr) {
+        debug!(?message, "OSC message from {}", source);
+        let mappings = self
+            .config
+            .mappings
+            .iter()
+            .filter(|mapping| mapping.address == message.address);
+        let mut is_mapped = false;
+        for mapping in mappings {
+            is_mapped = true;
+            if let Err(error) = self.perform(mapping, &message.arguments).await {
+                warn!(
+                    "Dropping OSC message {} from {}: {}.",
+                    message.address, source, error
+                );
+            }
+        }
+        if !is_mapped {
+            debug!("OSC address {} is not mapped, ignoring it.", message.address);
+        }
+    }
+
+    async fn perform(
+        &self,
+        mapping: &OscMapping,
+        arguments: &[OscArgument],
+    ) -> Result<(), OscError> {
+        let (service, action) = match mapping.command {
+            OscCommand::Colour => (
+                &self.colour,
+                Action::ChangeColour(colour_from_arguments(arguments)?),
+            ),
+            OscCommand::Subtitle => (
+                &self.subtitles,
+                Action::ShowNewSubtitles(Box::new(subtitles_from_arguments(arguments)?)),
+            ),
+            OscCommand::NextScene | OscCommand::RetriggerScene => {
+                if arguments.first().and_then(OscArgument::as_f64) == Some(0.0) {
+                    return Ok(());
+                }
+                let message = match mapping.command {
+                    OscCommand::NextScene => ExchangeMessage::NextScene,
+                    _ => ExchangeMessage::RetriggerScene,
+                };
+                if let Err(error) = self.sequencer.send(message).await {
+                    error!(?error, "Failed to send the OSC cue to the Sequencer.");
+                }
+                return Ok(());
+            }
+        };
+        if let Err(error) = service
+            .send(InternalEventMessageServer::PerformAction(
+                action,
+                Box::new(mapping.target.clone()),
+                schedule_in(self.scene_lead_time),
+            ))
+            .await
+        {
+            error!(?error, "Failed to send the OSC action to its Service.");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// String followed by its null byte and the padding to 4 bytes.
+    fn osc_string(string: &str) -> Vec<u8> {
+        let mut bytes = string.as_bytes().to_vec();
+        bytes.resize((string.len() + 1).next_multiple_of(4), 0);
+        bytes
+    }
+
+    fn bundle(elements: &[Vec<u8>]) -> Vec<u8> {
+        let mut bundle = BUNDLE_TAG.to_vec();
+        // Immediately.
+        bundle.extend_from_slice(&1u64.to_be_bytes());
+        for element in elements {
+            bundle.extend_from_slice(&(element.len() as u32).to_be_bytes());
+            bundle.extend_from_slice(element);
+        }
+        bundle
+    }
+
+    #[test]
+    fn strings_are_padded_to_4_bytes() {
+        // Addresses of 3 and 4 bytes, padded to 4 and 8 bytes.
+        for address in ["/ab", "/abc", "/abcdefg"] {
+            let mut packet = osc_string(address);
+            packet.extend(osc_string(",si"));
+            packet.extend(osc_string("red"));
+            packet.extend(7i32.to_be_bytes());
+
+            assert_eq!(
+                parse_osc_packet(&packet),
+                Ok(vec![OscMessage {
+                    address: address.to_string(),
+                    arguments: vec![OscArgument::String("red".to_string()), OscArgument::Int(7)],
+                }])
+            );
+        }
+    }
+
+    #[test]
+    fn blobs_are_padded_to_4_bytes() {
+        let mut packet = osc_string("/blob");
+        packet.extend(osc_string(",bT"));
+        packet.extend(5u32.to_be_bytes());
+        packet.extend([1, 2, 3, 4, 5, 0, 0, 0]);
+
+        assert_eq!(
+            parse_osc_packet(&packet).unwrap()[0].arguments,
+            vec![OscArgument::Blob(vec![1, 2, 3, 4, 5]), OscArgument::True]
+        );
+    }
+
+    #[test]
+    fn messages_without_type_tags_have_no_arguments() {
+        assert_eq!(
+            parse_osc_packet(&osc_string("/lamarrs/cue/next")).unwrap()[0].arguments,
+            vec![]
+        );
+    }
+
+    #[test]
+    fn nested_bundles_are_flattened() {
+        let message = |address: &str| {
+            let mut message = osc_string(address);
+            message.extend(osc_string(","));
+            message
+        };
+        let packet = bundle(&[
+            message("/first"),
+            bundle(&[message("/second"), bundle(&[message("/third")])]),
+            message("/fourth"),
+        ]);
+
+        let addresses: Vec<String> = parse_osc_packet(&packet)
+            .unwrap()
+            .into_iter()
+            .map(|message| message.address)
+            .collect();
+        assert_eq!(addresses, ["/first", "/second", "/third", "/fourth"]);
+    }
+
+    #[test]
+    fn truncated_packets_are_refused() {
+        let mut message = osc_string("/lamarrs/colour");
+        message.extend(osc_string(",ifs"));
+        message.extend(1i32.to_be_bytes());
+        message.extend(0.5f32.to_be_bytes());
+        message.extend(osc_string("text"));
+        let packet = bundle(&[message.clone()]);
+
+        // Cut in the middle of the integer, the float and the string.
+        for length in [26, 30, 36] {
+            assert!(parse_osc_packet(&message[..length]).is_err());
+        }
+        // The bundle says its message is longer than what follows.
+        assert_eq!(
+            parse_osc_packet(&packet[..packet.len() - 4]),
+            Err(OscError::Truncated)
+        );
+        // Cut in the time tag.
+        assert_eq!(parse_osc_packet(&packet[..12]), Err(OscError::Truncated));
+    }
+
+    #[test]
+    fn packets_that_are_not_osc_are_refused() {
+        assert_eq!(parse_osc_packet(b"lamarrs\0"), Err(OscError::InvalidPacket));
+        assert_eq!(
+            parse_osc_packet(&osc_string("/no/null/byte")[..13]),
+            Err(OscError::InvalidString)
+        );
+        let mut message = osc_string("/lamarrs");
+        message.extend(osc_string("i"));
+        assert_eq!(parse_osc_packet(&message), Err(OscError::InvalidTypeTags));
+        let mut message = osc_string("/lamarrs");
+        message.extend(osc_string(",r"));
+        assert_eq!(
+            parse_osc_packet(&message),
+            Err(OscError::UnsupportedType('r'))
+        );
+    }
+
+    #[test]
+    fn colours_are_read_from_names_and_levels() {
+        assert_eq!(
+            colour_from_arguments(&[
+                OscArgument::Int(255),
+                OscArgument::Int(0),
+                OscArgument::Int(300),
+            ]),
+            Ok(ColourRgb::new(255, 0, 255))
+        );
+        assert_eq!(
+            colour_from_arguments(&[
+                OscArgument::Float(1.0),
+                OscArgument::Float(0.5),
+                OscArgument::Double(0.0),
+                OscArgument::Float(0.2),
+            ]),
+            Ok(ColourRgb::new(255, 128, 0).with_white(51))
+        );
+        assert_eq!(
+            colour_from_arguments(&[OscArgument::String("#FF0000".to_string())]),
+            Ok(ColourRgb::new(255, 0, 0))
+        );
+        assert_eq!(
+            colour_from_arguments(&[OscArgument::Int(1), OscArgument::Int(2)]),
+            Err(OscError::InvalidArguments)
+        );
+    }
+}
@@ -0,0 +1,216 @@
+//! Seat map
+//!
+//! The audience knows the seat they are sitting on, not their coordinates in the venue.
+//! A [`SeatMap`] translates the seat identifiers printed in the tickets into venue positions
+//! and sections, so the Clients can register with their seat instead of their location.
+//!
+//! Seat maps can be written as CSV, with a `seat,x,y,section` header:
+//! ```csv
+//! seat,x,y,section
+//! "Row F, Seat 12",0.42,0.31,Stalls
+//! ```
+//! or as YAML:
+//! ```yaml
+//! seats:
+//!   - seat: "Row F, Seat 12"
+//!     x: 0.42
+//!     y: 0.31
+//!     section: Stalls
+//! ```
+//! Seat identifiers are compared ignoring case and repeated whitespaces.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fs,
+    path::Path,
+};
+
+use lamarrs_utils::Position;
+use serde::Deserialize;
+use thiserror::Error;
+use tracing::{info, warn};
+
+#[derive(Debug, Error)]
+pub enum SeatMapError {
+    #[error("The seat map file could not be read")]
+    Io(#[from] std::io::Error),
+    #[error("Malformed CSV seat map: {0}")]
+    Csv(#[from] csv::Error),
+    #[error("Malformed YAML seat map: {0}")]
+    Yaml(#[from] serde_yml::Error),
+    #[error("Unsupported seat map format {0:?}, it must be a .csv, .yaml or .yml file.")]
+    UnsupportedFormat(String),
+    #[error("Seat {0:?} is defined more than once in the seat map.")]
+    DuplicatedSeat(String),
+}
+
+/// One entry of the seat map file.
+#[derive(Debug, Deserialize)]
+struct SeatDefinition {
+    seat: String,
+    x: f32,
+    y: f32,
+    section: Option<String>,
+}
+
+/// YAML seat maps wrap the seats in a `seats` key, to leave room for metadata in the future.
+#[derive(Debug, Deserialize)]
+struct YamlSeatMap {
+    seats: Vec<SeatDefinition>,
+}
+
+/// Where a seat is in the venue.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SeatLocation {
+    pub position: Position,
+    pub section: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct SeatMap {
+    seats: HashMap<String, SeatLocation>,
+}
+
+impl SeatMap {
+    /// Loads a seat map, choosing the format from the file extension.
+    pub fn load(path: &Path) -> Result<Self, SeatMapError> {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or_default()
+            .to_lowercase();
+        let seat_definitions = match extension.as_str() {
+            "csv" => csv::Reader::from_path(path)?
+                .deserialize()
+                .collect::<Result<Vec<SeatDefinition>, _>>()?,
+            "yaml" | "yml" => serde_yml::from_str::<YamlSeatMap>(&fs::read_to_string(path)?)?.seats,
+            _ => return Err(SeatMapError::UnsupportedFormat(extension)),
+        };
+        let seat_map = Self::try_from_definitions(seat_definitions)?;
+        info!(
+            "Loaded {} seats from {}",
+            seat_map.seats.len(),
+            path.display()
+        );
+        Ok(seat_map)
+    }
+
+    fn try_from_definitions(seat_definitions: Vec<SeatDefinition>) -> Result<Self, SeatMapError> {
+        let mut seats = HashMap::with_capacity(seat_definitions.len());
+        for SeatDefinition {
+            seat,
+            x,
+            y,
+            section,
+        } in seat_definitions
+        {
+            if !(0.0..=1.0).contains(&x) || !(0.0..=1.0).contains(&y) {
+                warn!(
+                    seat,
+                    x, y, "Seat is outside of the venue, coordinates must be between 0 and 1."
+                );
+            }
+            match seats.entry(normalise_seat_id(&seat)) {
+                Entry::Occupied(_) => return Err(SeatMapError::DuplicatedSeat(seat)),
+                Entry::Vacant(entry) => {
+                    entry.insert(SeatLocation {
+                        position: Position::new(x, y),
+                        section,
+                    });
+                }
+            }
+        }
+        Ok(Self { seats })
+    }
+
+    /// Returns the location of the seat, if it is part of the seat map.
+    pub fn resolve(&self, seat: &str) -> Option<&SeatLocation> {
+        self.seats.get(&normalise_seat_id(seat))
+    }
+}
+
+/// `row f,  seat 12` and `Row F, Seat 12` are the same seat.
+fn normalise_seat_id(seat: &str) -> String {
+    seat.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Loads the seat map from a file of its own, so the tests can run in parallel.
+    fn load(name: &str, content: &str) -> Result<SeatMap, SeatMapError> {
+        let path = std::env::temp_dir().join(format!(
+            "lamarrs-seat-map-{}-{}",
+            std::process::id(),
+            name
+        ));
+        fs::write(&path, content).unwrap();
+        let seat_map = SeatMap::load(&path);
+        fs::remove_file(path).unwrap();
+        seat_map
+    }
+
+    #[test]
+    fn csv_and_yaml_seat_maps_are_the_same() {
+        let csv = load(
+            "seats.csv",
+            "seat,x,y,section\n\"Row F, Seat 12\",0.42,0.31,Stalls\nA1,0.1,0.9,\n",
+        )
+        .unwrap();
+        let yaml = load(
+            "seats.YML",
+            "seats:\n\
+             \x20 - seat: \"Row F, Seat 12\"\n    x: 0.42\n    y: 0.31\n    section: Stalls\n\
+             \x20 - seat: A1\n    x: 0.1\n    y: 0.9\n",
+        )
+        .unwrap();
+
+        for seat_map in [csv, yaml] {
+            assert_eq!(
+                seat_map.resolve("Row F, Seat 12"),
+                Some(&SeatLocation {
+                    position: Position::new(0.42, 0.31),
+                    section: Some("Stalls".to_string()),
+                })
+            );
+            assert_eq!(
+                seat_map.resolve("a1").map(|location| &location.section),
+                Some(&None)
+            );
+            assert_eq!(seat_map.resolve("A2"), None);
+        }
+    }
+
+    #[test]
+    fn seats_are_found_ignoring_case_and_whitespaces() {
+        let seat_map =
+            load("seats.csv", "seat,x,y,section\n\"Row F, Seat 12\",0.42,0.31,\n").unwrap();
+
+        assert!(seat_map.resolve("  row f,   SEAT 12 ").is_some());
+        assert!(seat_map.resolve("Row F, Seat 1").is_none());
+    }
+
+    #[test]
+    fn seat_maps_that_cant_be_read_are_refused() {
+        assert!(matches!(
+            load("duplicated.csv", "seat,x,y,section\nA1,0.1,0.1,\na1 ,0.2,0.2,\n"),
+            Err(SeatMapError::DuplicatedSeat(seat)) if seat == "a1 "
+        ));
+        assert!(matches!(
+            load("seats.json", "{}"),
+            Err(SeatMapError::UnsupportedFormat(extension)) if extension == "json"
+        ));
+        assert!(matches!(
+            load("malformed.csv", "seat,x,y,section\nA1,left,0.1,\n"),
+            Err(SeatMapError::Csv(_))
+        ));
+        assert!(matches!(
+            load("malformed.yaml", "seats: A1"),
+            Err(SeatMapError::Yaml(_))
+        ));
+    }
+}
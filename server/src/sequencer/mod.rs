@@ -1,50 +1,142 @@
-use std::{collections::VecDeque, path::PathBuf};
+use std::{
+    collections::{HashSet, VecDeque},
+    path::{Path, PathBuf},
+    sync::atomic::Ordering,
+    time::Duration,
+};
 
 use crate::{
-    sequencer::sequence_parser::{Sequence, SequenceStep},
+    clock::schedule_in,
+    events::{EventPublisher, ServerEvent},
+    metrics::METRICS,
+    midi_file::{self, MidiFilePlayerMessage},
+    sequencer::{
+        sequence_parser::{
+            Sequence, SequenceDefinition, SequenceEntry, SequenceStep, StepAction,
+            TimedTextReference,
+        },
+        timed_text::TimedTextFormat,
+    },
     services::{InternalEventMessageServer, LamarrsServiceError},
+    show_control::{MtcRate, ShowControlEvent, Timecode},
 };
 use async_time_mock_tokio::MockableClock;
-use lamarrs_utils::{action_messages::Action, exchange_messages::ExchangeMessage};
+use lamarrs_utils::{exchange_messages::ExchangeMessage, Service};
+use serde::Serialize;
 use tokio::{
     fs,
-    sync::mpsc::{channel, Receiver, Sender},
+    task,
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        oneshot,
+    },
+    time::{sleep_until, Instant},
 };
 use tracing::{debug, error, info, instrument};
 
 mod sequence_parser;
+mod timed_text;
+
+/// Biggest advance of the MTC between two positions still considered playing. Bigger ones, or
+/// going backwards, are the timecode being located somewhere else.
+const MAX_TIMECODE_STEP: Duration = Duration::from_secs(1);
+
+/// When the MTC is located, the steps stamped this long before the new position are still played,
+/// as the first position of a running timecode is read a couple of frames after it started.
+const TIMECODE_LOCATE_WINDOW: Duration = Duration::from_millis(100);
+
+/// Commands controlling the Sequencer, besides the scenes requested by the Clients.
+#[derive(Debug)]
+pub enum SequencerCommand {
+    ShowControl(ShowControlEvent),
+    /// Plays the first step with the name, and the ones following it automatically.
+    GoToStep(String, oneshot::Sender<Result<(), LamarrsServiceError>>),
+    /// Reads the sequence file again and starts it from the beginning, replying with its
+    /// amount of steps.
+    Reload(oneshot::Sender<Result<usize, LamarrsServiceError>>),
+    Describe(oneshot::Sender<SequencerState>),
+}
+
+/// Where the Sequencer is in the sequence.
+#[derive(Clone, Debug, Serialize)]
+pub struct SequencerState {
+    pub steps: Vec<StepSummary>,
+    /// Index of the step played by the next `NextScene`.
+    pub next_step: usize,
+    pub is_stopped: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct StepSummary {
+    pub name: String,
+    pub cue: Option<String>,
+    pub timecode: Option<Timecode>,
+}
 
 pub struct Sequencer {
     subtitles_service: Sender<InternalEventMessageServer>,
     colour_service: Sender<InternalEventMessageServer>,
     playback_service: Sender<InternalEventMessageServer>,
     midi_service: Sender<InternalEventMessageServer>,
+    midi_file_player: Sender<MidiFilePlayerMessage>,
 
     pub sender: Sender<ExchangeMessage>,
     inbox: Receiver<ExchangeMessage>,
+    pub command_sender: Sender<SequencerCommand>,
+    command_inbox: Receiver<SequencerCommand>,
     pub sequence_path: PathBuf,
+    /// How long before the Clients must perform the steps they are sent.
+    scene_lead_time: Duration,
     last_sequence_step_played: Option<SequenceStep>,
+    steps: Vec<SequenceStep>,
+    /// Index of the step played by the next `NextScene`.
+    next_step: usize,
+    /// When the next step is played automatically, after the duration of the last one.
+    auto_advance_at: Option<Instant>,
+    /// Steps are not played automatically, nor by the timecode, until the show is resumed.
+    is_stopped: bool,
+    /// What was left of the duration of the last step when the show was stopped.
+    stopped_auto_advance: Option<Duration>,
+    /// Last position of the MTC.
+    timecode_position: Option<Duration>,
+    events: EventPublisher,
     clock: MockableClock,
 }
 
 impl Sequencer {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         subtitles_service: Sender<InternalEventMessageServer>,
         colour_service: Sender<InternalEventMessageServer>,
         playback_service: Sender<InternalEventMessageServer>,
         midi_service: Sender<InternalEventMessageServer>,
+        midi_file_player: Sender<MidiFilePlayerMessage>,
         sequence_path: PathBuf,
+        scene_lead_time: Duration,
+        events: EventPublisher,
     ) -> Self {
         let (sender, inbox) = channel(32);
+        let (command_sender, command_inbox) = channel(32);
         Self {
             subtitles_service,
             colour_service,
             playback_service,
             midi_service,
+            midi_file_player,
             sender,
             inbox,
+            command_sender,
+            command_inbox,
             sequence_path,
+            scene_lead_time,
             last_sequence_step_played: None,
+            steps: Vec::new(),
+            next_step: 0,
+            auto_advance_at: None,
+            is_stopped: false,
+            stopped_auto_advance: None,
+            timecode_position: None,
+            events,
             clock: MockableClock::Real,
         }
     }
@@ -65,18 +157,111 @@ impl Sequencer {
             }
         })?;
         debug!("Loaded sequence from file");
-        match serde_yml::from_str(&yaml) {
-            Ok(sequence) => {
-                debug!("Sequence results {:?}", sequence);
-                Ok(sequence)
-            }
+        let definition: SequenceDefinition = match serde_yml::from_str(&yaml) {
+            Ok(definition) => definition,
             Err(err) => {
                 error!("Failed to serialise file into Sequence: {}", err);
                 return Err(LamarrsServiceError::Service {
                     service: "Sequencer".into(),
                 });
             }
+        };
+        let mut sequence = Sequence {
+            version: definition.version,
+            sequence: VecDeque::new(),
+        };
+        for entry in definition.sequence {
+            match entry {
+                SequenceEntry::Step(sequence_step) => {
+                    if let StepAction::PlayMidiFile(midi_file_playback) = &sequence_step.action {
+                        self.check_midi_file(midi_file_playback.file.as_str()).await?;
+                    }
+                    sequence.sequence.push_back(*sequence_step)
+                }
+                SequenceEntry::TimedText(reference) => {
+                    sequence.sequence.extend(self.import_timed_text(&reference).await?)
+                }
+            }
+        }
+        let mut cues = HashSet::new();
+        for sequence_step in &sequence.sequence {
+            if let Some(cue) = &sequence_step.cue {
+                if !cues.insert(cue) {
+                    error!("Cue {} is used by more than one step.", cue);
+                    return Err(LamarrsServiceError::Service {
+                        service: "Sequencer".into(),
+                    });
+                }
+            }
+        }
+        debug!("Sequence results {:?}", sequence);
+        Ok(sequence)
+    }
+
+    /// Path of a file referenced by the sequence, relative to the sequence file.
+    fn referenced_path(&self, path: &Path) -> PathBuf {
+        match self.sequence_path.parent() {
+            Some(sequence_directory) => sequence_directory.join(path),
+            None => path.to_path_buf(),
+        }
+    }
+
+    /// Makes sure the MIDI files played by the sequence can be read before the show starts,
+    /// as the MIDI file player only reads them when they are played.
+    async fn check_midi_file(&self, file: &str) -> Result<(), LamarrsServiceError> {
+        // Resolved as the MIDI file player does, so files it would refuse fail now.
+        let directory = self
+            .sequence_path
+            .parent()
+            .unwrap_or(Path::new(""))
+            .to_path_buf();
+        let path = self.referenced_path(Path::new(file));
+        let timeline = task::spawn_blocking({
+            let file = file.to_string();
+            move || midi_file::load(&midi_file::resolve(&directory, &file)?)
+        })
+        .await
+        .map_err(|err| {
+            error!("Failed to load MIDI file {}: {}", path.display(), err);
+            LamarrsServiceError::Service {
+                service: "Sequencer".into(),
+            }
+        })?
+        .map_err(|err| {
+            error!("Failed to load MIDI file {}: {}", path.display(), err);
+            LamarrsServiceError::Service {
+                service: "Sequencer".into(),
+            }
+        })?;
+        info!("Checked MIDI file {}, {} events", path.display(), timeline.len());
+        Ok(())
+    }
+
+    /// Reads a timed-text file referenced by the sequence into the steps showing its cues.
+    async fn import_timed_text(
+        &self,
+        reference: &TimedTextReference,
+    ) -> Result<Vec<SequenceStep>, LamarrsServiceError> {
+        let path = self.referenced_path(&reference.timed_text);
+        let cues = async {
+            let format = TimedTextFormat::from_path(&path)?;
+            let content = fs::read_to_string(&path).await?;
+            timed_text::parse(format, &content)
         }
+        .await
+        .map_err(|err| {
+            error!("Failed to import timed text {}: {}", path.display(), err);
+            LamarrsServiceError::Service {
+                service: "Sequencer".into(),
+            }
+        })?;
+        info!("Imported {} cues from {}", cues.len(), path.display());
+        Ok(timed_text::sequence_steps(
+            &cues,
+            &reference.name,
+            reference.language.as_ref(),
+            &reference.target,
+        ))
     }
 
     #[instrument(
@@ -88,33 +273,19 @@ impl Sequencer {
         err
     )]
     pub async fn run(&mut self) -> Result<(), LamarrsServiceError> {
-        let mut sequence = self.load_show_sequence().await?.sequence;
+        let steps = self.load_show_sequence().await?.sequence.into();
+        self.restart(steps);
         loop {
-            while let Some(message) = self.inbox.recv().await {
-                match message {
-                    ExchangeMessage::NextScene => {
-                        if let Some(mut sequence_step) = sequence.pop_front() {
-                            // We save the step sequence that was manually triggered, as the most likeable case is
-                            // that the artist needs to re do the sequence from the beggining.
-                            self.last_sequence_step_played = Some(sequence_step.clone());
-                            // If the sequence step has a pre programmed duration, we automatise the steps.
-                            while let Some(timeout) = sequence_step.duration {
-                                self.dispatch_action_to_perform(&sequence_step).await?;
-                                // We wait before executing the next step.
-                                info!("Next step to be executed in {} seconds", timeout.as_secs());
-                                tokio::time::sleep(timeout).await;
-                                if let Some(next_sequence_step) = sequence.pop_front() {
-                                    sequence_step = next_sequence_step;
-                                } else {
-                                    info!("Sequence finished! Restart the show or restart the service with a new Sequence.")
-                                }
-                            }
-                            // Process the step that has None as `duration`.
-                            self.dispatch_action_to_perform(&sequence_step).await?
-                        } else {
-                            info!("Sequence finished! Restart the show or restart the service with a new Sequence.")
-                        }
-                    }
+            let auto_advance_at = self.auto_advance_at;
+            let auto_advance = async {
+                match auto_advance_at {
+                    Some(auto_advance_at) => sleep_until(auto_advance_at).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                Some(message) = self.inbox.recv() => match message {
+                    ExchangeMessage::NextScene => self.go(None).await?,
                     ExchangeMessage::RetriggerScene => {
                         if let Some(sequence_step) = &self.last_sequence_step_played {
                             self.dispatch_action_to_perform(sequence_step).await?
@@ -123,9 +294,186 @@ impl Sequencer {
                         }
                     }
                     _ => error!("Invalid ExchangeMessage received. Discarding message."),
+                },
+                Some(command) = self.command_inbox.recv() => match command {
+                    SequencerCommand::ShowControl(event) => self.on_show_control(event).await?,
+                    SequencerCommand::GoToStep(name, reply) => {
+                        let result = match self
+                            .steps
+                            .iter()
+                            .position(|sequence_step| sequence_step.name == name)
+                        {
+                            Some(index) => {
+                                self.next_step = index;
+                                self.go(None).await
+                            }
+                            None => Err(LamarrsServiceError::StepNotFound(name)),
+                        };
+                        // Nothing to do if the requester stopped waiting for the result.
+                        let _ = reply.send(result);
+                    }
+                    SequencerCommand::Reload(reply) => {
+                        let result = self.load_show_sequence().await.map(|sequence| {
+                            info!("Sequence reloaded, {} steps.", sequence.sequence.len());
+                            self.restart(sequence.sequence.into());
+                            self.steps.len()
+                        });
+                        let _ = reply.send(result);
+                    }
+                    SequencerCommand::Describe(reply) => {
+                        let _ = reply.send(self.state());
+                    }
+                },
+                _ = auto_advance => {
+                    self.auto_advance_at = None;
+                    self.play_next_step().await?
+                }
+            }
+        }
+    }
+
+    async fn on_show_control(&mut self, event: ShowControlEvent) -> Result<(), LamarrsServiceError> {
+        match event {
+            ShowControlEvent::Go { cue } => self.go(cue.as_deref()).await?,
+            ShowControlEvent::Stop => {
+                info!("Sequence stopped.");
+                self.is_stopped = true;
+                if let Some(auto_advance_at) = self.auto_advance_at.take() {
+                    self.stopped_auto_advance =
+                        Some(auto_advance_at.saturating_duration_since(Instant::now()));
+                }
+            }
+            ShowControlEvent::Resume => {
+                info!("Sequence resumed.");
+                self.is_stopped = false;
+                if let Some(remaining) = self.stopped_auto_advance.take() {
+                    self.auto_advance_at = Some(Instant::now() + remaining);
+                }
+            }
+            ShowControlEvent::Timecode { position, rate } => {
+                self.chase_timecode(position, rate).await?
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the steps, starting the sequence from the beginning.
+    fn restart(&mut self, steps: Vec<SequenceStep>) {
+        self.steps = steps;
+        self.next_step = 0;
+        self.auto_advance_at = None;
+        self.is_stopped = false;
+        self.stopped_auto_advance = None;
+        self.timecode_position = None;
+        self.last_sequence_step_played = None;
+        METRICS.sequencer_step.store(-1, Ordering::Relaxed);
+    }
+
+    fn state(&self) -> SequencerState {
+        SequencerState {
+            steps: self
+                .steps
+                .iter()
+                .map(|sequence_step| StepSummary {
+                    name: sequence_step.name.clone(),
+                    cue: sequence_step.cue.clone(),
+                    timecode: sequence_step.timecode,
+                })
+                .collect(),
+            next_step: self.next_step,
+            is_stopped: self.is_stopped,
+        }
+    }
+
+    /// Plays the next step, or the one with the cue number, and the ones following it
+    /// automatically.
+    async fn go(&mut self, cue: Option<&str>) -> Result<(), LamarrsServiceError> {
+        if let Some(cue) = cue {
+            match self
+                .steps
+                .iter()
+                .position(|sequence_step| sequence_step.cue.as_deref() == Some(cue))
+            {
+                Some(index) => self.next_step = index,
+                None => {
+                    error!("There is no step with cue {}!", cue);
+                    return Ok(());
                 }
             }
         }
+        // A new GO replaces whatever was stopped.
+        self.is_stopped = false;
+        self.stopped_auto_advance = None;
+        // We save the step sequence that was manually triggered, as the most likeable case is
+        // that the artist needs to re do the sequence from the beggining.
+        self.last_sequence_step_played = self.steps.get(self.next_step).cloned();
+        self.play_next_step().await
+    }
+
+    /// Plays the next step. If it has a pre programmed duration, the one after it is played
+    /// automatically once it is over.
+    async fn play_next_step(&mut self) -> Result<(), LamarrsServiceError> {
+        self.auto_advance_at = None;
+        let Some(sequence_step) = self.steps.get(self.next_step).cloned() else {
+            info!("Sequence finished! Restart the show or restart the service with a new Sequence.");
+            return Ok(());
+        };
+        METRICS
+            .sequencer_step
+            .store(self.next_step as i64, Ordering::Relaxed);
+        self.events.publish(ServerEvent::StepStarted {
+            index: self.next_step,
+            name: sequence_step.name.clone(),
+        });
+        self.next_step += 1;
+        self.dispatch_action_to_perform(&sequence_step).await?;
+        if let Some(timeout) = sequence_step.duration {
+            info!("Next step to be executed in {} seconds", timeout.as_secs_f32());
+            self.auto_advance_at = Some(Instant::now() + timeout);
+        }
+        Ok(())
+    }
+
+    /// Plays the steps stamped with a timecode the MTC went through since its last position.
+    /// When the timecode jumps, only the steps stamped right before the new position are played.
+    async fn chase_timecode(
+        &mut self,
+        position: Duration,
+        rate: MtcRate,
+    ) -> Result<(), LamarrsServiceError> {
+        let last_position = self.timecode_position.replace(position);
+        if self.is_stopped {
+            return Ok(());
+        }
+        let is_playing = last_position.is_some_and(|last_position| {
+            position >= last_position && position - last_position <= MAX_TIMECODE_STEP
+        });
+        if !is_playing {
+            info!("Chasing timecode from {:?}.", position);
+        }
+        let steps_reached: Vec<usize> = self
+            .steps
+            .iter()
+            .enumerate()
+            .filter_map(|(index, sequence_step)| {
+                let stamp = sequence_step.timecode?.position(rate);
+                let is_reached = match last_position {
+                    Some(last_position) if is_playing => {
+                        last_position < stamp && stamp <= position
+                    }
+                    _ => {
+                        position.saturating_sub(TIMECODE_LOCATE_WINDOW) <= stamp
+                            && stamp <= position
+                    }
+                };
+                is_reached.then_some(index)
+            })
+            .collect();
+        for index in steps_reached {
+            self.next_step = index;
+            self.play_next_step().await?;
+        }
+        Ok(())
     }
 
     async fn dispatch_action_to_perform(
@@ -133,40 +481,151 @@ impl Sequencer {
         sequence_step: &SequenceStep,
     ) -> Result<(), LamarrsServiceError> {
         info!("Executing step named {}.", sequence_step.name);
-        match sequence_step.action {
-            Action::ShowNewSubtitles(_) => {
-                self.subtitles_service
-                    .send(InternalEventMessageServer::PerformAction(
-                        sequence_step.action.clone(),
-                        sequence_step.target_location.clone(),
+        let schedule = schedule_in(self.scene_lead_time);
+        let action = match &sequence_step.action {
+            StepAction::Perform(action) => action.as_ref(),
+            StepAction::PlayMidiFile(midi_file_playback) => {
+                return Ok(self
+                    .midi_file_player
+                    .send(MidiFilePlayerMessage::Play(
+                        midi_file_playback.clone(),
+                        Box::new(sequence_step.target.clone()),
                     ))
-                    .await?
+                    .await?);
             }
-            Action::ChangeColour(_) => {
-                self.colour_service
-                    .send(InternalEventMessageServer::PerformAction(
-                        sequence_step.action.clone(),
-                        sequence_step.target_location.clone(),
-                    ))
-                    .await?
+            StepAction::StopMidiFile => {
+                return Ok(self.midi_file_player.send(MidiFilePlayerMessage::Stop).await?)
             }
-            Action::PlayAudio(_) => {
-                self.playback_service
-                    .send(InternalEventMessageServer::PerformAction(
-                        sequence_step.action.clone(),
-                        sequence_step.target_location.clone(),
-                    ))
-                    .await?
-            },
-            Action::Midi(_) => {
-                self.midi_service
-                    .send(InternalEventMessageServer::PerformAction(
-                        sequence_step.action.clone(),
-                        sequence_step.target_location.clone(),
-                    ))
-                    .await?
-            },
         };
+        let service = match action.service() {
+            Service::Subtitle => &self.subtitles_service,
+            Service::Colour => &self.colour_service,
+            Service::AudioPlayer => &self.playback_service,
+            Service::Midi => &self.midi_service,
+        };
+        service
+            .send(InternalEventMessageServer::PerformAction(
+                action.clone(),
+                Box::new(sequence_step.target.clone()),
+                schedule,
+            ))
+            .await?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lamarrs_utils::{action_messages::Action, target_selector::TargetSelector, ColourRgb};
+
+    /// Sequencer with steps changing the colour at the timecodes, at 25 frames per second.
+    fn sequencer(
+        timecodes: &[(u8, u8)],
+    ) -> (
+        Sequencer,
+        Receiver<ServerEvent>,
+        Receiver<InternalEventMessageServer>,
+    ) {
+        let (events, events_inbox) = EventPublisher::channel();
+        let (colour_service, colour_inbox) = channel(32);
+        let mut sequencer = Sequencer::new(
+            channel(1).0,
+            colour_service,
+            channel(1).0,
+            channel(1).0,
+            channel(1).0,
+            PathBuf::from("show.yaml"),
+            Duration::ZERO,
+            events,
+        );
+        let steps = timecodes
+            .iter()
+            .map(|(seconds, frames)| SequenceStep {
+                name: format!("{seconds}:{frames}"),
+                action: StepAction::Perform(Box::new(Action::ChangeColour(ColourRgb::new(
+                    0, 0, 0,
+                )))),
+                target: TargetSelector::default(),
+                duration: None,
+                cue: None,
+                timecode: Some(Timecode {
+                    hours: 0,
+                    minutes: 0,
+                    seconds: *seconds,
+                    frames: *frames,
+                }),
+            })
+            .collect();
+        sequencer.restart(steps);
+        (sequencer, events_inbox, colour_inbox)
+    }
+
+    /// Moves the timecode to the second and frame, returning the steps it played.
+    async fn chase(
+        sequencer: &mut Sequencer,
+        events_inbox: &mut Receiver<ServerEvent>,
+        seconds: u64,
+        frames: u32,
+    ) -> Vec<String> {
+        let position = Duration::from_secs(seconds) + Duration::from_millis(40) * frames;
+        sequencer
+            .chase_timecode(position, MtcRate::Fps25)
+            .await
+            .unwrap();
+        let mut started = Vec::new();
+        while let Ok(event) = events_inbox.try_recv() {
+            if let ServerEvent::StepStarted { name, .. } = event {
+                started.push(name);
+            }
+        }
+        started
+    }
+
+    #[tokio::test]
+    async fn steps_are_played_as_the_timecode_goes_through_them() {
+        let (mut sequencer, mut events, _colour) = sequencer(&[(1, 5), (1, 20), (2, 0), (5, 0)]);
+
+        assert!(chase(&mut sequencer, &mut events, 1, 0).await.is_empty());
+        assert_eq!(chase(&mut sequencer, &mut events, 1, 5).await, ["1:5"]);
+        assert_eq!(
+            chase(&mut sequencer, &mut events, 2, 0).await,
+            ["1:20", "2:0"]
+        );
+        assert!(chase(&mut sequencer, &mut events, 2, 0).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn jumps_only_play_the_steps_right_before_the_new_position() {
+        let (mut sequencer, mut events, _colour) =
+            sequencer(&[(1, 0), (3, 0), (9, 22), (9, 24), (10, 0), (10, 10)]);
+
+        assert!(chase(&mut sequencer, &mut events, 0, 0).await.is_empty());
+        // Located forward, past the steps at 1 and 3 seconds, and the one 4 frames before.
+        assert_eq!(
+            chase(&mut sequencer, &mut events, 10, 1).await,
+            ["9:24", "10:0"]
+        );
+        // Located backwards, the step at 1 second is played again once reached.
+        assert!(chase(&mut sequencer, &mut events, 0, 20).await.is_empty());
+        assert_eq!(chase(&mut sequencer, &mut events, 1, 0).await, ["1:0"]);
+    }
+
+    #[tokio::test]
+    async fn stopped_sequences_follow_the_timecode_without_playing() {
+        let (mut sequencer, mut events, _colour) = sequencer(&[(1, 0), (2, 0)]);
+
+        assert!(chase(&mut sequencer, &mut events, 0, 20).await.is_empty());
+        sequencer
+            .on_show_control(ShowControlEvent::Stop)
+            .await
+            .unwrap();
+        assert!(chase(&mut sequencer, &mut events, 1, 10).await.is_empty());
+        sequencer
+            .on_show_control(ShowControlEvent::Resume)
+            .await
+            .unwrap();
+        // The step at 1 second was gone through while stopped.
+        assert_eq!(chase(&mut sequencer, &mut events, 2, 0).await, ["2:0"]);
+    }
+}
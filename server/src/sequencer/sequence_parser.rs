@@ -1,19 +1,151 @@
-use std::{collections::VecDeque, time::Duration};
+use std::{collections::VecDeque, path::PathBuf, time::Duration};
 
-use lamarrs_utils::{action_messages::Action, RelativeLocation};
+use lamarrs_utils::{
+    action_messages::Action, midi::MidiFilePlayback, subtitles::LanguageTag,
+    target_selector::TargetSelector, Region,
+};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+use crate::show_control::Timecode;
+
+#[derive(Debug, Serialize, Clone)]
 pub struct Sequence {
     pub version: u8,
     pub sequence: VecDeque<SequenceStep>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// A Sequence as written in the sequence files, before importing the timed-text files.
+#[derive(Debug, Deserialize)]
+pub struct SequenceDefinition {
+    pub version: u8,
+    pub sequence: Vec<SequenceEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "SequenceStepDefinition")]
+pub enum SequenceEntry {
+    Step(Box<SequenceStep>),
+    TimedText(Box<TimedTextReference>),
+}
+
+/// Steps showing the cues of a SRT, WebVTT or LRC file as subtitles, one after the other.
+#[derive(Debug)]
+pub struct TimedTextReference {
+    pub name: String,
+    /// Path to the file, relative to the sequence file.
+    pub timed_text: PathBuf,
+    /// Language the subtitles are written in.
+    pub language: Option<LanguageTag>,
+    pub target: TargetSelector,
+}
+
+#[derive(Debug, Serialize, Clone)]
 pub struct SequenceStep {
     pub name: String,
-    pub action: Action,
-    pub target_location: Option<RelativeLocation>,
-    #[serde(deserialize_with = "humantime_serde::deserialize")]
+    pub action: StepAction,
+    /// Clients that perform the action.
+    pub target: TargetSelector,
     pub duration: Option<Duration>,
+    /// Number a MSC `GO` jumps to the step with.
+    pub cue: Option<String>,
+    /// The step is played when the MTC reaches this timecode.
+    pub timecode: Option<Timecode>,
+}
+
+/// What a SequenceStep does when it is played.
+#[derive(Debug, Serialize, Clone)]
+pub enum StepAction {
+    /// Performed by the targeted Clients.
+    Perform(Box<Action>),
+    /// Played by the Server, which sends its events to the targeted Clients.
+    PlayMidiFile(Box<MidiFilePlayback>),
+    StopMidiFile,
+}
+
+/// A SequenceStep, or a reference to a timed-text file, as written in the sequence files.
+#[derive(Deserialize)]
+struct SequenceStepDefinition {
+    name: String,
+    action: Option<Action>,
+    /// Replaces the `action` to import the cues of a timed-text file.
+    timed_text: Option<PathBuf>,
+    /// Replaces the `action` to play a MIDI file, relative to the sequence file.
+    midi_file: Option<MidiFilePlayback>,
+    /// Replaces the `action` to stop the MIDI file being played.
+    #[serde(default)]
+    stop_midi_file: bool,
+    /// Language of the timed-text file.
+    language: Option<LanguageTag>,
+    /// All the Clients if missing.
+    #[serde(default)]
+    target: TargetSelector,
+    /// Kept for the sequences written before `target` existed. It is added to the target regions.
+    target_location: Option<Region>,
+    #[serde(default, deserialize_with = "humantime_serde::deserialize")]
+    duration: Option<Duration>,
+    /// Not supported in timed-text steps, as they are several steps.
+    cue: Option<String>,
+    /// Not supported in timed-text steps, as they are several steps.
+    timecode: Option<Timecode>,
+}
+
+impl TryFrom<SequenceStepDefinition> for SequenceEntry {
+    type Error = String;
+
+    fn try_from(definition: SequenceStepDefinition) -> Result<Self, Self::Error> {
+        let mut target = definition.target;
+        if let Some(target_location) = definition.target_location {
+            target.regions.push(target_location).map_err(|_| {
+                format!(
+                    "Step {} targets too many regions, the maximum is {}.",
+                    definition.name,
+                    target.regions.capacity()
+                )
+            })?;
+        }
+        let step = |action| {
+            Ok(SequenceEntry::Step(Box::new(SequenceStep {
+                name: definition.name.clone(),
+                action,
+                target: target.clone(),
+                duration: definition.duration,
+                cue: definition.cue.clone(),
+                timecode: definition.timecode,
+            })))
+        };
+        match (
+            definition.action,
+            definition.timed_text,
+            definition.midi_file,
+            definition.stop_midi_file,
+        ) {
+            (Some(action), None, None, false) => step(StepAction::Perform(Box::new(action))),
+            (None, None, Some(midi_file), false) => step(StepAction::PlayMidiFile(Box::new(midi_file))),
+            (None, None, None, true) => step(StepAction::StopMidiFile),
+            (None, Some(_), None, false)
+                if definition.cue.is_some() || definition.timecode.is_some() =>
+            {
+                Err(format!(
+                    "Step {} can't have a cue nor a timecode, timed-text steps are several steps.",
+                    definition.name
+                ))
+            }
+            (None, Some(timed_text), None, false) if definition.duration.is_none() => {
+                Ok(SequenceEntry::TimedText(Box::new(TimedTextReference {
+                    name: definition.name,
+                    timed_text,
+                    language: definition.language,
+                    target,
+                })))
+            }
+            (None, Some(_), None, false) => Err(format!(
+                "Step {} can't have a duration, timed-text steps last as long as their cues.",
+                definition.name
+            )),
+            _ => Err(format!(
+                "Step {} must have exactly one of action, timed_text, midi_file or stop_midi_file.",
+                definition.name
+            )),
+        }
+    }
 }
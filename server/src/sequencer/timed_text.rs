@@ -0,0 +1,560 @@
+//! Timed text
+//!
+//! Subtitle teams deliver their work as SRT or WebVTT files, and lyrics come as LRC files.
+//! They are imported into the sequence as a list of [`SequenceStep`]s showing each cue for
+//! as long as the file says, with empty subtitles clearing the screen between cues.
+//!
+//! Sequences reference them with a step like:
+//! ```yaml
+//! - name: "Opening lyrics"
+//!   timed_text: "lyrics/opening.lrc"
+//!   language: es
+//! ```
+//! The cues start playing as soon as the step is reached, and the sequence waits for the
+//! next scene after the last one.
+
+use std::{path::Path, time::Duration};
+
+use heapless::Vec as HeaplessVec;
+use lamarrs_utils::{
+    action_messages::Action,
+    subtitles::{
+        LanguageTag, SpeakerLabel, SubtitleText, SubtitleVariant, Subtitles,
+    },
+    target_selector::TargetSelector,
+};
+use thiserror::Error;
+use tracing::warn;
+
+use crate::sequencer::sequence_parser::{SequenceStep, StepAction};
+
+/// How long the last line of an LRC file is shown, as they only say when each line starts.
+const LAST_LYRIC_DURATION: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Error)]
+pub enum TimedTextError {
+    #[error("The timed-text file could not be read")]
+    Io(#[from] std::io::Error),
+    #[error("Unsupported timed-text format {0:?}, it must be a .srt, .vtt or .lrc file.")]
+    UnsupportedFormat(String),
+    #[error("Line {line}: {reason}")]
+    Parse { line: usize, reason: String },
+}
+
+impl TimedTextError {
+    fn parse(line: usize, reason: impl Into<String>) -> Self {
+        TimedTextError::Parse {
+            line,
+            reason: reason.into(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimedTextFormat {
+    Srt,
+    WebVtt,
+    Lrc,
+}
+
+impl TimedTextFormat {
+    /// Chooses the format from the file extension.
+    pub fn from_path(path: &Path) -> Result<Self, TimedTextError> {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        match extension.as_str() {
+            "srt" => Ok(TimedTextFormat::Srt),
+            "vtt" => Ok(TimedTextFormat::WebVtt),
+            "lrc" => Ok(TimedTextFormat::Lrc),
+            _ => Err(TimedTextError::UnsupportedFormat(extension)),
+        }
+    }
+}
+
+/// A text shown from `start`, until `end` or the start of the next cue.
+#[derive(Debug, PartialEq)]
+pub struct Cue {
+    pub start: Duration,
+    pub end: Option<Duration>,
+    pub speaker: Option<String>,
+    pub text: String,
+}
+
+/// Parses the content of a timed-text file into its cues, sorted by start.
+pub fn parse(format: TimedTextFormat, content: &str) -> Result<Vec<Cue>, TimedTextError> {
+    let content = content.strip_prefix('\u{feff}').unwrap_or(content);
+    let mut cues = match format {
+        TimedTextFormat::Srt => parse_srt(content)?,
+        TimedTextFormat::WebVtt => parse_web_vtt(content)?,
+        TimedTextFormat::Lrc => parse_lrc(content)?,
+    };
+    cues.sort_by_key(|cue| cue.start);
+    Ok(cues)
+}
+
+/// Turns the cues into the steps showing them one after the other, named after `name`.
+pub fn sequence_steps(
+    cues: &[Cue],
+    name: &str,
+    language: Option<&LanguageTag>,
+    target: &TargetSelector,
+) -> Vec<SequenceStep> {
+    let step = |step_name: String, subtitles: Subtitles, duration: Option<Duration>| SequenceStep {
+        name: step_name,
+        action: StepAction::Perform(Box::new(Action::ShowNewSubtitles(Box::new(subtitles)))),
+        target: target.clone(),
+        duration,
+        cue: None,
+        timecode: None,
+    };
+    let mut steps = Vec::new();
+    let mut cursor = Duration::ZERO;
+    for (index, cue) in cues.iter().enumerate() {
+        if cue.start > cursor {
+            let blank = subtitles("", None, language, cue.start - cursor);
+            steps.push(step(format!("{name} gap {index}"), blank, Some(cue.start - cursor)));
+        }
+        // Overlapping cues are cut when the next one starts.
+        let next_start = cues.get(index + 1).map(|next| next.start);
+        let end = match (cue.end, next_start) {
+            (Some(end), Some(next_start)) => end.min(next_start),
+            (Some(end), None) => end,
+            (None, Some(next_start)) => next_start,
+            (None, None) => cue.start + LAST_LYRIC_DURATION,
+        };
+        if end <= cue.start {
+            continue;
+        }
+        // Cues too long for the screen are split in pages by the subtitles Service.
+        let duration = end - cue.start;
+        let shown = subtitles(&cue.text, cue.speaker.as_deref(), language, duration);
+        steps.push(step(format!("{name} {}", index + 1), shown, Some(duration)));
+        cursor = end;
+    }
+    // Clears the last cue and waits for the next scene.
+    steps.push(step(
+        format!("{name} end"),
+        subtitles("", None, language, LAST_LYRIC_DURATION),
+        None,
+    ));
+    steps
+}
+
+/// Subtitles whose pages, if the text doesn't fit a single one, share the `duration`.
+fn subtitles(
+    text: &str,
+    speaker: Option<&str>,
+    language: Option<&LanguageTag>,
+    duration: Duration,
+) -> Subtitles {
+    let speaker = speaker.map(|speaker| {
+        let label = truncate(speaker, SpeakerLabel::new().capacity());
+        if label.len() < speaker.len() {
+            warn!("Speaker {speaker:?} is too long, it will be shown as {label:?}");
+        }
+        // Truncated to fit.
+        SpeakerLabel::try_from(label).unwrap_or_default()
+    });
+    let variant = SubtitleVariant {
+        language: language.cloned(),
+        speaker,
+        text: SubtitleText::from(text),
+    };
+    let pages = variant.pages().count().max(1) as u32;
+    let mut variants = HeaplessVec::new();
+    // A single variant always fits.
+    let _ = variants.push(variant);
+    Subtitles {
+        variants,
+        page_duration_ms: (duration.as_millis() as u32 / pages).max(1),
+    }
+}
+
+/// The longest start of `text` up to `max_bytes` long that doesn't cut a character.
+fn truncate(text: &str, max_bytes: usize) -> &str {
+    let end = (0..=max_bytes.min(text.len()))
+        .rev()
+        .find(|index| text.is_char_boundary(*index))
+        .unwrap_or_default();
+    &text[..end]
+}
+
+/// Parses `[hh:]mm:ss[.,]fff` timestamps. The fraction can have any amount of digits, so
+/// it also reads the hundredths of a second of the LRC files.
+fn parse_timestamp(timestamp: &str) -> Option<Duration> {
+    let (clock, fraction) = match timestamp.trim().split_once(['.', ',']) {
+        Some((clock, fraction)) => (clock, fraction),
+        None => (timestamp.trim(), ""),
+    };
+    let mut seconds = 0u64;
+    let fields = clock.split(':').collect::<Vec<_>>();
+    if !(2..=3).contains(&fields.len()) {
+        return None;
+    }
+    for field in fields {
+        if field.is_empty() || !field.bytes().all(|byte| byte.is_ascii_digit()) {
+            return None;
+        }
+        seconds = seconds * 60 + field.parse::<u64>().ok()?;
+    }
+    if !fraction.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let millis = fraction
+        .bytes()
+        .chain(std::iter::repeat(b'0'))
+        .take(3)
+        .fold(0u64, |millis, digit| millis * 10 + (digit - b'0') as u64);
+    Some(Duration::from_secs(seconds) + Duration::from_millis(millis))
+}
+
+/// Parses a `start --> end` cue timing. WebVTT allows cue settings after the end.
+fn parse_cue_timing(line_number: usize, line: &str) -> Result<(Duration, Duration), TimedTextError> {
+    let invalid = || {
+        TimedTextError::parse(
+            line_number,
+            format!("expected a cue timing like 00:00:01,000 --> 00:00:04,000, found {line:?}"),
+        )
+    };
+    let (start, rest) = line.split_once("-->").ok_or_else(invalid)?;
+    let end = rest.split_whitespace().next().ok_or_else(invalid)?;
+    let start = parse_timestamp(start).ok_or_else(invalid)?;
+    let end = parse_timestamp(end).ok_or_else(invalid)?;
+    if end < start {
+        return Err(TimedTextError::parse(line_number, "the cue ends before it starts"));
+    }
+    Ok((start, end))
+}
+
+/// Groups the lines in blocks separated by empty lines, keeping the number of each line.
+fn blocks(content: &str) -> Vec<Vec<(usize, &str)>> {
+    let mut blocks = Vec::new();
+    let mut block = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim_end();
+        match line.is_empty() {
+            true if !block.is_empty() => blocks.push(std::mem::take(&mut block)),
+            true => {}
+            false => block.push((index + 1, line)),
+        }
+    }
+    if !block.is_empty() {
+        blocks.push(block);
+    }
+    blocks
+}
+
+/// Removes the formatting tags, like `<i>` or `{\an8}`, and decodes the escaped characters.
+fn strip_markup(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut closing = None;
+    for character in text.chars() {
+        match (closing, character) {
+            (None, '<') => closing = Some('>'),
+            (None, '{') => closing = Some('}'),
+            (None, _) => stripped.push(character),
+            (Some(end), _) if character == end => closing = None,
+            (Some(_), _) => {}
+        }
+    }
+    stripped
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+/// Joins the lines of a cue, as they are wrapped again to fit the screens of the Clients.
+fn cue_text<'a>(lines: impl Iterator<Item = &'a (usize, &'a str)>) -> String {
+    lines
+        .map(|(_, line)| strip_markup(line))
+        .collect::<Vec<_>>()
+        .join(" ")
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn parse_srt(content: &str) -> Result<Vec<Cue>, TimedTextError> {
+    let mut cues = Vec::new();
+    for block in blocks(content) {
+        let mut lines = block.iter().peekable();
+        // The cue number is optional for most players.
+        if let Some((_, line)) = lines.peek() {
+            if line.bytes().all(|byte| byte.is_ascii_digit()) {
+                lines.next();
+            }
+        }
+        let Some((line_number, timing)) = lines.next() else {
+            let (line_number, _) = block[0];
+            return Err(TimedTextError::parse(line_number, "expected a cue timing after the cue number"));
+        };
+        let (start, end) = parse_cue_timing(*line_number, timing)?;
+        cues.push(Cue {
+            start,
+            end: Some(end),
+            speaker: None,
+            text: cue_text(lines),
+        });
+    }
+    Ok(cues)
+}
+
+fn parse_web_vtt(content: &str) -> Result<Vec<Cue>, TimedTextError> {
+    let mut blocks = blocks(content).into_iter();
+    match blocks.next() {
+        Some(header) if header[0].1.starts_with("WEBVTT") => {}
+        Some(header) => {
+            return Err(TimedTextError::parse(header[0].0, "WebVTT files must start with WEBVTT"))
+        }
+        None => return Err(TimedTextError::parse(1, "WebVTT files must start with WEBVTT")),
+    }
+    let mut cues = Vec::new();
+    for block in blocks {
+        let (_, first_line) = block[0];
+        if ["NOTE", "STYLE", "REGION"]
+            .iter()
+            .any(|keyword| first_line.split_whitespace().next() == Some(keyword))
+        {
+            continue;
+        }
+        // Cues may have an identifier before the timing.
+        let timing_index = usize::from(!first_line.contains("-->"));
+        let Some((line_number, timing)) = block.get(timing_index) else {
+            return Err(TimedTextError::parse(
+                block[0].0,
+                "expected a cue timing after the cue identifier",
+            ));
+        };
+        let (start, end) = parse_cue_timing(*line_number, timing)?;
+        let text_lines = &block[timing_index + 1..];
+        // The voice tag tells who is speaking, like `<v Roger Bingham>`.
+        let speaker = text_lines.first().and_then(|(_, line)| {
+            let voice = line.strip_prefix("<v")?;
+            let (voice, _) = voice.split_once('>')?;
+            // Voices can have classes, like `<v.loud Roger>`.
+            let (_, name) = voice.split_once(char::is_whitespace)?;
+            Some(name.trim().to_string())
+        });
+        cues.push(Cue {
+            start,
+            end: Some(end),
+            speaker,
+            text: cue_text(text_lines.iter()),
+        });
+    }
+    Ok(cues)
+}
+
+fn parse_lrc(content: &str) -> Result<Vec<Cue>, TimedTextError> {
+    let mut cues = Vec::new();
+    // Milliseconds the lyrics are shown earlier, from the `[offset:]` tag.
+    let mut offset_ms = 0i64;
+    for (index, line) in content.lines().enumerate() {
+        let line_number = index + 1;
+        let mut rest = line.trim();
+        if rest.is_empty() {
+            continue;
+        }
+        let mut starts = Vec::new();
+        let mut is_metadata = false;
+        while let Some(tagged) = rest.strip_prefix('[') {
+            let Some((tag, after_tag)) = tagged.split_once(']') else {
+                return Err(TimedTextError::parse(line_number, "unclosed tag, missing ]"));
+            };
+            rest = after_tag.trim_start();
+            if let Some(start) = parse_timestamp(tag) {
+                starts.push(start);
+                continue;
+            }
+            // Metadata, like `[ar:Artist]`, is ignored but for the offset.
+            is_metadata = true;
+            if let Some(offset) = tag.strip_prefix("offset:") {
+                offset_ms = offset.trim().parse().map_err(|_| {
+                    TimedTextError::parse(line_number, format!("invalid offset {offset:?}"))
+                })?;
+            }
+        }
+        if starts.is_empty() {
+            if is_metadata {
+                continue;
+            }
+            return Err(TimedTextError::parse(
+                line_number,
+                format!("expected a timestamp like [01:23.45], found {line:?}"),
+            ));
+        }
+        // Enhanced LRC word timestamps, like `<01:23.45>`, are removed with the markup.
+        let text = cue_text([(line_number, rest)].iter());
+        for start in starts {
+            let start_ms = start.as_millis() as i64 - offset_ms;
+            cues.push(Cue {
+                start: Duration::from_millis(start_ms.max(0) as u64),
+                end: None,
+                speaker: None,
+                text: text.clone(),
+            });
+        }
+    }
+    Ok(cues)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cue(start_ms: u64, end_ms: Option<u64>, text: &str) -> Cue {
+        Cue {
+            start: Duration::from_millis(start_ms),
+            end: end_ms.map(Duration::from_millis),
+            speaker: None,
+            text: text.to_string(),
+        }
+    }
+
+    /// The text shown by the step, with its duration.
+    fn shown(step: &SequenceStep) -> (&str, Option<Duration>) {
+        let StepAction::Perform(action) = &step.action else {
+            panic!("{} doesn't perform an action", step.name);
+        };
+        let Action::ShowNewSubtitles(subtitles) = action.as_ref() else {
+            panic!("{} doesn't show subtitles", step.name);
+        };
+        (subtitles.variants[0].text.as_str(), step.duration)
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(parse_timestamp("00:00:01,500"), Some(Duration::from_millis(1500)));
+        assert_eq!(parse_timestamp("01:02:03.004"), Some(Duration::from_millis(3_723_004)));
+        // LRC hundredths of a second, and WebVTT timestamps without hours.
+        assert_eq!(parse_timestamp("01:23.45"), Some(Duration::from_millis(83_450)));
+        assert_eq!(parse_timestamp("00:07"), Some(Duration::from_secs(7)));
+        assert_eq!(parse_timestamp("7"), None);
+        assert_eq!(parse_timestamp("00:-1.000"), None);
+        assert_eq!(parse_timestamp("00:01.5a"), None);
+        assert_eq!(parse_timestamp("ar:Artist"), None);
+    }
+
+    #[test]
+    fn formats_are_chosen_by_extension() {
+        let format = |path| TimedTextFormat::from_path(Path::new(path)).unwrap();
+        assert_eq!(format("a/b.SRT"), TimedTextFormat::Srt);
+        assert_eq!(format("b.vtt"), TimedTextFormat::WebVtt);
+        assert_eq!(format("b.lrc"), TimedTextFormat::Lrc);
+        assert!(matches!(
+            TimedTextFormat::from_path(Path::new("b.txt")),
+            Err(TimedTextError::UnsupportedFormat(extension)) if extension == "txt"
+        ));
+    }
+
+    #[test]
+    fn srt_cues() {
+        let content = "\u{feff}2\r\n00:00:05,000 --> 00:00:07,250\r\n\
+                       <i>Second</i> &amp; last\r\n\r\n\
+                       1\n00:00:01,000 --> 00:00:04,000\n{\\an8}First line,\n  wrapped  \n\n\n";
+        let cues = parse(TimedTextFormat::Srt, content).unwrap();
+        assert_eq!(
+            cues,
+            [cue(1000, Some(4000), "First line, wrapped"), cue(5000, Some(7250), "Second & last")]
+        );
+    }
+
+    #[test]
+    fn web_vtt_cues() {
+        let content = "WEBVTT - Opening\n\n\
+                       NOTE Translated by the subtitle team\n\n\
+                       STYLE\n::cue { color: white }\n\n\
+                       intro\n00:01.000 --> 00:03.500 line:0 position:50%\n\
+                       <v.loud Roger Bingham>Hello &lt;there&gt;</v>\n\n\
+                       00:00:04.000 --> 00:00:06.000\nNo speaker\n";
+        let cues = parse(TimedTextFormat::WebVtt, content).unwrap();
+        assert_eq!(
+            cues,
+            [
+                Cue {
+                    speaker: Some("Roger Bingham".to_string()),
+                    ..cue(1000, Some(3500), "Hello <there>")
+                },
+                cue(4000, Some(6000), "No speaker"),
+            ]
+        );
+    }
+
+    #[test]
+    fn lrc_cues() {
+        let content = "[ar:Artist]\n[ti:Title]\n[offset:+500]\n\n\
+                       [00:12.00][00:42.50]Chorus <00:12.50>line\n\
+                       [00:01.20]Verse\n";
+        let cues = parse(TimedTextFormat::Lrc, content).unwrap();
+        assert_eq!(
+            cues,
+            [
+                cue(700, None, "Verse"),
+                cue(11_500, None, "Chorus line"),
+                cue(42_000, None, "Chorus line"),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_files_tell_the_line() {
+        let line = |result: Result<Vec<Cue>, TimedTextError>| match result {
+            Err(TimedTextError::Parse { line, .. }) => line,
+            other => panic!("expected a parse error, got {other:?}"),
+        };
+        let srt = "1\n00:00:01,000 --> 00:00:02,000\nFine\n\n\
+                   2\n00:00:03,000 -> 00:00:04,000\nOops\n";
+        assert_eq!(line(parse(TimedTextFormat::Srt, srt)), 6);
+        let backwards = "1\n00:00:04,000 --> 00:00:02,000\nBackwards\n";
+        assert_eq!(line(parse(TimedTextFormat::Srt, backwards)), 2);
+        assert_eq!(line(parse(TimedTextFormat::Srt, "\n\n1\n")), 3);
+        assert_eq!(line(parse(TimedTextFormat::WebVtt, "00:01.000 --> 00:02.000\nHi\n")), 1);
+        assert_eq!(line(parse(TimedTextFormat::WebVtt, "")), 1);
+        assert_eq!(line(parse(TimedTextFormat::Lrc, "[00:01.00]Fine\nNo timestamp\n")), 2);
+        assert_eq!(line(parse(TimedTextFormat::Lrc, "[00:01.00\n")), 1);
+        assert_eq!(line(parse(TimedTextFormat::Lrc, "[offset:soon]\n")), 1);
+    }
+
+    #[test]
+    fn steps_fill_the_gaps_and_cut_overlapping_cues() {
+        let cues = [
+            cue(1000, Some(3000), "One"),
+            cue(2500, Some(4000), "Two"),
+            cue(6000, None, "Three"),
+        ];
+        let steps = sequence_steps(&cues, "Intro", None, &TargetSelector::all());
+        let names = steps.iter().map(|step| step.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            ["Intro gap 0", "Intro 1", "Intro 2", "Intro gap 2", "Intro 3", "Intro end"]
+        );
+        let shown = steps.iter().map(shown).collect::<Vec<_>>();
+        let millis = |millis| Some(Duration::from_millis(millis));
+        assert_eq!(
+            shown,
+            [
+                ("", millis(1000)),
+                ("One", millis(1500)),
+                ("Two", millis(1500)),
+                ("", millis(2000)),
+                ("Three", Some(LAST_LYRIC_DURATION)),
+                // The sequence waits for the next scene.
+                ("", None),
+            ]
+        );
+    }
+
+    #[test]
+    fn long_speakers_are_truncated_on_a_character() {
+        let speaker = "ñ".repeat(SpeakerLabel::new().capacity());
+        let subtitles = subtitles("Hi", Some(&speaker), None, Duration::from_secs(1));
+        let label = subtitles.variants[0].speaker.as_ref().unwrap();
+        assert_eq!(label.as_str(), "ñ".repeat(SpeakerLabel::new().capacity() / 2));
+        assert_eq!(subtitles.page_duration_ms, 1000);
+    }
+}
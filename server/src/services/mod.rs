@@ -2,19 +2,31 @@ pub mod service;
 
 use lamarrs_utils::{
     action_messages::{Action, Event},
-    exchange_messages::{AckResult, ExchangeMessage, NackResult},
-    ClientIdAndLocation, RelativeLocation,
+    clock::Schedule,
+    exchange_messages::{AckResult, ExchangeMessage, NackResult, RequestId},
+    target_selector::TargetSelector,
+    ClientIdAndLocation, Position, Service,
 };
-use tokio::sync::mpsc::{self, Sender};
-use tracing::{error, info, instrument};
+use rand::seq::SliceRandom;
+use tokio::sync::{
+    mpsc::{self, Sender},
+    oneshot,
+};
+use tracing::{debug, error, info, instrument};
 use uuid::Uuid;
 
+use crate::events::{EventPublisher, ServerEvent};
+use crate::metrics::METRICS;
+use crate::midi_file::MidiFilePlayerMessage;
+
 use std::{
     collections::{
         hash_map::{self, Entry},
-        HashMap,
+        HashMap, HashSet,
     },
     fmt::Display,
+    sync::atomic::Ordering,
+    time::Instant,
 };
 
 #[derive(Debug, thiserror::Error)]
@@ -27,6 +39,8 @@ pub enum LamarrsServiceError {
     ),
     #[error("Error sending an InternalEventMessageServer")]
     SendInternalEventMessage(#[from] mpsc::error::SendError<InternalEventMessageServer>),
+    #[error("Error sending a MidiFilePlayerMessage")]
+    SendMidiFilePlayerMessage(#[from] mpsc::error::SendError<MidiFilePlayerMessage>),
     #[error("The message type {} requested to send to the Target Clients is not allowed for the service {}.", action_message, service)]
     NotAllowedMessageType {
         action_message: String,
@@ -36,6 +50,8 @@ pub enum LamarrsServiceError {
     ClientNotFound { service: String },
     #[error("Client is already subscribed to {}.", service)]
     ClientAlreadySubscribed { service: String },
+    #[error("There is no step named {0} in the sequence.")]
+    StepNotFound(String),
 }
 
 /// This is a distorted re-export of the ActionMessage from utils.
@@ -45,25 +61,86 @@ pub enum LamarrsServiceError {
 /// TODO: Add these to `utils` and gate them under a feature flag: https://stackoverflow.com/questions/75599346/what-are-the-consequences-of-a-feature-gated-enum-variant
 /// Internal message types to be transmited between actors inside Lamarrs server.
 /// These are also the payloads the clients will be sending inside the Exchange Messages.
+/// The `RequestId`s are the ones of the remote Client requests that originated the message, and
+/// are echoed back in the `Ack`/`Nack` sent to the Client.
 #[derive(Debug)]
 pub enum InternalEventMessageServer {
-    AddTargetClient(ClientIdAndLocation, Sender<ExchangeMessage>),
-    RemoveTargetClient(ClientIdAndLocation),
-    UpdateClientData(ClientIdAndLocation, Sender<ExchangeMessage>),
-    PerformAction(Action, Option<RelativeLocation>),
+    AddTargetClient(ClientProfile, Sender<ExchangeMessage>, RequestId),
+    /// The Client is only notified of the results if the removal was requested by it.
+    RemoveTargetClient(ClientIdAndLocation, Sender<ExchangeMessage>, Option<RequestId>),
+    /// The Client is not notified, as its request is answered by its handler. The Service
+    /// replies whether the Client is subscribed to it.
+    UpdateClientData(ClientProfile, Sender<ExchangeMessage>, oneshot::Sender<bool>),
+    /// The selector is boxed, as it is much bigger than the rest of the messages.
+    /// Without a schedule, the Clients perform the action as soon as they receive it.
+    PerformAction(Action, Box<TargetSelector>, Option<Schedule>),
+    JoinGroup(Uuid, String),
+    LeaveGroup(Uuid, String),
+    /// Replies with the Clients subscribed to the Service.
+    ListTargetClients(oneshot::Sender<Vec<TargetClientInfo>>),
+}
+
+/// What the Server knows about a Client, once its seat, if any, has been resolved.
+#[derive(Clone, Debug)]
+pub struct ClientProfile {
+    pub id: ClientIdAndLocation,
+    /// Section of the venue the Client seat belongs to.
+    pub section: Option<String>,
+    pub groups: HashSet<String>,
+    /// Language the Client prefers its subtitles in.
+    pub language: Option<String>,
 }
 
 #[derive(Debug)]
 pub struct TargetClient {
     sender: Sender<ExchangeMessage>,
-    location: Option<RelativeLocation>,
+    location: Option<Position>,
+    section: Option<String>,
+    groups: HashSet<String>,
+    language: Option<String>,
+}
+
+/// What a Service knows about one of its subscribed Clients.
+#[derive(Clone, Debug)]
+pub struct TargetClientInfo {
+    pub uuid: Uuid,
+    pub location: Option<Position>,
+    pub section: Option<String>,
+    pub groups: HashSet<String>,
+    pub language: Option<String>,
 }
 
 pub trait LamarrsService: Display {
-    fn action_is_allowed(&self, message: &Action) -> bool;
+    /// Service the Clients subscribe to, to be performed by this one.
+    fn service(&self) -> Service;
+    fn action_is_allowed(&self, message: &Action) -> bool {
+        message.service() == self.service()
+    }
     fn get_target_client_map(&mut self) -> &mut HashMap<Uuid, TargetClient>;
+    fn events(&self) -> &EventPublisher;
     async fn receive_message(&mut self) -> Option<InternalEventMessageServer>;
 
+    /// Adapts the action to the Client performing it, as the list of actions the Client must
+    /// perform one after the other, each with its own schedule. By default, the action is sent as is.
+    fn actions_for(
+        action: &Action,
+        schedule: Option<&Schedule>,
+        _target_client: &TargetClient,
+    ) -> Vec<(Action, Option<Schedule>)> {
+        vec![(action.clone(), schedule.cloned())]
+    }
+
+    /// Sends the actions adapted to a Client, as returned by `actions_for`. By default, they
+    /// are all sent straight away, each with its own schedule.
+    async fn send_actions(
+        &mut self,
+        _uuid: Uuid,
+        sender: Sender<ExchangeMessage>,
+        actions: Vec<(Action, Option<Schedule>)>,
+    ) -> Result<(), LamarrsServiceError> {
+        send_actions(&self.service(), &sender, actions).await
+    }
+
     /// Runs the Service.
     #[instrument(name = "service::run", skip(self), fields(service=self.to_string()), level = "INFO", ret, err)]
     async fn run(&mut self) -> Result<(), LamarrsServiceError> {
@@ -71,32 +148,67 @@ pub trait LamarrsService: Display {
             while let Some(message) = self.receive_message().await {
                 let results = match message {
                     InternalEventMessageServer::AddTargetClient(
-                        client_id_and_location,
+                        client_profile,
                         client_sender,
+                        request_id,
                     ) => {
-                        self.insert_target_client(client_id_and_location, client_sender)
+                        self.insert_target_client(client_profile, client_sender, request_id)
                             .await
                     }
                     InternalEventMessageServer::UpdateClientData(
+                        client_profile,
+                        client_sender,
+                        reply,
+                    ) => {
+                        let updated = self.update_target_client(client_profile, client_sender);
+                        // Nothing to do if the handler stopped waiting for the answer.
+                        let _ = reply.send(updated);
+                        Ok(())
+                    }
+                    InternalEventMessageServer::RemoveTargetClient(
                         client_id_and_location,
                         client_sender,
+                        request_id,
                     ) => {
-                        self.update_target_client(client_id_and_location, client_sender)
+                        self.remove_target_client(client_id_and_location, client_sender, request_id)
                             .await
                     }
-                    InternalEventMessageServer::RemoveTargetClient(client_id_and_location) => {
-                        self.remove_target_client(client_id_and_location).await
-                    }
                     InternalEventMessageServer::PerformAction(
                         message_for_subscribed_clients,
-                        relative_location,
+                        target_selector,
+                        schedule,
                     ) => {
                         self.write_to_target_clients(
                             message_for_subscribed_clients,
-                            relative_location,
+                            *target_selector,
+                            schedule,
                         )
                         .await
                     }
+                    InternalEventMessageServer::JoinGroup(uuid, group) => {
+                        self.update_target_client_groups(uuid, group, true);
+                        Ok(())
+                    }
+                    InternalEventMessageServer::LeaveGroup(uuid, group) => {
+                        self.update_target_client_groups(uuid, group, false);
+                        Ok(())
+                    }
+                    InternalEventMessageServer::ListTargetClients(reply) => {
+                        let target_clients = self
+                            .get_target_client_map()
+                            .iter()
+                            .map(|(uuid, target_client)| TargetClientInfo {
+                                uuid: *uuid,
+                                location: target_client.location,
+                                section: target_client.section.clone(),
+                                groups: target_client.groups.clone(),
+                                language: target_client.language.clone(),
+                            })
+                            .collect();
+                        // Nothing to do if the requester stopped waiting for the list.
+                        let _ = reply.send(target_clients);
+                        Ok(())
+                    }
                     _ => {
                         return Err(LamarrsServiceError::Service {
                             service: self.to_string(),
@@ -104,40 +216,53 @@ pub trait LamarrsService: Display {
                     }
                 };
                 if let Err(service_error) = results {
-                    error!("{:?}", service_error)
+                    error!("{:?}", service_error);
+                    // Lost Clients are removed from every Service, whether they subscribed
+                    // to it or not, so those are not failures of the Service.
+                    if !matches!(service_error, LamarrsServiceError::ClientNotFound { .. }) {
+                        self.events().publish(ServerEvent::ServiceError {
+                            service: self.to_string(),
+                            error: service_error.to_string(),
+                        });
+                    }
                 };
+                let subscribers = self.get_target_client_map().len() as u64;
+                METRICS
+                    .service(&self.service())
+                    .subscribers
+                    .store(subscribers, Ordering::Relaxed);
             }
         }
     }
 
-    /// Updates a Client into the current Service target list if already exists.
+    /// Updates a Client into the current Service target list if already exists, returning
+    /// whether it does.
     /// Services hold their TargetClients in a HashMap using the Client UUID as Key.
-    async fn update_target_client(
+    fn update_target_client(
         &mut self,
-        client_id_and_location: ClientIdAndLocation,
+        client_profile: ClientProfile,
         new_client_sender: Sender<ExchangeMessage>,
-    ) -> Result<(), LamarrsServiceError> {
+    ) -> bool {
         info!(
-            ?client_id_and_location,
-            "Processing adding Client to Service {}",
+            ?client_profile,
+            "Processing updating Client in Service {}",
             self.to_string()
         );
 
         let target_map = self.get_target_client_map();
 
-        if let Entry::Occupied(mut client_entry) = target_map.entry(client_id_and_location.uuid) {
-            info!(?client_id_and_location.uuid, "Found entry for");
+        if let Entry::Occupied(mut client_entry) = target_map.entry(client_profile.id.uuid) {
+            info!(?client_profile.id.uuid, "Found entry for");
             let client: &mut TargetClient = client_entry.get_mut();
             client.sender = new_client_sender;
-            // If already subscribed, it uses the saved sender to notify the Client.
-            Ok(client
-                .sender
-                .send(ExchangeMessage::Ack(AckResult::UpdatedSubscription))
-                .await?)
+            client.location = client_profile.id.location;
+            client.section = client_profile.section;
+            client.language = client_profile.language;
+            // A reconnecting Client doesn't know the groups it joined before, so they are kept.
+            client.groups.extend(client_profile.groups);
+            true
         } else {
-            Err(LamarrsServiceError::ClientNotFound {
-                service: self.to_string(),
-            })
+            false
         }
     }
 
@@ -145,25 +270,29 @@ pub trait LamarrsService: Display {
     /// Services hold their TargetClients in a HashMap using the Client UUID as Key.
     async fn insert_target_client(
         &mut self,
-        client_id_and_location: ClientIdAndLocation,
+        client_profile: ClientProfile,
         client_sender: Sender<ExchangeMessage>,
+        request_id: RequestId,
     ) -> Result<(), LamarrsServiceError> {
         info!(
-            ?client_id_and_location,
+            ?client_profile,
             "Processing adding Client to Service {}",
             self.to_string()
         );
 
         let target_map = self.get_target_client_map();
 
-        match target_map.entry(client_id_and_location.uuid) {
+        match target_map.entry(client_profile.id.uuid) {
             hash_map::Entry::Occupied(mut client_entry) => {
-                info!(?client_id_and_location.uuid, "Found entry for");
+                info!(?client_profile.id.uuid, "Found entry for");
                 let client: &mut TargetClient = client_entry.get_mut();
                 // If already subscribed, it uses the saved sender to notify the Client.
                 client
                     .sender
-                    .send(ExchangeMessage::Nack(NackResult::AlreadySubscribed))
+                    .send(ExchangeMessage::Nack(
+                        Some(request_id),
+                        NackResult::AlreadySubscribed,
+                    ))
                     .await?;
                 Err(LamarrsServiceError::ClientAlreadySubscribed {
                     service: self.to_string(),
@@ -172,24 +301,34 @@ pub trait LamarrsService: Display {
             hash_map::Entry::Vacant(_) => {
                 // This function inserts if it doesn't exist and updates if it does. Perfect for upserting.
                 target_map.insert(
-                    client_id_and_location.uuid,
+                    client_profile.id.uuid,
                     TargetClient {
                         sender: client_sender.clone(),
-                        location: client_id_and_location.location,
+                        location: client_profile.id.location,
+                        section: client_profile.section,
+                        groups: client_profile.groups,
+                        language: client_profile.language,
                     },
                 );
                 client_sender
-                    .send(ExchangeMessage::Ack(AckResult::Success))
+                    .send(ExchangeMessage::Ack(Some(request_id), AckResult::Success))
                     .await?; // If subscribed successfully, it uses the received sender to notify the Client.
+                self.events().publish(ServerEvent::ClientSubscribed {
+                    uuid: client_profile.id.uuid,
+                    service: self.service(),
+                });
                 Ok(())
             }
         }
     }
 
     /// Removes a Client from the Service target list.
+    /// If the removal was requested by the Client, it is notified of the results.
     async fn remove_target_client(
         &mut self,
         client_id_and_location: ClientIdAndLocation,
+        client_sender: Sender<ExchangeMessage>,
+        request_id: Option<RequestId>,
     ) -> Result<(), LamarrsServiceError> {
         info!(
             ?client_id_and_location,
@@ -203,25 +342,53 @@ pub trait LamarrsService: Display {
             hash_map::Entry::Occupied(client_entry) => {
                 info!(?client_id_and_location.uuid, "Found entry for");
                 client_entry.remove_entry();
+                if request_id.is_some() {
+                    client_sender
+                        .send(ExchangeMessage::Ack(request_id, AckResult::Success))
+                        .await?;
+                }
                 Ok(())
             }
-            hash_map::Entry::Vacant(_) => Err(LamarrsServiceError::ClientNotFound {
-                service: self.to_string(),
-            }),
+            hash_map::Entry::Vacant(_) => {
+                if request_id.is_some() {
+                    client_sender
+                        .send(ExchangeMessage::Nack(request_id, NackResult::NotSubscribed))
+                        .await?;
+                }
+                Err(LamarrsServiceError::ClientNotFound {
+                    service: self.to_string(),
+                })
+            }
+        }
+    }
+
+    /// Adds or removes a group from the Client groups. Clients that are not targets
+    /// of this Service are ignored, their groups will be known if they subscribe to it.
+    fn update_target_client_groups(&mut self, uuid: Uuid, group: String, join: bool) {
+        if let Some(client) = self.get_target_client_map().get_mut(&uuid) {
+            if join {
+                client.groups.insert(group);
+            } else {
+                client.groups.remove(&group);
+            }
         }
     }
 
     async fn write_to_target_clients(
         &mut self,
         message_for_subscribed_clients: Action,
-        relative_location: Option<RelativeLocation>,
+        target_selector: TargetSelector,
+        schedule: Option<Schedule>,
     ) -> Result<(), LamarrsServiceError> {
         info!(
-            "Updating all the target Client from Service {}. Target location is: {:?}",
+            ?schedule,
+            "Updating all the target Client from Service {}. Target selector is: {:?}",
             self.to_string(),
-            relative_location
+            target_selector
         );
 
+        let received_at = Instant::now();
+        let metrics = METRICS.service(&self.service());
         if !self.action_is_allowed(&message_for_subscribed_clients) {
             return Err(LamarrsServiceError::NotAllowedMessageType {
                 action_message: message_for_subscribed_clients.to_string(),
@@ -229,30 +396,55 @@ pub trait LamarrsService: Display {
             });
         }
 
-        let target_senders_filtered_by_location = self
+        let mut selected_target_clients = self
             .get_target_client_map()
+            .iter()
+            .filter(|(uuid, target_client)| {
+                target_selector.selects(
+                    uuid,
+                    target_client.location.as_ref(),
+                    target_client.section.as_deref(),
+                    |group| target_client.groups.contains(group),
+                )
+            })
+            .collect::<Vec<_>>();
+        let amount_of_targets = target_selector.amount_of_targets(selected_target_clients.len());
+        if amount_of_targets < selected_target_clients.len() {
+            selected_target_clients.shuffle(&mut rand::rng());
+            selected_target_clients.truncate(amount_of_targets);
+        }
+        let actions_per_client = selected_target_clients
             .into_iter()
-            .filter_map(|(_, target_client)| {
-                if relative_location.is_some() {
-                    if target_client.location == relative_location {
-                        Some(&target_client.sender)
-                    } else {
-                        None
-                    }
-                } else if target_client.location.is_none() {
-                    Some(&target_client.sender)
-                } else {
-                    None
-                }
+            .map(|(uuid, target_client)| {
+                debug!(?uuid, section = ?target_client.section, "Sending action to Client");
+                let actions =
+                    Self::actions_for(&message_for_subscribed_clients, schedule.as_ref(), target_client);
+                (*uuid, target_client.sender.clone(), actions)
             })
             .collect::<Vec<_>>();
-        for sender in target_senders_filtered_by_location {
-            sender
-                .send(ExchangeMessage::Scene(Event::PerformAction(
-                    message_for_subscribed_clients.clone(),
-                )))
-                .await?
+        for (uuid, sender, actions) in actions_per_client {
+            self.send_actions(uuid, sender, actions).await?;
         }
+        metrics.handoff_latency.observe(received_at.elapsed());
         Ok(())
     }
 }
+
+/// Sends the actions to the Client handler, counting them in the metrics of the Service.
+async fn send_actions(
+    service: &Service,
+    sender: &Sender<ExchangeMessage>,
+    actions: Vec<(Action, Option<Schedule>)>,
+) -> Result<(), LamarrsServiceError> {
+    let metrics = METRICS.service(service);
+    for (action, schedule) in actions {
+        sender
+            .send(ExchangeMessage::Scene(Event::PerformAction(action), schedule))
+            .await
+            .inspect_err(|_| {
+                metrics.send_failures.fetch_add(1, Ordering::Relaxed);
+            })?;
+        metrics.actions_dispatched.fetch_add(1, Ordering::Relaxed);
+    }
+    Ok(())
+}
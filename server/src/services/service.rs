@@ -1,25 +1,40 @@
-use lamarrs_utils::action_messages::Action;
-use tokio::sync::mpsc::{channel, Receiver, Sender};
+use lamarrs_utils::{
+    action_messages::Action, clock::Schedule, exchange_messages::ExchangeMessage, Service,
+};
+use tokio::{
+    sync::mpsc::{channel, Receiver, Sender},
+    task::AbortHandle,
+    time::sleep,
+};
 use uuid::Uuid;
 
-use std::{collections::HashMap, fmt};
+use std::{collections::HashMap, fmt, time::Duration};
 
-use crate::services::{InternalEventMessageServer, LamarrsService, TargetClient};
+use crate::clock::server_time_us;
+use crate::events::EventPublisher;
+use crate::services::{
+    send_actions, InternalEventMessageServer, LamarrsService, LamarrsServiceError, TargetClient,
+};
 
 #[derive(Debug)]
 pub struct SubtitleService {
     targets: HashMap<Uuid, TargetClient>,
+    /// Tasks sending the next pages of the last subtitles sent to each Client.
+    pending_pages: HashMap<Uuid, AbortHandle>,
     pub sender: Sender<InternalEventMessageServer>,
     receiver: Receiver<InternalEventMessageServer>,
+    events: EventPublisher,
 }
 
 impl SubtitleService {
-    pub fn new() -> Self {
+    pub fn new(events: EventPublisher) -> Self {
         let (sender, receiver) = channel(32);
         Self {
             targets: HashMap::new(),
+            pending_pages: HashMap::new(),
             sender,
             receiver,
+            events,
         }
     }
 }
@@ -33,12 +48,87 @@ impl fmt::Display for SubtitleService {
 
 // Implement `LamarrsService` for `SubtitleService`.
 impl LamarrsService for SubtitleService {
-    fn action_is_allowed(&self, message: &Action) -> bool {
-        matches!(message, Action::ShowNewSubtitles(_))
+    fn service(&self) -> Service {
+        Service::Subtitle
+    }
+    /// Sends only the variant in the language of the Client, or the default one, split in pages.
+    /// The pages after the first one are scheduled one after the other from it.
+    fn actions_for(
+        action: &Action,
+        schedule: Option<&Schedule>,
+        target_client: &TargetClient,
+    ) -> Vec<(Action, Option<Schedule>)> {
+        let Action::ShowNewSubtitles(subtitles) = action else {
+            return vec![(action.clone(), schedule.cloned())];
+        };
+        let Some(variant) = subtitles.variant_for(target_client.language.as_deref()) else {
+            return Vec::new();
+        };
+        let page_duration_us = subtitles.page_duration_ms as u64 * 1000;
+        let first_page_at_us = schedule.map_or_else(server_time_us, |schedule| schedule.execute_at_us);
+        variant
+            .pages()
+            .enumerate()
+            .map(|(index, page)| {
+                let page_schedule = match index {
+                    0 => schedule.cloned(),
+                    _ => {
+                        let execute_at_us = first_page_at_us + index as u64 * page_duration_us;
+                        Some(Schedule {
+                            execute_at_us,
+                            expires_at_us: Some(execute_at_us + page_duration_us),
+                        })
+                    }
+                };
+                (
+                    Action::ShowNewSubtitles(Box::new(subtitles.page_of(variant, page))),
+                    page_schedule,
+                )
+            })
+            .collect()
+    }
+    /// Sends the first page straight away, and the next ones from their own task, each as long
+    /// before it is shown as the first one was. The pages not sent yet are dropped when new
+    /// subtitles are sent to the same Client, so they never replace the newer ones.
+    async fn send_actions(
+        &mut self,
+        uuid: Uuid,
+        sender: Sender<ExchangeMessage>,
+        mut actions: Vec<(Action, Option<Schedule>)>,
+    ) -> Result<(), LamarrsServiceError> {
+        if let Some(pending_pages) = self.pending_pages.remove(&uuid) {
+            pending_pages.abort();
+        }
+        let later_pages = actions.split_off(actions.len().min(1));
+        let lead_time_us = actions
+            .first()
+            .and_then(|(_, schedule)| schedule.as_ref())
+            .map_or(0, |schedule| schedule.execute_at_us.saturating_sub(server_time_us()));
+        send_actions(&Service::Subtitle, &sender, actions).await?;
+        if later_pages.is_empty() {
+            return Ok(());
+        }
+        let task = tokio::spawn(async move {
+            for (page, schedule) in later_pages {
+                if let Some(schedule) = &schedule {
+                    let send_at_us = schedule.execute_at_us.saturating_sub(lead_time_us);
+                    sleep(Duration::from_micros(send_at_us.saturating_sub(server_time_us()))).await;
+                }
+                // The Client disconnected, there is nobody to show the rest to.
+                if send_actions(&Service::Subtitle, &sender, vec![(page, schedule)]).await.is_err() {
+                    return;
+                }
+            }
+        });
+        self.pending_pages.insert(uuid, task.abort_handle());
+        Ok(())
     }
     fn get_target_client_map(&mut self) -> &mut HashMap<Uuid, TargetClient> {
         &mut self.targets
     }
+    fn events(&self) -> &EventPublisher {
+        &self.events
+    }
     async fn receive_message(&mut self) -> Option<InternalEventMessageServer> {
         self.receiver.recv().await
     }
@@ -49,15 +139,17 @@ pub struct ColourService {
     targets: HashMap<Uuid, TargetClient>,
     pub sender: Sender<InternalEventMessageServer>,
     receiver: Receiver<InternalEventMessageServer>,
+    events: EventPublisher,
 }
 
 impl ColourService {
-    pub fn new() -> Self {
+    pub fn new(events: EventPublisher) -> Self {
         let (sender, receiver) = channel(32);
         Self {
             targets: HashMap::new(),
             sender,
             receiver,
+            events,
         }
     }
 }
@@ -71,12 +163,15 @@ impl fmt::Display for ColourService {
 
 // Implement `LamarrsService` for `ColoursService`.
 impl LamarrsService for ColourService {
-    fn action_is_allowed(&self, message: &Action) -> bool {
-        matches!(message, Action::ChangeColour(_))
+    fn service(&self) -> Service {
+        Service::Colour
     }
     fn get_target_client_map(&mut self) -> &mut HashMap<Uuid, TargetClient> {
         &mut self.targets
     }
+    fn events(&self) -> &EventPublisher {
+        &self.events
+    }
 
     async fn receive_message(&mut self) -> Option<InternalEventMessageServer> {
         self.receiver.recv().await
@@ -88,15 +183,17 @@ pub struct PlaybackService {
     targets: HashMap<Uuid, TargetClient>,
     pub sender: Sender<InternalEventMessageServer>,
     receiver: Receiver<InternalEventMessageServer>,
+    events: EventPublisher,
 }
 
 impl PlaybackService {
-    pub fn new() -> Self {
+    pub fn new(events: EventPublisher) -> Self {
         let (sender, receiver) = channel(32);
         Self {
             targets: HashMap::new(),
             sender,
             receiver,
+            events,
         }
     }
 }
@@ -110,12 +207,15 @@ impl fmt::Display for PlaybackService {
 
 // Implement `LamarrsService` for `PlaybackService`.
 impl LamarrsService for PlaybackService {
-    fn action_is_allowed(&self, message: &Action) -> bool {
-        matches!(message, Action::PlayAudio(_))
+    fn service(&self) -> Service {
+        Service::AudioPlayer
     }
     fn get_target_client_map(&mut self) -> &mut HashMap<Uuid, TargetClient> {
         &mut self.targets
     }
+    fn events(&self) -> &EventPublisher {
+        &self.events
+    }
 
     async fn receive_message(&mut self) -> Option<InternalEventMessageServer> {
         self.receiver.recv().await
@@ -128,15 +228,17 @@ pub struct MidiService {
     targets: HashMap<Uuid, TargetClient>,
     pub sender: Sender<InternalEventMessageServer>,
     receiver: Receiver<InternalEventMessageServer>,
+    events: EventPublisher,
 }
 
 impl MidiService {
-    pub fn new() -> Self {
+    pub fn new(events: EventPublisher) -> Self {
         let (sender, receiver) = channel(32);
         Self {
             targets: HashMap::new(),
             sender,
             receiver,
+            events,
         }
     }
 }
@@ -150,14 +252,102 @@ impl fmt::Display for MidiService {
 
 // Implement `LamarrsService` for `MidiService`.
 impl LamarrsService for MidiService {
-    fn action_is_allowed(&self, message: &Action) -> bool {
-        matches!(message, Action::Midi(_))
+    fn service(&self) -> Service {
+        Service::Midi
     }
     fn get_target_client_map(&mut self) -> &mut HashMap<Uuid, TargetClient> {
         &mut self.targets
     }
+    fn events(&self) -> &EventPublisher {
+        &self.events
+    }
 
     async fn receive_message(&mut self) -> Option<InternalEventMessageServer> {
         self.receiver.recv().await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use lamarrs_utils::{
+        action_messages::Event, exchange_messages::ExchangeMessage, subtitles::Subtitles,
+        target_selector::TargetSelector, ClientIdAndLocation,
+    };
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::services::ClientProfile;
+
+    /// Subtitles of `pages` pages of `letter`s, shown for `page_duration_ms` each.
+    fn subtitles(letter: &str, pages: usize, page_duration_ms: u32) -> Action {
+        let text = vec![letter.repeat(40); pages * 2].join(" ");
+        let mut subtitles = Subtitles::new(text);
+        subtitles.page_duration_ms = page_duration_ms;
+        Action::ShowNewSubtitles(Box::new(subtitles))
+    }
+
+    fn shown_text(message: ExchangeMessage) -> String {
+        match message {
+            ExchangeMessage::Scene(Event::PerformAction(Action::ShowNewSubtitles(subtitles)), _) => {
+                subtitles.variants[0].text.to_string()
+            }
+            message => panic!("{message:?} is not a subtitle"),
+        }
+    }
+
+    /// Runs a subtitles Service with a single Client subscribed, returning their inboxes.
+    async fn start_subtitle_service() -> (Sender<InternalEventMessageServer>, Receiver<ExchangeMessage>) {
+        let (events, _events_inbox) = EventPublisher::channel();
+        let mut service = SubtitleService::new(events);
+        let service_sender = service.sender.clone();
+        tokio::spawn(async move { service.run().await });
+        let (client_sender, mut client_inbox) = channel(32);
+        let client_profile = ClientProfile {
+            id: ClientIdAndLocation::new(Uuid::from_u128(1), None),
+            section: None,
+            groups: Default::default(),
+            language: None,
+        };
+        service_sender
+            .send(InternalEventMessageServer::AddTargetClient(client_profile, client_sender, 0))
+            .await
+            .unwrap();
+        assert!(matches!(client_inbox.recv().await, Some(ExchangeMessage::Ack(..))));
+        (service_sender, client_inbox)
+    }
+
+    async fn show(service_sender: &Sender<InternalEventMessageServer>, action: Action) {
+        service_sender
+            .send(InternalEventMessageServer::PerformAction(
+                action,
+                Box::new(TargetSelector::all()),
+                None,
+            ))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn later_pages_are_sent_one_after_the_other() {
+        let (service_sender, mut client_inbox) = start_subtitle_service().await;
+
+        show(&service_sender, subtitles("x", 3, 20)).await;
+
+        for _ in 0..3 {
+            let page = timeout(Duration::from_millis(200), client_inbox.recv()).await.unwrap();
+            assert!(!shown_text(page.unwrap()).is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn new_subtitles_supersede_the_pages_not_sent_yet() {
+        let (service_sender, mut client_inbox) = start_subtitle_service().await;
+
+        show(&service_sender, subtitles("x", 3, 50)).await;
+        show(&service_sender, subtitles("y", 1, 50)).await;
+
+        assert!(shown_text(client_inbox.recv().await.unwrap()).starts_with('x'));
+        assert!(shown_text(client_inbox.recv().await.unwrap()).starts_with('y'));
+        assert!(timeout(Duration::from_millis(200), client_inbox.recv()).await.is_err());
+    }
+}
@@ -0,0 +1,558 @@
+//! Show control input
+//!
+//! Lets theatre consoles and playback rigs drive the Sequencer, reading MIDI Show Control (MSC)
+//! commands and MIDI Time Code (MTC) from a MIDI input port, or from a raw MIDI byte stream, like
+//! a file or a named pipe, to try a show without the rig.
+//!
+//! The MSC commands understood are:
+//!  * `GO`: plays the next step, or jumps to the step with the cue number, if it has one.
+//!  * `STOP`: holds the steps played automatically after the `duration` of the previous one,
+//!    and the timecode chase.
+//!  * `RESUME`: releases what `STOP` held.
+//!
+//! Cue lists and cue paths are ignored, and so are the other commands. MTC, both quarter frames
+//! and full frame messages, is sent to the Sequencer, which plays the steps stamped with a
+//! `timecode` as it goes through them.
+
+use std::{io, path::PathBuf, time::Duration};
+
+use midir::{Ignore, MidiInput};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::{
+    fs::File,
+    io::AsyncReadExt,
+    sync::mpsc::{channel, Sender},
+};
+use tracing::{debug, info, instrument, warn};
+
+use crate::sequencer::SequencerCommand;
+
+/// Device ID of the MSC messages addressed to every device.
+pub const MSC_ALL_CALL: u8 = 0x7F;
+
+/// Longest SysEx message read, MSC ones are far shorter.
+const MAX_SYSEX_LENGTH: usize = 128;
+
+const SYSEX_START: u8 = 0xF0;
+const SYSEX_END: u8 = 0xF7;
+const MTC_QUARTER_FRAME: u8 = 0xF1;
+/// Sub-ID of the Universal Real Time SysEx messages.
+const UNIVERSAL_REAL_TIME: u8 = 0x7F;
+const SUB_ID_MTC: u8 = 0x01;
+const SUB_ID_MTC_FULL_FRAME: u8 = 0x01;
+const SUB_ID_MSC: u8 = 0x02;
+const MSC_GO: u8 = 0x01;
+const MSC_STOP: u8 = 0x02;
+const MSC_RESUME: u8 = 0x03;
+
+#[derive(Debug, Error)]
+pub enum ShowControlError {
+    #[error("The show control stream could not be read")]
+    Io(#[from] io::Error),
+    #[error("MIDI input could not be initialised")]
+    MidiInit(#[from] midir::InitError),
+    #[error("There is no MIDI input port named like {0:?}.")]
+    MidiPortNotFound(String),
+    #[error("Failed to connect to the MIDI input port: {0}")]
+    MidiConnect(String),
+    #[error("The Sequencer is gone.")]
+    SequencerGone,
+}
+
+/// Frame rates of MTC.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MtcRate {
+    Fps24,
+    Fps25,
+    /// 29.97 frames per second, dropping frame numbers to keep up with the clock.
+    Fps29_97DropFrame,
+    Fps30,
+}
+
+impl MtcRate {
+    /// Rate coded in the two bits MTC messages carry it in.
+    fn from_bits(bits: u8) -> Self {
+        match bits & 0b11 {
+            0 => MtcRate::Fps24,
+            1 => MtcRate::Fps25,
+            2 => MtcRate::Fps29_97DropFrame,
+            _ => MtcRate::Fps30,
+        }
+    }
+
+    fn frame_duration(&self) -> Duration {
+        match self {
+            MtcRate::Fps24 => Duration::from_secs(1) / 24,
+            MtcRate::Fps25 => Duration::from_secs(1) / 25,
+            MtcRate::Fps29_97DropFrame => Duration::from_nanos(1_001_000_000 / 30),
+            MtcRate::Fps30 => Duration::from_secs(1) / 30,
+        }
+    }
+}
+
+/// A timecode, written `hh:mm:ss:ff`, or `hh:mm:ss;ff` as drop frame ones usually are.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Timecode {
+    pub hours: u8,
+    pub minutes: u8,
+    pub seconds: u8,
+    pub frames: u8,
+}
+
+impl Timecode {
+    /// Time from `00:00:00:00` to the timecode at the rate.
+    pub fn position(&self, rate: MtcRate) -> Duration {
+        let minutes = self.hours as u64 * 60 + self.minutes as u64;
+        let seconds = minutes * 60 + self.seconds as u64;
+        match rate {
+            MtcRate::Fps29_97DropFrame => {
+                // Frame numbers 0 and 1 are skipped every minute, but every tenth one.
+                let frame = seconds * 30 + self.frames as u64 - 2 * (minutes - minutes / 10);
+                Duration::from_nanos(frame * 1_001_000_000 / 30)
+            }
+            _ => Duration::from_secs(seconds) + rate.frame_duration() * self.frames as u32,
+        }
+    }
+}
+
+impl TryFrom<String> for Timecode {
+    type Error = String;
+
+    fn try_from(timecode: String) -> Result<Self, Self::Error> {
+        let fields = timecode
+            .split([':', ';'])
+            .map(|field| field.trim().parse::<u8>())
+            .collect::<Result<Vec<_>, _>>();
+        match fields.as_deref() {
+            Ok(&[hours, minutes, seconds, frames])
+                if hours < 24 && minutes < 60 && seconds < 60 && frames < 30 =>
+            {
+                Ok(Timecode {
+                    hours,
+                    minutes,
+                    seconds,
+                    frames,
+                })
+            }
+            _ => Err(format!(
+                "Invalid timecode {:?}, it must be written as hh:mm:ss:ff.",
+                timecode
+            )),
+        }
+    }
+}
+
+impl From<Timecode> for String {
+    fn from(timecode: Timecode) -> Self {
+        format!(
+            "{:02}:{:02}:{:02}:{:02}",
+            timecode.hours, timecode.minutes, timecode.seconds, timecode.frames
+        )
+    }
+}
+
+/// What the show control input asks the Sequencer to do.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ShowControlEvent {
+    Go { cue: Option<String> },
+    Stop,
+    Resume,
+    /// The timecode reached the position, from `00:00:00:00`.
+    Timecode { position: Duration, rate: MtcRate },
+}
+
+/// Reads MSC and MTC from a MIDI byte stream, ignoring any other message.
+#[derive(Debug)]
+pub struct ShowControlParser {
+    /// MSC messages addressed to other devices are ignored, unless this is the all-call ID.
+    device_id: u8,
+    /// SysEx message being read, without its start byte.
+    sysex: Option<Vec<u8>>,
+    /// The data byte of a quarter frame is expected.
+    in_quarter_frame: bool,
+    /// Values of the quarter frames of the timecode being read.
+    quarter_frames: [u8; 8],
+    /// Next quarter frame expected, if the first one of the timecode was read.
+    next_quarter_frame: Option<usize>,
+}
+
+impl ShowControlParser {
+    pub fn new(device_id: u8) -> Self {
+        Self {
+            device_id,
+            sysex: None,
+            in_quarter_frame: false,
+            quarter_frames: [0; 8],
+            next_quarter_frame: None,
+        }
+    }
+
+    /// Reads the next byte of the stream, returning the event completed with it, if any.
+    pub fn feed(&mut self, byte: u8) -> Option<ShowControlEvent> {
+        match byte {
+            // Real time messages can be sent in the middle of any other, and are not used.
+            0xF8..=0xFF => None,
+            SYSEX_START => {
+                self.in_quarter_frame = false;
+                self.sysex = Some(Vec::new());
+                None
+            }
+            SYSEX_END => self.sysex.take().and_then(|sysex| self.on_sysex(&sysex)),
+            MTC_QUARTER_FRAME => {
+                self.sysex = None;
+                self.in_quarter_frame = true;
+                None
+            }
+            // Any other status byte starts a message this parser ignores.
+            0x80..=0xF6 => {
+                self.sysex = None;
+                self.in_quarter_frame = false;
+                None
+            }
+            data => {
+                if let Some(sysex) = self.sysex.as_mut() {
+                    if sysex.len() < MAX_SYSEX_LENGTH {
+                        sysex.push(data);
+                    } else {
+                        debug!("Dropping a SysEx longer than {} bytes.", MAX_SYSEX_LENGTH);
+                        self.sysex = None;
+                    }
+                    None
+                } else if self.in_quarter_frame {
+                    self.in_quarter_frame = false;
+                    self.on_quarter_frame(data)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    fn on_sysex(&mut self, sysex: &[u8]) -> Option<ShowControlEvent> {
+        match sysex {
+            [UNIVERSAL_REAL_TIME, _, SUB_ID_MTC, SUB_ID_MTC_FULL_FRAME, hours, minutes, seconds, frames] =>
+            {
+                // A full frame message is sent when the timecode is located, the quarter frames
+                // read so far are from before it.
+                self.next_quarter_frame = None;
+                let rate = MtcRate::from_bits(hours >> 5);
+                let timecode = Timecode {
+                    hours: hours & 0x1F,
+                    minutes: *minutes,
+                    seconds: *seconds,
+                    frames: *frames,
+                };
+                debug!("MTC full frame {}", String::from(timecode));
+                Some(ShowControlEvent::Timecode {
+                    position: timecode.position(rate),
+                    rate,
+                })
+            }
+            [UNIVERSAL_REAL_TIME, device_id, SUB_ID_MSC, _command_format, command, data @ ..] => {
+                if self.device_id != MSC_ALL_CALL
+                    && *device_id != MSC_ALL_CALL
+                    && *device_id != self.device_id
+                {
+                    debug!("Ignoring MSC for device {}.", device_id);
+                    return None;
+                }
+                match *command {
+                    MSC_GO => Some(ShowControlEvent::Go {
+                        cue: cue_number(data),
+                    }),
+                    MSC_STOP => Some(ShowControlEvent::Stop),
+                    MSC_RESUME => Some(ShowControlEvent::Resume),
+                    command => {
+                        debug!("Ignoring MSC command {:#04x}.", command);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn on_quarter_frame(&mut self, data: u8) -> Option<ShowControlEvent> {
+        let (piece, value) = ((data >> 4) as usize, data & 0x0F);
+        // Timecodes are read from their first piece, any piece out of order drops the timecode.
+        if piece != 0 && self.next_quarter_frame != Some(piece) {
+            self.next_quarter_frame = None;
+            return None;
+        }
+        self.quarter_frames[piece] = value;
+        if piece < 7 {
+            self.next_quarter_frame = Some(piece + 1);
+            return None;
+        }
+        self.next_quarter_frame = None;
+        let pieces = self.quarter_frames;
+        let rate = MtcRate::from_bits(pieces[7] >> 1);
+        let timecode = Timecode {
+            hours: pieces[6] | (pieces[7] & 0x01) << 4,
+            minutes: pieces[4] | pieces[5] << 4,
+            seconds: pieces[2] | pieces[3] << 4,
+            frames: pieces[0] | pieces[1] << 4,
+        };
+        // The timecode was sent in the first piece, two frames ago.
+        Some(ShowControlEvent::Timecode {
+            position: timecode.position(rate) + rate.frame_duration() * 2,
+            rate,
+        })
+    }
+}
+
+/// Cue number of the data of a MSC command, written in ASCII and ended by a null byte, if any.
+fn cue_number(data: &[u8]) -> Option<String> {
+    let cue = data.split(|byte| *byte == 0).next().unwrap_or_default();
+    if cue.is_empty() || !cue.iter().all(|byte| byte.is_ascii_digit() || *byte == b'.') {
+        return None;
+    }
+    Some(String::from_utf8_lossy(cue).into_owned())
+}
+
+/// Where the show control input is read from.
+#[derive(Clone, Debug)]
+pub enum ShowControlSource {
+    /// First MIDI input port whose name contains this one.
+    MidiPort(String),
+    /// File or named pipe with raw MIDI bytes.
+    Stream(PathBuf),
+}
+
+pub struct ShowControl {
+    sequencer: Sender<SequencerCommand>,
+    source: ShowControlSource,
+    parser: ShowControlParser,
+}
+
+impl ShowControl {
+    pub fn new(
+        sequencer: Sender<SequencerCommand>,
+        source: ShowControlSource,
+        device_id: u8,
+    ) -> Self {
+        Self {
+            sequencer,
+            source,
+            parser: ShowControlParser::new(device_id),
+        }
+    }
+
+    #[instrument(name = "ShowControl::run", skip(self), level = "INFO")]
+    pub async fn run(&mut self) -> Result<(), ShowControlError> {
+        match self.source.clone() {
+            ShowControlSource::MidiPort(port_name) => self.read_midi_port(&port_name).await,
+            ShowControlSource::Stream(path) => {
+                let mut stream = File::open(&path).await?;
+                info!("Reading show control from {}", path.display());
+                let mut bytes = [0; 256];
+                loop {
+                    let length = stream.read(&mut bytes).await?;
+                    if length == 0 {
+                        break;
+                    }
+                    self.feed(&bytes[..length]).await?;
+                }
+                info!("Show control stream {} ended.", path.display());
+                std::future::pending().await
+            }
+        }
+    }
+
+    async fn read_midi_port(&mut self, port_name: &str) -> Result<(), ShowControlError> {
+        let mut midi_input = MidiInput::new("lamarrs-server")?;
+        // MSC is sent in SysEx, and MTC as time messages, both ignored by default.
+        midi_input.ignore(Ignore::None);
+        let port = midi_input
+            .ports()
+            .into_iter()
+            .find(|port| {
+                midi_input
+                    .port_name(port)
+                    .is_ok_and(|name| name.contains(port_name))
+            })
+            .ok_or_else(|| ShowControlError::MidiPortNotFound(port_name.to_string()))?;
+        let name = midi_input
+            .port_name(&port)
+            .unwrap_or_else(|_| port_name.to_string());
+        let (sender, mut receiver) = channel::<Vec<u8>>(64);
+        // The connection is kept until the input stops being read.
+        let _connection = midi_input
+            .connect(
+                &port,
+                &format!("midir-conn:{}", name),
+                move |_, message, _| {
+                    if sender.blocking_send(message.to_vec()).is_err() {
+                        warn!("Show control input is gone, dropping a MIDI message.");
+                    }
+                },
+                (),
+            )
+            .map_err(|error| ShowControlError::MidiConnect(error.to_string()))?;
+        info!("Reading show control from MIDI input port {}", name);
+        while let Some(message) = receiver.recv().await {
+            self.feed(&message).await?;
+        }
+        Ok(())
+    }
+
+    async fn feed(&mut self, bytes: &[u8]) -> Result<(), ShowControlError> {
+        for byte in bytes {
+            if let Some(event) = self.parser.feed(*byte) {
+                if !matches!(event, ShowControlEvent::Timecode { .. }) {
+                    info!(?event, "Show control event");
+                }
+                self.sequencer
+                    .send(SequencerCommand::ShowControl(event))
+                    .await
+                    .map_err(|_| ShowControlError::SequencerGone)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMECODE: Timecode = Timecode {
+        hours: 1,
+        minutes: 2,
+        seconds: 3,
+        frames: 4,
+    };
+
+    fn feed(parser: &mut ShowControlParser, bytes: &[u8]) -> Vec<ShowControlEvent> {
+        bytes.iter().filter_map(|byte| parser.feed(*byte)).collect()
+    }
+
+    /// The 8 quarter frames of a timecode, as sent while it runs.
+    fn quarter_frames(timecode: Timecode, rate_bits: u8) -> Vec<u8> {
+        let pieces = [
+            timecode.frames & 0x0F,
+            timecode.frames >> 4,
+            timecode.seconds & 0x0F,
+            timecode.seconds >> 4,
+            timecode.minutes & 0x0F,
+            timecode.minutes >> 4,
+            timecode.hours & 0x0F,
+            timecode.hours >> 4 | rate_bits << 1,
+        ];
+        pieces
+            .iter()
+            .enumerate()
+            .flat_map(|(piece, value)| [MTC_QUARTER_FRAME, (piece as u8) << 4 | value])
+            .collect()
+    }
+
+    #[test]
+    fn quarter_frames_are_read_at_every_rate() {
+        for (rate_bits, rate) in [
+            (0, MtcRate::Fps24),
+            (1, MtcRate::Fps25),
+            (2, MtcRate::Fps29_97DropFrame),
+            (3, MtcRate::Fps30),
+        ] {
+            let mut parser = ShowControlParser::new(MSC_ALL_CALL);
+
+            assert_eq!(
+                feed(&mut parser, &quarter_frames(TIMECODE, rate_bits)),
+                [ShowControlEvent::Timecode {
+                    // Two frames went by while the quarter frames were sent.
+                    position: TIMECODE.position(rate) + rate.frame_duration() * 2,
+                    rate,
+                }]
+            );
+        }
+    }
+
+    #[test]
+    fn quarter_frames_out_of_order_are_dropped() {
+        let mut parser = ShowControlParser::new(MSC_ALL_CALL);
+        let mut bytes = quarter_frames(TIMECODE, 1);
+        // Without the third piece.
+        bytes.drain(4..6);
+        // A clock in the middle of the quarter frames is fine.
+        let mut next_timecode = quarter_frames(TIMECODE, 1);
+        next_timecode.insert(7, 0xF8);
+        bytes.extend(next_timecode);
+
+        assert_eq!(feed(&mut parser, &bytes).len(), 1);
+    }
+
+    #[test]
+    fn full_frames_locate_the_timecode() {
+        let mut parser = ShowControlParser::new(MSC_ALL_CALL);
+        // Quarter frames interrupted by the full frame are dropped.
+        let mut bytes = quarter_frames(TIMECODE, 0)[..8].to_vec();
+        bytes.extend([SYSEX_START, 0x7F, 0x7F, 0x01, 0x01, 2 << 5 | 1, 2, 3, 4, SYSEX_END]);
+        bytes.extend(&quarter_frames(TIMECODE, 0)[8..]);
+
+        assert_eq!(
+            feed(&mut parser, &bytes),
+            [ShowControlEvent::Timecode {
+                position: TIMECODE.position(MtcRate::Fps29_97DropFrame),
+                rate: MtcRate::Fps29_97DropFrame,
+            }]
+        );
+    }
+
+    #[test]
+    fn msc_commands_are_read_for_the_device() {
+        let mut parser = ShowControlParser::new(3);
+        let msc = |device_id: u8, command: u8, data: &[u8]| {
+            let mut bytes = vec![SYSEX_START, 0x7F, device_id, SUB_ID_MSC, 0x01, command];
+            bytes.extend_from_slice(data);
+            bytes.push(SYSEX_END);
+            bytes
+        };
+
+        assert_eq!(
+            feed(&mut parser, &msc(3, MSC_GO, b"12.5\x000\x00")),
+            [ShowControlEvent::Go {
+                cue: Some("12.5".to_string())
+            }]
+        );
+        assert_eq!(
+            feed(&mut parser, &msc(MSC_ALL_CALL, MSC_GO, &[])),
+            [ShowControlEvent::Go { cue: None }]
+        );
+        assert_eq!(
+            feed(&mut parser, &msc(3, MSC_STOP, &[])),
+            [ShowControlEvent::Stop]
+        );
+        assert_eq!(
+            feed(&mut parser, &msc(3, MSC_RESUME, &[])),
+            [ShowControlEvent::Resume]
+        );
+        // Other devices, and other commands, like SET.
+        assert_eq!(feed(&mut parser, &msc(4, MSC_GO, &[])), []);
+        assert_eq!(feed(&mut parser, &msc(3, 0x06, &[1, 2])), []);
+    }
+
+    #[test]
+    fn drop_frame_timecodes_skip_frame_numbers() {
+        let timecode = |minutes, frames| Timecode {
+            hours: 0,
+            minutes,
+            seconds: 0,
+            frames,
+        };
+        let frames =
+            |timecode: Timecode| timecode.position(MtcRate::Fps29_97DropFrame).as_nanos() * 30
+                / 1_001_000_000;
+
+        // 00:00:59;29 is followed by 00:01:00;02.
+        assert_eq!(frames(timecode(1, 2)), 1800);
+        // But not every tenth minute.
+        assert_eq!(frames(timecode(10, 0)), 17982);
+        assert_eq!(
+            Timecode::try_from("01:02:03;04".to_string()),
+            Ok(TIMECODE)
+        );
+        assert!(Timecode::try_from("01:02:03:30".to_string()).is_err());
+    }
+}
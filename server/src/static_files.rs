@@ -0,0 +1,232 @@
+//! Static files
+//!
+//! Serves a folder, like the `dist/` one of the compiled web UI, over plain HTTP on the same
+//! port as the WebSocket endpoint, so the audience phones only need the URL of the Server.
+//! Folders are answered with their `index.html`, and only `GET` and `HEAD` requests are accepted.
+
+use std::{
+    io,
+    path::{Component, Path, PathBuf},
+};
+
+use http::StatusCode;
+use percent_encoding::percent_decode_str;
+use thiserror::Error;
+use tracing::debug;
+
+use crate::http::{HttpRequest, HttpResponse};
+
+const INDEX_FILE: &str = "index.html";
+
+#[derive(Debug, Error)]
+pub enum StaticFilesError {
+    #[error("The static files folder could not be read")]
+    Io(#[from] io::Error),
+    #[error("{0} is not a folder.")]
+    NotAFolder(PathBuf),
+}
+
+/// MIME type of the file, from its extension.
+fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("js" | "mjs") => "text/javascript; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("wasm") => "application/wasm",
+        Some("json" | "map") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("ttf") => "font/ttf",
+        Some("mp3") => "audio/mpeg",
+        Some("wav") => "audio/wav",
+        Some("ogg") => "audio/ogg",
+        _ => "application/octet-stream",
+    }
+}
+
+#[derive(Debug)]
+pub struct StaticFiles {
+    root: PathBuf,
+}
+
+impl StaticFiles {
+    pub fn load(path: &Path) -> Result<Self, StaticFilesError> {
+        let root = path.canonicalize()?;
+        if !root.is_dir() {
+            return Err(StaticFilesError::NotAFolder(root));
+        }
+        Ok(Self { root })
+    }
+
+    /// File the request path points to, if it stays inside the folder.
+    fn resolve(&self, request_path: &str) -> Option<PathBuf> {
+        let decoded = percent_decode_str(request_path).decode_utf8().ok()?;
+        let mut path = self.root.clone();
+        for component in Path::new(decoded.as_ref()).components() {
+            match component {
+                Component::Normal(segment) => path.push(segment),
+                Component::RootDir | Component::CurDir => {}
+                Component::ParentDir | Component::Prefix(_) => return None,
+            }
+        }
+        if path.is_dir() {
+            path.push(INDEX_FILE);
+        }
+        Some(path)
+    }
+
+    pub async fn serve(&self, request: &HttpRequest) -> HttpResponse {
+        if request.method != "GET" && request.method != "HEAD" {
+            return HttpResponse::text(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed.");
+        }
+        let Some(path) = self.resolve(&request.path) else {
+            return HttpResponse::text(StatusCode::NOT_FOUND, "Not found.");
+        };
+        // Symbolic links must not lead out of the folder either.
+        let path = match tokio::fs::canonicalize(&path).await {
+            Ok(path) if path.starts_with(&self.root) => path,
+            _ => return HttpResponse::text(StatusCode::NOT_FOUND, "Not found."),
+        };
+        match tokio::fs::read(&path).await {
+            Ok(body) => HttpResponse::new(StatusCode::OK, content_type(&path), body),
+            Err(error) => {
+                debug!(path = %path.display(), "Static file not served: {}", error);
+                HttpResponse::text(StatusCode::NOT_FOUND, "Not found.")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `dist/` folder of a web UI, next to a file it has a symbolic link to, in a folder of
+    /// their own so the tests can run in parallel. Returns the folder holding both.
+    fn site(name: &str) -> PathBuf {
+        let base = std::env::temp_dir().join(format!(
+            "lamarrs-static-files-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let root = base.join("dist");
+        std::fs::create_dir_all(root.join("assets")).unwrap();
+        for (file, content) in [
+            ("index.html", "<html>"),
+            ("assets/index.html", "assets"),
+            ("app.wasm", "wasm"),
+            ("app.js", "js"),
+            ("style.css", "css"),
+        ] {
+            std::fs::write(root.join(file), content).unwrap();
+        }
+        std::fs::write(base.join("secret.txt"), "secret").unwrap();
+        std::os::unix::fs::symlink(base.join("secret.txt"), root.join("secret.txt")).unwrap();
+        base
+    }
+
+    fn request(method: &str, path: &str) -> HttpRequest {
+        HttpRequest {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: None,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    fn content_type_of(response: &HttpResponse) -> Option<&str> {
+        response
+            .headers
+            .iter()
+            .find(|(name, _)| name == "Content-Type")
+            .map(|(_, value)| value.as_str())
+    }
+
+    #[tokio::test]
+    async fn serves_the_files_with_their_type_and_folders_with_their_index() {
+        let base = site("serves");
+        let static_files = StaticFiles::load(&base.join("dist")).unwrap();
+        for (path, body, content_type) in [
+            ("/", "<html>", "text/html; charset=utf-8"),
+            ("/assets", "assets", "text/html; charset=utf-8"),
+            ("/assets/", "assets", "text/html; charset=utf-8"),
+            ("/app.wasm", "wasm", "application/wasm"),
+            ("/app.js", "js", "text/javascript; charset=utf-8"),
+            ("/style.css", "css", "text/css; charset=utf-8"),
+            ("/%61pp.js", "js", "text/javascript; charset=utf-8"),
+        ] {
+            let response = static_files.serve(&request("GET", path)).await;
+            assert_eq!(response.status, StatusCode::OK, "{path}");
+            assert_eq!(response.body, body.as_bytes(), "{path}");
+            assert_eq!(content_type_of(&response), Some(content_type), "{path}");
+        }
+        let response = static_files.serve(&request("HEAD", "/app.js")).await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(content_type(Path::new("APP.WASM")), "application/wasm");
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn only_gets_and_heads_are_allowed() {
+        let base = site("methods");
+        let static_files = StaticFiles::load(&base.join("dist")).unwrap();
+        for method in ["POST", "PUT", "DELETE"] {
+            let response = static_files.serve(&request(method, "/index.html")).await;
+            assert_eq!(response.status, StatusCode::METHOD_NOT_ALLOWED);
+        }
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn requests_cannot_leave_the_folder() {
+        let base = site("leaving");
+        let static_files = StaticFiles::load(&base.join("dist")).unwrap();
+        let absolute = base.join("secret.txt").display().to_string();
+        for path in [
+            "/../secret.txt",
+            "/assets/../../secret.txt",
+            "/%2e%2e/secret.txt",
+            "/%2E%2E%2Fsecret.txt",
+            "/assets/..%2f..%2fsecret.txt",
+            absolute.as_str(),
+            // A symbolic link to a file outside the folder.
+            "/secret.txt",
+            "/missing.js",
+        ] {
+            let response = static_files.serve(&request("GET", path)).await;
+            assert_eq!(response.status, StatusCode::NOT_FOUND, "{path}");
+            assert_ne!(response.body, b"secret", "{path}");
+        }
+        // Absolute paths are taken as relative to the folder.
+        assert!(static_files.resolve(&absolute).unwrap().starts_with(&static_files.root));
+        assert_eq!(static_files.resolve("/%2e%2e%2fsecret.txt"), None);
+        assert_eq!(static_files.resolve("/%ff"), None);
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn only_folders_are_served() {
+        let base = site("folder");
+        assert!(matches!(
+            StaticFiles::load(&base.join("dist/index.html")),
+            Err(StaticFilesError::NotAFolder(_))
+        ));
+        assert!(matches!(
+            StaticFiles::load(&base.join("missing")),
+            Err(StaticFilesError::Io(_))
+        ));
+        std::fs::remove_dir_all(base).unwrap();
+    }
+}
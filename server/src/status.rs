@@ -0,0 +1,179 @@
+//! Status actor
+//!
+//! [`StatusService`] keeps track of the registered Clients, of how well their clocks are
+//! synchronised with the Server one and of whether they have all the media of the show,
+//! and periodically logs a summary of them.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use lamarrs_utils::{action_messages::ClientKind, clock::TimeSyncResult, media::MediaStatus};
+use tokio::sync::{
+    mpsc::{channel, Receiver, Sender},
+    oneshot,
+};
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
+
+use crate::services::LamarrsServiceError;
+
+/// How often the status of the Clients is logged.
+const REPORT_PERIOD: Duration = Duration::from_secs(60);
+
+/// Clients whose clock was not synchronised for this long are reported as out of sync.
+const MAX_TIME_SYNC_AGE: Duration = Duration::from_secs(60);
+
+/// Events the Client handlers report to the status actor.
+#[derive(Debug)]
+pub enum StatusEvent {
+    Registered(Uuid, ClientKind),
+    ClockSynchronised(Uuid, TimeSyncResult),
+    /// The media manifest was sent to the Client. Until it reports otherwise, all the files
+    /// are considered missing.
+    MediaManifestSent(Uuid, u16),
+    MediaStatus(Uuid, MediaStatus),
+    Disconnected(Uuid),
+    /// Replies with the status of every registered Client.
+    ListClients(oneshot::Sender<HashMap<Uuid, ClientStatus>>),
+}
+
+#[derive(Clone, Debug)]
+pub struct ClientStatus {
+    pub kind: ClientKind,
+    /// Results of the latest clock synchronisation round, and when it happened.
+    pub clock: Option<(TimeSyncResult, Instant)>,
+    /// Only for the Clients the media manifest was sent to.
+    pub media: Option<MediaStatus>,
+}
+
+pub struct StatusService {
+    pub sender: Sender<StatusEvent>,
+    inbox: Receiver<StatusEvent>,
+    clients: HashMap<Uuid, ClientStatus>,
+}
+
+impl StatusService {
+    pub fn new() -> Self {
+        let (sender, inbox) = channel(32);
+        Self {
+            sender,
+            inbox,
+            clients: HashMap::new(),
+        }
+    }
+
+    #[instrument(
+        name = "service::run",
+        skip(self),
+        fields(service = "Status"),
+        level = "INFO",
+        ret,
+        err
+    )]
+    pub async fn run(&mut self) -> Result<(), LamarrsServiceError> {
+        let mut report_interval = tokio::time::interval(REPORT_PERIOD);
+        loop {
+            tokio::select! {
+                msg = self.inbox.recv() => match msg {
+                    Some(event) => self.on_event(event),
+                    None => {
+                        return Err(LamarrsServiceError::Service {
+                            service: "Status".into(),
+                        })
+                    }
+                },
+                _ = report_interval.tick() => self.report(),
+            }
+        }
+    }
+
+    fn on_event(&mut self, event: StatusEvent) {
+        match event {
+            StatusEvent::Registered(uuid, kind) => {
+                self.clients.insert(
+                    uuid,
+                    ClientStatus {
+                        kind,
+                        clock: None,
+                        media: None,
+                    },
+                );
+            }
+            StatusEvent::ClockSynchronised(uuid, time_sync_result) => {
+                match self.clients.get_mut(&uuid) {
+                    Some(client_status) => {
+                        client_status.clock = Some((time_sync_result, Instant::now()))
+                    }
+                    None => warn!(%uuid, "Clock synchronised for an unknown Client."),
+                }
+            }
+            StatusEvent::MediaManifestSent(uuid, files) => {
+                self.update_media_status(uuid, MediaStatus { missing: files })
+            }
+            StatusEvent::MediaStatus(uuid, media_status) => {
+                if media_status.is_ready() {
+                    info!(%uuid, "Client has all the media of the show.");
+                }
+                self.update_media_status(uuid, media_status)
+            }
+            StatusEvent::Disconnected(uuid) => {
+                self.clients.remove(&uuid);
+            }
+            StatusEvent::ListClients(reply) => {
+                // Nothing to do if the requester stopped waiting for the list.
+                let _ = reply.send(self.clients.clone());
+            }
+        }
+    }
+
+    fn update_media_status(&mut self, uuid: Uuid, media_status: MediaStatus) {
+        match self.clients.get_mut(&uuid) {
+            Some(client_status) => client_status.media = Some(media_status),
+            None => warn!(%uuid, "Media status reported for an unknown Client."),
+        }
+    }
+
+    /// Logs every registered Client, with the latest estimation of its clock offset and round
+    /// trip, and the ones that are not show-ready as they miss media files.
+    fn report(&self) {
+        info!("{} Clients registered.", self.clients.len());
+        for (uuid, client_status) in &self.clients {
+            match &client_status.clock {
+                Some((time_sync_result, synchronised_at))
+                    if synchronised_at.elapsed() <= MAX_TIME_SYNC_AGE =>
+                {
+                    info!(
+                        %uuid,
+                        kind = %client_status.kind,
+                        offset_us = time_sync_result.offset_us,
+                        round_trip_us = time_sync_result.round_trip_us,
+                        "Client clock synchronised."
+                    )
+                }
+                Some((_, synchronised_at)) => warn!(
+                    %uuid,
+                    kind = %client_status.kind,
+                    "Client clock not synchronised for {} seconds.",
+                    synchronised_at.elapsed().as_secs()
+                ),
+                None => warn!(
+                    %uuid,
+                    kind = %client_status.kind,
+                    "Client clock not synchronised yet."
+                ),
+            }
+        }
+        for (uuid, client_status) in &self.clients {
+            if let Some(media_status) = client_status.media.filter(|media_status| !media_status.is_ready()) {
+                warn!(
+                    %uuid,
+                    kind = %client_status.kind,
+                    "Client not show-ready, {} media files missing.",
+                    media_status.missing
+                );
+            }
+        }
+    }
+}
@@ -6,20 +6,62 @@ pub mod websocket;
 use dioxus::prelude::*;
 use futures_util::StreamExt;
 use lamarrs_utils::{
+    action_messages::{ClientKind, Event, GroupName, Registration, SeatId},
     enums::{ClientMessage, RelativeLocation, Service},
+    exchange_messages::{ExchangeMessage, PROTOCOL_VERSION},
     midi_event::{self, MidiEvent},
+    subtitles::LanguageTag,
+    target_selector::SectionName,
+    ClientIdAndLocation,
 };
 use log::{debug, error, info};
 use tracing::field::debug;
 use uuid::Uuid;
 const _TAILWIND_URL: &str = manganis::mg!(file("assets/tailwind.css"));
 
+/// Biggest frame the browser accepts from the Server.
+const MAX_FRAME_SIZE: u32 = 1 << 20;
+
 fn main() {
     // Init logger
     wasm_logger::init(wasm_logger::Config::new(log::Level::Debug));
     launch(App);
 }
 
+/// Registration of the browser, with the presets of the join URL it was opened with, like
+/// `?section=left`, `?group=choir` or `?seat=F12`. Presets too long to be valid are left out.
+fn registration(uuid: Uuid) -> ExchangeMessage {
+    let window = web_sys::window();
+    let query = window
+        .as_ref()
+        .and_then(|window| window.location().search().ok())
+        .unwrap_or_default();
+    let preset = |name: &str| {
+        form_urlencoded::parse(query.trim_start_matches('?').as_bytes())
+            .find(|(key, value)| key == name && !value.is_empty())
+            .map(|(_, value)| value.into_owned())
+    };
+    ExchangeMessage::Request(
+        0,
+        Event::Register(Registration {
+            protocol_version: PROTOCOL_VERSION,
+            client: ClientIdAndLocation::new(uuid, None),
+            kind: ClientKind::Browser,
+            services: [Service::Subtitle, Service::Colour, Service::Midi]
+                .into_iter()
+                .collect(),
+            max_frame_size: MAX_FRAME_SIZE,
+            seat: preset("seat").and_then(|seat| SeatId::try_from(seat.as_str()).ok()),
+            language: window
+                .and_then(|window| window.navigator().language())
+                .and_then(|language| LanguageTag::try_from(language.as_str()).ok()),
+            section: preset("section")
+                .and_then(|section| SectionName::try_from(section.as_str()).ok()),
+            group: preset("group").and_then(|group| GroupName::try_from(group.as_str()).ok()),
+        }),
+    )
+}
+
 #[component]
 fn App() -> Element {
     let uuid = Uuid::new_v4();
@@ -50,11 +92,7 @@ fn App() -> Element {
     let ws: Coroutine<ClientMessage> =
         use_coroutine(|mut rx: UnboundedReceiver<ClientMessage>| async move {
             let mut conn =
-                websocket::WebsocketService::new(background_color, subtitle, sound_engine);
-            let register = conn
-                .sender
-                .try_send(ClientMessage::Register((uuid, location.read().clone())));
-            debug!("Register results {:?}", register);
+                websocket::WebsocketService::new(registration(uuid), background_color, subtitle);
             loop {
                 while let Some(message) = rx.next().await {
                     debug!("Message received! {}", message);
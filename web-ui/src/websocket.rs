@@ -1,88 +1,239 @@
-use dioxus::{
-    hooks::Coroutine,
-    signals::{Signal, Writable},
-};
-use futures::{
-    channel::mpsc::{Receiver, Sender},
-    SinkExt, StreamExt,
-};
+use dioxus::signals::{Signal, Writable};
+use futures::{channel::mpsc::Sender, future::Either, SinkExt, StreamExt};
+use gloo_timers::future::TimeoutFuture;
 use lamarrs_utils::{
-    enums::{ClientMessage, ClientMessage, GatewayError},
-    midi_event::MidiEvent,
+    action_messages::{Action, Event},
+    colour_effect::ColourEffect,
+    enums::ClientMessage,
+    exchange_messages::{ExchangeMessage, PROTOCOL_VERSION},
+    ColourRgb,
 };
 use reqwasm::websocket::{futures::WebSocket, Message};
 
 use wasm_bindgen_futures::spawn_local;
 
-use crate::midi_processor;
+/// Time between two frames of a running colour effect.
+const EFFECT_FRAME_PERIOD_MS: u32 = 40;
+
+/// Colour effect being shown, along with the colour the page had when it started.
+struct RunningEffect {
+    effect: ColourEffect,
+    previous: ColourRgb,
+    started_at: f64,
+}
 
 pub struct WebsocketService {
     pub sender: Sender<ClientMessage>,
 }
 
 impl WebsocketService {
+    /// Connects to the Server that served the page, registering with `registration` before
+    /// sending anything else.
     pub fn new(
+        registration: ExchangeMessage,
         mut bg: Signal<String>,
         mut subs: Signal<String>,
-        mut sound_engine: Coroutine<MidiEvent>,
     ) -> Self {
-        let ws = WebSocket::open("ws://192.168.178.70:8080").unwrap();
+        let (sender, receiver) = futures::channel::mpsc::channel::<ClientMessage>(32);
+        let ws = match WebSocket::open(&server_url()) {
+            Ok(ws) => ws,
+            Err(error) => {
+                log::error!("Could not connect to the Server: {:?}", error);
+                return Self { sender };
+            }
+        };
 
         let (mut outgoing, mut incoming) = ws.split();
 
-        let (sender, mut receiver) = futures::channel::mpsc::channel::<ClientMessage>(32);
+        // Answers to the Server, like the heartbeat ones, sent along with the page requests.
+        let (mut replies, replies_inbox) = futures::channel::mpsc::channel::<ExchangeMessage>(8);
 
         spawn_local(async move {
-            while let Some(subscriber_message) = receiver.next().await {
+            log::debug!("Registering to Gateway {:?}", registration);
+            let registration =
+                futures::stream::once(async move { serde_json::to_string(&registration) });
+            let requests = receiver.map(|subscriber_message| {
                 log::debug!("Sending message to Gateway {}", subscriber_message);
-                outgoing
-                    .send(Message::Text(
-                        serde_json::to_string(&subscriber_message).unwrap(),
-                    ))
-                    .await
-                    .unwrap();
+                serde_json::to_string(&subscriber_message)
+            });
+            let replies = replies_inbox.map(|reply| serde_json::to_string(&reply));
+            let mut frames = registration.chain(futures::stream::select(requests, replies));
+            while let Some(frame) = frames.next().await {
+                let frame = match frame {
+                    Ok(frame) => frame,
+                    Err(error) => {
+                        log::error!("Message could not be encoded, it was not sent: {}", error);
+                        continue;
+                    }
+                };
+                if let Err(error) = outgoing.send(Message::Text(frame)).await {
+                    log::error!(
+                        "Connection to the Server lost, nothing else will be sent: {:?}",
+                        error
+                    );
+                    return;
+                }
             }
         });
 
         spawn_local(async move {
             log::info!("Waiting for Gateway messages...");
+            let mut colour = ColourRgb::new(0, 0, 0);
+            let mut running_effect: Option<RunningEffect> = None;
             loop {
-                while let Some(msg) = incoming.next().await {
-                    log::debug!("Processing new msg");
-                    match msg {
-                        Ok(Message::Text(payload)) => {
-                            log::debug!("From Gateway: {}", payload);
-                            match serde_json::from_str(&payload) {
-                                Ok(ClientMessage::Subtitle(subtitle)) => {
-                                    log::info!("New subtitles sent by Gateway: {}", subtitle);
-                                    subs.set(subtitle.to_string());
-                                }
-                                Ok(ClientMessage::Color(color)) => {
-                                    log::info!("Request change of Color by Gateway: {}", color);
-                                    bg.set(color.to_string());
+                // While a colour effect runs, a new frame is shown whenever no message arrives.
+                let msg = if let Some(running) = &running_effect {
+                    let frame = TimeoutFuture::new(EFFECT_FRAME_PERIOD_MS);
+                    match futures::future::select(incoming.next(), frame).await {
+                        Either::Left((msg, _)) => msg,
+                        Either::Right(_) => {
+                            let elapsed_ms = (now_ms() - running.started_at) as u32;
+                            colour = running.effect.evaluate(&running.previous, elapsed_ms);
+                            bg.set(css_colour(&colour));
+                            if running.effect.is_finished(elapsed_ms) {
+                                log::debug!("Colour effect finished");
+                                running_effect = None;
+                            }
+                            continue;
+                        }
+                    }
+                } else {
+                    incoming.next().await
+                };
+                let Some(msg) = msg else {
+                    break;
+                };
+                log::debug!("Processing new msg");
+                match msg {
+                    Ok(Message::Text(payload)) => {
+                        log::debug!("From Gateway: {}", payload);
+                        match serde_json::from_str::<ExchangeMessage>(&payload) {
+                            // Browsers don't synchronise their clock, scheduled actions are performed straight away.
+                            Ok(ExchangeMessage::Scene(Event::PerformAction(action), _)) => {
+                                match action {
+                                    Action::ShowNewSubtitles(subtitles) => {
+                                        // The Server sends each Client the variant in its own language.
+                                        let text = subtitles
+                                            .variants
+                                            .first()
+                                            .map(|variant| variant.text.to_string())
+                                            .unwrap_or_default();
+                                        log::info!("New subtitles sent by Gateway: {}", text);
+                                        subs.set(text);
+                                    }
+                                    Action::ChangeColour(new_colour) => {
+                                        log::info!(
+                                            "Request change of Color by Gateway: {}",
+                                            new_colour
+                                        );
+                                        colour = new_colour;
+                                        running_effect = None;
+                                        bg.set(css_colour(&colour));
+                                    }
+                                    Action::ColourEffect(effect) => {
+                                        log::info!(
+                                            "{} colour effect requested by Gateway",
+                                            effect.kind
+                                        );
+                                        running_effect = Some(RunningEffect {
+                                            effect,
+                                            previous: colour.clone(),
+                                            started_at: now_ms(),
+                                        });
+                                    }
+                                    other => log::warn!(
+                                        "Browsers can't perform {} actions, ignoring it",
+                                        other
+                                    ),
                                 }
-                                Ok(ClientMessage::Midi(midi_event)) => {
-                                    log::info!("Midi Event received: {}", midi_event);
-                                    sound_engine.send(midi_event)
+                            }
+                            Ok(ExchangeMessage::ServerInfo(server_info)) => {
+                                if server_info.is_compatible_with(PROTOCOL_VERSION) {
+                                    log::info!(
+                                        "Connected to lamarrs server {}",
+                                        server_info.server_version
+                                    )
+                                } else {
+                                    log::error!(
+                                        "Lamarrs server speaks protocol version {}, but this page speaks {}",
+                                        server_info.protocol_version,
+                                        PROTOCOL_VERSION
+                                    )
                                 }
-                                Err(_) => todo!(),
-                                _ => {
-                                    log::info!("Notification Message Received: {}", payload);
+                            }
+                            Ok(ExchangeMessage::Ack(request_id, result)) => {
+                                log::info!("Request {:?} was successful: {:?}", request_id, result)
+                            }
+                            Ok(ExchangeMessage::Nack(request_id, result)) => {
+                                log::warn!(
+                                    "Request {:?} was not accepted by the server: {:?}",
+                                    request_id,
+                                    result
+                                )
+                            }
+                            Ok(ExchangeMessage::Heartbeat) => {
+                                if replies.send(ExchangeMessage::HeartbeatAck).await.is_err() {
+                                    log::error!(
+                                        "Heartbeat could not be answered, the connection is lost"
+                                    );
                                 }
                             }
+                            Ok(ExchangeMessage::Error(error_description)) => {
+                                log::error!(
+                                    "An error was reported by the server: {}",
+                                    error_description.error_descr
+                                )
+                            }
+                            Ok(_) => {
+                                log::info!("Notification Message Received: {}", payload);
+                            }
+                            Err(error) => {
+                                log::warn!("Ignoring a message from the Gateway that could not be decoded: {}", error)
+                            }
                         }
-                        Err(e) => {
-                            log::error!("ERROR: {:?}", e)
-                        }
-                        _ => {
-                            log::debug!("Weird message received from Gateway")
-                        }
+                    }
+                    Err(e) => {
+                        log::error!("ERROR: {:?}", e)
+                    }
+                    _ => {
+                        log::debug!("Weird message received from Gateway")
                     }
                 }
-                log::debug!("WebSocket Closed");
             }
+            log::debug!("WebSocket Closed");
         });
 
         Self { sender }
     }
 }
+
+/// WebSocket URL of the Server the page was loaded from, as it serves both on the same port.
+fn server_url() -> String {
+    let location = web_sys::window().map(|window| window.location());
+    let host = location
+        .as_ref()
+        .and_then(|location| location.host().ok())
+        .unwrap_or_default();
+    let scheme = match location
+        .and_then(|location| location.protocol().ok())
+        .as_deref()
+    {
+        Some("https:") => "wss",
+        _ => "ws",
+    };
+    format!("{scheme}://{host}")
+}
+
+/// Milliseconds since the page was loaded.
+fn now_ms() -> f64 {
+    web_sys::window()
+        .and_then(|window| window.performance())
+        .map(|performance| performance.now())
+        .unwrap_or_default()
+}
+
+/// The colour as CSS understands it. Screens have no white channel, so it is mixed in.
+fn css_colour(colour: &ColourRgb) -> String {
+    let [r, g, b] = colour.to_rgb();
+    format!("rgb({r}, {g}, {b})")
+}